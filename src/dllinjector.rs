@@ -1,6 +1,7 @@
 mod components;
 mod injectionmethods;
 
+use components::inspector::Inspector;
 use components::processeslist::ProcessesList;
 use components::sidebar::Sidebar;
 use eframe::CreationContext;
@@ -8,6 +9,7 @@ use winapi::um::tlhelp32::PROCESSENTRY32;
 
 pub struct DllInejctorApp {
     sidebar: Sidebar,
+    inspector: Inspector,
     process_list: ProcessesList,
     state: AppState,
 }
@@ -42,6 +44,7 @@ impl DllInejctorApp {
     pub fn new(_creation_contex: &CreationContext) -> DllInejctorApp {
         return DllInejctorApp {
             sidebar: Sidebar::new(),
+            inspector: Inspector::new(),
             process_list: ProcessesList::new(),
             state: AppState::new(),
         };
@@ -54,6 +57,7 @@ impl DllInejctorApp {
         match prev_state.save_state {
             true => DllInejctorApp {
                 sidebar: Sidebar::load(storage),
+                inspector: Inspector::new(),
                 process_list: ProcessesList::load(storage),
                 state: prev_state,
             },
@@ -65,6 +69,7 @@ impl DllInejctorApp {
 impl eframe::App for DllInejctorApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         self.sidebar.show(ctx, &mut self.state);
+        self.inspector.show(ctx, self.sidebar.dll_path());
         self.process_list.show(ctx, &mut self.state);
     }
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
@@ -0,0 +1,720 @@
+use std::collections::BTreeMap;
+
+use egui::{Color32, Frame, Grid, ScrollArea, SidePanel, Ui};
+
+use crate::utils::{
+    files::{self, ValidatedDll},
+    inspection,
+    pe::{
+        authenticode::{self, AuthenticodeSignature},
+        checksum::ChecksumStatus,
+        clr::{self, ClrHeader},
+        debug::{DebugEntry, DebugInfo},
+        error::PeValidationError,
+        exceptions::{self, RuntimeFunction},
+        exports::ExportTable,
+        imports::{BoundImport, DelayImportedModule, ImportThunk, ImportedModule},
+        loadconfig::LoadConfig,
+        relocs::Relocation,
+        rich::RichHeader,
+        tls::TlsDirectory,
+    },
+};
+
+///Everything the inspector shows about a dll, parsed once when the dll is picked
+struct Inspection {
+    dll: ValidatedDll,
+    exports: Result<ExportTable, PeValidationError>,
+    imports: Result<Vec<ImportedModule>, PeValidationError>,
+    delay_imports: Result<Vec<DelayImportedModule>, PeValidationError>,
+    bound_imports: Result<Vec<BoundImport>, PeValidationError>,
+    relocations: Result<Vec<Relocation>, PeValidationError>,
+    tls: Result<Option<TlsDirectory>, PeValidationError>,
+    runtime_functions: Result<Vec<RuntimeFunction>, PeValidationError>,
+    debug_entries: Result<Vec<DebugEntry>, PeValidationError>,
+    load_config: Result<Option<LoadConfig>, PeValidationError>,
+    signature: Result<Option<AuthenticodeSignature>, PeValidationError>,
+    rich_header: Option<RichHeader>,
+    clr_header: Option<ClrHeader>,
+}
+
+impl Inspection {
+    fn load(dll_path: &String) -> Result<Inspection, PeValidationError> {
+        let dll = files::is_valid_dll(dll_path.clone())?;
+        return Ok(Inspection {
+            exports: dll.image.exports(),
+            imports: dll.image.imports(),
+            delay_imports: dll.image.delay_imports(),
+            bound_imports: dll.image.bound_imports(),
+            relocations: dll.image.relocations(),
+            tls: dll.image.tls(),
+            runtime_functions: dll.image.runtime_functions(),
+            debug_entries: dll.image.debug_entries(),
+            load_config: dll.image.load_config(),
+            signature: dll.image.authenticode(),
+            rich_header: dll.image.rich_header(),
+            //validation already rejected a malformed CLR header
+            clr_header: dll.image.clr_header().unwrap_or_default(),
+            dll,
+        });
+    }
+}
+
+pub struct Inspector {
+    dll_path: Option<String>,
+    inspection: Option<Result<Inspection, PeValidationError>>,
+    export_filter: String,
+    function_lookup: String,
+}
+
+impl Inspector {
+    pub fn new() -> Inspector {
+        return Inspector {
+            dll_path: None,
+            inspection: None,
+            export_filter: String::default(),
+            function_lookup: String::default(),
+        };
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, dll_path: Option<&String>) {
+        //only parse the dll again when a different one is picked
+        if self.dll_path.as_ref() != dll_path {
+            self.dll_path = dll_path.cloned();
+            self.inspection = dll_path.map(Inspection::load);
+        }
+
+        SidePanel::right("Right SidePanel")
+            .frame(Frame::default().fill(Color32::LIGHT_YELLOW))
+            .show(ctx, |ui| {
+                ui.label("Dll Inspector");
+
+                match &self.inspection {
+                    None => {
+                        ui.label("No dll picked");
+                    }
+                    Some(Err(err)) => {
+                        ui.colored_label(Color32::RED, err.to_string());
+                    }
+                    Some(Ok(inspection)) => {
+                        if ui.button("Copy JSON report").clicked() {
+                            let dll_path = self.dll_path.as_ref().unwrap();
+                            let report = inspection::inspection_report(dll_path, &inspection.dll);
+                            ui.output().copied_text =
+                                serde_json::to_string_pretty(&report).unwrap();
+                        }
+                        ScrollArea::vertical().show(ui, |ui| {
+                            headers(ui, &inspection.dll);
+                            clr_header(ui, &inspection.clr_header);
+                            sections(ui, &inspection.dll);
+                            exports(ui, &inspection.exports, &mut self.export_filter);
+                            imports(ui, inspection);
+                            relocations(ui, &inspection.relocations);
+                            tls(ui, &inspection.tls);
+                            runtime_functions(ui, inspection, &mut self.function_lookup);
+                            debug_entries(ui, &inspection.debug_entries);
+                            load_config(ui, inspection);
+                            signature(ui, &inspection.signature);
+                            rich_header(ui, &inspection.rich_header);
+                        });
+                    }
+                }
+            });
+    }
+}
+
+fn headers(ui: &mut Ui, dll: &ValidatedDll) {
+    let optional_header = dll.image.optional_header();
+    ui.collapsing("Headers", |ui| {
+        Grid::new("inspector_headers").striped(true).show(ui, |ui| {
+            ui.label("Architecture");
+            ui.monospace(dll.architecture.to_string());
+            ui.end_row();
+
+            ui.label("Checksum");
+            match dll.checksum {
+                ChecksumStatus::Mismatch { .. } => {
+                    ui.colored_label(Color32::RED, dll.checksum.to_string());
+                }
+                _ => {
+                    ui.monospace(dll.checksum.to_string());
+                }
+            }
+            ui.end_row();
+
+            ui.label("Image base");
+            ui.monospace(format!("0x{:x}", optional_header.image_base));
+            ui.end_row();
+
+            ui.label("Entry point");
+            ui.monospace(format!("0x{:x}", optional_header.address_of_entry_point));
+            ui.end_row();
+
+            ui.label("Size of image");
+            ui.monospace(format!("0x{:x}", optional_header.size_of_image));
+            ui.end_row();
+
+            ui.label("Size of headers");
+            ui.monospace(format!("0x{:x}", optional_header.size_of_headers));
+            ui.end_row();
+        });
+    });
+}
+
+fn sections(ui: &mut Ui, dll: &ValidatedDll) {
+    ui.collapsing("Sections", |ui| {
+        Grid::new("inspector_sections")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("Name");
+                ui.label("RVA");
+                ui.label("Virtual size");
+                ui.label("Raw size");
+                ui.label("Characteristics");
+                ui.label("Entropy");
+                ui.end_row();
+
+                //the analysis has one entry per section header, in the same order
+                for (section, analysis) in dll.image.sections().iter().zip(&dll.packing.sections) {
+                    ui.monospace(section.name());
+                    ui.monospace(format!("0x{:x}", section.virtual_address));
+                    ui.monospace(format!("0x{:x}", section.virtual_size));
+                    ui.monospace(format!("0x{:x}", section.size_of_raw_data));
+                    match analysis.writable_and_executable || analysis.empty_on_disk {
+                        true => ui.colored_label(
+                            Color32::RED,
+                            format!("0x{:08x}", section.characteristics),
+                        ),
+                        false => ui.monospace(format!("0x{:08x}", section.characteristics)),
+                    };
+                    match analysis.high_entropy() {
+                        true => ui.colored_label(Color32::RED, format!("{:.2}", analysis.entropy)),
+                        false => ui.monospace(format!("{:.2}", analysis.entropy)),
+                    };
+                    ui.end_row();
+                }
+            });
+        if let Some(overlay) = dll.packing.overlay {
+            ui.colored_label(
+                Color32::RED,
+                format!(
+                    "Overlay of 0x{:x} bytes at file offset 0x{:x}",
+                    overlay.size, overlay.offset
+                ),
+            );
+        }
+    });
+}
+
+fn exports(ui: &mut Ui, exports: &Result<ExportTable, PeValidationError>, filter: &mut String) {
+    let table = match exports {
+        Ok(table) => table,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    ui.collapsing(format!("Exports ({})", table.exports.len()), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Filter:");
+            ui.text_edit_singleline(filter);
+        });
+
+        Grid::new("inspector_exports").striped(true).show(ui, |ui| {
+            ui.label("Ordinal");
+            ui.label("Name");
+            ui.label("RVA / Forwarder");
+            ui.end_row();
+
+            let filter = filter.to_ascii_lowercase();
+            for export in &table.exports {
+                let name = export.name.clone().unwrap_or_default();
+                if !name.to_ascii_lowercase().contains(&filter) {
+                    continue;
+                }
+
+                ui.monospace(export.ordinal.to_string());
+                ui.monospace(name);
+                match &export.forwarder {
+                    Some(forwarder) => ui.monospace(format!("-> {forwarder}")),
+                    None => ui.monospace(format!("0x{:x}", export.rva)),
+                };
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn imports(ui: &mut Ui, inspection: &Inspection) {
+    match &inspection.imports {
+        Ok(modules) => {
+            ui.collapsing(format!("Imports ({} modules)", modules.len()), |ui| {
+                for module in modules {
+                    thunks(ui, &module.name, &module.thunks);
+                }
+            });
+        }
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+        }
+    }
+
+    match &inspection.delay_imports {
+        Ok(modules) if modules.is_empty() => {}
+        Ok(modules) => {
+            ui.collapsing(format!("Delay imports ({} modules)", modules.len()), |ui| {
+                for module in modules {
+                    thunks(ui, &module.name, &module.thunks);
+                }
+            });
+        }
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+        }
+    }
+
+    match &inspection.bound_imports {
+        Ok(bound) if bound.is_empty() => {}
+        Ok(bound) => {
+            ui.collapsing(format!("Bound imports ({})", bound.len()), |ui| {
+                for module in bound {
+                    ui.monospace(format!(
+                        "{} (0x{:08x})",
+                        module.name, module.time_date_stamp
+                    ));
+                    for forwarder in &module.forwarder_refs {
+                        ui.monospace(format!(
+                            "    -> {} (0x{:08x})",
+                            forwarder.name, forwarder.time_date_stamp
+                        ));
+                    }
+                }
+            });
+        }
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+        }
+    }
+}
+
+fn thunks(ui: &mut Ui, module: &String, thunks: &Vec<ImportThunk>) {
+    ui.collapsing(format!("{module} ({})", thunks.len()), |ui| {
+        for thunk in thunks {
+            ui.monospace(format!("0x{:x}  {}", thunk.iat_rva, thunk.target));
+        }
+    });
+}
+
+fn relocations(ui: &mut Ui, relocations: &Result<Vec<Relocation>, PeValidationError>) {
+    let relocations = match relocations {
+        Ok(relocations) => relocations,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    //count how many of each type there are, listing thousands of entries is not useful
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for relocation in relocations {
+        *counts.entry(relocation.kind.name()).or_default() += 1;
+    }
+
+    ui.collapsing(format!("Relocations ({})", relocations.len()), |ui| {
+        for (kind, count) in counts {
+            ui.monospace(format!("{kind}: {count}"));
+        }
+    });
+}
+
+fn tls(ui: &mut Ui, tls: &Result<Option<TlsDirectory>, PeValidationError>) {
+    let tls = match tls {
+        Ok(Some(tls)) => tls,
+        Ok(None) => return,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    ui.collapsing("TLS", |ui| {
+        Grid::new("inspector_tls").striped(true).show(ui, |ui| {
+            ui.label("Raw data");
+            ui.monospace(format!(
+                "0x{:x} - 0x{:x}",
+                tls.start_address_of_raw_data, tls.end_address_of_raw_data
+            ));
+            ui.end_row();
+
+            ui.label("Index");
+            ui.monospace(format!("0x{:x}", tls.address_of_index));
+            ui.end_row();
+
+            ui.label("Zero fill");
+            ui.monospace(format!("0x{:x}", tls.size_of_zero_fill));
+            ui.end_row();
+
+            ui.label("Callbacks");
+            ui.monospace(format!("0x{:x}", tls.address_of_callbacks));
+            ui.end_row();
+
+            for callback in &tls.callbacks {
+                ui.label("");
+                ui.monospace(format!("RVA 0x{callback:x}"));
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn runtime_functions(ui: &mut Ui, inspection: &Inspection, lookup: &mut String) {
+    let functions = match &inspection.runtime_functions {
+        Ok(functions) if functions.is_empty() => return,
+        Ok(functions) => functions,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    ui.collapsing(format!("Exception table ({})", functions.len()), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Function at RVA:");
+            ui.text_edit_singleline(lookup);
+        });
+        if lookup.trim().is_empty() {
+            return;
+        }
+        let rva = match u32::from_str_radix(lookup.trim().trim_start_matches("0x"), 16) {
+            Ok(rva) => rva,
+            Err(_) => {
+                ui.colored_label(Color32::RED, "Not a hex rva");
+                return;
+            }
+        };
+        let function = match exceptions::lookup_function(functions, rva) {
+            Some(function) => function,
+            None => {
+                ui.label("No function covers this rva, it is a leaf function or not code");
+                return;
+            }
+        };
+        ui.monospace(format!(
+            "0x{:x} - 0x{:x}, unwind info at 0x{:x}",
+            function.begin, function.end, function.unwind_info
+        ));
+
+        let chain = match inspection.dll.image.unwind_chain(function) {
+            Ok(chain) => chain,
+            Err(err) => {
+                ui.colored_label(Color32::RED, err.to_string());
+                return;
+            }
+        };
+        for info in chain {
+            ui.separator();
+            ui.monospace(format!(
+                "v{} flags 0x{:x}, prolog 0x{:x} bytes, frame register {}",
+                info.version,
+                info.flags,
+                info.size_of_prolog,
+                info.frame_register_name().unwrap_or("none")
+            ));
+            for code in &info.codes {
+                ui.monospace(format!("  +0x{:02x} {}", code.prolog_offset, code.op));
+            }
+            if let Some(handler) = info.handler {
+                ui.monospace(format!(
+                    "Handler 0x{:x}, data at 0x{:x}",
+                    handler.rva, handler.data_rva
+                ));
+            }
+            if let Some(chained) = info.chained {
+                ui.monospace(format!(
+                    "Chained to 0x{:x} - 0x{:x}",
+                    chained.begin, chained.end
+                ));
+            }
+        }
+    });
+}
+
+fn debug_entries(ui: &mut Ui, entries: &Result<Vec<DebugEntry>, PeValidationError>) {
+    let entries = match entries {
+        Ok(entries) if entries.is_empty() => return,
+        Ok(entries) => entries,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    ui.collapsing(format!("Debug ({})", entries.len()), |ui| {
+        for entry in entries {
+            match &entry.info {
+                DebugInfo::CodeView(codeview) => {
+                    ui.label("CodeView");
+                    Grid::new("inspector_codeview")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.label("Pdb");
+                            ui.monospace(&codeview.pdb_path);
+                            ui.end_row();
+
+                            ui.label("Guid");
+                            ui.monospace(codeview.guid.to_string());
+                            ui.end_row();
+
+                            ui.label("Age");
+                            ui.monospace(codeview.age.to_string());
+                            ui.end_row();
+
+                            ui.label("Symbol key");
+                            ui.monospace(codeview.symbol_key());
+                            ui.end_row();
+                        });
+                }
+                DebugInfo::Pogo { signature, entries } => {
+                    ui.collapsing(format!("POGO {signature} ({})", entries.len()), |ui| {
+                        for pogo in entries {
+                            ui.monospace(format!(
+                                "0x{:x} 0x{:x} {}",
+                                pogo.rva, pogo.size, pogo.name
+                            ));
+                        }
+                    });
+                }
+                DebugInfo::Repro(hash) => {
+                    let hash: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+                    ui.monospace(format!("REPRO {hash}"));
+                }
+                DebugInfo::Raw => {
+                    ui.monospace(format!(
+                        "{} (0x{:x} bytes)",
+                        entry.kind_name(),
+                        entry.size_of_data
+                    ));
+                }
+            }
+        }
+    });
+}
+
+fn load_config(ui: &mut Ui, inspection: &Inspection) {
+    ui.collapsing("Mitigations", |ui| {
+        for (name, enabled) in inspection.dll.image.mitigation_policy().flags() {
+            let color = match enabled {
+                true => Color32::DARK_GREEN,
+                false => Color32::GRAY,
+            };
+            ui.colored_label(color, name);
+        }
+    });
+
+    let load_config = match &inspection.load_config {
+        Ok(Some(load_config)) => load_config,
+        Ok(None) => return,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    let optional = |value: Option<u64>| match value {
+        Some(value) => format!("0x{value:x}"),
+        None => "-".to_string(),
+    };
+    ui.collapsing("Load config", |ui| {
+        Grid::new("inspector_load_config")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("Size");
+                ui.monospace(format!("0x{:x}", load_config.size));
+                ui.end_row();
+
+                ui.label("Security cookie");
+                ui.monospace(optional(load_config.security_cookie));
+                ui.end_row();
+
+                ui.label("SEHandlerTable");
+                ui.monospace(format!(
+                    "{} ({} handlers)",
+                    optional(load_config.se_handler_table),
+                    load_config.se_handlers.len()
+                ));
+                ui.end_row();
+
+                ui.label("GuardCFFunctionTable");
+                ui.monospace(format!(
+                    "{} ({} targets)",
+                    optional(load_config.guard_cf_function_table),
+                    load_config.guard_cf_functions.len()
+                ));
+                ui.end_row();
+
+                ui.label("GuardFlags");
+                ui.monospace(optional(load_config.guard_flags.map(|flags| flags as u64)));
+                ui.end_row();
+
+                ui.label("Dynamic relocations");
+                ui.monospace(match &load_config.dynamic_relocation_table {
+                    Some(table) => format!(
+                        "RVA 0x{:x} v{} 0x{:x} bytes",
+                        table.rva, table.version, table.size
+                    ),
+                    None => "-".to_string(),
+                });
+                ui.end_row();
+            });
+    });
+}
+
+fn signature(ui: &mut Ui, signature: &Result<Option<AuthenticodeSignature>, PeValidationError>) {
+    let signature = match signature {
+        Ok(Some(signature)) => signature,
+        Ok(None) => return,
+        Err(err) => {
+            ui.colored_label(Color32::RED, err.to_string());
+            return;
+        }
+    };
+
+    ui.collapsing("Signature", |ui| {
+        Grid::new("inspector_signature")
+            .striped(true)
+            .show(ui, |ui| {
+                if let Some(signer) = &signature.signer {
+                    ui.label("Subject");
+                    ui.monospace(&signer.subject);
+                    ui.end_row();
+
+                    ui.label("Issuer");
+                    ui.monospace(&signer.issuer);
+                    ui.end_row();
+
+                    ui.label("Serial");
+                    ui.monospace(&signer.serial);
+                    ui.end_row();
+
+                    ui.label("Timestamp");
+                    ui.monospace(signer.timestamp.as_deref().unwrap_or("-"));
+                    ui.end_row();
+                }
+
+                ui.label("Algorithm");
+                ui.monospace(match signature.digest_algorithm {
+                    Some(algorithm) => algorithm.to_string(),
+                    None => "unsupported".to_string(),
+                });
+                ui.end_row();
+
+                ui.label("Signed digest");
+                ui.monospace(authenticode::hex(&signature.signed_digest));
+                ui.end_row();
+
+                ui.label("File digest");
+                match &signature.computed_digest {
+                    Some(digest) => {
+                        let color = match signature.digest_matches() {
+                            true => Color32::DARK_GREEN,
+                            false => Color32::RED,
+                        };
+                        ui.colored_label(color, authenticode::hex(digest));
+                    }
+                    None => {
+                        ui.monospace("-");
+                    }
+                }
+                ui.end_row();
+
+                ui.label("Certificates");
+                ui.monospace(signature.certificate_count.to_string());
+                ui.end_row();
+            });
+    });
+}
+
+fn rich_header(ui: &mut Ui, rich_header: &Option<RichHeader>) {
+    let rich_header = match rich_header {
+        Some(rich_header) => rich_header,
+        None => return,
+    };
+
+    ui.collapsing(
+        format!("Rich header ({})", rich_header.entries.len()),
+        |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Key");
+                ui.monospace(format!("0x{:08x}", rich_header.key));
+            });
+            //a mismatch means the header was edited after linking, or copied from another binary
+            match rich_header.checksum_valid() {
+                true => ui.colored_label(Color32::DARK_GREEN, "Checksum matches"),
+                false => ui.colored_label(
+                    Color32::RED,
+                    format!(
+                        "Checksum mismatch, computed 0x{:08x}",
+                        rich_header.computed_checksum
+                    ),
+                ),
+            };
+
+            Grid::new("inspector_rich_header")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("Product");
+                    ui.label("Build");
+                    ui.label("Count");
+                    ui.label("Toolset");
+                    ui.end_row();
+
+                    for entry in &rich_header.entries {
+                        ui.monospace(match entry.product_name() {
+                            Some(name) => name.to_string(),
+                            None => format!("0x{:04x}", entry.product_id),
+                        });
+                        ui.monospace(entry.build.to_string());
+                        ui.monospace(entry.count.to_string());
+                        ui.monospace(entry.toolset().unwrap_or("-"));
+                        ui.end_row();
+                    }
+                });
+        },
+    );
+}
+
+fn clr_header(ui: &mut Ui, clr_header: &Option<ClrHeader>) {
+    let clr_header = match clr_header {
+        Some(clr_header) => clr_header,
+        None => return,
+    };
+
+    ui.collapsing(format!("CLR ({})", clr_header.kind()), |ui| {
+        Grid::new("inspector_clr").striped(true).show(ui, |ui| {
+            ui.label("Runtime header");
+            ui.monospace(format!(
+                "{}.{}",
+                clr_header.major_runtime_version, clr_header.minor_runtime_version
+            ));
+            ui.end_row();
+
+            ui.label("Metadata version");
+            ui.monospace(clr_header.metadata_version.as_deref().unwrap_or("-"));
+            ui.end_row();
+
+            let flags = [
+                ("IL only", clr::COMIMAGE_FLAGS_ILONLY),
+                ("32 bit required", clr::COMIMAGE_FLAGS_32BITREQUIRED),
+                ("32 bit preferred", clr::COMIMAGE_FLAGS_32BITPREFERRED),
+                ("Strong name signed", clr::COMIMAGE_FLAGS_STRONGNAMESIGNED),
+                ("Native entry point", clr::COMIMAGE_FLAGS_NATIVE_ENTRYPOINT),
+            ];
+            for (name, flag) in flags {
+                ui.label(name);
+                ui.monospace((clr_header.flags & flag != 0).to_string());
+                ui.end_row();
+            }
+        });
+    });
+}
@@ -1,2 +1,3 @@
+pub mod inspector;
 pub mod processeslist;
 pub mod sidebar;
@@ -1,17 +1,44 @@
-use crate::dllinjector::{components::processeslist::sz_exe_to_string, injectionmethods, AppState};
+use crate::dllinjector::{
+    components::processeslist::sz_exe_to_string,
+    injectionmethods::{self, manualmap::ManualMapOptions, InjectionError},
+    AppState,
+};
+use crate::utils::{
+    dependencies::{self, SearchPath},
+    files,
+    pe::{
+        authenticode::AuthenticodeSignature, checksum::ChecksumStatus, clr::ManagedKind,
+        debug::CodeView, error::PeValidationError, version::VersionInfo,
+    },
+};
 use egui::{
-    Align2, Color32, ComboBox, Frame, Id, LayerId, Order, RichText, SidePanel, TextStyle, Ui,
+    Align2, Button, Color32, ComboBox, Frame, Grid, Id, LayerId, Order, RichText, SidePanel,
+    TextStyle, Ui,
 };
 use std::fmt::Write;
+use std::path::Path;
+use std::rc::Rc;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
 pub struct Sidebar {
     injection_type: InjectionTypes,
+    manual_map_options: ManualMapOptions,
     injection_msg: Option<RichText>,
     dll_path: Option<String>,
+    //the dll the cached info below belongs to
+    inspected_dll: Option<String>,
+    manual_map_warnings: Vec<String>,
+    managed: Option<ManagedKind>,
+    version_info: Option<VersionInfo>,
+    codeview: Option<CodeView>,
+    signature_msg: Option<RichText>,
+    checksum: Option<ChecksumStatus>,
+    dependency_problems: Vec<String>,
 }
 
+const WARNING_COLOR: Color32 = Color32::from_rgb(160, 80, 0);
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, EnumIter)]
 enum InjectionTypes {
     Native,
@@ -46,8 +73,17 @@ impl Sidebar {
     pub fn new() -> Sidebar {
         return Sidebar {
             injection_type: InjectionTypes::Native,
+            manual_map_options: ManualMapOptions::default(),
             injection_msg: None,
             dll_path: None,
+            inspected_dll: None,
+            manual_map_warnings: Vec::new(),
+            managed: None,
+            version_info: None,
+            codeview: None,
+            signature_msg: None,
+            checksum: None,
+            dependency_problems: Vec::new(),
         };
     }
     pub fn show(&mut self, ctx: &egui::Context, app_state: &mut AppState) -> () {
@@ -65,8 +101,11 @@ impl Sidebar {
                 ));
 
                 self.injection_selection(ui);
+                self.manual_map_selection(ui);
                 self.file_selector(ui);
                 self.file_dropper(ctx);
+                self.refresh_dll_info();
+                self.dll_warnings(ui);
                 self.injection_button(app_state, ui);
 
                 if !self.injection_msg.is_none() {
@@ -80,6 +119,96 @@ impl Sidebar {
             });
     }
 
+    ///the dll that is currently picked
+    pub fn dll_path(&self) -> Option<&String> {
+        return self.dll_path.as_ref();
+    }
+
+    ///re-reads the picked dll when it changes
+    fn refresh_dll_info(&mut self) {
+        if self.inspected_dll == self.dll_path {
+            return;
+        }
+        self.inspected_dll = self.dll_path.clone();
+        self.manual_map_warnings = Vec::new();
+        self.managed = None;
+        self.version_info = None;
+        self.codeview = None;
+        self.signature_msg = None;
+        self.checksum = None;
+        self.dependency_problems = Vec::new();
+
+        let dll = match &self.dll_path {
+            Some(dll_path) => match files::is_valid_dll(dll_path.clone()) {
+                Ok(dll) => dll,
+                Err(_) => return,
+            },
+            None => return,
+        };
+        self.manual_map_warnings = injectionmethods::manualmap::preflight_warnings(&dll);
+        self.managed = Some(dll.managed);
+        self.version_info = match dll.image.version_info() {
+            Ok(version_info) => version_info,
+            Err(err) => {
+                println!("[{}] {err}", err.code());
+                None
+            }
+        };
+        self.codeview = dll.image.codeview().unwrap_or_default();
+        self.signature_msg = Some(signature_msg(dll.image.authenticode()));
+        self.checksum = Some(dll.checksum);
+
+        //both injection methods need every dependency to be loadable
+        let dll_path = Path::new(self.dll_path.as_ref().unwrap());
+        let search_path = SearchPath::new(
+            dll_path,
+            &[],
+            dependencies::system_dir(dll.architecture),
+            dependencies::system_api_set(dll.architecture).map(Rc::new),
+        );
+        self.dependency_problems = match dependencies::walk_dependencies(dll_path, &search_path) {
+            Ok(tree) => tree.problems(),
+            Err(err) => vec![err.to_string()],
+        };
+        for problem in &self.dependency_problems {
+            println!("Warning: {problem}");
+        }
+    }
+
+    ///why the picked dll cannot be injected with the selected method
+    fn blocked_reason(&self) -> Option<String> {
+        match (&self.injection_type, self.managed) {
+            (InjectionTypes::ManualMap, Some(kind)) if kind.is_managed() => Some(format!(
+                "Dll is a {kind} assembly, its code only runs once the CLR loads it which manual mapping never does, use native injection instead"
+            )),
+            _ => None,
+        }
+    }
+
+    fn dll_warnings(&self, ui: &mut Ui) {
+        for problem in &self.dependency_problems {
+            ui.label(RichText::new(problem).color(WARNING_COLOR));
+        }
+        if let Some(reason) = self.blocked_reason() {
+            ui.label(RichText::new(reason).color(Color32::RED));
+            return;
+        }
+        if self.injection_type == InjectionTypes::Native
+            && self.managed == Some(ManagedKind::PureIl)
+        {
+            ui.label(
+                RichText::new("Dll is pure IL, LoadLibrary maps it but none of its code runs unless the target already hosts the CLR")
+                    .color(WARNING_COLOR),
+            );
+        }
+        if self.injection_type != InjectionTypes::ManualMap {
+            return;
+        }
+        for warning in &self.manual_map_warnings {
+            ui.label(RichText::new(warning).color(WARNING_COLOR));
+        }
+    }
+
     fn injection_selection(&mut self, ui: &mut Ui) {
         ComboBox::from_label("Select Injection Type")
             .selected_text(&*self.injection_type.to_string())
@@ -97,12 +226,36 @@ impl Sidebar {
             });
     }
 
+    fn manual_map_selection(&mut self, ui: &mut Ui) {
+        if self.injection_type != InjectionTypes::ManualMap {
+            return;
+        }
+        ui.checkbox(
+            &mut self.manual_map_options.bind_imports,
+            "Resolve imports from the injector",
+        )
+        .on_hover_text("Fills the IAT before the image is written, a missing import fails the injection by name instead of crashing the target");
+    }
+
     fn file_selector(&mut self, ui: &mut Ui) {
         if let Some(picked_path) = &self.dll_path {
             ui.horizontal(|ui| {
                 ui.label("Picked file:");
                 ui.monospace(picked_path);
             });
+            //version info of the last dll that was read, the path may have just changed
+            if self.inspected_dll.as_ref() == Some(picked_path) {
+                if let Some(version_info) = &self.version_info {
+                    version_details(ui, version_info);
+                }
+                if let Some(signature_msg) = &self.signature_msg {
+                    ui.label(signature_msg.clone());
+                }
+                //most dlls are linked without a checksum, only a wrong one is worth pointing out
+                if let Some(checksum @ ChecksumStatus::Mismatch { .. }) = self.checksum {
+                    ui.label(RichText::new(format!("Dll {checksum}")).color(WARNING_COLOR));
+                }
+            }
         }
         if ui.button("Open file…").clicked() {
             if let Some(path) = rfd::FileDialog::new()
@@ -166,26 +319,30 @@ impl Sidebar {
     }
 
     fn injection_button(&mut self, app_state: &AppState, ui: &mut Ui) {
-        if ui.button("Inject").clicked() {
-            self.injection_msg = match app_state.selected_process {
-                Some(proc) => match self.injection_type {
-                    InjectionTypes::Native => {
-                        injectionmethods::native::inject(
-                            proc,
-                            self.dll_path.as_ref().unwrap().clone(),
-                        );
-                        Some(RichText::new("Injecting with native").color(Color32::GREEN))
-                    }
-                    InjectionTypes::ManualMap => {
+        let blocked = self.blocked_reason().is_some();
+        if ui.add_enabled(!blocked, Button::new("Inject")).clicked() {
+            self.injection_msg = match (app_state.selected_process, &self.dll_path) {
+                (Some(proc), Some(dll_path)) => match self.injection_type {
+                    InjectionTypes::Native => Some(injection_result_msg(
+                        "native",
+                        injectionmethods::native::inject(proc, dll_path.clone())
+                            .map(|_| String::new()),
+                        self.codeview.as_ref(),
+                    )),
+                    InjectionTypes::ManualMap => Some(injection_result_msg(
+                        "mm",
                         injectionmethods::manualmap::inject(
                             proc,
-                            self.dll_path.as_ref().unwrap().clone(),
-                        );
-                        Some(RichText::new("Injecting with mm").color(Color32::GREEN))
-                    }
+                            dll_path.clone(),
+                            self.manual_map_options,
+                        )
+                        .map(|report| report.to_string()),
+                        self.codeview.as_ref(),
+                    )),
                     _ => Some(RichText::new("Unknown Injection Type").color(Color32::RED)),
                 },
-                _ => Some(RichText::new("No Selected Process").color(Color32::RED)),
+                (None, _) => Some(RichText::new("No Selected Process").color(Color32::RED)),
+                (_, None) => Some(RichText::new("No Selected Dll").color(Color32::RED)),
             };
         };
     }
@@ -199,6 +356,10 @@ impl Sidebar {
             "sidebar_injection_type",
             self.injection_type.to_string().to_owned(),
         );
+        storage.set_string(
+            "sidebar_bind_imports",
+            self.manual_map_options.bind_imports.to_string(),
+        );
     }
 
     pub fn load(storage: &dyn eframe::Storage) -> Sidebar {
@@ -209,8 +370,107 @@ impl Sidebar {
                     .unwrap_or_default()
                     .as_str(),
             ),
+            manual_map_options: ManualMapOptions {
+                bind_imports: storage.get_string("sidebar_bind_imports").as_deref() == Some("true"),
+            },
             injection_msg: None,
             dll_path: storage.get_string("sidebar_last_dll"),
+            inspected_dll: None,
+            manual_map_warnings: Vec::new(),
+            managed: None,
+            version_info: None,
+            codeview: None,
+            signature_msg: None,
+            checksum: None,
+            dependency_problems: Vec::new(),
         }
     }
 }
+
+///turns the result of an injection into the message shown under the inject button, along with
+///whatever details the injection method reported
+///
+///the pdb identity of the dll is part of the report so a crash in the target can be matched to the
+///exact build that was injected
+fn injection_result_msg(
+    method: &str,
+    result: Result<String, InjectionError>,
+    codeview: Option<&CodeView>,
+) -> RichText {
+    let pdb = match codeview {
+        Some(codeview) => format!("Pdb: {codeview}"),
+        None => "Pdb: none".to_string(),
+    };
+    println!("{pdb}");
+
+    match result {
+        Ok(details) if details.is_empty() => {
+            RichText::new(format!("Injected with {method}\n{pdb}")).color(Color32::GREEN)
+        }
+        Ok(details) => {
+            println!("{details}");
+            RichText::new(format!("Injected with {method}\n{details}\n{pdb}")).color(Color32::GREEN)
+        }
+        Err(err) => {
+            println!("[{}] {err}", err.code());
+            RichText::new(format!("{err}\n{pdb}")).color(Color32::RED)
+        }
+    }
+}
+
+///whether the dll is signed and still hashes to what was signed
+fn signature_msg(signature: Result<Option<AuthenticodeSignature>, PeValidationError>) -> RichText {
+    let signature = match signature {
+        Ok(Some(signature)) => signature,
+        Ok(None) => return RichText::new("Unsigned").color(WARNING_COLOR),
+        Err(err) => return RichText::new(err.to_string()).color(Color32::RED),
+    };
+
+    let signer = match &signature.signer {
+        Some(signer) => signer.subject.clone(),
+        None => "unknown signer".to_string(),
+    };
+    return match (signature.digest_matches(), &signature.computed_digest) {
+        (true, _) => {
+            RichText::new(format!("Signed by {signer}, digest matches")).color(Color32::DARK_GREEN)
+        }
+        (false, None) => RichText::new(format!(
+            "Signed by {signer}, digest algorithm is not supported"
+        ))
+        .color(WARNING_COLOR),
+        (false, Some(_)) => RichText::new(format!(
+            "Signed by {signer} but the file was modified after signing"
+        ))
+        .color(Color32::RED),
+    };
+}
+
+///the version resource fields that tell builds of a payload apart
+fn version_details(ui: &mut Ui, version_info: &VersionInfo) {
+    let fields = [
+        ("File version", version_info.file_version()),
+        (
+            "Product name",
+            version_info.product_name().map(str::to_string),
+        ),
+        ("Company", version_info.company_name().map(str::to_string)),
+        (
+            "Original filename",
+            version_info.original_filename().map(str::to_string),
+        ),
+        (
+            "Description",
+            version_info.file_description().map(str::to_string),
+        ),
+    ];
+
+    Grid::new("sidebar_version_info").show(ui, |ui| {
+        for (label, value) in fields {
+            if let Some(value) = value {
+                ui.label(label);
+                ui.monospace(value);
+                ui.end_row();
+            }
+        }
+    });
+}
@@ -1,118 +1,155 @@
-use crate::utils;
+use super::{
+    check_architecture,
+    remote::{remote_modules, resolve_export, ProcessMemory, TargetModules},
+    InjectionError,
+};
+use crate::utils::{
+    self,
+    binding::bind_imports,
+    dependencies,
+    files::ValidatedDll,
+    pe::{mapper::map_image, protection::ProtectionRegion},
+    remote::{ExportResolver, MemoryReader},
+    stub::{
+        loader::{loader_stub, LoaderStage, LOADER_BAD_PARAMS},
+        params::{LoaderParams, LoaderStatus, LOADER_OPTION_RESOLVE_IMPORTS},
+    },
+};
+use std::{
+    fmt,
+    mem::size_of,
+    path::Path,
+    time::{Duration, Instant},
+};
 use winapi::{
     shared::{
-        basetsd::{SIZE_T, ULONG_PTR},
-        minwindef::{BOOL, DWORD, FARPROC, HINSTANCE, HMODULE, LPCVOID, LPDWORD, LPVOID, WORD},
-        ntdef::{HANDLE, LPCSTR},
+        basetsd::SIZE_T,
+        minwindef::{BOOL, DWORD, LPCVOID, LPDWORD, LPVOID},
+        ntdef::HANDLE,
     },
     um::{
         handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
-        libloaderapi::{GetModuleHandleA, GetProcAddress},
-        memoryapi::{VirtualAllocEx, VirtualFreeEx, WriteProcessMemory},
+        memoryapi::{VirtualAllocEx, VirtualFreeEx, VirtualProtectEx, WriteProcessMemory},
         minwinbase::LPSECURITY_ATTRIBUTES,
-        processthreadsapi::{CreateRemoteThreadEx, OpenProcess, LPPROC_THREAD_ATTRIBUTE_LIST},
+        processthreadsapi::{
+            CreateRemoteThreadEx, GetExitCodeThread, OpenProcess, LPPROC_THREAD_ATTRIBUTE_LIST,
+        },
+        synchapi::WaitForSingleObject,
         tlhelp32::PROCESSENTRY32,
+        winbase::WAIT_OBJECT_0,
         winnt::{
-            IMAGE_IMPORT_DESCRIPTOR_u, DLL_PROCESS_ATTACH, IMAGE_BASE_RELOCATION,
-            IMAGE_DIRECTORY_ENTRY_BASERELOC, IMAGE_DIRECTORY_ENTRY_IMPORT,
-            IMAGE_DIRECTORY_ENTRY_TLS, IMAGE_DOS_HEADER, IMAGE_FILE_HEADER, IMAGE_IMPORT_BY_NAME,
-            IMAGE_IMPORT_DESCRIPTOR, IMAGE_NT_HEADERS, IMAGE_OPTIONAL_HEADER, IMAGE_TLS_DIRECTORY,
-            MEM_COMMIT, MEM_FREE, MEM_RESERVE, PAGE_EXECUTE_READWRITE, PIMAGE_NT_HEADERS,
-            PIMAGE_SECTION_HEADER, PIMAGE_TLS_CALLBACK, PROCESS_ALL_ACCESS, PVOID,
+            MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE,
+            PAGE_READWRITE, PROCESS_ALL_ACCESS,
         },
     },
-    vc::vadefs::uintptr_t,
 };
 
-#[cfg(target_pointer_width = "64")]
-use winapi::um::winnt::{IMAGE_ORDINAL_FLAG64, IMAGE_REL_BASED_DIR64};
-
-#[cfg(target_pointer_width = "32")]
-use winapi::um::winnt::{IMAGE_ORDINAL_FLAG32, IMAGE_REL_BASED_HIGHLOW};
-
-//function pointer types
-#[allow(non_camel_case_types)]
-type f_LoadLibraryA = unsafe extern "system" fn(lpLibraryFilename: LPCSTR) -> HINSTANCE;
-#[allow(non_camel_case_types)]
-type f_GetProcAddress = unsafe extern "system" fn(hModule: HMODULE, lpProcName: LPCSTR) -> FARPROC;
-#[allow(non_camel_case_types)]
-type f_DllMain = unsafe extern "system" fn(
-    hModule: HMODULE,
-    dw_reason_for_call: DWORD,
-    lpReserved: LPVOID,
-) -> BOOL;
-
-///Data struct to be populated and passed to the loader function inside target process
-struct ManualMapLoaderData {
-    p_load_library_a: f_LoadLibraryA,
-    p_get_proc_address: f_GetProcAddress,
+///how long to wait for the loader to resolve imports and run DllMain before protecting the image
+const LOADER_TIMEOUT_MS: DWORD = 5000;
+///how often the status block of the loader is read while waiting for it
+const LOADER_POLL_MS: u64 = 10;
+
+///How a manual map injection should be carried out
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ManualMapOptions {
+    ///resolve every import from the injector and write the IAT with the image instead of having
+    ///the loader call LoadLibraryA and GetProcAddress inside the target
+    pub bind_imports: bool,
 }
 
-/// rust implementation of the cpp IMAGE_FIRST_SECTION macro
-fn image_first_section(pnt_header: PIMAGE_NT_HEADERS) -> PIMAGE_SECTION_HEADER {
-    let base = pnt_header as ULONG_PTR;
-    let off1 = memoffset::offset_of!(IMAGE_NT_HEADERS, OptionalHeader);
-    let off2 = ((unsafe { *pnt_header } as IMAGE_NT_HEADERS)
-        .FileHeader
-        .SizeOfOptionalHeader) as usize;
-    return (base + off1 + off2) as PIMAGE_SECTION_HEADER;
+///What the loader reported back for a dll it loaded
+#[derive(Debug, Clone, Copy)]
+pub struct LoaderReport {
+    ///0 when the dll has no entry point
+    pub dllmain_return: u32,
+    pub elapsed: Duration,
 }
 
-///wrapper for the pe image section names so that they can be printed nicely
-//.text
-//.rdata
-//.bss
-//etc...
-struct SectionName {
-    bytes: [u8; 8],
-}
-impl SectionName {
-    fn from(name_array: [u8; 8]) -> SectionName {
-        return SectionName { bytes: name_array };
+impl fmt::Display for LoaderReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.dllmain_return {
+            0 => write!(f, "Dll has no entry point"),
+            dllmain_return => write!(f, "DllMain returned {dllmain_return}"),
+        }?;
+        write!(f, ", loader finished in {}ms", self.elapsed.as_millis())
     }
 }
-impl std::fmt::Display for SectionName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for i in 0..8 {
-            match write!(f, "{}", self.bytes[i] as char) {
-                Ok(_) => {}
-                Err(err) => {
-                    println!("Error printing section name {err}")
-                }
+
+///things about the dll that manual mapping does not take care of, shown before injecting
+pub fn preflight_warnings(dll: &ValidatedDll) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    //packers unpack and fix up the image themselves, which assumes the real loader mapped it
+    if dll.packing.looks_packed() {
+        warnings.push(format!(
+            "This dll looks packed or self-modifying: {}",
+            dll.packing.reasons().join(", ")
+        ));
+    }
+
+    match dll.image.tls() {
+        Ok(Some(tls)) if tls.has_static_data() => warnings.push(format!(
+            "Dll uses static TLS (0x{:x} byte template, 0x{:x} bytes zero fill) which is not initialised when manual mapping",
+            tls.template_size(),
+            tls.size_of_zero_fill
+        )),
+        Ok(_) => {}
+        Err(err) => warnings.push(err.to_string()),
+    }
+
+    match dll.image.load_config() {
+        Ok(Some(load_config)) => {
+            if load_config.uses_security_cookie() {
+                warnings.push(
+                    "Dll uses a /GS security cookie which is not randomised when manual mapping"
+                        .to_string(),
+                );
+            }
+            if dll.image.mitigation_policy().guard_cf && load_config.uses_guard_cf() {
+                warnings.push(format!(
+                    "Dll is built with Control Flow Guard, its {} call targets are not registered when manual mapping",
+                    load_config.guard_cf_functions.len()
+                ));
+            }
+            //x86 exception dispatch only accepts handlers of modules the loader knows about
+            if load_config.uses_safe_seh() {
+                warnings.push("Dll uses SafeSEH, exceptions raised inside it will not reach its handlers when manual mapping".to_string());
             }
         }
-        Ok(())
+        Ok(None) => {}
+        Err(err) => warnings.push(err.to_string()),
     }
-}
 
-///takes a windows dll as a *const u8 to the bytes and returns refernces to the DOS, NT, OPTIONAL, and FILE headers
-fn get_headers_from_dll<'a>(
-    p_data: *const u8,
-) -> (
-    &'a IMAGE_DOS_HEADER,
-    &'a IMAGE_NT_HEADERS,
-    &'a IMAGE_OPTIONAL_HEADER,
-    &'a IMAGE_FILE_HEADER,
-) {
-    let dos_header: &IMAGE_DOS_HEADER = unsafe { &*(p_data as *const IMAGE_DOS_HEADER) };
-    let nt_header = unsafe {
-        &*(p_data.add(dos_header.e_lfanew.try_into().unwrap()) as *const IMAGE_NT_HEADERS)
-    };
-    let optional_header = &nt_header.OptionalHeader;
-    let file_header = &nt_header.FileHeader;
+    //RtlAddFunctionTable is never called for the mapped image so the unwinder cannot find these
+    match dll.image.runtime_functions() {
+        Ok(functions) if !functions.is_empty() => warnings.push(format!(
+            "Dll has {} x64 function table entries that need registration, exceptions thrown inside it will crash the target when manual mapping",
+            functions.len()
+        )),
+        Ok(_) => {}
+        Err(err) => warnings.push(err.to_string()),
+    }
 
-    return (dos_header, nt_header, optional_header, file_header);
+    return warnings;
 }
 
 ///Manual Map injection function
 ///
-/// Reads in and validates the dll. Then opens the target process and allocates/writes the dll sections, the dll pe headers, the loader function, and the data for the loader function. It then creates a remote thread calling the loader function
-pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
+/// Reads in and validates the dll. Then opens the target process, lays the dll out for the allocated base (sections and relocations applied) and writes it in one go along with a loader stub generated for the target's architecture, and the data for the loader stub. It then creates a remote thread calling the loader stub and once that is done gives every section its final protection
+pub fn inject(
+    proc: PROCESSENTRY32,
+    dll_path: String,
+    options: ManualMapOptions,
+) -> Result<LoaderReport, InjectionError> {
     //read in and validate dll
-    let dll_data = utils::files::is_valid_dll(dll_path.clone());
-    if !(dll_data.len() > 0) {
-        println!("Unable to read dll");
-        return false;
+    let dll = utils::files::is_valid_dll(dll_path.clone())?;
+    if dll.managed.is_managed() {
+        return Err(InjectionError::ManagedDll(dll.managed));
+    }
+    let image = &dll.image;
+    let dll_data = image.data();
+    for warning in preflight_warnings(&dll) {
+        println!("Warning: {warning}");
     }
 
     println!(
@@ -124,11 +161,18 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
     let target_proc: HANDLE =
         unsafe { OpenProcess(PROCESS_ALL_ACCESS, false as BOOL, proc.th32ProcessID) };
 
-    if target_proc == INVALID_HANDLE_VALUE {
-        println!("Unable to open target process");
-        return false;
+    if target_proc.is_null() {
+        return Err(InjectionError::OpenProcess);
     }
 
+    let process = match check_architecture(dll.architecture, target_proc) {
+        Ok(process) => process,
+        Err(err) => {
+            unsafe { CloseHandle(target_proc) };
+            return Err(err);
+        }
+    };
+
     println!(
         "Opened process [{}] {}, Handle: 0x{:x}",
         proc.th32ProcessID,
@@ -136,34 +180,49 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
         target_proc as usize
     );
 
-    //get the dll headers
-    let (dos_header, nt_header, optional_header, file_header) =
-        get_headers_from_dll(dll_data.as_ptr());
+    //the loader gets LoadLibraryA and GetProcAddress of the target's own kernel32, read out of the
+    //target's memory instead of assuming kernel32 sits at the same address as in the injector
+    let memory = ProcessMemory {
+        process: target_proc,
+    };
+    let api_set = dependencies::system_api_set(process);
+    let modules = match remote_modules(proc.th32ProcessID, &memory, process) {
+        Ok(modules) => modules,
+        Err(err) => {
+            unsafe { CloseHandle(target_proc) };
+            return Err(err);
+        }
+    };
+    let mut resolver = ExportResolver::new(&memory, modules, api_set.as_ref());
+    let resolved =
+        resolve_export(&mut resolver, "kernel32.dll", "LoadLibraryA").and_then(|load_library_a| {
+            let get_proc_address = resolve_export(&mut resolver, "kernel32.dll", "GetProcAddress")?;
+            return Ok((load_library_a, get_proc_address));
+        });
+    let (load_library_a, get_proc_address) = match resolved {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            unsafe { CloseHandle(target_proc) };
+            return Err(err);
+        }
+    };
 
-    println!(
-        "Dll dos header in host process found at 0x{:x}",
-        dos_header as *const IMAGE_DOS_HEADER as usize
-    );
-    println!(
-        "Dll nt header in host process found at 0x{:x}",
-        nt_header as *const IMAGE_NT_HEADERS as usize
-    );
-    println!(
-        "Dll optional header in host process found at 0x{:x}",
-        optional_header as *const IMAGE_OPTIONAL_HEADER as usize
-    );
+    //get the dll headers
+    let dos_header = image.dos_header();
+    let optional_header = image.optional_header();
+    let file_header = image.file_header();
 
     println!(
-        "Dll file header in host process found at 0x{:x}",
-        file_header as *const IMAGE_FILE_HEADER as usize
+        "Dll nt headers found at offset 0x{:x}, {} image, {} sections",
+        dos_header.e_lfanew, dll.architecture, file_header.number_of_sections
     );
 
     //allocate enough memory inside the target process for the dll sections/code
     let mut base_addr_ex = unsafe {
         VirtualAllocEx(
             target_proc,
-            optional_header.ImageBase as LPVOID,
-            optional_header.SizeOfImage as SIZE_T,
+            optional_header.image_base as LPVOID,
+            optional_header.size_of_image as SIZE_T,
             MEM_RESERVE | MEM_COMMIT,
             PAGE_EXECUTE_READWRITE,
         ) as *mut u8
@@ -174,7 +233,7 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
             VirtualAllocEx(
                 target_proc,
                 0 as LPVOID,
-                optional_header.SizeOfImage as SIZE_T,
+                optional_header.size_of_image as SIZE_T,
                 MEM_RESERVE | MEM_COMMIT,
                 PAGE_EXECUTE_READWRITE,
             ) as *mut u8
@@ -182,129 +241,158 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
     }
 
     if base_addr_ex as usize == 0 {
-        println!("Unable to allocate memory inside target process for dll");
         unsafe { CloseHandle(target_proc) };
+        return Err(InjectionError::AllocateMemory("dll"));
     }
     println!(
         "Allocated 0x{:x} bytes in target proc at 0x{:x}",
-        optional_header.SizeOfImage, base_addr_ex as usize
+        optional_header.size_of_image, base_addr_ex as usize
     );
 
-    let mut psection_header =
-        image_first_section(nt_header as *const IMAGE_NT_HEADERS as PIMAGE_NT_HEADERS);
+    //lay the dll out for the address it got and write it over in one go
+    let mut mapped = match map_image(image, base_addr_ex as u64) {
+        Ok(mapped) => mapped,
+        Err(err) => {
+            abort_injection(target_proc, &[base_addr_ex as LPVOID]);
+            return Err(InjectionError::MapImage(err));
+        }
+    };
+    for section_header in image.sections() {
+        println!(
+            "Mapped dll section {} (0x{:x}) at 0x{:x}",
+            section_header.name(),
+            section_header.mapped_size(),
+            base_addr_ex as usize + section_header.virtual_address as usize
+        );
+    }
 
-    //Write the dll sections to the target process
-    for _ in 0..file_header.NumberOfSections {
-        unsafe {
-            let section_header = &*psection_header;
-            println!(
-                "Found section header {} at 0x{:x}",
-                SectionName::from(section_header.Name),
-                psection_header as usize
-            );
-            //write the section so long as it has a size > 0
-            if section_header.SizeOfRawData > 0 {
-                let name = section_header.Name;
-                if WriteProcessMemory(
-                    target_proc,
-                    base_addr_ex.add(section_header.VirtualAddress as usize) as LPVOID,
-                    dll_data
-                        .as_ptr()
-                        .add(section_header.PointerToRawData as usize)
-                        as LPCVOID,
-                    section_header.SizeOfRawData as SIZE_T,
-                    0 as *mut usize,
-                ) == 0
-                {
-                    println!(
-                        "Unable to map section {} into target process memory",
-                        SectionName::from(name)
-                    );
-                    CloseHandle(target_proc);
-                    VirtualFreeEx(
-                        target_proc,
-                        base_addr_ex as LPVOID,
-                        optional_header.SizeOfImage as SIZE_T,
-                        MEM_FREE,
-                    );
-                    return false;
-                }
-                println!(
-                    "Mapped dll section {} ({}) into target process as 0x{:x}",
-                    SectionName::from(name),
-                    section_header.SizeOfRawData,
-                    base_addr_ex.add(section_header.VirtualAddress as usize) as usize
-                );
+    //fill the IAT here so a missing import fails the injection by name before anything runs
+    if options.bind_imports {
+        let importer = Path::new(&dll_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut target_modules = TargetModules {
+            process_id: proc.th32ProcessID,
+            memory: &memory,
+            architecture: process,
+            load_library_a,
+            api_set: api_set.as_ref(),
+        };
+        let bound = image
+            .imports()
+            .map_err(InjectionError::from)
+            .and_then(|imports| {
+                return bind_imports(
+                    &imports,
+                    &importer,
+                    image.pointer_size(),
+                    &mut mapped,
+                    &mut resolver,
+                    &mut target_modules,
+                )
+                .map_err(InjectionError::UnresolvedImports);
+            });
+        match bound {
+            Ok(bound) => println!(
+                "Bound {} imports from the injector, loaded {} dependencies first",
+                bound.bound,
+                bound.loaded.len()
+            ),
+            Err(err) => {
+                abort_injection(target_proc, &[base_addr_ex as LPVOID]);
+                return Err(err);
             }
-            psection_header = psection_header.add(1);
         }
     }
 
-    //write the first 0x1000 bytes of the pe headers to the target process
-    //would be better to calculate the actual size
     if unsafe {
         WriteProcessMemory(
             target_proc,
             base_addr_ex as LPVOID,
-            dll_data.as_ptr() as LPCVOID,
-            0x1000,
+            mapped.as_ptr() as LPCVOID,
+            mapped.len() as SIZE_T,
             0 as *mut SIZE_T,
         )
     } == 0
     {
-        println!("Unable to write pe headers to target process");
+        abort_injection(target_proc, &[base_addr_ex as LPVOID]);
+        return Err(InjectionError::WriteMemory("mapped image".to_string()));
     }
-    println!("Wrote pe headers to target process");
-
-    //setup the loader data
-    //get functions pointers to LoadLibraryA and GetProcAddress. The function pointers need to point to the functions withing kernel32.dll so use GetProcAddress to get the correct addresss
-    let kernel32 = unsafe { GetModuleHandleA("kernel32.dll\0".as_ptr() as LPCSTR) };
-    let mm_data = ManualMapLoaderData {
-        p_load_library_a: unsafe {
-            std::mem::transmute(GetProcAddress(
-                kernel32,
-                "LoadLibraryA\0".as_ptr() as LPCSTR,
-            ))
-        },
-        p_get_proc_address: unsafe {
-            std::mem::transmute(GetProcAddress(
-                kernel32,
-                "GetProcAddress\0".as_ptr() as LPCSTR,
-            ))
-        }
+    println!(
+        "Wrote 0x{:x} byte mapped image to target process",
+        mapped.len()
+    );
+
+    //the loader gets its own block instead of borrowing the start of the image, the headers stay
+    //intact and the status in it is zeroed for the loader to report in
+    let params_addr = unsafe {
+        VirtualAllocEx(
+            target_proc,
+            0 as LPVOID,
+            size_of::<LoaderParams>(),
+            MEM_RESERVE | MEM_COMMIT,
+            PAGE_READWRITE,
+        )
     };
+    if params_addr as usize == 0 {
+        abort_injection(target_proc, &[base_addr_ex as LPVOID]);
+        return Err(InjectionError::AllocateMemory("loader parameters"));
+    }
 
-    //write the loader data
+    let loader_options = match options.bind_imports {
+        true => 0,
+        false => LOADER_OPTION_RESOLVE_IMPORTS,
+    };
+    let loader_params = LoaderParams::new(
+        base_addr_ex as u64,
+        optional_header.size_of_image,
+        load_library_a,
+        get_proc_address,
+        loader_options,
+    )
+    .to_bytes();
+
+    //write the loader parameters
     if unsafe {
         WriteProcessMemory(
             target_proc,
-            base_addr_ex as LPVOID,
-            &mm_data as *const ManualMapLoaderData as LPCVOID,
-            std::mem::size_of::<ManualMapLoaderData>(),
+            params_addr,
+            loader_params.as_ptr() as LPCVOID,
+            loader_params.len(),
             0 as *mut SIZE_T,
         )
     } == 0
     {
-        println!("Unable to write loader data");
+        abort_injection(target_proc, &[params_addr, base_addr_ex as LPVOID]);
+        return Err(InjectionError::WriteMemory("loader parameters".to_string()));
     }
-    println!("Wrote loader data to target process");
+    println!(
+        "Wrote loader parameters to 0x{:x} inside the target process",
+        params_addr as usize
+    );
 
-    //allocate 0x1000 bytes for the loader function within the target process
+    //the loader stub is generated for the target so its exact size is known, it is made
+    //executable once written
+    let loader = loader_stub(process);
     let loader_addr = unsafe {
         VirtualAllocEx(
             target_proc,
             0 as LPVOID,
-            0x1000,
+            loader.len(),
             MEM_RESERVE | MEM_COMMIT,
-            PAGE_EXECUTE_READWRITE,
+            PAGE_READWRITE,
         )
     };
     if loader_addr as usize == 0 {
-        println!("Unable to allocate data in target process for oader funtion");
+        abort_injection(target_proc, &[params_addr, base_addr_ex as LPVOID]);
+        return Err(InjectionError::AllocateMemory("loader function"));
     }
     println!(
-        "Allocated 0x1000 bytes at 0x{:x} inside the target process for the loader function",
-        loader_addr as usize
+        "Allocated 0x{:x} bytes at 0x{:x} inside the target process for the {} loader stub",
+        loader.len(),
+        loader_addr as usize,
+        process
     );
 
     //write the loader function to the target process
@@ -312,179 +400,200 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
         WriteProcessMemory(
             target_proc,
             loader_addr,
-            loader as LPCVOID,
-            0x1000,
+            loader.as_ptr() as LPCVOID,
+            loader.len(),
             0 as *mut SIZE_T,
         )
     } == 0
     {
-        println!("Unable to write loader function to the target process");
+        abort_injection(
+            target_proc,
+            &[loader_addr, params_addr, base_addr_ex as LPVOID],
+        );
+        return Err(InjectionError::WriteMemory("loader function".to_string()));
     }
     println!("Wrote loader function to the target process");
 
-    //create a remote thread withing the target process and call the loader function
+    let mut old_protection: DWORD = 0;
     if unsafe {
+        VirtualProtectEx(
+            target_proc,
+            loader_addr,
+            loader.len(),
+            PAGE_EXECUTE_READ,
+            &mut old_protection,
+        )
+    } == 0
+    {
+        abort_injection(
+            target_proc,
+            &[loader_addr, params_addr, base_addr_ex as LPVOID],
+        );
+        return Err(InjectionError::ProtectMemory("loader function".to_string()));
+    }
+
+    //create a remote thread withing the target process and call the loader function
+    let loader_thread = unsafe {
         CreateRemoteThreadEx(
             target_proc,
             0 as LPSECURITY_ATTRIBUTES,
             0,
             std::mem::transmute(loader_addr),
-            base_addr_ex as LPVOID,
+            params_addr,
             0,
             0 as LPPROC_THREAD_ATTRIBUTE_LIST,
             0 as LPDWORD,
         )
-    } == INVALID_HANDLE_VALUE
-    {
-        println!("Unable to create remote thread inside the target process");
+    };
+    if loader_thread.is_null() || loader_thread == INVALID_HANDLE_VALUE {
+        abort_injection(
+            target_proc,
+            &[loader_addr, params_addr, base_addr_ex as LPVOID],
+        );
+        return Err(InjectionError::CreateRemoteThread);
     }
     println!("Created remote thread inside the target process");
 
-    return true;
-}
-
-unsafe extern "system" fn loader(pmm_data: *mut ManualMapLoaderData) {
-    //make sure the base address and data is a valid pointer
-    if pmm_data as usize == 0 {
-        return;
+    //the loader still writes the IAT and runs DllMain, the final protections have to wait for it
+    let started = Instant::now();
+    let waited = wait_for_loader(&memory, loader_thread, params_addr as u64);
+    //the stub may still be on its way out after reporting, its memory can only go once it is gone
+    let exit_timeout = match waited {
+        Ok(_) => LOADER_TIMEOUT_MS,
+        Err(_) => 0,
+    };
+    let exited = unsafe { WaitForSingleObject(loader_thread, exit_timeout) } == WAIT_OBJECT_0;
+    unsafe { CloseHandle(loader_thread) };
+    if exited {
+        unsafe {
+            VirtualFreeEx(target_proc, loader_addr, 0, MEM_RELEASE);
+            VirtualFreeEx(target_proc, params_addr, 0, MEM_RELEASE);
+        }
     }
-
-    //turn the function pointers back into functions for use later
-    #[allow(non_snake_case)]
-    let _LoadLibraryA = (*pmm_data).p_load_library_a;
-    #[allow(non_snake_case)]
-    let _GetProcAddress = &(*pmm_data).p_get_proc_address;
-
-
-    let base_addr = pmm_data as *const u8;
-
-    //get the dll headers again
-    let dos_header: &IMAGE_DOS_HEADER = &*(base_addr as *const IMAGE_DOS_HEADER);
-    let nt_header = &*(base_addr.add(dos_header.e_lfanew as usize) as *const IMAGE_NT_HEADERS);
-    let optional_header = &nt_header.OptionalHeader;
-    let _file_header = &nt_header.FileHeader;
-
-    #[allow(non_snake_case)]
-    let _DllMain: f_DllMain =
-        std::mem::transmute(base_addr.add(optional_header.AddressOfEntryPoint as usize));
-
-    //perform relocations if necessary
-    let loc_delta = base_addr as u64 - optional_header.ImageBase;
-    if loc_delta != 0 {
-        //make sure the dll has a valid relocation section
-        if optional_header.DataDirectory[IMAGE_DIRECTORY_ENTRY_BASERELOC as usize].Size == 0 {
-            return;
+    let status = match waited {
+        Ok(status) => status,
+        Err(err) => {
+            println!("{err}, the image is left RWX");
+            unsafe { CloseHandle(target_proc) };
+            return Err(err);
         }
+    };
+    println!(
+        "Loader finished at stage {} with code {} in {}ms",
+        status.stage(),
+        status.error,
+        started.elapsed().as_millis()
+    );
 
-        let mut preloc_data: *mut IMAGE_BASE_RELOCATION = base_addr.add(
-            optional_header.DataDirectory[IMAGE_DIRECTORY_ENTRY_BASERELOC as usize].VirtualAddress
-                as usize,
-        ) as *mut IMAGE_BASE_RELOCATION;
-        let reloc_data = &*preloc_data;
-
-        while reloc_data.VirtualAddress != 0 {
-            let number_of_entries = (reloc_data.SizeOfBlock as usize
-                - std::mem::size_of::<IMAGE_BASE_RELOCATION>())
-                / std::mem::size_of::<WORD>();
-            let mut prelative_info = preloc_data.add(1) as *const WORD;
-
-            for _ in 0..number_of_entries {
-                #[cfg(target_pointer_width = "64")]
-                if (*prelative_info >> 0x0C) == IMAGE_REL_BASED_DIR64 {
-                    let p_patch = base_addr
-                        .add(reloc_data.VirtualAddress as usize)
-                        .add((*prelative_info & 0xFFF) as usize)
-                        as *mut uintptr_t;
-                    *p_patch += loc_delta as usize;
-                }
-
-                #[cfg(target_pointer_width = "32")]
-                if (*prelative_info >> 0x0C) == IMAGE_REL_BASED_HIGHLOW {
-                    let p_patch = base_addr
-                        .add(reloc_data.VirtualAddress as usize)
-                        .add((*prelative_info & 0xFFF) as usize)
-                        as *mut uintptr_t;
-                    *p_patch += loc_delta as usize;
-                }
-                prelative_info = prelative_info.add(1);
+    let failure = match status.failure(image, process) {
+        Some(failure) => failure,
+        None => {
+            let result = apply_protections(target_proc, base_addr_ex, &image.protection_plan());
+            unsafe { CloseHandle(target_proc) };
+            return result.map(|_| LoaderReport {
+                dllmain_return: status.dllmain_return,
+                elapsed: started.elapsed(),
+            });
+        }
+    };
+    //none of the dll's code ran before the imports were done so it can go, after that its TLS
+    //callbacks or DllMain may have left something behind that still points into the image
+    match failure.stage {
+        LoaderStage::NotStarted | LoaderStage::Relocations | LoaderStage::Imports => unsafe {
+            VirtualFreeEx(target_proc, base_addr_ex as LPVOID, 0, MEM_RELEASE);
+        },
+        _ => {
+            if let Err(err) = apply_protections(target_proc, base_addr_ex, &image.protection_plan())
+            {
+                println!("{err}");
             }
-
-            preloc_data = (preloc_data as *mut u8).add(reloc_data.SizeOfBlock as usize)
-                as *mut IMAGE_BASE_RELOCATION;
         }
     }
+    unsafe { CloseHandle(target_proc) };
+    return Err(InjectionError::LoaderFailed(failure));
+}
 
-    //check the IAT for imports
-    if optional_header.DataDirectory[IMAGE_DIRECTORY_ENTRY_IMPORT as usize].Size != 0 {
-        let mut pimport_desc = base_addr.add(
-            optional_header.DataDirectory[IMAGE_DIRECTORY_ENTRY_IMPORT as usize].VirtualAddress
-                as usize,
-        ) as *const IMAGE_IMPORT_DESCRIPTOR;
-        let mut import_desc = &*pimport_desc;
-
-        //import the functions and modules if they are in the IAT
-        while import_desc.Name != 0 {
-            let sz_module = base_addr.add(import_desc.Name as usize) as *const i8;
-
-            let loaded_module = _LoadLibraryA(sz_module);
-
-            let original_first_thunk =
-                *(&import_desc.u as *const IMAGE_IMPORT_DESCRIPTOR_u as *const usize);
-            let mut p_thunk = base_addr.add(original_first_thunk) as *mut uintptr_t;
-
-            let mut p_func = base_addr.add(import_desc.FirstThunk as usize) as *mut uintptr_t;
-
-            if p_thunk as usize != 0 {
-                p_thunk = p_func;
-            }
+///frees the regions allocated in the target so far and closes the handle to it
+///
+///only for failures before the loader thread is started, nothing in the target refers to the
+///regions yet
+fn abort_injection(target_proc: HANDLE, regions: &[LPVOID]) {
+    for region in regions {
+        unsafe { VirtualFreeEx(target_proc, *region, 0, MEM_RELEASE) };
+    }
+    unsafe { CloseHandle(target_proc) };
+}
 
-            while *p_thunk != 0 {
-                #[cfg(target_pointer_width = "64")]
-                if ((*p_thunk as u64) & IMAGE_ORDINAL_FLAG64) != 0 {
-                    *p_func =
-                        _GetProcAddress(loaded_module, (*p_thunk & 0xFFFF) as *const i8) as usize;
-                } else {
-                    let import_name = base_addr.add(*p_thunk) as *const IMAGE_IMPORT_BY_NAME;
-                    *p_func =
-                        _GetProcAddress(loaded_module, &(*import_name).Name[0] as LPCSTR) as usize;
-                }
-
-                #[cfg(target_pointer_width = "32")]
-                if ((*p_thunk as u64) & IMAGE_ORDINAL_FLAG32) != 0 {
-                    *p_func =
-                        _GetProcAddress(loaded_module, (*p_thunk & 0xFFFF) as *const i8) as usize;
-                } else {
-                    let import_name = base_addr.add(*p_thunk) as *const IMAGE_IMPORT_BY_NAME;
-                    *p_func =
-                        _GetProcAddress(loaded_module, &(*import_name).Name[0] as LPCSTR) as usize;
-                }
-
-                p_thunk = p_thunk.add(1);
-                p_func = p_func.add(1);
+///reads the status in the parameter block until the loader reports back, its thread ends or
+///LOADER_TIMEOUT_MS ran out
+fn wait_for_loader(
+    memory: &ProcessMemory,
+    thread: HANDLE,
+    params: u64,
+) -> Result<LoaderStatus, InjectionError> {
+    let read_status = || {
+        let mut block = [0u8; size_of::<LoaderStatus>()];
+        return match memory.read(LoaderParams::status_address(params), &mut block) {
+            true => Some(LoaderStatus::parse(&block)),
+            false => None,
+        };
+    };
+    let timeout = Duration::from_millis(LOADER_TIMEOUT_MS as u64);
+    let started = Instant::now();
+    let mut stage = LoaderStage::NotStarted;
+    loop {
+        //checked before reading the status so a report written right before the thread ended counts
+        let ended = unsafe { WaitForSingleObject(thread, 0) } == WAIT_OBJECT_0;
+        if let Some(current) = read_status() {
+            if current.finished() {
+                return Ok(current);
             }
-            pimport_desc = pimport_desc.add(1);
-            import_desc = &*pimport_desc;
+            stage = current.stage();
+        }
+        //an exception inside the loader or the dll ends the thread without a report, so does a
+        //loader that did not accept its parameter block
+        if ended {
+            let mut exit_code: DWORD = 0;
+            unsafe { GetExitCodeThread(thread, &mut exit_code) };
+            return match exit_code {
+                LOADER_BAD_PARAMS => Err(InjectionError::LoaderParams),
+                _ => Err(InjectionError::LoaderExited(stage)),
+            };
+        }
+        if started.elapsed() >= timeout {
+            return Err(InjectionError::LoaderTimeout(stage));
         }
+        std::thread::sleep(Duration::from_millis(LOADER_POLL_MS));
     }
+}
 
-    //call the necessary TLS callbacks
-    if optional_header.DataDirectory[IMAGE_DIRECTORY_ENTRY_TLS as usize].Size != 0 {
-        let p_tls_dir = base_addr.add(
-            optional_header.DataDirectory[IMAGE_DIRECTORY_ENTRY_TLS as usize].VirtualAddress
-                as usize,
-        ) as *const IMAGE_TLS_DIRECTORY;
-
-        let mut p_tls_callback = (*p_tls_dir).AddressOfCallBacks as *const PIMAGE_TLS_CALLBACK;
-
-        while p_tls_callback as usize != 0 {
-            match *p_tls_callback {
-                Some(callback) => callback(base_addr as PVOID, DLL_PROCESS_ATTACH, 0 as PVOID),
-                None => break,
-            }
-            p_tls_callback = p_tls_callback.add(1);
+///applies the protection plan of a mapped image, everything was committed as RWX up to here
+fn apply_protections(
+    target_proc: HANDLE,
+    base_addr: *mut u8,
+    plan: &[ProtectionRegion],
+) -> Result<(), InjectionError> {
+    for region in plan {
+        let mut old_protection: DWORD = 0;
+        if unsafe {
+            VirtualProtectEx(
+                target_proc,
+                base_addr.add(region.rva as usize) as LPVOID,
+                region.size as SIZE_T,
+                region.protection.page_flags(),
+                &mut old_protection,
+            )
+        } == 0
+        {
+            return Err(InjectionError::ProtectMemory(region.name.clone()));
         }
+        println!(
+            "Protected {} (0x{:x} bytes at 0x{:x}) as {}",
+            region.name,
+            region.size,
+            base_addr as usize + region.rva as usize,
+            region.protection
+        );
     }
-
-    //call the dll main
-    _DllMain(base_addr as HMODULE, DLL_PROCESS_ATTACH, 0 as PVOID);
+    return Ok(());
 }
@@ -1,2 +1,178 @@
 pub mod manualmap;
 pub mod native;
+pub mod remote;
+
+use std::fmt;
+
+use winapi::{
+    shared::minwindef::FALSE,
+    um::{processthreadsapi::GetCurrentProcess, winnt::HANDLE, wow64apiset::IsWow64Process},
+};
+
+use crate::utils::{
+    binding::UnresolvedImport,
+    pe::{clr::ManagedKind, error::PeValidationError, headers::Architecture, mapper::MapError},
+    remote::ResolveError,
+    stub::loader::{LoaderFailure, LoaderStage},
+};
+
+///Why an injection attempt failed
+#[derive(Debug)]
+pub enum InjectionError {
+    InvalidDll(PeValidationError),
+    OpenProcess,
+    ArchitectureMismatch {
+        dll: Architecture,
+        process: Architecture,
+    },
+    UnsupportedTarget(Architecture),
+    ManagedDll(ManagedKind),
+    AllocateMemory(&'static str),
+    MapImage(MapError),
+    WriteMemory(String),
+    ProtectMemory(String),
+    ListModules,
+    ResolveExport(ResolveError),
+    UnresolvedImports(Vec<UnresolvedImport>),
+    CreateRemoteThread,
+    LoaderFailed(LoaderFailure),
+    LoaderTimeout(LoaderStage),
+    LoaderExited(LoaderStage),
+    LoaderParams,
+}
+
+impl InjectionError {
+    ///stable machine readable code for logs and scripts
+    pub fn code(&self) -> &'static str {
+        match self {
+            InjectionError::InvalidDll(err) => err.code(),
+            InjectionError::OpenProcess => "INJ_OPEN_PROCESS",
+            InjectionError::ArchitectureMismatch { .. } => "INJ_ARCHITECTURE_MISMATCH",
+            InjectionError::UnsupportedTarget(_) => "INJ_UNSUPPORTED_TARGET",
+            InjectionError::ManagedDll(_) => "INJ_MANAGED_DLL",
+            InjectionError::AllocateMemory(_) => "INJ_ALLOCATE_MEMORY",
+            InjectionError::MapImage(_) => "INJ_MAP_IMAGE",
+            InjectionError::WriteMemory(_) => "INJ_WRITE_MEMORY",
+            InjectionError::ProtectMemory(_) => "INJ_PROTECT_MEMORY",
+            InjectionError::ListModules => "INJ_LIST_MODULES",
+            InjectionError::ResolveExport(err) => err.code(),
+            InjectionError::UnresolvedImports(_) => "INJ_UNRESOLVED_IMPORT",
+            InjectionError::CreateRemoteThread => "INJ_CREATE_REMOTE_THREAD",
+            InjectionError::LoaderFailed(_) => "INJ_LOADER_FAILED",
+            InjectionError::LoaderTimeout(_) => "INJ_LOADER_TIMEOUT",
+            InjectionError::LoaderExited(_) => "INJ_LOADER_EXITED",
+            InjectionError::LoaderParams => "INJ_LOADER_PARAMS",
+        }
+    }
+}
+
+impl fmt::Display for InjectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectionError::InvalidDll(err) => write!(f, "Invalid dll: {err}"),
+            InjectionError::OpenProcess => write!(f, "Unable to open target process"),
+            InjectionError::ArchitectureMismatch { dll, process } => {
+                write!(f, "Cannot inject a {dll} dll into a {process} process")
+            }
+            InjectionError::UnsupportedTarget(process) => write!(
+                f,
+                "Injecting into a {process} process from a {} injector is not supported",
+                host_architecture()
+            ),
+            InjectionError::ManagedDll(kind) => write!(
+                f,
+                "Cannot manual map a {kind} dll, the CLR has to load it, use native injection instead"
+            ),
+            InjectionError::AllocateMemory(what) => {
+                write!(
+                    f,
+                    "Unable to allocate memory inside target process for {what}"
+                )
+            }
+            InjectionError::MapImage(err) => write!(f, "Unable to map the dll: {err}"),
+            InjectionError::WriteMemory(what) => {
+                write!(f, "Unable to write {what} to target process")
+            }
+            InjectionError::ProtectMemory(what) => {
+                write!(f, "Unable to change the protection of {what} in target process")
+            }
+            InjectionError::ListModules => {
+                write!(f, "Unable to list the modules loaded in target process")
+            }
+            InjectionError::ResolveExport(err) => write!(f, "Unable to resolve an export: {err}"),
+            InjectionError::UnresolvedImports(imports) => {
+                let imports: Vec<String> = imports.iter().map(|import| import.to_string()).collect();
+                write!(f, "Unresolved imports: {}", imports.join(", "))
+            }
+            InjectionError::CreateRemoteThread => write!(f, "Unable to create a remote thread"),
+            InjectionError::LoaderFailed(failure) => {
+                write!(f, "Loader failed during {}: {failure}", failure.stage)
+            }
+            InjectionError::LoaderTimeout(stage) => {
+                write!(f, "Loader did not finish in time, it was still in {stage}")
+            }
+            InjectionError::LoaderExited(stage) => write!(
+                f,
+                "Loader thread ended during {stage} without reporting back, the target probably crashed"
+            ),
+            InjectionError::LoaderParams => write!(
+                f,
+                "Loader rejected its parameter block, it was written for another version of the loader"
+            ),
+        }
+    }
+}
+
+impl From<PeValidationError> for InjectionError {
+    fn from(err: PeValidationError) -> Self {
+        return InjectionError::InvalidDll(err);
+    }
+}
+
+///the architecture the injector itself was built for
+pub fn host_architecture() -> Architecture {
+    #[cfg(target_pointer_width = "64")]
+    return Architecture::X64;
+
+    #[cfg(target_pointer_width = "32")]
+    return Architecture::X86;
+}
+
+///the architecture of the code running inside a process
+pub fn process_architecture(process: HANDLE) -> Architecture {
+    let is_wow64 = |process: HANDLE| -> bool {
+        let mut wow64 = FALSE;
+        return unsafe { IsWow64Process(process, &mut wow64) } != 0 && wow64 != FALSE;
+    };
+
+    if is_wow64(process) {
+        return Architecture::X86;
+    }
+
+    //a native process has the architecture of the os, a 32bit injector only runs natively on a 32bit os
+    return match host_architecture() {
+        Architecture::X64 => Architecture::X64,
+        Architecture::X86 => match is_wow64(unsafe { GetCurrentProcess() }) {
+            true => Architecture::X64,
+            false => Architecture::X86,
+        },
+    };
+}
+
+///makes sure the dll can run inside the target and that the injector can set it up from here
+///
+///exports are resolved by reading the target's memory which a 32 bit injector cannot do for a 64
+///bit process
+pub fn check_architecture(
+    dll: Architecture,
+    target_proc: HANDLE,
+) -> Result<Architecture, InjectionError> {
+    let process = process_architecture(target_proc);
+    if dll != process {
+        return Err(InjectionError::ArchitectureMismatch { dll, process });
+    }
+    if host_architecture() == Architecture::X86 && process == Architecture::X64 {
+        return Err(InjectionError::UnsupportedTarget(process));
+    }
+    return Ok(process);
+}
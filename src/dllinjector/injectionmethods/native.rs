@@ -3,11 +3,9 @@ use winapi::{
     shared::{
         basetsd::SIZE_T,
         minwindef::{FALSE, LPDWORD, LPVOID},
-        ntdef::LPCSTR,
     },
     um::{
-        handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
-        libloaderapi::{GetModuleHandleA, GetProcAddress},
+        handleapi::CloseHandle,
         memoryapi::{VirtualAllocEx, WriteProcessMemory},
         minwinbase::LPSECURITY_ATTRIBUTES,
         processthreadsapi::{CreateRemoteThreadEx, OpenProcess, LPPROC_THREAD_ATTRIBUTE_LIST},
@@ -16,36 +14,63 @@ use winapi::{
     },
 };
 
-use crate::utils;
+use super::{
+    check_architecture,
+    remote::{remote_modules, resolve_export, ProcessMemory},
+    InjectionError,
+};
+use crate::utils::{self, dependencies, remote::ExportResolver};
 
-pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
-    let dll_data = utils::files::is_valid_dll(dll_path.clone());
-    if !(dll_data.len() > 0) {
-        println!("Unable to read dll");
-        return false;
-    }
+pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> Result<(), InjectionError> {
+    let dll = utils::files::is_valid_dll(dll_path.clone())?;
 
     let target_proc: HANDLE = unsafe { OpenProcess(PROCESS_ALL_ACCESS, FALSE, proc.th32ProcessID) };
 
-    if target_proc == INVALID_HANDLE_VALUE {
-        println!("Unable to open target process");
-        return false;
+    if target_proc.is_null() {
+        return Err(InjectionError::OpenProcess);
     }
 
+    let process = match check_architecture(dll.architecture, target_proc) {
+        Ok(process) => process,
+        Err(err) => {
+            unsafe { CloseHandle(target_proc) };
+            return Err(err);
+        }
+    };
+
+    //LoadLibraryA is looked up in the target's own kernel32, which does not have to be at the
+    //same address as in the injector
+    let memory = ProcessMemory {
+        process: target_proc,
+    };
+    let api_set = dependencies::system_api_set(process);
+    let load_library_a =
+        match remote_modules(proc.th32ProcessID, &memory, process).and_then(|modules| {
+            let mut resolver = ExportResolver::new(&memory, modules, api_set.as_ref());
+            return resolve_export(&mut resolver, "kernel32.dll", "LoadLibraryA");
+        }) {
+            Ok(load_library_a) => load_library_a,
+            Err(err) => {
+                unsafe { CloseHandle(target_proc) };
+                return Err(err);
+            }
+        };
+
+    //LoadLibraryA reads the path up to its nul
+    let remote_path = format!("{dll_path}\0");
     let addr: LPVOID = unsafe {
         VirtualAllocEx(
             target_proc,
             0 as LPVOID,
-            (&dll_path).len(),
+            remote_path.len(),
             MEM_RESERVE | MEM_COMMIT,
             PAGE_EXECUTE_READWRITE,
         )
     };
 
     if (addr as usize) == 0 {
-        println!("Unable to allocte memory inside target process");
         unsafe { CloseHandle(target_proc) };
-        return false;
+        return Err(InjectionError::AllocateMemory("dll path"));
     }
 
     let mut _f: SIZE_T = 0;
@@ -53,15 +78,14 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
         WriteProcessMemory(
             target_proc,
             addr,
-            dll_path.as_ptr() as *const c_void,
-            dll_path.len(),
+            remote_path.as_ptr() as *const c_void,
+            remote_path.len(),
             &mut _f as *mut SIZE_T,
         )
     } == 0
     {
-        println!("Unable to write dll path to target process");
         unsafe { CloseHandle(target_proc) };
-        return false;
+        return Err(InjectionError::WriteMemory("dll path".to_string()));
     }
 
     let new_thread = unsafe {
@@ -69,10 +93,7 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
             target_proc,
             0 as LPSECURITY_ATTRIBUTES,
             0,
-            std::mem::transmute(GetProcAddress(
-                GetModuleHandleA("kernel32.dll\0".as_ptr() as LPCSTR),
-                "LoadLibraryA\0".as_ptr() as LPCSTR,
-            )),
+            std::mem::transmute(load_library_a as usize),
             addr,
             0,
             0 as LPPROC_THREAD_ATTRIBUTE_LIST,
@@ -80,10 +101,9 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
         )
     };
 
-    if new_thread == INVALID_HANDLE_VALUE {
-        println!("Unable to create a remote thread");
+    if new_thread.is_null() {
         unsafe { CloseHandle(target_proc) };
-        return false;
+        return Err(InjectionError::CreateRemoteThread);
     }
 
     unsafe {
@@ -91,5 +111,5 @@ pub fn inject(proc: PROCESSENTRY32, dll_path: String) -> bool {
         CloseHandle(target_proc);
     };
 
-    return true;
+    return Ok(());
 }
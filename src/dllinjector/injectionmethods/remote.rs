@@ -0,0 +1,206 @@
+use winapi::{
+    shared::{
+        basetsd::SIZE_T,
+        minwindef::{DWORD, LPCVOID, LPDWORD, LPVOID},
+        ntdef::{CHAR, HANDLE},
+    },
+    um::{
+        handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
+        memoryapi::{ReadProcessMemory, VirtualAllocEx, VirtualFreeEx, WriteProcessMemory},
+        minwinbase::LPSECURITY_ATTRIBUTES,
+        processthreadsapi::{CreateRemoteThreadEx, LPPROC_THREAD_ATTRIBUTE_LIST},
+        synchapi::WaitForSingleObject,
+        tlhelp32::{
+            CreateToolhelp32Snapshot, Module32First, Module32Next, MODULEENTRY32,
+            TH32CS_SNAPMODULE, TH32CS_SNAPMODULE32,
+        },
+        winbase::WAIT_OBJECT_0,
+        winnt::{MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_READWRITE},
+    },
+};
+
+use super::InjectionError;
+use crate::utils::{
+    binding::ModuleList,
+    dependencies::module_key,
+    pe::{
+        apiset::{self, ApiSetSchema},
+        headers::Architecture,
+    },
+    remote::{self, ExportResolver, MemoryReader, RemoteModule},
+};
+
+///how long LoadLibraryA inside the target gets to load a dependency
+const LOAD_LIBRARY_TIMEOUT_MS: DWORD = 5000;
+
+///The memory of a process opened with PROCESS_VM_READ
+pub struct ProcessMemory {
+    pub process: HANDLE,
+}
+
+impl MemoryReader for ProcessMemory {
+    fn read(&self, address: u64, buffer: &mut [u8]) -> bool {
+        let mut read: SIZE_T = 0;
+        return unsafe {
+            ReadProcessMemory(
+                self.process,
+                address as usize as LPCVOID,
+                buffer.as_mut_ptr() as LPVOID,
+                buffer.len() as SIZE_T,
+                &mut read,
+            )
+        } != 0
+            && read == buffer.len();
+    }
+}
+
+///the modules loaded in a process, only the ones of the given architecture
+///
+///a 64 bit injector sees the 64 bit ntdll and the wow64 layer of a 32 bit process as well, those
+///share names with the 32 bit modules the dll actually links against
+pub fn remote_modules(
+    process_id: DWORD,
+    memory: &ProcessMemory,
+    architecture: Architecture,
+) -> Result<Vec<RemoteModule>, InjectionError> {
+    let snapshot =
+        unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPMODULE | TH32CS_SNAPMODULE32, process_id) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        return Err(InjectionError::ListModules);
+    }
+
+    let mut modules = Vec::new();
+    let mut entry = MODULEENTRY32::default();
+    entry.dwSize = std::mem::size_of::<MODULEENTRY32>() as DWORD;
+    let mut more = unsafe { Module32First(snapshot, &mut entry) } != 0;
+    while more {
+        let base = entry.modBaseAddr as usize as u64;
+        if remote::module_architecture(memory, base) == Some(architecture) {
+            modules.push(RemoteModule {
+                name: module_name(&entry.szModule),
+                base,
+                size: entry.modBaseSize,
+            });
+        }
+        more = unsafe { Module32Next(snapshot, &mut entry) } != 0;
+    }
+    unsafe { CloseHandle(snapshot) };
+
+    //a process that has not finished starting up has no module list yet
+    if modules.is_empty() {
+        return Err(InjectionError::ListModules);
+    }
+    return Ok(modules);
+}
+
+fn module_name(name: &[CHAR]) -> String {
+    let bytes: Vec<u8> = name
+        .iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as u8)
+        .collect();
+    return String::from_utf8_lossy(&bytes).to_string();
+}
+
+///resolves a single export inside the target
+pub fn resolve_export(
+    resolver: &mut ExportResolver<ProcessMemory>,
+    module: &str,
+    name: &str,
+) -> Result<u64, InjectionError> {
+    let address = resolver
+        .resolve_name(module, name, None)
+        .map_err(InjectionError::ResolveExport)?;
+    println!("Resolved {module}!{name} to 0x{address:x} inside the target process");
+    return Ok(address);
+}
+
+///The module list of the target, dependencies are loaded with the target's own LoadLibraryA
+pub struct TargetModules<'m> {
+    pub process_id: DWORD,
+    pub memory: &'m ProcessMemory,
+    pub architecture: Architecture,
+    ///LoadLibraryA inside the target
+    pub load_library_a: u64,
+    ///tells which host a contract name ends up loading
+    pub api_set: Option<&'m ApiSetSchema>,
+}
+
+impl<'m> ModuleList for TargetModules<'m> {
+    fn modules(&self) -> Vec<RemoteModule> {
+        return remote_modules(self.process_id, self.memory, self.architecture).unwrap_or_default();
+    }
+
+    fn load(&mut self, name: &str) -> bool {
+        let process = self.memory.process;
+        let terminated = format!("{name}\0");
+
+        let remote_name = unsafe {
+            VirtualAllocEx(
+                process,
+                0 as LPVOID,
+                terminated.len(),
+                MEM_RESERVE | MEM_COMMIT,
+                PAGE_READWRITE,
+            )
+        };
+        if remote_name.is_null() {
+            return false;
+        }
+        let written = unsafe {
+            WriteProcessMemory(
+                process,
+                remote_name,
+                terminated.as_ptr() as LPCVOID,
+                terminated.len(),
+                0 as *mut SIZE_T,
+            )
+        } != 0;
+
+        let mut finished = false;
+        let mut running = false;
+        if written {
+            let thread = unsafe {
+                CreateRemoteThreadEx(
+                    process,
+                    0 as LPSECURITY_ATTRIBUTES,
+                    0,
+                    std::mem::transmute(self.load_library_a as usize),
+                    remote_name,
+                    0,
+                    0 as LPPROC_THREAD_ATTRIBUTE_LIST,
+                    0 as LPDWORD,
+                )
+            };
+            if !thread.is_null() && thread != INVALID_HANDLE_VALUE {
+                match unsafe { WaitForSingleObject(thread, LOAD_LIBRARY_TIMEOUT_MS) } {
+                    WAIT_OBJECT_0 => finished = true,
+                    _ => running = true,
+                }
+                unsafe { CloseHandle(thread) };
+            }
+        }
+
+        //a LoadLibraryA that is still running may still read the name
+        if !running {
+            unsafe { VirtualFreeEx(process, remote_name, 0, MEM_RELEASE) };
+        }
+        if !finished {
+            return false;
+        }
+
+        //the thread's exit code is the HMODULE cut to 32 bits, which is 0 for a 64 bit module at
+        //a 4GB aligned base, so the module list has the final say
+        let expected = match (self.api_set, apiset::is_api_set_name(name)) {
+            (Some(schema), true) => match schema.resolve(name, None) {
+                Some(host) => module_key(host),
+                None => return false,
+            },
+            _ => module_key(name),
+        };
+        return self
+            .modules()
+            .iter()
+            .any(|module| module_key(&module.name) == expected);
+    }
+}
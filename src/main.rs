@@ -3,7 +3,23 @@
 mod dllinjector;
 mod utils;
 
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use utils::dependencies::{self, SearchPath};
+
 fn main() {
+    //--inspect <dll> prints the JSON inspection output instead of starting the ui
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "--inspect" {
+        std::process::exit(inspect(&args[2]));
+    }
+    //--dependencies <dll> [extra dirs...] [--apiset <apisetschema.dll or dump>] prints the
+    //dependency tree
+    if args.len() >= 3 && args[1] == "--dependencies" {
+        std::process::exit(dependencies(&args[2], &args[3..]));
+    }
+
     #[cfg(not(debug_assertions))]
     let options = eframe::NativeOptions {
         icon_data: Some(utils::files::load_icon("res/icon.png")),
@@ -24,3 +40,77 @@ fn main() {
         }),
     );
 }
+
+fn inspect(dll_path: &String) -> i32 {
+    let dll = match utils::files::validate_dll(dll_path) {
+        Ok(dll) => dll,
+        Err(err) => {
+            eprintln!("[{}] {err}", err.code());
+            return 1;
+        }
+    };
+    let report = utils::inspection::inspection_report(dll_path, &dll);
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    return 0;
+}
+
+fn dependencies(dll_path: &String, extra_dirs: &[String]) -> i32 {
+    let dll = match utils::files::validate_dll(dll_path) {
+        Ok(dll) => dll,
+        Err(err) => {
+            eprintln!("[{}] {err}", err.code());
+            return 1;
+        }
+    };
+    //an explicit schema replaces the one of the system the injector runs on
+    let (extra_dirs, api_set) = match extra_dirs.iter().position(|arg| arg == "--apiset") {
+        Some(index) => {
+            let path = match extra_dirs.get(index + 1) {
+                Some(path) => path,
+                None => {
+                    eprintln!("--apiset needs a path");
+                    return 1;
+                }
+            };
+            let schema = match dependencies::load_api_set(Path::new(path)) {
+                Ok(schema) => schema,
+                Err(err) => {
+                    eprintln!("[{}] {err}", err.code());
+                    return 1;
+                }
+            };
+            let mut dirs = extra_dirs[..index].to_vec();
+            dirs.extend_from_slice(&extra_dirs[index + 2..]);
+            (dirs, Some(schema))
+        }
+        None => (
+            extra_dirs.to_vec(),
+            dependencies::system_api_set(dll.architecture),
+        ),
+    };
+    let extra_dirs: Vec<PathBuf> = extra_dirs.iter().map(PathBuf::from).collect();
+    let search_path = SearchPath::new(
+        Path::new(dll_path),
+        &extra_dirs,
+        dependencies::system_dir(dll.architecture),
+        api_set.map(Rc::new),
+    );
+    let tree = match dependencies::walk_dependencies(Path::new(dll_path), &search_path) {
+        Ok(tree) => tree,
+        Err(err) => {
+            eprintln!("[{}] {err}", err.code());
+            return 1;
+        }
+    };
+
+    print!("{}", tree.render());
+    let problems = tree.problems();
+    for problem in &problems {
+        println!("{problem}");
+    }
+    //2 keeps a missing dependency apart from a dll that could not be read at all
+    return match problems.is_empty() {
+        true => 0,
+        false => 2,
+    };
+}
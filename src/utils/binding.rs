@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::utils::dependencies::module_key;
+use crate::utils::pe::imports::{ImportTarget, ImportedModule};
+use crate::utils::remote::{ExportResolver, MemoryReader, RemoteModule, ResolveError};
+
+///The modules of the target process and a way to load more into it
+pub trait ModuleList {
+    ///every module currently loaded in the target
+    fn modules(&self) -> Vec<RemoteModule>;
+    ///has the target load a module with its own loader, false when that failed
+    ///
+    ///the real loader takes care of the module's own imports so anything it pulls in shows up in
+    ///modules() afterwards as well
+    fn load(&mut self, name: &str) -> bool;
+}
+
+///An import the injector could not find an address for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedImport {
+    pub module: String,
+    pub import: ImportTarget,
+    pub reason: ResolveError,
+}
+
+impl fmt::Display for UnresolvedImport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}!{} ({})", self.module, self.import, self.reason)
+    }
+}
+
+///What binding the imports took
+#[derive(Debug, Clone, Default)]
+pub struct BoundImports {
+    ///modules that had to be loaded into the target first
+    pub loaded: Vec<String>,
+    ///number of IAT slots written
+    pub bound: usize,
+}
+
+///fills the IAT of an image mapped for the target with addresses inside the target
+///
+///modules the image or one of the forwarders it runs into need are loaded through module_list
+///first. Every import is attempted so all of the unresolved ones are reported at once
+pub fn bind_imports<R: MemoryReader>(
+    imports: &[ImportedModule],
+    importer: &str,
+    pointer_size: usize,
+    mapped: &mut [u8],
+    resolver: &mut ExportResolver<R>,
+    module_list: &mut impl ModuleList,
+) -> Result<BoundImports, Vec<UnresolvedImport>> {
+    let mut binder = Binder {
+        resolver,
+        module_list,
+        attempted: HashSet::new(),
+        bound: BoundImports::default(),
+    };
+    let mut unresolved = Vec::new();
+
+    for module in imports {
+        for thunk in &module.thunks {
+            let address = match binder.resolve(&module.name, importer, &thunk.target) {
+                Ok(address) => address,
+                Err(reason) => {
+                    unresolved.push(UnresolvedImport {
+                        module: module.name.clone(),
+                        import: thunk.target.clone(),
+                        reason,
+                    });
+                    continue;
+                }
+            };
+            let start = thunk.iat_rva as usize;
+            //the import parser only hands out thunks inside a section, which map_image laid out
+            if let Some(slot) = mapped.get_mut(start..start + pointer_size) {
+                match pointer_size {
+                    8 => slot.copy_from_slice(&address.to_le_bytes()),
+                    _ => slot.copy_from_slice(&(address as u32).to_le_bytes()),
+                }
+                binder.bound.bound += 1;
+            }
+        }
+    }
+
+    return match unresolved.is_empty() {
+        true => Ok(binder.bound),
+        false => Err(unresolved),
+    };
+}
+
+struct Binder<'b, 'r, R: MemoryReader, M: ModuleList> {
+    resolver: &'b mut ExportResolver<'r, R>,
+    module_list: &'b mut M,
+    ///every module gets one chance to be loaded
+    attempted: HashSet<String>,
+    bound: BoundImports,
+}
+
+impl<'b, 'r, R: MemoryReader, M: ModuleList> Binder<'b, 'r, R, M> {
+    fn resolve(
+        &mut self,
+        module: &str,
+        importer: &str,
+        target: &ImportTarget,
+    ) -> Result<u64, ResolveError> {
+        if self.resolver.module(module, Some(importer)).is_none() {
+            self.load(module);
+        }
+        loop {
+            let result = match target {
+                ImportTarget::Name { name, .. } => {
+                    self.resolver.resolve_name(module, name, Some(importer))
+                }
+                ImportTarget::Ordinal(ordinal) => {
+                    self.resolver
+                        .resolve_ordinal(module, *ordinal as u32, Some(importer))
+                }
+            };
+            //a forwarder can lead to a module nothing loaded yet
+            match result {
+                Err(ResolveError::ModuleNotLoaded(missing)) if self.load(&missing) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    ///loads a module unless that was tried already, true when the module list changed
+    fn load(&mut self, name: &str) -> bool {
+        if !self.attempted.insert(module_key(name)) {
+            return false;
+        }
+        println!("Loading {name} into the target process");
+        if !self.module_list.load(name) {
+            return false;
+        }
+        self.bound.loaded.push(name.to_string());
+        self.resolver.add_modules(self.module_list.modules());
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::apiset::{ApiSetEntry, ApiSetHost, ApiSetSchema};
+    use crate::utils::pe::fixture::{by_name, FixtureExport};
+    use crate::utils::pe::headers::Architecture;
+    use crate::utils::pe::imports::ImportThunk;
+    use crate::utils::remote::fixture::FixtureMemory;
+
+    ///modules that are loaded already and ones the target could load on request
+    struct FakeModules {
+        loaded: Vec<RemoteModule>,
+        loadable: Vec<RemoteModule>,
+        requests: Vec<String>,
+    }
+
+    impl ModuleList for FakeModules {
+        fn modules(&self) -> Vec<RemoteModule> {
+            return self.loaded.clone();
+        }
+
+        fn load(&mut self, name: &str) -> bool {
+            self.requests.push(name.to_string());
+            let key = module_key(name);
+            return match self
+                .loadable
+                .iter()
+                .position(|module| module_key(&module.name) == key)
+            {
+                Some(index) => {
+                    let module = self.loadable.remove(index);
+                    self.loaded.push(module);
+                    true
+                }
+                None => false,
+            };
+        }
+    }
+
+    const A: u64 = 0x10000000;
+    const B: u64 = 0x20000000;
+    const C: u64 = 0x30000000;
+    const IAT: u32 = 0x10;
+
+    ///a.dll is loaded, b.dll and c.dll only once asked for
+    fn target() -> (FixtureMemory, FakeModules) {
+        let mut memory = FixtureMemory::default();
+        let a = memory.load(
+            Architecture::X64,
+            "a.dll",
+            A,
+            &[
+                FixtureExport::Code("a_fn", 0x1000),
+                FixtureExport::Forward("to_c", "c.c_fn"),
+            ],
+        );
+        let b = memory.load(
+            Architecture::X64,
+            "b.dll",
+            B,
+            &[FixtureExport::Code("b_fn", 0x1010)],
+        );
+        let c = memory.load(
+            Architecture::X64,
+            "c.dll",
+            C,
+            &[FixtureExport::Code("c_fn", 0x1020)],
+        );
+        let modules = FakeModules {
+            loaded: vec![a],
+            loadable: vec![b, c],
+            requests: Vec::new(),
+        };
+        return (memory, modules);
+    }
+
+    ///imports laid out back to back in one IAT starting at IAT
+    fn imports(modules: &[(&str, &[ImportTarget])], pointer_size: usize) -> Vec<ImportedModule> {
+        let mut iat_rva = IAT;
+        let mut imported = Vec::new();
+        for (name, targets) in modules {
+            let mut thunks = Vec::new();
+            for target in targets.iter() {
+                thunks.push(ImportThunk {
+                    iat_rva,
+                    target: target.clone(),
+                });
+                iat_rva += pointer_size as u32;
+            }
+            imported.push(ImportedModule {
+                name: name.to_string(),
+                time_date_stamp: 0,
+                iat_rva: thunks.first().map_or(iat_rva, |thunk| thunk.iat_rva),
+                thunks,
+            });
+        }
+        return imported;
+    }
+
+    fn slot(mapped: &[u8], index: usize) -> u64 {
+        let start = IAT as usize + index * 8;
+        return u64::from_le_bytes(mapped[start..start + 8].try_into().unwrap());
+    }
+
+    #[test]
+    fn loads_dependencies_before_binding() {
+        let (memory, mut modules) = target();
+        let mut resolver = ExportResolver::new(&memory, modules.modules(), None);
+        let imports = imports(
+            &[
+                ("a.dll", &[by_name("a_fn"), by_name("to_c")]),
+                ("B.dll", &[by_name("b_fn"), ImportTarget::Ordinal(1)]),
+            ],
+            8,
+        );
+        let mut mapped = vec![0u8; 0x100];
+
+        let bound = bind_imports(
+            &imports,
+            "x.dll",
+            8,
+            &mut mapped,
+            &mut resolver,
+            &mut modules,
+        )
+        .unwrap();
+        //c.dll is only reached through the forwarder in a.dll
+        assert_eq!(bound.loaded, vec!["c.dll".to_string(), "B.dll".to_string()]);
+        assert_eq!(bound.bound, 4);
+        assert_eq!(modules.requests, bound.loaded);
+        assert_eq!(
+            (0..4).map(|index| slot(&mapped, index)).collect::<Vec<_>>(),
+            vec![A + 0x1000, C + 0x1020, B + 0x1010, B + 0x1010]
+        );
+    }
+
+    #[test]
+    fn reports_modules_that_fail_to_load() {
+        let (memory, mut modules) = target();
+        let mut resolver = ExportResolver::new(&memory, modules.modules(), None);
+        let imports = imports(
+            &[
+                ("gone.dll", &[by_name("x"), ImportTarget::Ordinal(3)]),
+                ("a.dll", &[by_name("a_fn")]),
+            ],
+            8,
+        );
+        let mut mapped = vec![0u8; 0x100];
+
+        let unresolved = bind_imports(
+            &imports,
+            "x.dll",
+            8,
+            &mut mapped,
+            &mut resolver,
+            &mut modules,
+        )
+        .unwrap_err();
+        let not_loaded = ResolveError::ModuleNotLoaded("gone.dll".to_string());
+        assert_eq!(
+            unresolved,
+            vec![
+                UnresolvedImport {
+                    module: "gone.dll".to_string(),
+                    import: by_name("x"),
+                    reason: not_loaded.clone(),
+                },
+                UnresolvedImport {
+                    module: "gone.dll".to_string(),
+                    import: ImportTarget::Ordinal(3),
+                    reason: not_loaded,
+                },
+            ]
+        );
+        //one attempt per module, and the imports that do resolve are still written
+        assert_eq!(modules.requests, vec!["gone.dll".to_string()]);
+        assert_eq!(slot(&mapped, 2), A + 0x1000);
+    }
+
+    #[test]
+    fn names_every_unresolved_import() {
+        let (memory, mut modules) = target();
+        let mut resolver = ExportResolver::new(&memory, modules.modules(), None);
+        let imports = imports(
+            &[(
+                "a.dll",
+                &[by_name("nope"), ImportTarget::Ordinal(42), by_name("a_fn")],
+            )],
+            8,
+        );
+        let mut mapped = vec![0u8; 0x100];
+
+        let unresolved = bind_imports(
+            &imports,
+            "x.dll",
+            8,
+            &mut mapped,
+            &mut resolver,
+            &mut modules,
+        )
+        .unwrap_err();
+        assert_eq!(unresolved.len(), 2);
+        assert_eq!(unresolved[0].import, by_name("nope"));
+        assert_eq!(unresolved[1].import, ImportTarget::Ordinal(42));
+        assert_eq!(
+            unresolved[0].to_string(),
+            "a.dll!nope (a.dll does not export nope)"
+        );
+        assert_eq!(
+            unresolved[1].to_string(),
+            "a.dll!#42 (a.dll does not export #42)"
+        );
+        assert!(modules.requests.is_empty());
+        assert_eq!(slot(&mapped, 0), 0);
+    }
+
+    #[test]
+    fn binds_api_set_imports_to_the_host_of_the_importer() {
+        //both hosts are loaded and export the name, at different addresses
+        let mut memory = FixtureMemory::default();
+        let b = memory.load(
+            Architecture::X64,
+            "b.dll",
+            B,
+            &[FixtureExport::Code("shared", 0x1030)],
+        );
+        let c = memory.load(
+            Architecture::X64,
+            "c.dll",
+            C,
+            &[FixtureExport::Code("shared", 0x1040)],
+        );
+        let mut modules = FakeModules {
+            loaded: vec![b, c],
+            loadable: Vec::new(),
+            requests: Vec::new(),
+        };
+        let schema = ApiSetSchema {
+            version: 6,
+            entries: vec![ApiSetEntry {
+                name: "api-ms-win-test-l1-1-0".to_string(),
+                hashed_length: "api-ms-win-test-l1-1".len(),
+                hosts: vec![
+                    ApiSetHost {
+                        importer: None,
+                        host: "b.dll".to_string(),
+                    },
+                    ApiSetHost {
+                        importer: Some("x.dll".to_string()),
+                        host: "c.dll".to_string(),
+                    },
+                ],
+            }],
+        };
+        let imports = imports(&[("api-ms-win-test-l1-1-0.dll", &[by_name("shared")])], 8);
+
+        for (importer, address) in [("X.dll", C + 0x1040), ("y.dll", B + 0x1030)] {
+            let mut resolver = ExportResolver::new(&memory, modules.modules(), Some(&schema));
+            let mut mapped = vec![0u8; 0x100];
+            let bound = bind_imports(
+                &imports,
+                importer,
+                8,
+                &mut mapped,
+                &mut resolver,
+                &mut modules,
+            )
+            .unwrap();
+            assert_eq!(bound.bound, 1);
+            assert_eq!(slot(&mapped, 0), address, "imported by {importer}");
+        }
+        assert!(modules.requests.is_empty());
+    }
+
+    #[test]
+    fn writes_slots_at_the_pointer_width() {
+        //the 64 bit module sits above 4GB so a truncated slot would show
+        for (architecture, pointer_size, base) in [
+            (Architecture::X86, 4, 0x70000000),
+            (Architecture::X64, 8, 0x7FF812340000),
+        ] {
+            let mut memory = FixtureMemory::default();
+            let module = memory.load(
+                architecture,
+                "a.dll",
+                base,
+                &[FixtureExport::Code("a_fn", 0x1000)],
+            );
+            let mut modules = FakeModules {
+                loaded: vec![module],
+                loadable: Vec::new(),
+                requests: Vec::new(),
+            };
+            let mut resolver = ExportResolver::new(&memory, modules.modules(), None);
+            let imports = imports(
+                &[("a.dll", &[by_name("a_fn"), ImportTarget::Ordinal(1)])],
+                pointer_size,
+            );
+            let mut mapped = vec![0xAAu8; 0x100];
+
+            let bound = bind_imports(
+                &imports,
+                "x.dll",
+                pointer_size,
+                &mut mapped,
+                &mut resolver,
+                &mut modules,
+            )
+            .unwrap();
+            assert_eq!(bound.bound, 2);
+            let address: u64 = base + 0x1000;
+            let iat = IAT as usize;
+            for index in 0..2 {
+                let start = iat + index * pointer_size;
+                assert_eq!(
+                    &mapped[start..start + pointer_size],
+                    &address.to_le_bytes()[..pointer_size]
+                );
+            }
+            //nothing around the two slots is touched
+            assert_eq!(mapped[iat - 1], 0xAA);
+            assert_eq!(mapped[iat + 2 * pointer_size], 0xAA);
+        }
+    }
+}
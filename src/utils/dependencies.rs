@@ -0,0 +1,580 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::utils::pe::{
+    apiset::{self, ApiSetSchema},
+    error::PeValidationError,
+    exports::ExportTable,
+    headers::Architecture,
+    image::PeImage,
+    imports::{ImportTarget, ImportThunk},
+};
+
+///Directories a dependency is looked up in, in order
+///
+///only plain directories are searched, there is no KnownDLLs, loaded module list or PATH so the
+///same search works against a folder of fixture dlls on any os
+#[derive(Debug, Clone)]
+pub struct SearchPath {
+    pub dirs: Vec<PathBuf>,
+    ///api-ms-win-* and ext-ms-* imports go through this first, without it they are searched for
+    ///on disk like any other module
+    pub api_set: Option<Rc<ApiSetSchema>>,
+}
+
+impl SearchPath {
+    ///the dll's own directory first, then the extra directories, then the system directory
+    pub fn new(
+        dll_path: &Path,
+        extra_dirs: &[PathBuf],
+        system_dir: Option<PathBuf>,
+        api_set: Option<Rc<ApiSetSchema>>,
+    ) -> SearchPath {
+        let mut dirs = Vec::new();
+        if let Some(dll_dir) = dll_path.parent() {
+            dirs.push(dll_dir.to_path_buf());
+        }
+        dirs.extend(extra_dirs.iter().cloned());
+        dirs.extend(system_dir);
+        return SearchPath { dirs, api_set };
+    }
+}
+
+///the windows system directory dlls of the given architecture are loaded from, None off windows
+pub fn system_dir(architecture: Architecture) -> Option<PathBuf> {
+    let windows = PathBuf::from(std::env::var_os("SystemRoot")?);
+    let system32 = windows.join("System32");
+    let wow64 = windows.join("SysWOW64");
+    //a 32 bit injector gets System32 redirected to SysWOW64, Sysnative is the way around that
+    let native = match cfg!(target_pointer_width = "32") {
+        true => windows.join("Sysnative"),
+        false => system32.clone(),
+    };
+    return Some(match architecture {
+        Architecture::X86 if wow64.is_dir() => wow64,
+        Architecture::X86 => system32,
+        Architecture::X64 => native,
+    });
+}
+
+///the schema of the system the injector runs on, from apisetschema.dll in the system directory
+pub fn system_api_set(architecture: Architecture) -> Option<ApiSetSchema> {
+    let path = system_dir(architecture)?.join("apisetschema.dll");
+    return load_api_set(&path).ok();
+}
+
+///reads a schema from apisetschema.dll or from a raw dump of its .apiset section
+pub fn load_api_set(path: &Path) -> Result<ApiSetSchema, PeValidationError> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => return Err(PeValidationError::Unreadable(err.to_string())),
+    };
+    if !data.starts_with(b"MZ") {
+        return ApiSetSchema::parse(&data);
+    }
+    match PeImage::parse(&data)?.api_set_schema() {
+        Some(schema) => return schema,
+        None => return Err(PeValidationError::MalformedDirectory("api set schema")),
+    }
+}
+
+///Where a dependency was found, if anywhere
+#[derive(Debug, Clone)]
+pub enum ModuleStatus {
+    Found(PathBuf),
+    Missing,
+    ///an api set contract the schema does not know or that has no host
+    UnresolvedApiSet,
+    ///a file with the right name exists but could not be parsed
+    Invalid(PathBuf, PeValidationError),
+}
+
+///A module in the dependency tree
+#[derive(Debug, Clone)]
+pub struct DependencyNode {
+    pub name: String,
+    pub delay_loaded: bool,
+    ///the dll an api set contract was redirected to, status is about that dll
+    pub api_set_host: Option<String>,
+    pub status: ModuleStatus,
+    ///what the importing module wants from this one that it does not export
+    pub missing_imports: Vec<ImportTarget>,
+    ///false when the module is already expanded elsewhere in the tree, its children are left out
+    pub expanded: bool,
+    pub children: Vec<DependencyNode>,
+}
+
+impl DependencyNode {
+    ///one line per missing module or missing import anywhere below this node
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.collect_problems(&mut problems);
+        return problems;
+    }
+
+    fn collect_problems(&self, problems: &mut Vec<String>) {
+        for child in &self.children {
+            let delay = match child.delay_loaded {
+                true => " (delay loaded)",
+                false => "",
+            };
+            match &child.status {
+                ModuleStatus::Found(_) => {}
+                ModuleStatus::Missing => problems.push(format!(
+                    "{} needs {}{delay} which was not found",
+                    self.name, child.name
+                )),
+                ModuleStatus::UnresolvedApiSet => problems.push(format!(
+                    "{} needs api set {}{delay} which has no host",
+                    self.name, child.name
+                )),
+                ModuleStatus::Invalid(path, err) => problems.push(format!(
+                    "{} needs {}{delay} but {} is invalid: {err}",
+                    self.name,
+                    child.name,
+                    path.display()
+                )),
+            }
+            for target in &child.missing_imports {
+                problems.push(format!(
+                    "{} imports {target} from {} which does not export it",
+                    self.name, child.name
+                ));
+            }
+            child.collect_problems(problems);
+        }
+    }
+
+    ///indented text version of the tree
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_into(&mut out, 0);
+        return out;
+    }
+
+    fn render_into(&self, out: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth);
+        let delay = match self.delay_loaded {
+            true => " [delay]",
+            false => "",
+        };
+        let host = match &self.api_set_host {
+            Some(host) => format!(" => {host}"),
+            None => String::new(),
+        };
+        let status = match &self.status {
+            ModuleStatus::Found(path) => path.display().to_string(),
+            ModuleStatus::Missing => "MISSING".to_string(),
+            ModuleStatus::UnresolvedApiSet => "NO API SET HOST".to_string(),
+            ModuleStatus::Invalid(path, err) => format!("INVALID {} ({err})", path.display()),
+        };
+        let repeated = match self.expanded {
+            true => "",
+            false => " (see above)",
+        };
+        writeln!(
+            out,
+            "{indent}{}{delay}{host} -> {status}{repeated}",
+            self.name
+        )
+        .ok();
+        for target in &self.missing_imports {
+            writeln!(out, "{indent}  ! missing export {target}").ok();
+        }
+        for child in &self.children {
+            child.render_into(out, depth + 1);
+        }
+    }
+}
+
+enum Resolution {
+    Found(PathBuf, Rc<PeImage<'static>>),
+    Missing,
+    Invalid(PathBuf, PeValidationError),
+}
+
+struct Walker {
+    architecture: Architecture,
+    api_set: Option<Rc<ApiSetSchema>>,
+    ///lowercase file name to path for every search directory, in search order
+    listings: Vec<HashMap<String, PathBuf>>,
+    resolved: HashMap<String, Rc<Resolution>>,
+    exports: HashMap<String, Result<Rc<ExportTable>, PeValidationError>>,
+    expanded: HashSet<String>,
+}
+
+///walks the import and delay import tables of dll_path and everything they pull in
+///
+///modules are matched case insensitively and files of the wrong architecture are skipped like the
+///windows loader does
+pub fn walk_dependencies(
+    dll_path: &Path,
+    search_path: &SearchPath,
+) -> Result<DependencyNode, PeValidationError> {
+    let data = match fs::read(dll_path) {
+        Ok(data) => data,
+        Err(err) => return Err(PeValidationError::Unreadable(err.to_string())),
+    };
+    let image = PeImage::from_vec(data)?;
+    let architecture = match image.architecture() {
+        Some(architecture) => architecture,
+        None => return Err(PeValidationError::WrongMachine(image.file_header().machine)),
+    };
+
+    let mut walker = Walker {
+        architecture,
+        api_set: search_path.api_set.clone(),
+        listings: search_path.dirs.iter().map(|dir| list_dir(dir)).collect(),
+        resolved: HashMap::new(),
+        exports: HashMap::new(),
+        expanded: HashSet::new(),
+    };
+    let name = dll_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let key = name.to_ascii_lowercase();
+    walker.expanded.insert(key.clone());
+
+    return Ok(DependencyNode {
+        children: walker.children(&image, &key),
+        name,
+        delay_loaded: false,
+        api_set_host: None,
+        status: ModuleStatus::Found(dll_path.to_path_buf()),
+        missing_imports: Vec::new(),
+        expanded: true,
+    });
+}
+
+impl Walker {
+    ///importer is the lowercase file name of the image, api set overrides are keyed on it
+    fn children(&mut self, image: &PeImage, importer: &str) -> Vec<DependencyNode> {
+        let mut children = Vec::new();
+        //a broken import table is already reported by validation, the tree just ends there
+        for module in image.imports().unwrap_or_default() {
+            children.push(self.node(&module.name, false, &module.thunks, importer));
+        }
+        for module in image.delay_imports().unwrap_or_default() {
+            children.push(self.node(&module.name, true, &module.thunks, importer));
+        }
+        return children;
+    }
+
+    fn node(
+        &mut self,
+        name: &String,
+        delay_loaded: bool,
+        thunks: &[ImportThunk],
+        importer: &str,
+    ) -> DependencyNode {
+        let mut api_set_host = None;
+        if let (Some(schema), true) = (&self.api_set, apiset::is_api_set_name(name)) {
+            match schema.resolve(name, Some(importer)) {
+                Some(host) => api_set_host = Some(host.to_string()),
+                None => {
+                    return DependencyNode {
+                        name: name.clone(),
+                        delay_loaded,
+                        api_set_host: None,
+                        status: ModuleStatus::UnresolvedApiSet,
+                        missing_imports: Vec::new(),
+                        expanded: true,
+                        children: Vec::new(),
+                    }
+                }
+            }
+        }
+
+        let key = module_key(api_set_host.as_ref().unwrap_or(name));
+        let resolution = self.resolve(&key);
+
+        let (status, missing_imports, image) = match resolution.as_ref() {
+            Resolution::Found(path, image) => match self.exports_of(&key, image) {
+                Ok(exports) => (
+                    ModuleStatus::Found(path.clone()),
+                    missing_imports(&exports, thunks),
+                    Some(image.clone()),
+                ),
+                Err(err) => (ModuleStatus::Invalid(path.clone(), err), Vec::new(), None),
+            },
+            Resolution::Missing => (ModuleStatus::Missing, Vec::new(), None),
+            Resolution::Invalid(path, err) => (
+                ModuleStatus::Invalid(path.clone(), err.clone()),
+                Vec::new(),
+                None,
+            ),
+        };
+
+        //every module is expanded once, later occurrences only carry their own missing imports
+        let expanded = image.is_some() && self.expanded.insert(key.clone());
+        let children = match (&image, expanded) {
+            (Some(image), true) => self.children(image, &key),
+            _ => Vec::new(),
+        };
+
+        return DependencyNode {
+            name: name.clone(),
+            delay_loaded,
+            api_set_host,
+            status,
+            missing_imports,
+            expanded: expanded || image.is_none(),
+            children,
+        };
+    }
+
+    fn resolve(&mut self, key: &String) -> Rc<Resolution> {
+        if let Some(resolution) = self.resolved.get(key) {
+            return resolution.clone();
+        }
+
+        let mut resolution = Resolution::Missing;
+        for listing in &self.listings {
+            let path = match listing.get(key) {
+                Some(path) => path,
+                None => continue,
+            };
+            let image = match fs::read(path) {
+                Ok(data) => PeImage::from_vec(data),
+                Err(err) => Err(PeValidationError::Unreadable(err.to_string())),
+            };
+            match image {
+                Ok(image) if image.architecture() == Some(self.architecture) => {
+                    resolution = Resolution::Found(path.clone(), Rc::new(image));
+                    break;
+                }
+                //a dll of the other bitness is skipped and the search goes on
+                Ok(_) => continue,
+                Err(err) => {
+                    //only report a broken file when nothing later in the search path works
+                    if let Resolution::Missing = resolution {
+                        resolution = Resolution::Invalid(path.clone(), err);
+                    }
+                }
+            }
+        }
+
+        let resolution = Rc::new(resolution);
+        self.resolved.insert(key.clone(), resolution.clone());
+        return resolution;
+    }
+
+    fn exports_of(
+        &mut self,
+        key: &String,
+        image: &PeImage,
+    ) -> Result<Rc<ExportTable>, PeValidationError> {
+        return self
+            .exports
+            .entry(key.clone())
+            .or_insert_with(|| image.exports().map(Rc::new))
+            .clone();
+    }
+}
+
+///imports that the export table cannot satisfy, forwarded exports count as present
+fn missing_imports(exports: &ExportTable, thunks: &[ImportThunk]) -> Vec<ImportTarget> {
+    return thunks
+        .iter()
+        .filter(|thunk| match &thunk.target {
+            ImportTarget::Name { name, .. } => exports.find_by_name(name).is_none(),
+            ImportTarget::Ordinal(ordinal) => exports.find_by_ordinal(*ordinal as u32).is_none(),
+        })
+        .map(|thunk| thunk.target.clone())
+        .collect();
+}
+
+///lowercase module name the way the loader compares them, names without an extension mean .dll
+pub fn module_key(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    match name.contains('.') {
+        true => return name,
+        false => return format!("{name}.dll"),
+    }
+}
+
+//an unreadable directory just contributes nothing
+fn list_dir(dir: &Path) -> HashMap<String, PathBuf> {
+    let mut listing = HashMap::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_ascii_lowercase();
+            listing.insert(name, entry.path());
+        }
+    }
+    return listing;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{by_name, FixtureExport, PeBuilder, SCN_TEXT};
+
+    ///a fresh directory per test so parallel tests do not see each others dlls
+    fn fixture_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustyinjector-{}-{test}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    //rva of the .text section every fixture dll starts with
+    const CODE: u32 = 0x1000;
+
+    fn write_dll(
+        dir: &Path,
+        file_name: &str,
+        imports: &[(&str, &[ImportTarget])],
+        exports: &[FixtureExport],
+    ) -> PathBuf {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        assert_eq!(builder.section(".text", SCN_TEXT, vec![0xC3]), CODE);
+        if !imports.is_empty() {
+            builder.imports(imports);
+        }
+        builder.exports(file_name, 1, exports);
+        let path = dir.join(file_name);
+        fs::write(&path, builder.build()).unwrap();
+        return path;
+    }
+
+    fn walk(dll_path: &Path) -> DependencyNode {
+        let search_path = SearchPath::new(dll_path, &[], None, None);
+        return walk_dependencies(dll_path, &search_path).unwrap();
+    }
+
+    fn found_at(node: &DependencyNode) -> Option<PathBuf> {
+        return match &node.status {
+            ModuleStatus::Found(path) => Some(path.clone()),
+            _ => None,
+        };
+    }
+
+    #[test]
+    fn resolves_a_chain_of_dependencies() {
+        let dir = fixture_dir("chain");
+        let c = write_dll(&dir, "c.dll", &[], &[FixtureExport::Code("c_fn", CODE)]);
+        let b = write_dll(
+            &dir,
+            "b.dll",
+            &[("c.dll", &[by_name("c_fn")])],
+            &[FixtureExport::Code("b_fn", CODE)],
+        );
+        let a = write_dll(&dir, "a.dll", &[("b.dll", &[by_name("b_fn")])], &[]);
+
+        let tree = walk(&a);
+        assert_eq!(tree.children.len(), 1);
+        let b_node = &tree.children[0];
+        assert_eq!(b_node.name, "b.dll");
+        assert_eq!(found_at(b_node), Some(b));
+        assert_eq!(b_node.children.len(), 1);
+        let c_node = &b_node.children[0];
+        assert_eq!(found_at(c_node), Some(c));
+        assert!(c_node.children.is_empty());
+        assert!(tree.problems().is_empty());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn reports_missing_modules_and_imports() {
+        let dir = fixture_dir("missing");
+        //ordinal 2 is exported without a name, forwarded exports count as present
+        let b_exports = [
+            FixtureExport::Code("b_fn", CODE),
+            FixtureExport::Ordinal(CODE),
+            FixtureExport::Forward("b_forward", "elsewhere.fn"),
+        ];
+        write_dll(&dir, "b.dll", &[], &b_exports);
+        let b_imports = [
+            by_name("b_fn"),
+            by_name("nope"),
+            ImportTarget::Ordinal(2),
+            ImportTarget::Ordinal(7),
+            by_name("b_forward"),
+        ];
+        let a = write_dll(
+            &dir,
+            "a.dll",
+            &[("gone.dll", &[by_name("x")]), ("b.dll", &b_imports)],
+            &[],
+        );
+
+        let tree = walk(&a);
+        assert!(matches!(tree.children[0].status, ModuleStatus::Missing));
+        assert!(tree.children[0].missing_imports.is_empty());
+        assert_eq!(
+            tree.children[1].missing_imports,
+            vec![by_name("nope"), ImportTarget::Ordinal(7)]
+        );
+        assert_eq!(
+            tree.problems(),
+            vec![
+                "a.dll needs gone.dll which was not found".to_string(),
+                "a.dll imports nope from b.dll which does not export it".to_string(),
+                "a.dll imports #7 from b.dll which does not export it".to_string(),
+            ]
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn matches_module_names_case_insensitively() {
+        let dir = fixture_dir("case");
+        let mixed = write_dll(
+            &dir,
+            "MixedCase.DLL",
+            &[],
+            &[FixtureExport::Code("Fn", CODE)],
+        );
+        let a = write_dll(
+            &dir,
+            "a.dll",
+            &[
+                ("MIXEDCASE.dll", &[by_name("Fn")]),
+                ("mixedcase", &[by_name("Fn")]),
+            ],
+            &[],
+        );
+
+        //a name without an extension is looked up as a .dll
+        let tree = walk(&a);
+        assert_eq!(tree.children.len(), 2);
+        for child in &tree.children {
+            assert_eq!(found_at(child), Some(mixed.clone()));
+            assert!(child.missing_imports.is_empty());
+        }
+        assert!(tree.problems().is_empty());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn stops_at_cycles() {
+        let dir = fixture_dir("cycle");
+        write_dll(
+            &dir,
+            "b.dll",
+            &[("a.dll", &[by_name("a_fn")])],
+            &[FixtureExport::Code("b_fn", CODE)],
+        );
+        let a = write_dll(
+            &dir,
+            "a.dll",
+            &[("b.dll", &[by_name("b_fn")])],
+            &[FixtureExport::Code("a_fn", CODE)],
+        );
+
+        let tree = walk(&a);
+        let b_node = &tree.children[0];
+        assert!(b_node.expanded);
+        let a_again = &b_node.children[0];
+        assert_eq!(found_at(a_again), Some(a));
+        assert!(!a_again.expanded);
+        assert!(a_again.children.is_empty());
+        assert!(tree.render().contains("(see above)"));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,78 +1,75 @@
-use std::{io::Read, mem::size_of};
-
 use std::fs;
-use winapi::um::winnt::{
-    IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_FILE_MACHINE_AMD64, IMAGE_NT_HEADERS,
-};
-
-pub fn is_valid_dll(dll_path: String) -> Vec<u8> {
-    let file_name = &dll_path;
-    let empty_vec: Vec<u8> = Vec::new();
-
-    println!("Checking that {file_name} exists");
-
-    let file_res = fs::File::open(&dll_path);
-
-    if file_res.is_err() {
-        println!("Unable to open/access {file_name}");
-        return empty_vec;
-    }
-
-    let mut file = file_res.unwrap();
-    let file_metadata_res = file.metadata();
-
-    if file_metadata_res.is_err() {
-        println!("Unable to access {file_name} metadata");
-        return empty_vec;
-    }
-
-    let file_metadata = file_metadata_res.unwrap();
-    if file_metadata.len() < size_of::<IMAGE_DOS_HEADER>().try_into().unwrap() {
-        println!("{file_name} has an invalid size");
-        return empty_vec;
-    }
+use std::io::ErrorKind;
+
+use crate::utils::pe::checksum::ChecksumStatus;
+use crate::utils::pe::clr::ManagedKind;
+use crate::utils::pe::error::PeValidationError;
+use crate::utils::pe::headers::Architecture;
+use crate::utils::pe::image::PeImage;
+use crate::utils::pe::packing::PackingAnalysis;
+
+///A dll that passed validation along with what was learned about it
+pub struct ValidatedDll {
+    pub image: PeImage<'static>,
+    pub architecture: Architecture,
+    ///whether the dll is a .NET assembly, those cannot be manual mapped
+    pub managed: ManagedKind,
+    ///a mismatch points at a corrupted or patched file
+    pub checksum: ChecksumStatus,
+    ///section entropy, overlay and layout oddities of packed images
+    pub packing: PackingAnalysis,
+}
 
-    let mut file_contents: Vec<u8> = Vec::new();
-    if file.read_to_end(&mut file_contents).is_err() {
-        println!("Unable to read dll");
-        return empty_vec;
+///reads the file at dll_path and checks that it is a dll the injector can handle
+///
+///every failure is logged with its machine readable code before being returned
+pub fn is_valid_dll(dll_path: String) -> Result<ValidatedDll, PeValidationError> {
+    println!("Checking that {dll_path} exists");
+    let result = validate_dll(&dll_path);
+    match &result {
+        Ok(dll) => println!(
+            "Dll is valid ({}, {}, {})",
+            dll.architecture, dll.managed, dll.checksum
+        ),
+        Err(err) => println!("[{}] {dll_path}: {err}", err.code()),
     }
+    return result;
+}
 
-    let dos_header: IMAGE_DOS_HEADER =
-        unsafe { *(file_contents.as_ptr() as *const IMAGE_DOS_HEADER) };
-    if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
-        println!("{file_name} is not a valid pe image");
-        return empty_vec;
-    }
+///same checks as is_valid_dll without logging, for output that has to stay machine readable
+pub fn validate_dll(dll_path: &String) -> Result<ValidatedDll, PeValidationError> {
+    let file_contents = match fs::read(dll_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(PeValidationError::FileNotFound(dll_path.clone()))
+        }
+        Err(err) => return Err(PeValidationError::Unreadable(err.to_string())),
+    };
 
-    if file_metadata.len() < dos_header.e_lfanew.try_into().unwrap() {
-        println!("{file_name} has an invalid size");
-        return empty_vec;
-    }
+    let image = PeImage::from_vec(file_contents)?;
+    let file_header = image.file_header();
 
-    let nt_header = unsafe {
-        *(file_contents
-            .as_ptr()
-            .add(dos_header.e_lfanew.try_into().unwrap()) as *const IMAGE_NT_HEADERS)
+    //both PE32 and PE32+ images are accepted no matter what the injector was built as
+    let architecture = match image.architecture() {
+        Some(architecture) => architecture,
+        None => return Err(PeValidationError::WrongMachine(file_header.machine)),
     };
-    let _optional_header = nt_header.OptionalHeader;
-    let file_header = nt_header.FileHeader;
-
-    #[cfg(target_pointer_width = "64")]
-    if file_header.Machine != IMAGE_FILE_MACHINE_AMD64 {
-        println!("Host is 64bit and dll is not");
-        return empty_vec;
-    }
 
-    #[cfg(target_pointer_width = "32")]
-    if file_header.Machine != IMAGE_FILE_MACHINE_I386 {
-        println!("Host is 32bit and dll is not");
-        return null;
+    if !file_header.is_dll() {
+        return Err(PeValidationError::NotADll);
     }
 
-    println!("Dll is valid");
-
-    return file_contents;
+    let managed = image.managed_kind()?;
+    let checksum = image.checksum_status();
+    let packing = image.packing_analysis();
+
+    return Ok(ValidatedDll {
+        image,
+        architecture,
+        managed,
+        checksum,
+        packing,
+    });
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,219 @@
+use serde_json::{json, Value};
+
+use crate::utils::files::ValidatedDll;
+use crate::utils::pe::{
+    authenticode::{self, AuthenticodeSignature},
+    checksum::ChecksumStatus,
+    debug::DebugInfo,
+    error::PeValidationError,
+    rich::RichHeader,
+};
+
+///the JSON inspection output, the same information the inspector shows in a form scripts can diff
+///
+///every part that fails to parse is replaced by an object holding the error code and message so
+///one malformed directory does not hide the rest of the report
+pub fn inspection_report(dll_path: &String, dll: &ValidatedDll) -> Value {
+    let image = &dll.image;
+    let optional_header = image.optional_header();
+
+    let sections: Vec<Value> = image
+        .sections()
+        .iter()
+        .zip(&dll.packing.sections)
+        .map(|(section, analysis)| {
+            json!({
+                "name": section.name(),
+                "virtual_address": section.virtual_address,
+                "virtual_size": section.virtual_size,
+                "size_of_raw_data": section.size_of_raw_data,
+                "characteristics": section.characteristics,
+                "entropy": analysis.entropy,
+            })
+        })
+        .collect();
+
+    let exports = match image.exports() {
+        Ok(table) => json!({
+            "dll_name": table.dll_name,
+            "count": table.exports.len(),
+            "names": table
+                .exports
+                .iter()
+                .filter_map(|export| export.name.clone())
+                .collect::<Vec<String>>(),
+        }),
+        Err(err) => error(&err),
+    };
+
+    let imports = match image.imports() {
+        Ok(modules) => Value::Array(
+            modules
+                .iter()
+                .map(|module| {
+                    json!({
+                        "name": module.name,
+                        "functions": module
+                            .thunks
+                            .iter()
+                            .map(|thunk| thunk.target.to_string())
+                            .collect::<Vec<String>>(),
+                    })
+                })
+                .collect(),
+        ),
+        Err(err) => error(&err),
+    };
+
+    let tls = match image.tls() {
+        Ok(Some(tls)) => json!({
+            "template_size": tls.template_size(),
+            "size_of_zero_fill": tls.size_of_zero_fill,
+            "callbacks": tls.callbacks,
+        }),
+        Ok(None) => Value::Null,
+        Err(err) => error(&err),
+    };
+
+    let debug = match image.debug_entries() {
+        Ok(entries) => Value::Array(
+            entries
+                .iter()
+                .map(|entry| match &entry.info {
+                    DebugInfo::CodeView(codeview) => json!({
+                        "type": entry.kind_name(),
+                        "pdb_path": codeview.pdb_path,
+                        "guid": codeview.guid.to_string(),
+                        "age": codeview.age,
+                        "symbol_key": codeview.symbol_key(),
+                    }),
+                    DebugInfo::Repro(hash) => json!({
+                        "type": entry.kind_name(),
+                        "hash": authenticode::hex(hash),
+                    }),
+                    _ => json!({ "type": entry.kind_name() }),
+                })
+                .collect(),
+        ),
+        Err(err) => error(&err),
+    };
+
+    let load_config = match image.load_config() {
+        Ok(Some(load_config)) => json!({
+            "security_cookie": load_config.security_cookie,
+            "se_handler_count": load_config.se_handlers.len(),
+            "guard_cf_function_count": load_config.guard_cf_functions.len(),
+            "guard_flags": load_config.guard_flags,
+        }),
+        Ok(None) => Value::Null,
+        Err(err) => error(&err),
+    };
+
+    let clr = match image.clr_header() {
+        Ok(Some(clr)) => json!({
+            "runtime_version": format!("{}.{}", clr.major_runtime_version, clr.minor_runtime_version),
+            "metadata_version": clr.metadata_version,
+            "flags": clr.flags,
+        }),
+        Ok(None) => Value::Null,
+        Err(err) => error(&err),
+    };
+
+    let runtime_functions = match image.runtime_functions() {
+        Ok(functions) => json!({
+            "count": functions.len(),
+            "handlers": functions
+                .iter()
+                .filter(|function| {
+                    image
+                        .unwind_info(function)
+                        .map(|info| info.handler.is_some())
+                        .unwrap_or(false)
+                })
+                .count(),
+        }),
+        Err(err) => error(&err),
+    };
+
+    let mut mitigations = serde_json::Map::new();
+    for (name, enabled) in image.mitigation_policy().flags() {
+        mitigations.insert(name.to_string(), Value::Bool(enabled));
+    }
+
+    return json!({
+        "path": dll_path,
+        "architecture": dll.architecture.to_string(),
+        "managed": dll.managed.to_string(),
+        "checksum": checksum(dll.checksum),
+        "clr": clr,
+        "image_base": optional_header.image_base,
+        "entry_point": optional_header.address_of_entry_point,
+        "size_of_image": optional_header.size_of_image,
+        "time_date_stamp": image.file_header().time_date_stamp,
+        "sections": sections,
+        "overlay": dll.packing.overlay.map(|overlay| json!({ "offset": overlay.offset, "size": overlay.size })),
+        "packing_warnings": dll.packing.reasons(),
+        "exports": exports,
+        "imports": imports,
+        "tls": tls,
+        "runtime_functions": runtime_functions,
+        "debug": debug,
+        "load_config": load_config,
+        "mitigations": mitigations,
+        "signature": signature(image.authenticode()),
+        "rich_header": image.rich_header().as_ref().map(rich_header),
+    });
+}
+
+fn checksum(checksum: ChecksumStatus) -> Value {
+    return match checksum {
+        ChecksumStatus::Match(stored) => json!({ "status": "match", "stored": stored }),
+        ChecksumStatus::Mismatch { stored, computed } => {
+            json!({ "status": "mismatch", "stored": stored, "computed": computed })
+        }
+        ChecksumStatus::Zero => json!({ "status": "zero" }),
+    };
+}
+
+fn signature(signature: Result<Option<AuthenticodeSignature>, PeValidationError>) -> Value {
+    let signature = match signature {
+        Ok(Some(signature)) => signature,
+        Ok(None) => return Value::Null,
+        Err(err) => return error(&err),
+    };
+    let signer = signature.signer.as_ref();
+    return json!({
+        "subject": signer.map(|signer| signer.subject.clone()),
+        "issuer": signer.map(|signer| signer.issuer.clone()),
+        "serial": signer.map(|signer| signer.serial.clone()),
+        "timestamp": signer.and_then(|signer| signer.timestamp.clone()),
+        "digest_algorithm": signature.digest_algorithm.map(|algorithm| algorithm.to_string()),
+        "signed_digest": authenticode::hex(&signature.signed_digest),
+        "computed_digest": signature.computed_digest.as_ref().map(|digest| authenticode::hex(digest)),
+        "digest_matches": signature.digest_matches(),
+    });
+}
+
+fn rich_header(rich_header: &RichHeader) -> Value {
+    return json!({
+        "key": format!("{:08x}", rich_header.key),
+        "checksum_valid": rich_header.checksum_valid(),
+        "entries": rich_header
+            .entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "product_id": entry.product_id,
+                    "product": entry.product_name(),
+                    "build": entry.build,
+                    "count": entry.count,
+                    "toolset": entry.toolset(),
+                })
+            })
+            .collect::<Vec<Value>>(),
+    });
+}
+
+fn error(err: &PeValidationError) -> Value {
+    return json!({ "error": err.code(), "message": err.to_string() });
+}
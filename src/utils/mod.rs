@@ -1 +1,7 @@
+pub mod binding;
+pub mod dependencies;
 pub mod files;
+pub mod inspection;
+pub mod pe;
+pub mod remote;
+pub mod stub;
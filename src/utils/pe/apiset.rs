@@ -0,0 +1,477 @@
+use super::error::PeValidationError;
+use super::image::PeImage;
+use super::reader::{read_u32, read_utf16};
+
+///the section apisetschema.dll keeps the schema in
+pub const APISET_SECTION: &str = ".apiset";
+
+const MALFORMED: PeValidationError = PeValidationError::MalformedDirectory("api set schema");
+
+///A dll that implements a contract, either for everyone or for one importing module only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiSetHost {
+    ///lowercase name of the importer this host applies to, None for the default host
+    pub importer: Option<String>,
+    ///empty when the contract is declared but not implemented on this system
+    pub host: String,
+}
+
+///An api set contract and the dlls that implement it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiSetEntry {
+    ///lowercase contract name as stored, without .dll. Versions 2 and 4 also leave out api-
+    pub name: String,
+    ///number of leading characters of name that take part in a lookup
+    pub hashed_length: usize,
+    pub hosts: Vec<ApiSetHost>,
+}
+
+impl ApiSetEntry {
+    ///the host an importer gets, an override for that importer wins over the default
+    pub fn host_for(&self, importer: Option<&str>) -> Option<&str> {
+        let overridden = importer.and_then(|importer| {
+            return self.hosts.iter().find(|host| match &host.importer {
+                Some(name) => name.eq_ignore_ascii_case(importer),
+                None => false,
+            });
+        });
+        let host = overridden
+            .or_else(|| self.hosts.iter().find(|host| host.importer.is_none()))
+            .or_else(|| self.hosts.first())?;
+        return match host.host.is_empty() {
+            true => None,
+            false => Some(&host.host),
+        };
+    }
+}
+
+///API_SET_NAMESPACE, the mapping from api-ms-win-* and ext-ms-* contracts to real dlls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiSetSchema {
+    pub version: u32,
+    pub entries: Vec<ApiSetEntry>,
+}
+
+///true for module names the loader sends through the api set schema
+pub fn is_api_set_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    return name.starts_with("api-") || name.starts_with("ext-");
+}
+
+impl ApiSetSchema {
+    ///parses a schema blob, offsets inside it are relative to the start of data
+    pub fn parse(data: &[u8]) -> Result<ApiSetSchema, PeValidationError> {
+        let version = read_u32(data, 0).ok_or(MALFORMED)?;
+        let entries = match version {
+            2 => parse_v2(data),
+            4 => parse_v4(data),
+            6 => parse_v6(data),
+            _ => return Err(PeValidationError::UnsupportedApiSetVersion(version)),
+        };
+        return Ok(ApiSetSchema {
+            version,
+            entries: entries.ok_or(MALFORMED)?,
+        });
+    }
+
+    ///finds the contract a module name refers to, the name may carry .dll
+    ///
+    ///version 6 only compares up to the last hyphen so any minor version of a contract matches
+    ///like it does in the loader
+    pub fn find(&self, name: &str) -> Option<&ApiSetEntry> {
+        let name = name.to_ascii_lowercase();
+        let name = name.strip_suffix(".dll").unwrap_or(&name);
+        if self.version < 6 {
+            let name = name
+                .strip_prefix("api-")
+                .or_else(|| name.strip_prefix("ext-"))
+                .unwrap_or(name);
+            return self.entries.iter().find(|entry| {
+                return entry.name == name
+                    || entry.name.strip_prefix("api-") == Some(name)
+                    || entry.name.strip_prefix("ext-") == Some(name);
+            });
+        }
+
+        let hashed = match name.rfind('-') {
+            Some(end) => &name[..end],
+            None => name,
+        };
+        return self.entries.iter().find(|entry| {
+            return entry.name.get(..entry.hashed_length) == Some(hashed);
+        });
+    }
+
+    ///the dll a contract resolves to for the given importer, None when the name is not a known
+    ///contract or the contract has no host
+    pub fn resolve(&self, name: &str, importer: Option<&str>) -> Option<&str> {
+        return self.find(name)?.host_for(importer);
+    }
+}
+
+fn read_name(data: &[u8], offset: u32, len: u32) -> Option<String> {
+    //lengths are in bytes of utf16
+    let name = read_utf16(data, offset as usize, len as usize / 2)?;
+    return Some(name.to_ascii_lowercase());
+}
+
+fn importer(name: String) -> Option<String> {
+    return match name.is_empty() {
+        true => None,
+        false => Some(name),
+    };
+}
+
+//windows 7, API_SET_NAMESPACE_ARRAY_V2
+//header: Version, Count then 12 byte entries of NameOffset, NameLength, DataOffset
+//data: Count then 16 byte values of NameOffset, NameLength, ValueOffset, ValueLength
+fn parse_v2(data: &[u8]) -> Option<Vec<ApiSetEntry>> {
+    let count = read_u32(data, 4)? as usize;
+    let mut entries = Vec::new();
+    for index in 0..count {
+        let entry = 8 + index * 12;
+        let name = read_name(data, read_u32(data, entry)?, read_u32(data, entry + 4)?)?;
+        let values = read_u32(data, entry + 8)? as usize;
+        let value_count = read_u32(data, values)? as usize;
+        let mut hosts = Vec::new();
+        for value in 0..value_count {
+            let value = values + 4 + value * 16;
+            hosts.push(ApiSetHost {
+                importer: importer(read_name(
+                    data,
+                    read_u32(data, value)?,
+                    read_u32(data, value + 4)?,
+                )?),
+                host: read_name(
+                    data,
+                    read_u32(data, value + 8)?,
+                    read_u32(data, value + 12)?,
+                )?,
+            });
+        }
+        entries.push(ApiSetEntry {
+            hashed_length: name.len(),
+            name,
+            hosts,
+        });
+    }
+    return Some(entries);
+}
+
+//windows 8 and 8.1, API_SET_NAMESPACE_ARRAY_V4
+//header: Version, Size, Flags, Count then 24 byte entries of Flags, NameOffset, NameLength,
+//AliasOffset, AliasLength, DataOffset
+//data: Flags, Count then 20 byte values of Flags, NameOffset, NameLength, ValueOffset, ValueLength
+fn parse_v4(data: &[u8]) -> Option<Vec<ApiSetEntry>> {
+    let count = read_u32(data, 12)? as usize;
+    let mut entries = Vec::new();
+    for index in 0..count {
+        let entry = 16 + index * 24;
+        let name = read_name(data, read_u32(data, entry + 4)?, read_u32(data, entry + 8)?)?;
+        let values = read_u32(data, entry + 20)? as usize;
+        let value_count = read_u32(data, values + 4)? as usize;
+        entries.push(ApiSetEntry {
+            hashed_length: name.len(),
+            name,
+            hosts: read_values(data, values + 8, value_count)?,
+        });
+    }
+    return Some(entries);
+}
+
+//windows 10 and later, API_SET_NAMESPACE
+//header: Version, Size, Flags, Count, EntryOffset, HashOffset, HashFactor
+//entries: 24 bytes of Flags, NameOffset, NameLength, HashedLength, ValueOffset, ValueCount
+//values: same 20 byte layout as version 4
+fn parse_v6(data: &[u8]) -> Option<Vec<ApiSetEntry>> {
+    let count = read_u32(data, 12)? as usize;
+    let entry_offset = read_u32(data, 16)? as usize;
+    let mut entries = Vec::new();
+    for index in 0..count {
+        let entry = entry_offset + index * 24;
+        let name = read_name(data, read_u32(data, entry + 4)?, read_u32(data, entry + 8)?)?;
+        let hashed_length = (read_u32(data, entry + 12)? / 2) as usize;
+        let values = read_u32(data, entry + 16)? as usize;
+        let value_count = read_u32(data, entry + 20)? as usize;
+        entries.push(ApiSetEntry {
+            hashed_length: hashed_length.min(name.len()),
+            name,
+            hosts: read_values(data, values, value_count)?,
+        });
+    }
+    return Some(entries);
+}
+
+fn read_values(data: &[u8], offset: usize, count: usize) -> Option<Vec<ApiSetHost>> {
+    let mut hosts = Vec::new();
+    for index in 0..count {
+        let value = offset + index * 20;
+        hosts.push(ApiSetHost {
+            importer: importer(read_name(
+                data,
+                read_u32(data, value + 4)?,
+                read_u32(data, value + 8)?,
+            )?),
+            host: read_name(
+                data,
+                read_u32(data, value + 12)?,
+                read_u32(data, value + 16)?,
+            )?,
+        });
+    }
+    return Some(hosts);
+}
+
+impl<'a> PeImage<'a> {
+    ///the schema in the .apiset section, None when the image does not have one
+    pub fn api_set_schema(&self) -> Option<Result<ApiSetSchema, PeValidationError>> {
+        let section = self
+            .sections()
+            .iter()
+            .find(|section| section.name() == APISET_SECTION)?;
+        let size = section.size_of_raw_data.min(section.mapped_size()) as usize;
+        let data = match self
+            .data()
+            .get(section.pointer_to_raw_data as usize..)
+            .and_then(|data| data.get(..size))
+        {
+            Some(data) => data,
+            None => return Some(Err(MALFORMED)),
+        };
+        return Some(ApiSetSchema::parse(data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_RDATA};
+    use crate::utils::pe::headers::Architecture;
+
+    ///a contract and its (importer, host) values, an empty importer is the default host
+    type Contract = (&'static str, &'static [(&'static str, &'static str)]);
+
+    const SYNCH: Contract = ("api-MS-Win-Core-Synch-L1-2-0", &[("", "kernelbase.dll")]);
+    const COM: Contract = (
+        "api-ms-win-core-com-l1-1-0",
+        &[("", "combase.dll"), ("Ole32.dll", "ole32_override.dll")],
+    );
+    const DECLARED: Contract = ("api-ms-win-core-declared-l1-1-0", &[("", "")]);
+
+    struct Blob(Vec<u8>);
+
+    impl Blob {
+        fn u32(&mut self, offset: usize, value: usize) {
+            put(&mut self.0, offset, &(value as u32).to_le_bytes());
+        }
+
+        ///zeroed room at the end of the blob, gives back its offset
+        fn reserve(&mut self, len: usize) -> usize {
+            let offset = self.0.len();
+            self.0.resize(offset + len, 0);
+            return offset;
+        }
+
+        ///appends name as utf16 and stores its offset and byte length at field
+        fn name(&mut self, field: usize, name: &str) {
+            let offset = self.0.len();
+            for unit in name.encode_utf16() {
+                self.0.extend_from_slice(&unit.to_le_bytes());
+            }
+            self.u32(field, offset);
+            self.u32(field + 4, self.0.len() - offset);
+        }
+
+        ///the 20 byte values shared by versions 4 and 6
+        fn values(&mut self, offset: usize, hosts: &[(&str, &str)]) {
+            for (index, (importer, host)) in hosts.iter().enumerate() {
+                let value = offset + index * 20;
+                self.name(value + 4, importer);
+                self.name(value + 12, host);
+            }
+        }
+    }
+
+    //versions 2 and 4 store contracts without the api- prefix
+    fn stored_name(name: &str) -> &str {
+        return name.strip_prefix("api-").unwrap_or(name);
+    }
+
+    fn schema_v2(contracts: &[Contract]) -> Vec<u8> {
+        let mut blob = Blob(vec![0; 8]);
+        blob.u32(0, 2);
+        blob.u32(4, contracts.len());
+        blob.reserve(contracts.len() * 12);
+        for (index, (name, hosts)) in contracts.iter().enumerate() {
+            let entry = 8 + index * 12;
+            blob.name(entry, stored_name(name));
+            let values = blob.reserve(4 + hosts.len() * 16);
+            blob.u32(entry + 8, values);
+            blob.u32(values, hosts.len());
+            for (index, (importer, host)) in hosts.iter().enumerate() {
+                let value = values + 4 + index * 16;
+                blob.name(value, importer);
+                blob.name(value + 8, host);
+            }
+        }
+        return blob.0;
+    }
+
+    fn schema_v4(contracts: &[Contract]) -> Vec<u8> {
+        let mut blob = Blob(vec![0; 16]);
+        blob.u32(0, 4);
+        blob.u32(12, contracts.len());
+        blob.reserve(contracts.len() * 24);
+        for (index, (name, hosts)) in contracts.iter().enumerate() {
+            let entry = 16 + index * 24;
+            blob.name(entry + 4, stored_name(name));
+            let values = blob.reserve(8 + hosts.len() * 20);
+            blob.u32(entry + 20, values);
+            blob.u32(values + 4, hosts.len());
+            blob.values(values + 8, hosts);
+        }
+        let size = blob.0.len();
+        blob.u32(4, size);
+        return blob.0;
+    }
+
+    fn schema_v6(contracts: &[Contract]) -> Vec<u8> {
+        let mut blob = Blob(vec![0; 28]);
+        blob.u32(0, 6);
+        blob.u32(12, contracts.len());
+        blob.u32(16, 28);
+        blob.reserve(contracts.len() * 24);
+        for (index, (name, hosts)) in contracts.iter().enumerate() {
+            let entry = 28 + index * 24;
+            blob.name(entry + 4, name);
+            //the hash covers the name up to the last hyphen
+            blob.u32(entry + 12, name.rfind('-').unwrap() * 2);
+            let values = blob.reserve(hosts.len() * 20);
+            blob.u32(entry + 16, values);
+            blob.u32(entry + 20, hosts.len());
+            blob.values(values, hosts);
+        }
+        let size = blob.0.len();
+        blob.u32(4, size);
+        return blob.0;
+    }
+
+    #[test]
+    fn parses_every_supported_version() {
+        let contracts = [SYNCH, COM, DECLARED];
+        let schemas = [
+            (2, schema_v2(&contracts)),
+            (4, schema_v4(&contracts)),
+            (6, schema_v6(&contracts)),
+        ];
+        for (version, data) in schemas {
+            let schema = ApiSetSchema::parse(&data).unwrap();
+            assert_eq!(schema.version, version);
+            assert_eq!(schema.entries.len(), 3);
+            //names are lowercased on the way in
+            let synch = match version {
+                6 => "api-ms-win-core-synch-l1-2-0",
+                _ => "ms-win-core-synch-l1-2-0",
+            };
+            assert_eq!(schema.entries[0].name, synch);
+
+            for name in [
+                "api-ms-win-core-synch-l1-2-0",
+                "API-MS-WIN-CORE-SYNCH-L1-2-0.DLL",
+            ] {
+                assert_eq!(schema.resolve(name, None), Some("kernelbase.dll"));
+            }
+            assert_eq!(
+                schema.resolve("api-ms-win-core-com-l1-1-0.dll", None),
+                Some("combase.dll")
+            );
+            //declared without a host
+            assert!(schema.find("api-ms-win-core-declared-l1-1-0").is_some());
+            assert_eq!(
+                schema.resolve("api-ms-win-core-declared-l1-1-0", None),
+                None
+            );
+            assert_eq!(schema.resolve("api-ms-win-core-unknown-l1-1-0", None), None);
+        }
+    }
+
+    #[test]
+    fn matches_version_6_up_to_the_last_hyphen() {
+        let schema = ApiSetSchema::parse(&schema_v6(&[SYNCH])).unwrap();
+        assert_eq!(schema.entries[0].hashed_length, 26);
+        for name in [
+            "api-ms-win-core-synch-l1-2-0.dll",
+            "api-ms-win-core-synch-l1-2-1.dll",
+            "api-ms-win-core-synch-l1-2-9",
+        ] {
+            assert_eq!(schema.resolve(name, None), Some("kernelbase.dll"), "{name}");
+        }
+        for name in [
+            "api-ms-win-core-synch-l1-3-0",
+            "api-ms-win-core-synch-l1-2",
+            "api-ms-win-core-synch-l2-2-0",
+        ] {
+            assert_eq!(schema.resolve(name, None), None, "{name}");
+        }
+
+        //versions before 6 only match the exact contract
+        let schema = ApiSetSchema::parse(&schema_v4(&[SYNCH])).unwrap();
+        assert_eq!(schema.resolve("api-ms-win-core-synch-l1-2-1", None), None);
+    }
+
+    #[test]
+    fn prefers_the_host_of_the_importer() {
+        for data in [schema_v2(&[COM]), schema_v4(&[COM]), schema_v6(&[COM])] {
+            let schema = ApiSetSchema::parse(&data).unwrap();
+            let com = "api-ms-win-core-com-l1-1-0";
+            assert_eq!(
+                schema.resolve(com, Some("ole32.dll")),
+                Some("ole32_override.dll")
+            );
+            assert_eq!(
+                schema.resolve(com, Some("OLE32.DLL")),
+                Some("ole32_override.dll")
+            );
+            assert_eq!(schema.resolve(com, Some("other.dll")), Some("combase.dll"));
+            assert_eq!(schema.resolve(com, None), Some("combase.dll"));
+            assert_eq!(
+                schema.entries[0].hosts[1].importer.as_deref(),
+                Some("ole32.dll")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_and_truncated_schemas() {
+        for version in [0, 1, 3, 5, 7] {
+            let mut data = schema_v6(&[SYNCH]);
+            data[0] = version as u8;
+            assert_eq!(
+                ApiSetSchema::parse(&data),
+                Err(PeValidationError::UnsupportedApiSetVersion(version))
+            );
+        }
+        assert_eq!(ApiSetSchema::parse(&[6, 0]), Err(MALFORMED));
+
+        let data = schema_v6(&[SYNCH]);
+        for len in [8, 28, 40, data.len() - 1] {
+            assert_eq!(ApiSetSchema::parse(&data[..len]), Err(MALFORMED), "{len}");
+        }
+    }
+
+    #[test]
+    fn reads_the_schema_out_of_the_apiset_section() {
+        let blob = schema_v6(&[SYNCH, COM]);
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(APISET_SECTION, SCN_RDATA, blob.clone());
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        assert_eq!(
+            image.api_set_schema(),
+            Some(Ok(ApiSetSchema::parse(&blob).unwrap()))
+        );
+
+        let data = PeBuilder::new(Architecture::X64).build();
+        assert_eq!(PeImage::parse(&data).unwrap().api_set_schema(), None);
+    }
+}
@@ -0,0 +1,640 @@
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use super::der::{tag_context, Der, TAG_INTEGER, TAG_OCTET_STRING, TAG_SEQUENCE, TAG_SET};
+use super::error::PeValidationError;
+use super::headers::{IMAGE_DIRECTORY_ENTRY_SECURITY, SIZE_OF_DATA_DIRECTORY, SIZE_OF_FILE_HEADER};
+use super::image::PeImage;
+use super::reader::{read_bytes, read_u16, read_u32};
+
+const SIZE_OF_WIN_CERTIFICATE_HEADER: usize = 8;
+pub const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+const OID_SIGNED_DATA: &str = "1.2.840.113549.1.7.2";
+const OID_SPC_INDIRECT_DATA: &str = "1.3.6.1.4.1.311.2.1.4";
+const OID_MESSAGE_DIGEST: &str = "1.2.840.113549.1.9.4";
+const OID_SIGNING_TIME: &str = "1.2.840.113549.1.9.5";
+const OID_COUNTER_SIGNATURE: &str = "1.2.840.113549.1.9.6";
+const OID_RFC3161_TIMESTAMP: &str = "1.3.6.1.4.1.311.3.3.1";
+
+///Hash algorithms authenticode signatures are made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    fn from_oid(oid: &str) -> Option<DigestAlgorithm> {
+        return match oid {
+            "1.3.14.3.2.26" => Some(DigestAlgorithm::Sha1),
+            "2.16.840.1.101.3.4.2.1" => Some(DigestAlgorithm::Sha256),
+            "2.16.840.1.101.3.4.2.2" => Some(DigestAlgorithm::Sha384),
+            "2.16.840.1.101.3.4.2.3" => Some(DigestAlgorithm::Sha512),
+            _ => None,
+        };
+    }
+
+    fn hash(&self, chunks: &[&[u8]]) -> Vec<u8> {
+        fn run<D: Digest>(chunks: &[&[u8]]) -> Vec<u8> {
+            let mut hasher = D::new();
+            for chunk in chunks {
+                hasher.update(chunk);
+            }
+            return hasher.finalize().to_vec();
+        }
+        return match self {
+            DigestAlgorithm::Sha1 => run::<Sha1>(chunks),
+            DigestAlgorithm::Sha256 => run::<Sha256>(chunks),
+            DigestAlgorithm::Sha384 => run::<Sha384>(chunks),
+            DigestAlgorithm::Sha512 => run::<Sha512>(chunks),
+        };
+    }
+}
+
+impl std::fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DigestAlgorithm::Sha1 => write!(f, "SHA-1"),
+            DigestAlgorithm::Sha256 => write!(f, "SHA-256"),
+            DigestAlgorithm::Sha384 => write!(f, "SHA-384"),
+            DigestAlgorithm::Sha512 => write!(f, "SHA-512"),
+        }
+    }
+}
+
+///Who signed the image, taken from the certificate the SignerInfo points at
+#[derive(Debug, Clone)]
+pub struct Signer {
+    pub subject: String,
+    pub issuer: String,
+    ///hex encoded certificate serial number
+    pub serial: String,
+    ///when the signature was counter signed by a timestamp authority
+    pub timestamp: Option<String>,
+}
+
+///The first PKCS#7 signature of the security directory
+///
+///only the digests are checked, the signature over them and the certificate chain are not
+///verified so a matching digest means the file is unmodified, not that the signer is trusted
+#[derive(Debug, Clone)]
+pub struct AuthenticodeSignature {
+    pub revision: u16,
+    pub certificate_type: u16,
+    pub digest_algorithm: Option<DigestAlgorithm>,
+    ///the PE hash stored in the signature
+    pub signed_digest: Vec<u8>,
+    ///the PE hash recomputed over the file, None if the algorithm is not supported
+    pub computed_digest: Option<Vec<u8>>,
+    ///whether the messageDigest attribute matches the signed content, None if there is none
+    pub content_digest_matches: Option<bool>,
+    pub signer: Option<Signer>,
+    pub certificate_count: usize,
+}
+
+impl AuthenticodeSignature {
+    ///true when the file still hashes to what was signed
+    pub fn digest_matches(&self) -> bool {
+        return self.computed_digest.as_ref() == Some(&self.signed_digest)
+            && self.content_digest_matches != Some(false);
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///reads the signature out of the security directory, None for unsigned images
+    ///
+    ///unlike every other directory the security directory address is a file offset
+    pub fn authenticode(&self) -> Result<Option<AuthenticodeSignature>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY) {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+        let malformed = PeValidationError::MalformedDirectory("security");
+
+        let table = match read_bytes(
+            self.data(),
+            directory.virtual_address as usize,
+            directory.size as usize,
+        ) {
+            Some(table) => table,
+            None => return Err(malformed),
+        };
+
+        //WIN_CERTIFICATE entries are 8 byte aligned, the first PKCS#7 one is the primary signature
+        let mut offset = 0;
+        while offset + SIZE_OF_WIN_CERTIFICATE_HEADER <= table.len() {
+            let (length, revision, certificate_type) = match (
+                read_u32(table, offset),
+                read_u16(table, offset + 4),
+                read_u16(table, offset + 6),
+            ) {
+                (Some(length), Some(revision), Some(kind)) => (length as usize, revision, kind),
+                _ => return Err(malformed),
+            };
+            let certificate = match length {
+                0..=SIZE_OF_WIN_CERTIFICATE_HEADER => return Err(malformed),
+                _ => match read_bytes(
+                    table,
+                    offset + SIZE_OF_WIN_CERTIFICATE_HEADER,
+                    length - SIZE_OF_WIN_CERTIFICATE_HEADER,
+                ) {
+                    Some(certificate) => certificate,
+                    None => return Err(malformed),
+                },
+            };
+
+            if certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+                let mut signature = match parse_signed_data(certificate) {
+                    Some(signature) => signature,
+                    None => return Err(malformed),
+                };
+                signature.revision = revision;
+                signature.certificate_type = certificate_type;
+                signature.computed_digest = signature
+                    .digest_algorithm
+                    .and_then(|algorithm| self.authenticode_digest(algorithm));
+                return Ok(Some(signature));
+            }
+            offset += (length + 7) & !7;
+        }
+
+        return Ok(None);
+    }
+
+    ///the authenticode PE hash
+    ///
+    ///covers the headers minus the CheckSum field and the security directory entry, the sections in
+    ///file order and whatever trails them except for the certificate table itself
+    pub fn authenticode_digest(&self, algorithm: DigestAlgorithm) -> Option<Vec<u8>> {
+        let data = self.data();
+        let optional_header_offset = self.dos_header().e_lfanew as usize + 4 + SIZE_OF_FILE_HEADER;
+        let checksum = optional_header_offset + 64;
+        let security_entry = optional_header_offset
+            + self.optional_header().fixed_size()
+            + IMAGE_DIRECTORY_ENTRY_SECURITY * SIZE_OF_DATA_DIRECTORY;
+        let size_of_headers = (self.optional_header().size_of_headers as usize).min(data.len());
+        if security_entry + SIZE_OF_DATA_DIRECTORY > size_of_headers {
+            return None;
+        }
+
+        let mut chunks = vec![
+            &data[..checksum],
+            &data[checksum + 4..security_entry],
+            &data[security_entry + SIZE_OF_DATA_DIRECTORY..size_of_headers],
+        ];
+
+        let mut sections: Vec<_> = self
+            .sections()
+            .iter()
+            .filter(|section| section.size_of_raw_data != 0)
+            .collect();
+        sections.sort_by_key(|section| section.pointer_to_raw_data);
+        let mut hashed_end = size_of_headers;
+        for section in sections {
+            let start = section.pointer_to_raw_data as usize;
+            chunks.push(read_bytes(data, start, section.size_of_raw_data as usize)?);
+            hashed_end = hashed_end.max(start + section.size_of_raw_data as usize);
+        }
+
+        //trailing data is hashed around the certificate table
+        let (table_start, table_end) = match self.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY) {
+            Some(directory) => (
+                directory.virtual_address as usize,
+                directory.virtual_address as usize + directory.size as usize,
+            ),
+            None => (data.len(), data.len()),
+        };
+        if hashed_end < table_start {
+            chunks.push(data.get(hashed_end..table_start)?);
+        }
+        if table_end < data.len() {
+            chunks.push(&data[table_end.max(hashed_end)..]);
+        }
+
+        return Some(algorithm.hash(&chunks));
+    }
+}
+
+//ContentInfo { signedData, [0] SignedData { version, digestAlgorithms, contentInfo, [0] certificates,
+//[1] crls, signerInfos } }
+fn parse_signed_data(blob: &[u8]) -> Option<AuthenticodeSignature> {
+    let content_info = Der::parse_single(blob)?.children()?;
+    if content_info.first()?.oid()? != OID_SIGNED_DATA {
+        return None;
+    }
+    let signed_data = Der::parse_single(content_info.get(1)?.contents)?.children()?;
+
+    //SpcIndirectDataContent { data, DigestInfo { AlgorithmIdentifier, digest } }
+    let encap = signed_data.get(2)?.children()?;
+    if encap.first()?.oid()? != OID_SPC_INDIRECT_DATA {
+        return None;
+    }
+    let indirect_data = Der::parse_single(encap.get(1)?.contents)?;
+    let digest_info = indirect_data.children()?.get(1)?.children()?;
+    let algorithm_oid = digest_info.first()?.children()?.first()?.oid()?;
+    let signed_digest = digest_info.get(1)?;
+    if signed_digest.tag != TAG_OCTET_STRING {
+        return None;
+    }
+
+    let certificates = signed_data
+        .iter()
+        .find(|element| element.tag == tag_context(0))
+        .and_then(|element| element.children())
+        .unwrap_or_default();
+    let signer_info = signed_data
+        .iter()
+        .rev()
+        .find(|element| element.tag == TAG_SET)?
+        .children()?
+        .into_iter()
+        .next();
+
+    let mut content_digest_matches = None;
+    let signer = match signer_info {
+        Some(signer_info) => {
+            let signer_info = signer_info.children()?;
+            //the messageDigest attribute hashes the value of SpcIndirectDataContent
+            let algorithm = DigestAlgorithm::from_oid(&algorithm_oid);
+            if let (Some(message_digest), Some(algorithm)) = (
+                attribute(&signer_info, tag_context(0), OID_MESSAGE_DIGEST),
+                algorithm,
+            ) {
+                content_digest_matches =
+                    Some(message_digest.contents == algorithm.hash(&[indirect_data.contents]));
+            }
+            Some(parse_signer(&signer_info, &certificates)?)
+        }
+        None => None,
+    };
+
+    return Some(AuthenticodeSignature {
+        revision: 0,
+        certificate_type: 0,
+        digest_algorithm: DigestAlgorithm::from_oid(&algorithm_oid),
+        signed_digest: signed_digest.contents.to_vec(),
+        computed_digest: None,
+        content_digest_matches,
+        signer,
+        certificate_count: certificates.len(),
+    });
+}
+
+//SignerInfo { version, IssuerAndSerialNumber, digestAlgorithm, [0] authenticatedAttributes,
+//digestEncryptionAlgorithm, encryptedDigest, [1] unauthenticatedAttributes }
+fn parse_signer(signer_info: &[Der], certificates: &[Der]) -> Option<Signer> {
+    let issuer_and_serial = signer_info.get(1)?.children()?;
+    let issuer = issuer_and_serial.first()?;
+    let serial = issuer_and_serial.get(1)?;
+    if serial.tag != TAG_INTEGER {
+        return None;
+    }
+
+    //Certificate { TBSCertificate { [0] version, serial, signature, issuer, validity, subject, .. } }
+    let subject = certificates.iter().find_map(|certificate| {
+        let tbs = certificate.children()?.first()?.children()?;
+        let skip = match tbs.first()?.tag == tag_context(0) {
+            true => 1,
+            false => 0,
+        };
+        let matches =
+            tbs.get(skip)?.contents == serial.contents && tbs.get(skip + 2)?.raw == issuer.raw;
+        match matches {
+            true => name(tbs.get(skip + 4)?),
+            false => None,
+        }
+    });
+
+    return Some(Signer {
+        subject: subject.unwrap_or_default(),
+        issuer: name(issuer).unwrap_or_default(),
+        serial: hex(serial
+            .contents
+            .strip_prefix(&[0])
+            .unwrap_or(serial.contents)),
+        timestamp: timestamp(signer_info),
+    });
+}
+
+//a legacy counter signature carries signingTime, an RFC 3161 token carries a TSTInfo with genTime
+fn timestamp(signer_info: &[Der]) -> Option<String> {
+    if let Some(counter_signature) = attribute(signer_info, tag_context(1), OID_COUNTER_SIGNATURE) {
+        let counter_signature = counter_signature.children()?;
+        return attribute(&counter_signature, tag_context(0), OID_SIGNING_TIME)?.time();
+    }
+
+    let token = attribute(signer_info, tag_context(1), OID_RFC3161_TIMESTAMP)?;
+    let signed_data = Der::parse_single(token.children()?.get(1)?.contents)?.children()?;
+    let tst_info = Der::parse_single(signed_data.get(2)?.children()?.get(1)?.contents)?;
+    //the TSTInfo sits in an OCTET STRING
+    let tst_info = Der::parse_single(tst_info.contents)?.children()?;
+    return tst_info.get(4)?.time();
+}
+
+//first value of the attribute with the given OID inside the [0]/[1] attribute set
+fn attribute<'a>(signer_info: &[Der<'a>], set_tag: u8, oid: &str) -> Option<Der<'a>> {
+    let attributes = signer_info.iter().find(|element| element.tag == set_tag)?;
+    for attribute in attributes.children()? {
+        let attribute = attribute.children()?;
+        if attribute.first()?.oid()? == oid {
+            return attribute.get(1)?.children()?.into_iter().next();
+        }
+    }
+    return None;
+}
+
+//Name ::= SEQUENCE OF SET OF { type, value } rendered as C=US, O=Example, CN=Example
+fn name(name: &Der) -> Option<String> {
+    if name.tag != TAG_SEQUENCE {
+        return None;
+    }
+    let mut parts = Vec::new();
+    for rdn in name.children()? {
+        for attribute in rdn.children()? {
+            let attribute = attribute.children()?;
+            let oid = attribute.first()?.oid()?;
+            let key = match oid.as_str() {
+                "2.5.4.3" => "CN".to_string(),
+                "2.5.4.5" => "serialNumber".to_string(),
+                "2.5.4.6" => "C".to_string(),
+                "2.5.4.7" => "L".to_string(),
+                "2.5.4.8" => "ST".to_string(),
+                "2.5.4.10" => "O".to_string(),
+                "2.5.4.11" => "OU".to_string(),
+                "2.5.4.15" => "businessCategory".to_string(),
+                "1.2.840.113549.1.9.1" => "emailAddress".to_string(),
+                "1.3.6.1.4.1.311.60.2.1.2" => "jurisdictionST".to_string(),
+                "1.3.6.1.4.1.311.60.2.1.3" => "jurisdictionC".to_string(),
+                _ => oid,
+            };
+            let value = attribute.get(1)?.string().unwrap_or_default();
+            parts.push(format!("{key}={value}"));
+        }
+    }
+    return Some(parts.join(", "));
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::der::fixture::{constructed, tlv};
+    use crate::utils::pe::der::{TAG_NULL, TAG_OID, TAG_PRINTABLE_STRING};
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_DATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    const OID_SIGNED_DATA: [u8; 9] = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+    const OID_SPC_INDIRECT_DATA: [u8; 10] =
+        [0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+    const OID_SHA256: [u8; 9] = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+    const OID_MESSAGE_DIGEST: [u8; 9] = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x04];
+    const OID_COMMON_NAME: [u8; 3] = [0x55, 0x04, 0x03];
+
+    //x64 headers: the optional header starts at 0x58 and its data directories at 0xC8
+    const CHECKSUM: usize = 0x58 + 64;
+    const SECURITY_ENTRY: usize = 0xC8 + IMAGE_DIRECTORY_ENTRY_SECURITY * SIZE_OF_DATA_DIRECTORY;
+    const OVERLAY: &[u8] = b"overlay data";
+    const TIME_DATE_STAMP: usize = 0x40 + 4 + 4;
+
+    ///an x64 image with two sections, data after them and a certificate table carrying blob
+    ///
+    ///returns the file and where the certificate table starts. The headers end at 0x200, the
+    ///sections sit at 0x200 and 0x400 and the overlay at 0x600
+    fn signed_image(blob: &[u8]) -> (Vec<u8>, usize) {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x30]);
+        builder.section(".data", SCN_DATA, (0..=255).collect());
+        let mut data = builder.build();
+        put(&mut data, CHECKSUM, &0x12345678u32.to_le_bytes());
+        data.extend_from_slice(OVERLAY);
+        //the certificate table starts 8 byte aligned
+        data.resize((data.len() + 7) & !7, 0);
+
+        let table = data.len();
+        let length = SIZE_OF_WIN_CERTIFICATE_HEADER + blob.len();
+        data.extend((length as u32).to_le_bytes());
+        data.extend(0x0200u16.to_le_bytes());
+        data.extend(WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+        data.extend_from_slice(blob);
+        data.resize(table + ((length + 7) & !7), 0);
+        let size = (data.len() - table) as u32;
+        put(&mut data, SECURITY_ENTRY, &(table as u32).to_le_bytes());
+        put(&mut data, SECURITY_ENTRY + 4, &size.to_le_bytes());
+        return (data, table);
+    }
+
+    ///authenticode SHA-256 of signed_image() worked out separately from this parser
+    const DIGEST: &str = "3251BC6C93216FFB3A2D9838DC45CCEE366877BB79E391F0BCF1D0EFBA408703";
+
+    fn sha256() -> Vec<u8> {
+        return constructed(
+            TAG_SEQUENCE,
+            &[tlv(TAG_OID, &OID_SHA256), tlv(TAG_NULL, &[])],
+        );
+    }
+
+    ///ContentInfo around a SignedData for digest with one signer and no certificates
+    ///
+    ///message_digest is what the signer's messageDigest attribute says SpcIndirectDataContent hashes to,
+    ///None puts in the right one
+    fn signed_data(digest: &[u8], message_digest: Option<Vec<u8>>) -> Vec<u8> {
+        let indirect_data = constructed(
+            TAG_SEQUENCE,
+            &[
+                constructed(TAG_SEQUENCE, &[tlv(TAG_NULL, &[])]),
+                constructed(TAG_SEQUENCE, &[sha256(), tlv(TAG_OCTET_STRING, digest)]),
+            ],
+        );
+        let message_digest = message_digest.unwrap_or_else(|| {
+            let contents = Der::parse_single(&indirect_data).unwrap().contents;
+            return DigestAlgorithm::Sha256.hash(&[contents]);
+        });
+        let issuer = constructed(
+            TAG_SEQUENCE,
+            &[constructed(
+                TAG_SET,
+                &[constructed(
+                    TAG_SEQUENCE,
+                    &[
+                        tlv(TAG_OID, &OID_COMMON_NAME),
+                        tlv(TAG_PRINTABLE_STRING, b"Test Signer"),
+                    ],
+                )],
+            )],
+        );
+        let signer_info = constructed(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_INTEGER, &[1]),
+                constructed(
+                    TAG_SEQUENCE,
+                    &[issuer, tlv(TAG_INTEGER, &[0x00, 0x81, 0x02])],
+                ),
+                sha256(),
+                constructed(
+                    tag_context(0),
+                    &[constructed(
+                        TAG_SEQUENCE,
+                        &[
+                            tlv(TAG_OID, &OID_MESSAGE_DIGEST),
+                            constructed(TAG_SET, &[tlv(TAG_OCTET_STRING, &message_digest)]),
+                        ],
+                    )],
+                ),
+                constructed(TAG_SEQUENCE, &[tlv(TAG_NULL, &[])]),
+                tlv(TAG_OCTET_STRING, &[0x5A; 16]),
+            ],
+        );
+        let signed_data = constructed(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_INTEGER, &[1]),
+                constructed(TAG_SET, &[sha256()]),
+                constructed(
+                    TAG_SEQUENCE,
+                    &[
+                        tlv(TAG_OID, &OID_SPC_INDIRECT_DATA),
+                        constructed(tag_context(0), &[indirect_data]),
+                    ],
+                ),
+                constructed(TAG_SET, &[signer_info]),
+            ],
+        );
+        return constructed(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_OID, &OID_SIGNED_DATA),
+                constructed(tag_context(0), &[signed_data]),
+            ],
+        );
+    }
+
+    fn digest() -> Vec<u8> {
+        return (0..DIGEST.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&DIGEST[i..i + 2], 16).unwrap())
+            .collect();
+    }
+
+    fn parse_signature(data: &[u8]) -> Result<Option<AuthenticodeSignature>, PeValidationError> {
+        return PeImage::parse(data).unwrap().authenticode();
+    }
+
+    #[test]
+    fn digest_matches_a_known_answer() {
+        let (data, _) = signed_image(&signed_data(&digest(), None));
+        let image = PeImage::parse(&data).unwrap();
+        assert_eq!(
+            image
+                .authenticode_digest(DigestAlgorithm::Sha256)
+                .map(|digest| hex(&digest)),
+            Some(DIGEST.to_string())
+        );
+
+        let signature = image.authenticode().unwrap().unwrap();
+        assert_eq!(signature.revision, 0x0200);
+        assert_eq!(signature.certificate_type, WIN_CERT_TYPE_PKCS_SIGNED_DATA);
+        assert_eq!(signature.digest_algorithm, Some(DigestAlgorithm::Sha256));
+        assert_eq!(signature.signed_digest, digest());
+        assert_eq!(signature.computed_digest, Some(digest()));
+        assert_eq!(signature.content_digest_matches, Some(true));
+        assert_eq!(signature.certificate_count, 0);
+        assert!(signature.digest_matches());
+        let signer = signature.signer.unwrap();
+        assert_eq!(signer.issuer, "CN=Test Signer");
+        assert_eq!(signer.serial, "8102");
+        assert_eq!(signer.timestamp, None);
+    }
+
+    #[test]
+    fn digest_skips_the_checksum_the_security_entry_and_the_certificate_table() {
+        let (data, table) = signed_image(&signed_data(&digest(), None));
+        let digest_of = |data: &[u8]| {
+            PeImage::parse(data)
+                .unwrap()
+                .authenticode_digest(DigestAlgorithm::Sha256)
+        };
+        let original = digest_of(&data);
+
+        let mut checksum = data.clone();
+        put(&mut checksum, CHECKSUM, &0xFFFFFFFFu32.to_le_bytes());
+        assert_eq!(digest_of(&checksum), original);
+        //a longer signature moves nothing but the security entry size and the table
+        let (longer, _) = signed_image(&signed_data(&digest(), Some(vec![0xEE; 64])));
+        assert_ne!(longer.len(), data.len());
+        assert_eq!(digest_of(&longer), original);
+        let mut revision = data.clone();
+        revision[table + 4] = 0x01;
+        assert_eq!(digest_of(&revision), original);
+
+        //the file header, both sections and the overlay in front of the table are all covered
+        for offset in [TIME_DATE_STAMP, 0x200, 0x400, 0x600] {
+            let mut changed = data.clone();
+            changed[offset] ^= 0xFF;
+            assert_ne!(
+                digest_of(&changed),
+                original,
+                "byte 0x{offset:x} is not hashed"
+            );
+            assert!(!parse_signature(&changed).unwrap().unwrap().digest_matches());
+        }
+    }
+
+    #[test]
+    fn reports_a_signature_over_other_content() {
+        let (data, _) = signed_image(&signed_data(&digest(), Some(vec![0; 32])));
+        let signature = parse_signature(&data).unwrap().unwrap();
+        assert_eq!(signature.computed_digest, Some(digest()));
+        assert_eq!(signature.content_digest_matches, Some(false));
+        assert!(!signature.digest_matches());
+
+        let (data, _) = signed_image(&signed_data(&[0; 32], None));
+        assert!(!parse_signature(&data).unwrap().unwrap().digest_matches());
+    }
+
+    #[test]
+    fn rejects_malformed_certificate_tables() {
+        let malformed = Some(PeValidationError::MalformedDirectory("security"));
+        let blob = signed_data(&digest(), None);
+
+        //WIN_CERTIFICATE lengths that stop inside the header or run past the table
+        let (mut data, table) = signed_image(&blob);
+        for length in [0u32, 8, (data.len() - table) as u32 + 1] {
+            put(&mut data, table, &length.to_le_bytes());
+            assert_eq!(parse_signature(&data).err(), malformed.clone());
+        }
+        //a table past the end of the file
+        let (mut data, table) = signed_image(&blob);
+        put(&mut data, SECURITY_ENTRY, &(table as u32 + 8).to_le_bytes());
+        assert_eq!(parse_signature(&data).err(), malformed.clone());
+
+        //PKCS#7 cut short, or not SignedData at all
+        for cut in [1, 2, blob.len() / 2, blob.len() - 1] {
+            let (data, _) = signed_image(&blob[..cut]);
+            assert_eq!(
+                parse_signature(&data).err(),
+                malformed.clone(),
+                "{cut} bytes"
+            );
+        }
+        let mut other = blob.clone();
+        let oid = other
+            .windows(OID_SIGNED_DATA.len())
+            .position(|window| window == OID_SIGNED_DATA)
+            .unwrap();
+        other[oid + OID_SIGNED_DATA.len() - 1] = 0x01;
+        let (data, _) = signed_image(&other);
+        assert_eq!(parse_signature(&data).err(), malformed);
+
+        //certificates of other types are skipped
+        let (mut data, table) = signed_image(&blob);
+        put(&mut data, table + 6, &0x0001u16.to_le_bytes());
+        assert!(parse_signature(&data).unwrap().is_none());
+    }
+}
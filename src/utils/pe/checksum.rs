@@ -0,0 +1,130 @@
+use std::fmt;
+
+use super::headers::SIZE_OF_FILE_HEADER;
+use super::image::PeImage;
+
+///How the CheckSum field of the optional header compares to the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    Match(u32),
+    Mismatch {
+        stored: u32,
+        computed: u32,
+    },
+    ///the linker did not fill it in, which is the default for user mode dlls
+    Zero,
+}
+
+impl fmt::Display for ChecksumStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumStatus::Match(checksum) => write!(f, "checksum 0x{checksum:08x} matches"),
+            ChecksumStatus::Mismatch { stored, computed } => write!(
+                f,
+                "checksum mismatch, header says 0x{stored:08x} but the file sums to 0x{computed:08x}"
+            ),
+            ChecksumStatus::Zero => write!(f, "no checksum"),
+        }
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///the checksum CheckSumMappedFile computes
+    ///
+    ///a 16 bit ones' complement style sum over the whole file with the CheckSum field counted as zero,
+    ///plus the file size
+    pub fn compute_checksum(&self) -> u32 {
+        let data = self.data();
+        let checksum_field = self.dos_header().e_lfanew as usize + 4 + SIZE_OF_FILE_HEADER + 64;
+
+        let mut sum: u32 = 0;
+        for (index, word) in data.chunks(2).enumerate() {
+            let offset = index * 2;
+            if offset >= checksum_field && offset < checksum_field + 4 {
+                continue;
+            }
+            //an odd sized file has its last byte summed as the low half of a word
+            let value = match word {
+                [low, high] => u16::from_le_bytes([*low, *high]),
+                [low] => *low as u16,
+                _ => 0,
+            };
+            sum += value as u32;
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        sum = (sum & 0xFFFF) + (sum >> 16);
+
+        return sum.wrapping_add(data.len() as u32);
+    }
+
+    pub fn checksum_status(&self) -> ChecksumStatus {
+        let stored = self.optional_header().check_sum;
+        if stored == 0 {
+            return ChecksumStatus::Zero;
+        }
+        let computed = self.compute_checksum();
+        match stored == computed {
+            true => return ChecksumStatus::Match(stored),
+            false => return ChecksumStatus::Mismatch { stored, computed },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_DATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    //x86 headers: the optional header starts at 0x58
+    const CHECKSUM: usize = 0x58 + 64;
+
+    ///an x86 image whose words overflow 16 bits plenty, with trailing bytes appended
+    fn image(trailing: &[u8]) -> Vec<u8> {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xFF; 0x200]);
+        builder.section(
+            ".data",
+            SCN_DATA,
+            (0..0x300).map(|i| (i * 7) as u8).collect(),
+        );
+        let mut data = builder.build();
+        data.extend_from_slice(trailing);
+        return data;
+    }
+
+    fn with_checksum(mut data: Vec<u8>, checksum: u32) -> Vec<u8> {
+        put(&mut data, CHECKSUM, &checksum.to_le_bytes());
+        return data;
+    }
+
+    #[test]
+    fn matches_check_sum_mapped_file() {
+        //worked out with the dword based sum CheckSumMappedFile uses rather than this word based one
+        for (trailing, expected) in [(&[][..], 0x000105C1), (&[0xAB, 0xCD, 0xEF][..], 0x0000D45F)] {
+            let data = image(trailing);
+            assert_eq!(PeImage::parse(&data).unwrap().compute_checksum(), expected);
+            //the stored checksum does not count towards the sum
+            let data = with_checksum(data, 0xDEADBEEF);
+            assert_eq!(PeImage::parse(&data).unwrap().compute_checksum(), expected);
+        }
+    }
+
+    #[test]
+    fn compares_the_stored_checksum() {
+        let status = |checksum: u32| {
+            let data = with_checksum(image(&[0xAB]), checksum);
+            return PeImage::parse(&data).unwrap().checksum_status();
+        };
+        let computed = PeImage::parse(&image(&[0xAB])).unwrap().compute_checksum();
+        assert_eq!(status(0), ChecksumStatus::Zero);
+        assert_eq!(status(computed), ChecksumStatus::Match(computed));
+        assert_eq!(
+            status(computed + 1),
+            ChecksumStatus::Mismatch {
+                stored: computed + 1,
+                computed
+            }
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+
+use super::error::PeValidationError;
+use super::headers::{DataDirectory, IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR};
+use super::image::PeImage;
+use super::reader::{read_u16, read_u32};
+
+const SIZE_OF_COR20_HEADER: usize = 72;
+///"BSJB", the signature of the metadata root
+const METADATA_SIGNATURE: u32 = 0x424A5342;
+///the version string is at most 255 bytes, padded to a multiple of 4
+const MAX_METADATA_VERSION_LENGTH: usize = 256;
+
+pub const COMIMAGE_FLAGS_ILONLY: u32 = 0x00000001;
+pub const COMIMAGE_FLAGS_32BITREQUIRED: u32 = 0x00000002;
+#[allow(dead_code)]
+pub const COMIMAGE_FLAGS_IL_LIBRARY: u32 = 0x00000004;
+pub const COMIMAGE_FLAGS_STRONGNAMESIGNED: u32 = 0x00000008;
+pub const COMIMAGE_FLAGS_NATIVE_ENTRYPOINT: u32 = 0x00000010;
+#[allow(dead_code)]
+pub const COMIMAGE_FLAGS_TRACKDEBUGDATA: u32 = 0x00010000;
+pub const COMIMAGE_FLAGS_32BITPREFERRED: u32 = 0x00020000;
+
+///What kind of code a dll carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManagedKind {
+    ///no CLR header, plain machine code
+    Native,
+    ///only IL, nothing runs unless the CLR is hosted in the target
+    PureIl,
+    ///C++/CLI, machine code next to IL that needs the CLR to be initialised by the loader
+    Mixed,
+}
+
+impl ManagedKind {
+    pub fn is_managed(&self) -> bool {
+        return *self != ManagedKind::Native;
+    }
+}
+
+impl fmt::Display for ManagedKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManagedKind::Native => write!(f, "native"),
+            ManagedKind::PureIl => write!(f, "pure IL .NET"),
+            ManagedKind::Mixed => write!(f, "mixed-mode .NET"),
+        }
+    }
+}
+
+///The parsed IMAGE_COR20_HEADER along with the version string of the metadata root
+#[derive(Debug, Clone)]
+pub struct ClrHeader {
+    pub major_runtime_version: u16,
+    pub minor_runtime_version: u16,
+    #[allow(dead_code)]
+    pub metadata: DataDirectory,
+    pub flags: u32,
+    ///a metadata token, or an rva when COMIMAGE_FLAGS_NATIVE_ENTRYPOINT is set
+    #[allow(dead_code)]
+    pub entry_point: u32,
+    ///runtime the assembly was built against, e.g. v4.0.30319
+    pub metadata_version: Option<String>,
+}
+
+impl ClrHeader {
+    pub fn kind(&self) -> ManagedKind {
+        match self.flags & COMIMAGE_FLAGS_ILONLY {
+            0 => ManagedKind::Mixed,
+            _ => ManagedKind::PureIl,
+        }
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///reads IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR, None for native images
+    pub fn clr_header(&self) -> Result<Option<ClrHeader>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR) {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+        let malformed = PeValidationError::MalformedDirectory("CLR runtime header");
+
+        let header = match self.bytes_at_rva(directory.virtual_address, SIZE_OF_COR20_HEADER) {
+            Some(header) => header,
+            None => return Err(malformed),
+        };
+        let field = |offset: usize| read_u32(header, offset).unwrap_or_default();
+        //cb holds the size of the header, anything smaller than what is read here is not a CLR header
+        if (field(0) as usize) < SIZE_OF_COR20_HEADER {
+            return Err(malformed);
+        }
+
+        let metadata = DataDirectory {
+            virtual_address: field(8),
+            size: field(12),
+        };
+        return Ok(Some(ClrHeader {
+            major_runtime_version: read_u16(header, 4).unwrap_or_default(),
+            minor_runtime_version: read_u16(header, 6).unwrap_or_default(),
+            metadata,
+            flags: field(16),
+            entry_point: field(20),
+            metadata_version: self.metadata_version(metadata),
+        }));
+    }
+
+    ///classifies the image, a broken CLR header is reported instead of guessing
+    pub fn managed_kind(&self) -> Result<ManagedKind, PeValidationError> {
+        return Ok(match self.clr_header()? {
+            Some(clr) => clr.kind(),
+            None => ManagedKind::Native,
+        });
+    }
+
+    //the metadata root starts with BSJB, major, minor, reserved and the length of the version string
+    fn metadata_version(&self, metadata: DataDirectory) -> Option<String> {
+        if self.read_u32_at_rva(metadata.virtual_address)? != METADATA_SIGNATURE {
+            return None;
+        }
+        let length = self.read_u32_at_rva(metadata.virtual_address.wrapping_add(12))? as usize;
+        let version = self.bytes_at_rva(
+            metadata.virtual_address.wrapping_add(16),
+            length.min(MAX_METADATA_VERSION_LENGTH),
+        )?;
+        let end = version
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(version.len());
+        return Some(String::from_utf8_lossy(&version[..end]).to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_RDATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    ///an image with a COR20 header of size cb at the start of .rdata and the metadata root after it
+    fn clr_image(cb: u32, flags: u32) -> Vec<u8> {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        let rdata = builder.next_rva();
+        let metadata = 0x50;
+
+        let mut data = Vec::new();
+        put(&mut data, 0, &cb.to_le_bytes());
+        put(&mut data, 4, &2u16.to_le_bytes());
+        put(&mut data, 6, &5u16.to_le_bytes());
+        put(&mut data, 8, &(rdata + metadata).to_le_bytes());
+        put(&mut data, 12, &0x20u32.to_le_bytes());
+        put(&mut data, 16, &flags.to_le_bytes());
+        put(&mut data, 20, &0x06000001u32.to_le_bytes());
+        let version = b"v4.0.30319\0\0";
+        put(
+            &mut data,
+            metadata as usize,
+            &METADATA_SIGNATURE.to_le_bytes(),
+        );
+        put(&mut data, metadata as usize + 4, &[1, 0, 1, 0, 0, 0, 0, 0]);
+        put(
+            &mut data,
+            metadata as usize + 12,
+            &(version.len() as u32).to_le_bytes(),
+        );
+        put(&mut data, metadata as usize + 16, version);
+
+        builder.section(".rdata", SCN_RDATA, data);
+        builder.directory(
+            IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR,
+            rdata,
+            SIZE_OF_COR20_HEADER as u32,
+        );
+        return builder.build();
+    }
+
+    #[test]
+    fn only_il_images_are_pure() {
+        let kind = |flags: u32| {
+            ClrHeader {
+                major_runtime_version: 2,
+                minor_runtime_version: 5,
+                metadata: DataDirectory {
+                    virtual_address: 0,
+                    size: 0,
+                },
+                flags,
+                entry_point: 0,
+                metadata_version: None,
+            }
+            .kind()
+        };
+        assert_eq!(kind(COMIMAGE_FLAGS_ILONLY), ManagedKind::PureIl);
+        assert_eq!(
+            kind(
+                COMIMAGE_FLAGS_ILONLY
+                    | COMIMAGE_FLAGS_32BITREQUIRED
+                    | COMIMAGE_FLAGS_STRONGNAMESIGNED
+            ),
+            ManagedKind::PureIl
+        );
+        //C++/CLI clears ILONLY, with or without a native entry point
+        assert_eq!(kind(0), ManagedKind::Mixed);
+        assert_eq!(kind(COMIMAGE_FLAGS_NATIVE_ENTRYPOINT), ManagedKind::Mixed);
+        assert!(kind(0).is_managed());
+        assert!(!ManagedKind::Native.is_managed());
+    }
+
+    #[test]
+    fn reads_the_header_and_metadata_version() {
+        let data = clr_image(SIZE_OF_COR20_HEADER as u32, COMIMAGE_FLAGS_ILONLY);
+        let image = PeImage::parse(&data).unwrap();
+        let clr = image.clr_header().unwrap().unwrap();
+        assert_eq!(
+            (clr.major_runtime_version, clr.minor_runtime_version),
+            (2, 5)
+        );
+        assert_eq!(clr.entry_point, 0x06000001);
+        assert_eq!(clr.metadata_version.as_deref(), Some("v4.0.30319"));
+        assert_eq!(image.managed_kind(), Ok(ManagedKind::PureIl));
+
+        let data = clr_image(SIZE_OF_COR20_HEADER as u32, 0);
+        assert_eq!(
+            PeImage::parse(&data).unwrap().managed_kind(),
+            Ok(ManagedKind::Mixed)
+        );
+
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        let data = builder.build();
+        assert_eq!(
+            PeImage::parse(&data).unwrap().managed_kind(),
+            Ok(ManagedKind::Native)
+        );
+    }
+
+    #[test]
+    fn rejects_a_short_header() {
+        let malformed = Some(PeValidationError::MalformedDirectory("CLR runtime header"));
+        let data = clr_image(SIZE_OF_COR20_HEADER as u32 - 4, COMIMAGE_FLAGS_ILONLY);
+        let image = PeImage::parse(&data).unwrap();
+        assert_eq!(image.clr_header().err(), malformed);
+        //a broken header is not taken for a native image
+        assert_eq!(image.managed_kind().err(), malformed);
+    }
+}
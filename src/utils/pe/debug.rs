@@ -0,0 +1,399 @@
+use super::error::PeValidationError;
+use super::headers::IMAGE_DIRECTORY_ENTRY_DEBUG;
+use super::image::PeImage;
+use super::reader::{read_bytes, read_cstr, read_u16, read_u32};
+
+const SIZE_OF_DEBUG_DIRECTORY: usize = 28;
+
+pub const IMAGE_DEBUG_TYPE_COFF: u32 = 1;
+pub const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+pub const IMAGE_DEBUG_TYPE_FPO: u32 = 3;
+pub const IMAGE_DEBUG_TYPE_MISC: u32 = 4;
+pub const IMAGE_DEBUG_TYPE_VC_FEATURE: u32 = 12;
+pub const IMAGE_DEBUG_TYPE_POGO: u32 = 13;
+pub const IMAGE_DEBUG_TYPE_ILTCG: u32 = 14;
+pub const IMAGE_DEBUG_TYPE_REPRO: u32 = 16;
+pub const IMAGE_DEBUG_TYPE_EX_DLLCHARACTERISTICS: u32 = 20;
+
+const CODEVIEW_RSDS_SIGNATURE: u32 = 0x53445352; //RSDS
+
+///A GUID as stored on disk, the first three fields are little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            u16::from_le_bytes([bytes[4], bytes[5]]),
+            u16::from_le_bytes([bytes[6], bytes[7]])
+        )?;
+        for (i, byte) in bytes[8..].iter().enumerate() {
+            if i == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{byte:02X}")?;
+        }
+        return Ok(());
+    }
+}
+
+///The CodeView RSDS record that ties an image to its pdb
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeView {
+    pub guid: Guid,
+    pub age: u32,
+    pub pdb_path: String,
+}
+
+impl CodeView {
+    ///the key symbol servers store the pdb under, the guid without dashes followed by the age in hex
+    pub fn symbol_key(&self) -> String {
+        return format!("{}{:X}", self.guid.to_string().replace('-', ""), self.age);
+    }
+}
+
+impl std::fmt::Display for CodeView {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {{{}}} age {}", self.pdb_path, self.guid, self.age)
+    }
+}
+
+///A section the profile guided optimisation record lists
+#[derive(Debug, Clone)]
+pub struct PogoEntry {
+    pub rva: u32,
+    pub size: u32,
+    pub name: String,
+}
+
+///What the data of a debug directory entry decoded to
+#[derive(Debug, Clone)]
+pub enum DebugInfo {
+    CodeView(CodeView),
+    ///signature is PGU/PGI/LTCG depending on how the image was optimised
+    Pogo {
+        signature: String,
+        entries: Vec<PogoEntry>,
+    },
+    ///the hash deterministic builds put in place of a timestamp, empty for older linkers
+    Repro(Vec<u8>),
+    ///a type this parser does not decode or data it could not read
+    Raw,
+}
+
+///An IMAGE_DEBUG_DIRECTORY entry
+#[derive(Debug, Clone)]
+pub struct DebugEntry {
+    #[allow(dead_code)]
+    pub characteristics: u32,
+    #[allow(dead_code)]
+    pub time_date_stamp: u32,
+    #[allow(dead_code)]
+    pub major_version: u16,
+    #[allow(dead_code)]
+    pub minor_version: u16,
+    pub kind: u32,
+    pub size_of_data: u32,
+    pub address_of_raw_data: u32,
+    pub pointer_to_raw_data: u32,
+    pub info: DebugInfo,
+}
+
+impl DebugEntry {
+    ///IMAGE_DEBUG_TYPE_* name
+    pub fn kind_name(&self) -> String {
+        return match self.kind {
+            IMAGE_DEBUG_TYPE_COFF => "COFF".to_string(),
+            IMAGE_DEBUG_TYPE_CODEVIEW => "CODEVIEW".to_string(),
+            IMAGE_DEBUG_TYPE_FPO => "FPO".to_string(),
+            IMAGE_DEBUG_TYPE_MISC => "MISC".to_string(),
+            IMAGE_DEBUG_TYPE_VC_FEATURE => "VC_FEATURE".to_string(),
+            IMAGE_DEBUG_TYPE_POGO => "POGO".to_string(),
+            IMAGE_DEBUG_TYPE_ILTCG => "ILTCG".to_string(),
+            IMAGE_DEBUG_TYPE_REPRO => "REPRO".to_string(),
+            IMAGE_DEBUG_TYPE_EX_DLLCHARACTERISTICS => "EX_DLLCHARACTERISTICS".to_string(),
+            kind => format!("UNKNOWN({kind})"),
+        };
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///reads every IMAGE_DEBUG_DIRECTORY entry and decodes the ones the injector cares about
+    pub fn debug_entries(&self) -> Result<Vec<DebugEntry>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_DEBUG) {
+            Some(directory) => directory,
+            None => return Ok(Vec::new()),
+        };
+        let table = match self.bytes_at_rva(directory.virtual_address, directory.size as usize) {
+            Some(table) => table,
+            None => return Err(PeValidationError::MalformedDirectory("debug")),
+        };
+
+        let mut entries = Vec::new();
+        for entry in table.chunks_exact(SIZE_OF_DEBUG_DIRECTORY) {
+            let field = |offset: usize| read_u32(entry, offset).unwrap_or_default();
+            let mut debug_entry = DebugEntry {
+                characteristics: field(0),
+                time_date_stamp: field(4),
+                major_version: read_u16(entry, 8).unwrap_or_default(),
+                minor_version: read_u16(entry, 10).unwrap_or_default(),
+                kind: field(12),
+                size_of_data: field(16),
+                address_of_raw_data: field(20),
+                pointer_to_raw_data: field(24),
+                info: DebugInfo::Raw,
+            };
+            if let Some(data) = self.debug_data(&debug_entry) {
+                debug_entry.info = decode_debug_data(debug_entry.kind, data);
+            }
+            entries.push(debug_entry);
+        }
+
+        return Ok(entries);
+    }
+
+    ///the CodeView record of the image if it has one
+    pub fn codeview(&self) -> Result<Option<CodeView>, PeValidationError> {
+        for entry in self.debug_entries()? {
+            if let DebugInfo::CodeView(codeview) = entry.info {
+                return Ok(Some(codeview));
+            }
+        }
+        return Ok(None);
+    }
+
+    //the data is normally mapped but debug info appended after the sections only has a file offset
+    fn debug_data(&self, entry: &DebugEntry) -> Option<&[u8]> {
+        let size = entry.size_of_data as usize;
+        if entry.address_of_raw_data != 0 {
+            if let Some(data) = self.bytes_at_rva(entry.address_of_raw_data, size) {
+                return Some(data);
+            }
+        }
+        return read_bytes(self.data(), entry.pointer_to_raw_data as usize, size);
+    }
+}
+
+fn decode_debug_data(kind: u32, data: &[u8]) -> DebugInfo {
+    let decoded = match kind {
+        IMAGE_DEBUG_TYPE_CODEVIEW => decode_codeview(data),
+        IMAGE_DEBUG_TYPE_POGO => decode_pogo(data),
+        IMAGE_DEBUG_TYPE_REPRO => decode_repro(data),
+        _ => None,
+    };
+    return decoded.unwrap_or(DebugInfo::Raw);
+}
+
+//RSDS, 16 byte guid, age, nul terminated utf8 path
+fn decode_codeview(data: &[u8]) -> Option<DebugInfo> {
+    if read_u32(data, 0)? != CODEVIEW_RSDS_SIGNATURE {
+        return None;
+    }
+    let guid = Guid(read_bytes(data, 4, 16)?.try_into().ok()?);
+    let age = read_u32(data, 20)?;
+    //some linkers do not terminate the path when it fills the record exactly
+    let path = match read_cstr(data, 24) {
+        Some(path) => path,
+        None => data.get(24..)?,
+    };
+    return Some(DebugInfo::CodeView(CodeView {
+        guid,
+        age,
+        pdb_path: String::from_utf8_lossy(path).to_string(),
+    }));
+}
+
+//signature followed by rva, size and a 4 byte aligned nul terminated name per section
+fn decode_pogo(data: &[u8]) -> Option<DebugInfo> {
+    let signature = read_bytes(data, 0, 4)?;
+    let signature = String::from_utf8_lossy(signature)
+        .trim_end_matches('\0')
+        .to_string();
+
+    let mut entries = Vec::new();
+    let mut offset = 4;
+    while offset + 8 < data.len() {
+        let name = read_cstr(data, offset + 8)?;
+        entries.push(PogoEntry {
+            rva: read_u32(data, offset)?,
+            size: read_u32(data, offset + 4)?,
+            name: String::from_utf8_lossy(name).to_string(),
+        });
+        offset = (offset + 8 + name.len() + 1 + 3) & !3;
+    }
+
+    return Some(DebugInfo::Pogo { signature, entries });
+}
+
+//a length prefixed hash, older linkers leave the entry empty and only change the timestamp
+fn decode_repro(data: &[u8]) -> Option<DebugInfo> {
+    if data.is_empty() {
+        return Some(DebugInfo::Repro(Vec::new()));
+    }
+    let len = read_u32(data, 0)? as usize;
+    return Some(DebugInfo::Repro(read_bytes(data, 4, len)?.to_vec()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_RDATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    const GUID: [u8; 16] = [
+        0x78, 0x56, 0x34, 0x12, 0x34, 0x12, 0x78, 0x56, 0x9A, 0xBC, 0xDE, 0xF0, 0x11, 0x22, 0x33,
+        0x44,
+    ];
+    const CODEVIEW: usize = 0x80;
+    const POGO: usize = 0xC0;
+
+    fn codeview(path: &[u8]) -> Vec<u8> {
+        let mut record = CODEVIEW_RSDS_SIGNATURE.to_le_bytes().to_vec();
+        record.extend(GUID);
+        record.extend(3u32.to_le_bytes());
+        record.extend_from_slice(path);
+        return record;
+    }
+
+    fn pogo() -> Vec<u8> {
+        let mut record = b"PGU\0".to_vec();
+        for (rva, size, name) in [(0x1000u32, 0x100u32, ".text$mn"), (0x2000, 0x20, ".rdata")] {
+            record.extend(rva.to_le_bytes());
+            record.extend(size.to_le_bytes());
+            record.extend(name.as_bytes());
+            record.push(0);
+            record.resize((record.len() + 3) & !3, 0);
+        }
+        return record;
+    }
+
+    ///an image with CodeView and POGO records in .rdata, a REPRO hash appended to the file that
+    ///only has a file offset, and an entry of a type nothing decodes
+    fn debug_image(path: &[u8]) -> Vec<u8> {
+        let record = codeview(path);
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+        let base = builder.next_rva();
+        let pogo = pogo();
+        let mut rdata = Vec::new();
+        put(&mut rdata, CODEVIEW, &record);
+        put(&mut rdata, POGO, &pogo);
+        builder.section(".rdata", SCN_RDATA, rdata);
+        //appended right behind the raw data of .rdata
+        let appended = builder.build().len() as u32;
+
+        let entries = [
+            (
+                IMAGE_DEBUG_TYPE_CODEVIEW,
+                record.len(),
+                base + CODEVIEW as u32,
+                0,
+            ),
+            (IMAGE_DEBUG_TYPE_POGO, pogo.len(), base + POGO as u32, 0),
+            (IMAGE_DEBUG_TYPE_REPRO, 36, 0, appended),
+            (IMAGE_DEBUG_TYPE_VC_FEATURE, 20, 0, 0),
+        ];
+        for (i, (kind, size, rva, file_offset)) in entries.iter().enumerate() {
+            let rdata = &mut builder.sections[1].data;
+            let entry = i * SIZE_OF_DEBUG_DIRECTORY;
+            put(rdata, entry + 12, &kind.to_le_bytes());
+            put(rdata, entry + 16, &(*size as u32).to_le_bytes());
+            put(rdata, entry + 20, &rva.to_le_bytes());
+            put(rdata, entry + 24, &file_offset.to_le_bytes());
+        }
+        let size = (entries.len() * SIZE_OF_DEBUG_DIRECTORY) as u32;
+        builder.directory(IMAGE_DIRECTORY_ENTRY_DEBUG, base, size);
+
+        let mut data = builder.build();
+        data.extend(32u32.to_le_bytes());
+        data.extend([0xAA; 32]);
+        return data;
+    }
+
+    #[test]
+    fn decodes_codeview_pogo_and_repro_entries() {
+        let data = debug_image(b"C:\\build\\test.pdb\0");
+        let image = PeImage::parse(&data).unwrap();
+        let entries = image.debug_entries().unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(DebugEntry::kind_name)
+                .collect::<Vec<_>>(),
+            vec!["CODEVIEW", "POGO", "REPRO", "VC_FEATURE"]
+        );
+
+        let codeview = image.codeview().unwrap().unwrap();
+        assert_eq!(codeview.pdb_path, "C:\\build\\test.pdb");
+        assert_eq!(codeview.age, 3);
+        assert_eq!(
+            codeview.guid.to_string(),
+            "12345678-1234-5678-9ABC-DEF011223344"
+        );
+        assert_eq!(codeview.symbol_key(), "12345678123456789ABCDEF0112233443");
+
+        match &entries[1].info {
+            DebugInfo::Pogo { signature, entries } => {
+                assert_eq!(signature, "PGU");
+                let sections: Vec<_> = entries
+                    .iter()
+                    .map(|entry| (entry.rva, entry.size, entry.name.as_str()))
+                    .collect();
+                assert_eq!(
+                    sections,
+                    vec![(0x1000, 0x100, ".text$mn"), (0x2000, 0x20, ".rdata")]
+                );
+            }
+            info => panic!("POGO decoded to {info:?}"),
+        }
+        match &entries[2].info {
+            DebugInfo::Repro(hash) => assert_eq!(hash, &vec![0xAA; 32]),
+            info => panic!("REPRO decoded to {info:?}"),
+        }
+        assert!(matches!(entries[3].info, DebugInfo::Raw));
+    }
+
+    #[test]
+    fn reads_unterminated_pdb_paths() {
+        let data = debug_image(b"test.pdb");
+        let codeview = PeImage::parse(&data).unwrap().codeview().unwrap().unwrap();
+        assert_eq!(codeview.pdb_path, "test.pdb");
+    }
+
+    #[test]
+    fn leaves_undecodable_records_raw() {
+        //not an RSDS record
+        let mut data = debug_image(b"test.pdb\0");
+        let signature = data
+            .windows(4)
+            .position(|window| window == b"RSDS")
+            .unwrap();
+        data[signature] = b'N';
+        let image = PeImage::parse(&data).unwrap();
+        assert!(matches!(
+            image.debug_entries().unwrap()[0].info,
+            DebugInfo::Raw
+        ));
+        assert_eq!(image.codeview(), Ok(None));
+        //too short to hold the guid and age
+        assert!(matches!(
+            decode_debug_data(IMAGE_DEBUG_TYPE_CODEVIEW, &codeview(b"")[..20]),
+            DebugInfo::Raw
+        ));
+        //a REPRO hash longer than the record
+        let mut repro = 64u32.to_le_bytes().to_vec();
+        repro.extend([0; 8]);
+        assert!(matches!(
+            decode_debug_data(IMAGE_DEBUG_TYPE_REPRO, &repro),
+            DebugInfo::Raw
+        ));
+        assert!(matches!(
+            decode_debug_data(IMAGE_DEBUG_TYPE_REPRO, &[]),
+            DebugInfo::Repro(hash) if hash.is_empty()
+        ));
+    }
+}
@@ -0,0 +1,310 @@
+///just enough of a DER reader to walk the PKCS#7 blobs authenticode signatures are stored in
+///
+///like the rest of the parser every read is bounds checked and returns None on bad input
+
+pub const TAG_INTEGER: u8 = 0x02;
+#[allow(dead_code)]
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+#[allow(dead_code)]
+pub const TAG_NULL: u8 = 0x05;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STRING: u8 = 0x0C;
+pub const TAG_PRINTABLE_STRING: u8 = 0x13;
+pub const TAG_T61_STRING: u8 = 0x14;
+pub const TAG_IA5_STRING: u8 = 0x16;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_BMP_STRING: u8 = 0x1E;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+///context specific constructed tag [n]
+pub const fn tag_context(n: u8) -> u8 {
+    return 0xA0 | n;
+}
+
+///A single tag/length/value element
+#[derive(Debug, Clone, Copy)]
+pub struct Der<'a> {
+    pub tag: u8,
+    ///the value without the tag and length
+    pub contents: &'a [u8],
+    ///the whole encoding including the tag and length
+    pub raw: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    ///parses the first element of data and returns it along with whatever follows it
+    pub fn parse(data: &'a [u8]) -> Option<(Der<'a>, &'a [u8])> {
+        let tag = *data.first()?;
+        //multi byte tags never show up in the structures we read
+        if tag & 0x1F == 0x1F {
+            return None;
+        }
+
+        let first = *data.get(1)? as usize;
+        let (len, header) = match first {
+            0..=0x7F => (first, 2),
+            0x81..=0x84 => {
+                let count = first & 0x7F;
+                let bytes = data.get(2..2 + count)?;
+                let len = bytes
+                    .iter()
+                    .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+                (len, 2 + count)
+            }
+            //indefinite lengths are BER only
+            _ => return None,
+        };
+
+        let end = header.checked_add(len)?;
+        let raw = data.get(..end)?;
+        return Some((
+            Der {
+                tag,
+                contents: &raw[header..],
+                raw,
+            },
+            &data[end..],
+        ));
+    }
+
+    ///parses data as exactly one element
+    pub fn parse_single(data: &'a [u8]) -> Option<Der<'a>> {
+        return Der::parse(data).map(|(der, _)| der);
+    }
+
+    ///the elements inside a constructed value
+    pub fn children(&self) -> Option<Vec<Der<'a>>> {
+        let mut children = Vec::new();
+        let mut rest = self.contents;
+        while !rest.is_empty() {
+            let (child, next) = Der::parse(rest)?;
+            children.push(child);
+            rest = next;
+        }
+        return Some(children);
+    }
+
+    ///dotted notation of an OBJECT IDENTIFIER, e.g. 1.2.840.113549.1.7.2
+    pub fn oid(&self) -> Option<String> {
+        if self.tag != TAG_OID || self.contents.is_empty() {
+            return None;
+        }
+        let mut parts = Vec::new();
+        let mut value = 0u64;
+        for &byte in self.contents {
+            value = value.checked_mul(128)? | (byte & 0x7F) as u64;
+            if byte & 0x80 == 0 {
+                parts.push(value);
+                value = 0;
+            }
+        }
+
+        //the first byte packs the first two arcs together
+        let first = *parts.first()?;
+        let (a, b) = match first {
+            0..=39 => (0, first),
+            40..=79 => (1, first - 40),
+            _ => (2, first - 80),
+        };
+        let mut oid = format!("{a}.{b}");
+        for part in &parts[1..] {
+            oid += &format!(".{part}");
+        }
+        return Some(oid);
+    }
+
+    ///the text of any of the string types used in certificate names
+    pub fn string(&self) -> Option<String> {
+        return match self.tag {
+            TAG_UTF8_STRING | TAG_PRINTABLE_STRING | TAG_IA5_STRING | TAG_T61_STRING => {
+                Some(String::from_utf8_lossy(self.contents).to_string())
+            }
+            TAG_BMP_STRING => {
+                let units: Vec<u16> = self
+                    .contents
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                    .collect();
+                Some(String::from_utf16_lossy(&units))
+            }
+            _ => None,
+        };
+    }
+
+    ///UTCTime or GeneralizedTime as YYYY-MM-DD HH:MM:SS UTC
+    pub fn time(&self) -> Option<String> {
+        let text = std::str::from_utf8(self.contents).ok()?;
+        let digits = text.trim_end_matches('Z');
+        let full = match self.tag {
+            //two digit years below 50 belong to the 2000s
+            TAG_UTC_TIME => {
+                let year: u32 = digits.get(..2)?.parse().ok()?;
+                let century = if year < 50 { "20" } else { "19" };
+                format!("{century}{digits}")
+            }
+            TAG_GENERALIZED_TIME => digits.to_string(),
+            _ => return None,
+        };
+        return Some(format!(
+            "{}-{}-{} {}:{}:{} UTC",
+            full.get(0..4)?,
+            full.get(4..6)?,
+            full.get(6..8)?,
+            full.get(8..10)?,
+            full.get(10..12)?,
+            full.get(12..14).unwrap_or("00")
+        ));
+    }
+}
+
+///DER encoding for the unit tests, enough to build PKCS#7 blobs by hand
+#[cfg(test)]
+pub mod fixture {
+    ///tag, definite length in the shortest form and contents
+    pub fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let len = contents.len();
+        let mut encoded = vec![tag];
+        match len {
+            0..=0x7F => encoded.push(len as u8),
+            0x80..=0xFF => encoded.extend([0x81, len as u8]),
+            _ => encoded.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        encoded.extend_from_slice(contents);
+        return encoded;
+    }
+
+    ///an element made of the given elements
+    pub fn constructed(tag: u8, children: &[Vec<u8>]) -> Vec<u8> {
+        return tlv(tag, &children.concat());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{constructed, tlv};
+    use super::*;
+
+    #[test]
+    fn parses_short_and_long_form_lengths() {
+        for len in [0, 1, 0x7F, 0x80, 0xFF, 0x100, 0x1234] {
+            let contents = vec![0xAB; len];
+            let mut data = tlv(TAG_OCTET_STRING, &contents);
+            data.extend([0x05, 0x00]);
+            let (der, rest) = Der::parse(&data).unwrap();
+            assert_eq!(der.tag, TAG_OCTET_STRING);
+            assert_eq!(der.contents, &contents[..]);
+            assert_eq!(der.raw, &data[..data.len() - 2]);
+            assert_eq!(rest, &[0x05, 0x00]);
+        }
+        //long form is allowed for short values too, and up to 4 length bytes
+        let der = Der::parse_single(&[0x04, 0x84, 0, 0, 0, 2, 0xAA, 0xBB]).unwrap();
+        assert_eq!(der.contents, &[0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_lengths() {
+        let malformed: [&[u8]; 10] = [
+            &[],
+            //tag without a length
+            &[0x30],
+            //long form with fewer length bytes than it announces
+            &[0x30, 0x82, 0x01],
+            &[0x30, 0x84, 0x00, 0x00, 0x00],
+            //lengths past the end of the buffer
+            &[0x04, 0x03, 0xAA, 0xBB],
+            &[0x04, 0x81, 0x80, 0xAA],
+            &[0x30, 0x84, 0xFF, 0xFF, 0xFF, 0xFF, 0x00],
+            //indefinite and 5+ byte lengths
+            &[0x30, 0x80, 0x00, 0x00],
+            &[0x30, 0x85, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00],
+            //multi byte tags
+            &[0x1F, 0x01, 0x00],
+        ];
+        for data in malformed {
+            assert!(Der::parse(data).is_none(), "{data:02X?} parsed");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_children() {
+        let sequence = constructed(TAG_SEQUENCE, &[tlv(TAG_INTEGER, &[1]), tlv(TAG_NULL, &[])]);
+        let children = Der::parse_single(&sequence).unwrap().children().unwrap();
+        assert_eq!(
+            children.iter().map(|child| child.tag).collect::<Vec<_>>(),
+            vec![TAG_INTEGER, TAG_NULL]
+        );
+
+        //the outer length is fine but the last child runs past it
+        let mut truncated = sequence.clone();
+        truncated[1] -= 1;
+        truncated.pop();
+        let outer = Der::parse_single(&truncated).unwrap();
+        assert!(outer.children().is_none());
+        //a child that claims more than its parent holds
+        let overlong = constructed(TAG_SEQUENCE, &[vec![TAG_OCTET_STRING, 0x81, 0xFF, 0]]);
+        assert!(Der::parse_single(&overlong).unwrap().children().is_none());
+    }
+
+    #[test]
+    fn decodes_oids() {
+        let oid = |contents: &[u8]| Der::parse_single(&tlv(TAG_OID, contents)).unwrap().oid();
+        assert_eq!(
+            oid(&[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02]),
+            Some("1.2.840.113549.1.7.2".to_string())
+        );
+        assert_eq!(
+            oid(&[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]),
+            Some("2.16.840.1.101.3.4.2.1".to_string())
+        );
+        assert_eq!(
+            oid(&[0x2B, 0x0E, 0x03, 0x02, 0x1A]),
+            Some("1.3.14.3.2.26".to_string())
+        );
+        assert_eq!(oid(&[]), None);
+        //an arc too large for 64 bits
+        assert_eq!(
+            oid(&[0x2A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]),
+            None
+        );
+        let integer = Der::parse_single(&[TAG_INTEGER, 1, 0x2A]).unwrap();
+        assert_eq!(integer.oid(), None);
+    }
+
+    #[test]
+    fn formats_times_and_strings() {
+        let time = |tag: u8, text: &str| {
+            Der::parse_single(&tlv(tag, text.as_bytes()))
+                .unwrap()
+                .time()
+        };
+        assert_eq!(
+            time(TAG_UTC_TIME, "230102030405Z"),
+            Some("2023-01-02 03:04:05 UTC".to_string())
+        );
+        assert_eq!(
+            time(TAG_UTC_TIME, "9912312359Z"),
+            Some("1999-12-31 23:59:00 UTC".to_string())
+        );
+        assert_eq!(
+            time(TAG_GENERALIZED_TIME, "20240229120000Z"),
+            Some("2024-02-29 12:00:00 UTC".to_string())
+        );
+        assert_eq!(time(TAG_UTC_TIME, "23"), None);
+        assert_eq!(time(TAG_OCTET_STRING, "230102030405Z"), None);
+
+        let string =
+            |tag: u8, contents: &[u8]| Der::parse_single(&tlv(tag, contents)).unwrap().string();
+        assert_eq!(
+            string(TAG_BMP_STRING, &[0, b'h', 0, b'i']),
+            Some("hi".to_string())
+        );
+        assert_eq!(
+            string(TAG_UTF8_STRING, "é".as_bytes()),
+            Some("é".to_string())
+        );
+        assert_eq!(string(TAG_NULL, &[]), None);
+    }
+}
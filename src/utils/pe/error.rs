@@ -0,0 +1,101 @@
+use std::fmt;
+
+///Reasons a file can be rejected as a dll before anything is injected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeValidationError {
+    FileNotFound(String),
+    Unreadable(String),
+    TooSmall(usize),
+    BadDosMagic(u16),
+    ELfanewOutOfRange(u32),
+    BadNtSignature(u32),
+    OptionalHeaderTooSmall(u16),
+    BadOptionalHeaderMagic(u16),
+    SectionTableOutOfFile,
+    SectionOutOfFile(String),
+    WrongMachine(u16),
+    NotADll,
+    MalformedDirectory(&'static str),
+    RuntimeFunctionOutsideCode(u32),
+    UnsupportedApiSetVersion(u32),
+}
+
+impl PeValidationError {
+    ///stable machine readable code for logs and scripts
+    pub fn code(&self) -> &'static str {
+        match self {
+            PeValidationError::FileNotFound(_) => "PE_FILE_NOT_FOUND",
+            PeValidationError::Unreadable(_) => "PE_UNREADABLE",
+            PeValidationError::TooSmall(_) => "PE_TOO_SMALL",
+            PeValidationError::BadDosMagic(_) => "PE_BAD_DOS_MAGIC",
+            PeValidationError::ELfanewOutOfRange(_) => "PE_E_LFANEW_OUT_OF_RANGE",
+            PeValidationError::BadNtSignature(_) => "PE_BAD_NT_SIGNATURE",
+            PeValidationError::OptionalHeaderTooSmall(_) => "PE_OPTIONAL_HEADER_TOO_SMALL",
+            PeValidationError::BadOptionalHeaderMagic(_) => "PE_BAD_OPTIONAL_HEADER_MAGIC",
+            PeValidationError::SectionTableOutOfFile => "PE_SECTION_TABLE_OUT_OF_FILE",
+            PeValidationError::SectionOutOfFile(_) => "PE_SECTION_OUT_OF_FILE",
+            PeValidationError::WrongMachine(_) => "PE_WRONG_MACHINE",
+            PeValidationError::NotADll => "PE_NOT_A_DLL",
+            PeValidationError::MalformedDirectory(_) => "PE_MALFORMED_DIRECTORY",
+            PeValidationError::RuntimeFunctionOutsideCode(_) => "PE_RUNTIME_FUNCTION_OUTSIDE_CODE",
+            PeValidationError::UnsupportedApiSetVersion(_) => "PE_UNSUPPORTED_APISET_VERSION",
+        }
+    }
+}
+
+impl fmt::Display for PeValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeValidationError::FileNotFound(path) => write!(f, "{path} does not exist"),
+            PeValidationError::Unreadable(reason) => write!(f, "Unable to read dll: {reason}"),
+            PeValidationError::TooSmall(size) => {
+                write!(f, "File is too small to be a pe image ({size} bytes)")
+            }
+            PeValidationError::BadDosMagic(magic) => {
+                write!(f, "Bad DOS header magic 0x{magic:04x}, expected MZ")
+            }
+            PeValidationError::ELfanewOutOfRange(e_lfanew) => {
+                write!(f, "e_lfanew 0x{e_lfanew:x} points outside the file")
+            }
+            PeValidationError::BadNtSignature(signature) => {
+                write!(f, "Bad NT signature 0x{signature:08x}, expected PE")
+            }
+            PeValidationError::OptionalHeaderTooSmall(size) => {
+                write!(f, "Optional header is too small ({size} bytes)")
+            }
+            PeValidationError::BadOptionalHeaderMagic(magic) => {
+                write!(f, "Unknown optional header magic 0x{magic:x}")
+            }
+            PeValidationError::SectionTableOutOfFile => {
+                write!(f, "Section table extends past the end of the file")
+            }
+            PeValidationError::SectionOutOfFile(name) => {
+                write!(f, "Section {name} extends past the end of the file")
+            }
+            PeValidationError::WrongMachine(machine) => {
+                write!(
+                    f,
+                    "Unsupported machine 0x{machine:x}, only x86 and x64 dlls can be injected"
+                )
+            }
+            PeValidationError::NotADll => write!(f, "Image is not a dll"),
+            PeValidationError::MalformedDirectory(directory) => {
+                write!(f, "The {directory} directory is malformed")
+            }
+            PeValidationError::RuntimeFunctionOutsideCode(begin) => {
+                write!(
+                    f,
+                    "Exception table entry for 0x{begin:x} lies outside the executable sections"
+                )
+            }
+            PeValidationError::UnsupportedApiSetVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported api set schema version {version}, expected 2, 4 or 6"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PeValidationError {}
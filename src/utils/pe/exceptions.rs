@@ -0,0 +1,502 @@
+use std::fmt;
+
+use super::error::PeValidationError;
+use super::headers::{IMAGE_DIRECTORY_ENTRY_EXCEPTION, IMAGE_SCN_MEM_EXECUTE};
+use super::image::PeImage;
+use super::reader::{read_u16, read_u32};
+
+const SIZE_OF_RUNTIME_FUNCTION: usize = 12;
+const SIZE_OF_UNWIND_INFO_HEADER: u32 = 4;
+///chained unwind info can point back at itself, nothing real nests this deep
+const MAX_UNWIND_CHAIN: usize = 32;
+
+pub const UNW_FLAG_EHANDLER: u8 = 0x1;
+pub const UNW_FLAG_UHANDLER: u8 = 0x2;
+pub const UNW_FLAG_CHAININFO: u8 = 0x4;
+
+const REGISTERS: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+///An x64 RUNTIME_FUNCTION, the range of a non leaf function and where its unwind info lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeFunction {
+    pub begin: u32,
+    pub end: u32,
+    pub unwind_info: u32,
+}
+
+impl RuntimeFunction {
+    pub fn contains(&self, rva: u32) -> bool {
+        return rva >= self.begin && rva < self.end;
+    }
+}
+
+///What a single unwind code undoes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnwindOp {
+    PushNonVolatile(u8),
+    Alloc(u32),
+    SetFramePointer,
+    SaveNonVolatile {
+        register: u8,
+        offset: u32,
+    },
+    SaveXmm128 {
+        register: u8,
+        offset: u32,
+    },
+    ///version 2 epilog descriptor, kept raw
+    Epilog(u16),
+    PushMachineFrame {
+        error_code: bool,
+    },
+    Unknown(u8),
+}
+
+impl fmt::Display for UnwindOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnwindOp::PushNonVolatile(register) => write!(f, "push {}", register_name(*register)),
+            UnwindOp::Alloc(size) => write!(f, "alloc 0x{size:x}"),
+            UnwindOp::SetFramePointer => write!(f, "set frame pointer"),
+            UnwindOp::SaveNonVolatile { register, offset } => {
+                write!(f, "save {} at [rsp+0x{offset:x}]", register_name(*register))
+            }
+            UnwindOp::SaveXmm128 { register, offset } => {
+                write!(f, "save xmm{register} at [rsp+0x{offset:x}]")
+            }
+            UnwindOp::Epilog(raw) => write!(f, "epilog 0x{raw:04x}"),
+            UnwindOp::PushMachineFrame { error_code } => match error_code {
+                true => write!(f, "push machine frame with error code"),
+                false => write!(f, "push machine frame"),
+            },
+            UnwindOp::Unknown(op) => write!(f, "unknown op {op}"),
+        }
+    }
+}
+
+///An UNWIND_CODE, prolog_offset is where in the prolog the operation ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnwindCode {
+    pub prolog_offset: u8,
+    pub op: UnwindOp,
+}
+
+///The exception or termination handler of a function and the data the language runtime passes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionHandler {
+    pub rva: u32,
+    pub data_rva: u32,
+}
+
+///The parsed UNWIND_INFO a RUNTIME_FUNCTION points at
+#[derive(Debug, Clone)]
+pub struct UnwindInfo {
+    #[allow(dead_code)]
+    pub rva: u32,
+    pub version: u8,
+    pub flags: u8,
+    pub size_of_prolog: u8,
+    ///register used as frame pointer, 0 when the function does not use one
+    pub frame_register: u8,
+    ///scaled by 16 when the frame pointer is set up
+    #[allow(dead_code)]
+    pub frame_offset: u8,
+    pub codes: Vec<UnwindCode>,
+    pub handler: Option<ExceptionHandler>,
+    ///the function this one continues, its unwind info runs after this one
+    pub chained: Option<RuntimeFunction>,
+}
+
+impl UnwindInfo {
+    pub fn frame_register_name(&self) -> Option<&'static str> {
+        match self.frame_register {
+            0 => None,
+            register => Some(register_name(register)),
+        }
+    }
+}
+
+pub fn register_name(register: u8) -> &'static str {
+    return REGISTERS.get(register as usize).copied().unwrap_or("?");
+}
+
+///finds the entry covering rva, the table is sorted by begin address
+pub fn lookup_function(functions: &[RuntimeFunction], rva: u32) -> Option<&RuntimeFunction> {
+    let index = functions.partition_point(|function| function.begin <= rva);
+    let function = functions.get(index.checked_sub(1)?)?;
+    match function.contains(rva) {
+        true => return Some(function),
+        false => return None,
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///reads IMAGE_DIRECTORY_ENTRY_EXCEPTION as x64 RUNTIME_FUNCTION entries
+    ///
+    ///x86 images have no function tables so they give back an empty list, every returned entry
+    ///covers code inside an executable section
+    pub fn runtime_functions(&self) -> Result<Vec<RuntimeFunction>, PeValidationError> {
+        if !self.optional_header().is_64bit() {
+            return Ok(Vec::new());
+        }
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION) {
+            Some(directory) => directory,
+            None => return Ok(Vec::new()),
+        };
+        let malformed = PeValidationError::MalformedDirectory("exception");
+        let table = match self.bytes_at_rva(directory.virtual_address, directory.size as usize) {
+            Some(table) => table,
+            None => return Err(malformed),
+        };
+
+        let mut functions: Vec<RuntimeFunction> = Vec::new();
+        for entry in table.chunks_exact(SIZE_OF_RUNTIME_FUNCTION) {
+            let field = |offset: usize| read_u32(entry, offset).unwrap_or_default();
+            let function = RuntimeFunction {
+                begin: field(0),
+                end: field(4),
+                unwind_info: field(8),
+            };
+            //the linker pads the table with zeroed entries
+            if function.begin == 0 && function.end == 0 {
+                continue;
+            }
+            if function.end <= function.begin {
+                return Err(malformed);
+            }
+            //lookups are a binary search, same as RtlLookupFunctionEntry
+            if let Some(previous) = functions.last() {
+                if function.begin < previous.end {
+                    return Err(malformed);
+                }
+            }
+            if !self.is_executable_range(function.begin, function.end) {
+                return Err(PeValidationError::RuntimeFunctionOutsideCode(
+                    function.begin,
+                ));
+            }
+            functions.push(function);
+        }
+
+        return Ok(functions);
+    }
+
+    ///decodes the UNWIND_INFO of a function, chained info is left for unwind_chain
+    pub fn unwind_info(&self, function: &RuntimeFunction) -> Result<UnwindInfo, PeValidationError> {
+        let malformed = PeValidationError::MalformedDirectory("exception");
+        //a set low bit means the entry shares the unwind info of another RUNTIME_FUNCTION
+        let rva = match function.unwind_info & 1 {
+            0 => function.unwind_info,
+            _ => match self.read_u32_at_rva((function.unwind_info & !1).wrapping_add(8)) {
+                Some(rva) => rva,
+                None => return Err(malformed),
+            },
+        };
+
+        let header = match self.bytes_at_rva(rva, SIZE_OF_UNWIND_INFO_HEADER as usize) {
+            Some(header) => header,
+            None => return Err(malformed),
+        };
+        let version = header[0] & 0x7;
+        let flags = header[0] >> 3;
+        let count_of_codes = header[2] as usize;
+
+        let slots = match self.bytes_at_rva(
+            rva.wrapping_add(SIZE_OF_UNWIND_INFO_HEADER),
+            count_of_codes * 2,
+        ) {
+            Some(slots) => slots,
+            None => return Err(malformed),
+        };
+        let codes = match decode_unwind_codes(slots) {
+            Some(codes) => codes,
+            None => return Err(malformed),
+        };
+
+        //the code array is padded to an even number of slots
+        let tail = rva
+            .wrapping_add(SIZE_OF_UNWIND_INFO_HEADER)
+            .wrapping_add(((count_of_codes as u32 + 1) & !1) * 2);
+        let mut handler = None;
+        let mut chained = None;
+        if flags & UNW_FLAG_CHAININFO != 0 {
+            chained = match (
+                self.read_u32_at_rva(tail),
+                self.read_u32_at_rva(tail.wrapping_add(4)),
+                self.read_u32_at_rva(tail.wrapping_add(8)),
+            ) {
+                (Some(begin), Some(end), Some(unwind_info)) => Some(RuntimeFunction {
+                    begin,
+                    end,
+                    unwind_info,
+                }),
+                _ => return Err(malformed),
+            };
+        } else if flags & (UNW_FLAG_EHANDLER | UNW_FLAG_UHANDLER) != 0 {
+            handler = match self.read_u32_at_rva(tail) {
+                Some(handler_rva) => Some(ExceptionHandler {
+                    rva: handler_rva,
+                    data_rva: tail.wrapping_add(4),
+                }),
+                None => return Err(malformed),
+            };
+        }
+
+        return Ok(UnwindInfo {
+            rva,
+            version,
+            flags,
+            size_of_prolog: header[1],
+            frame_register: header[3] & 0xF,
+            frame_offset: header[3] >> 4,
+            codes,
+            handler,
+            chained,
+        });
+    }
+
+    ///the unwind info of a function followed by every chained parent, in the order they unwind
+    pub fn unwind_chain(
+        &self,
+        function: &RuntimeFunction,
+    ) -> Result<Vec<UnwindInfo>, PeValidationError> {
+        let mut chain: Vec<UnwindInfo> = Vec::new();
+        let mut current = *function;
+        loop {
+            if chain.len() == MAX_UNWIND_CHAIN {
+                return Err(PeValidationError::MalformedDirectory("exception"));
+            }
+            let info = self.unwind_info(&current)?;
+            let next = info.chained;
+            chain.push(info);
+            match next {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        return Ok(chain);
+    }
+
+    fn is_executable_range(&self, begin: u32, end: u32) -> bool {
+        return match self.section_for_rva(begin) {
+            Some(section) => {
+                section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+                    && end as u64 <= section.virtual_address as u64 + section.mapped_size() as u64
+            }
+            None => false,
+        };
+    }
+}
+
+//every code takes one to three 16 bit slots depending on the operation
+fn decode_unwind_codes(slots: &[u8]) -> Option<Vec<UnwindCode>> {
+    let slot = |index: usize| read_u16(slots, index * 2);
+    let count = slots.len() / 2;
+
+    let mut codes = Vec::new();
+    let mut index = 0;
+    while index < count {
+        let prolog_offset = slots[index * 2];
+        let op = slots[index * 2 + 1] & 0xF;
+        let info = slots[index * 2 + 1] >> 4;
+        let (op, used) = match op {
+            0 => (UnwindOp::PushNonVolatile(info), 1),
+            1 if info == 0 => (UnwindOp::Alloc(slot(index + 1)? as u32 * 8), 2),
+            1 => {
+                let size = (slot(index + 1)? as u32) | ((slot(index + 2)? as u32) << 16);
+                (UnwindOp::Alloc(size), 3)
+            }
+            2 => (UnwindOp::Alloc(info as u32 * 8 + 8), 1),
+            3 => (UnwindOp::SetFramePointer, 1),
+            4 => (
+                UnwindOp::SaveNonVolatile {
+                    register: info,
+                    offset: slot(index + 1)? as u32 * 8,
+                },
+                2,
+            ),
+            5 => (
+                UnwindOp::SaveNonVolatile {
+                    register: info,
+                    offset: (slot(index + 1)? as u32) | ((slot(index + 2)? as u32) << 16),
+                },
+                3,
+            ),
+            6 => (UnwindOp::Epilog(slot(index)?), 2),
+            8 => (
+                UnwindOp::SaveXmm128 {
+                    register: info,
+                    offset: slot(index + 1)? as u32 * 16,
+                },
+                2,
+            ),
+            9 => (
+                UnwindOp::SaveXmm128 {
+                    register: info,
+                    offset: (slot(index + 1)? as u32) | ((slot(index + 2)? as u32) << 16),
+                },
+                3,
+            ),
+            10 => (
+                UnwindOp::PushMachineFrame {
+                    error_code: info == 1,
+                },
+                1,
+            ),
+            //UWOP_SPARE_CODE, still takes up three slots
+            7 => (UnwindOp::Unknown(op), 3),
+            op => (UnwindOp::Unknown(op), 1),
+        };
+        codes.push(UnwindCode { prolog_offset, op });
+        index += used;
+    }
+
+    return Some(codes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///little endian slots, codes are written as prolog offset | op << 8 | info << 12
+    fn decode(slots: &[u16]) -> Option<Vec<UnwindCode>> {
+        let bytes: Vec<u8> = slots.iter().flat_map(|slot| slot.to_le_bytes()).collect();
+        return decode_unwind_codes(&bytes);
+    }
+
+    fn code(prolog_offset: u8, op: u8, info: u8) -> u16 {
+        return u16::from_le_bytes([prolog_offset, op | info << 4]);
+    }
+
+    fn ops(codes: &[UnwindCode]) -> Vec<UnwindOp> {
+        return codes.iter().map(|code| code.op).collect();
+    }
+
+    #[test]
+    fn multi_slot_codes_skip_their_operands() {
+        //every operand is followed by a push whose register would be misread if a slot was miscounted
+        let codes = decode(&[
+            code(0x20, 1, 0),
+            0x0011,
+            code(0x1E, 0, 3),
+            code(0x1C, 1, 1),
+            0x5678,
+            0x0001,
+            code(0x1A, 0, 5),
+            code(0x18, 4, 6),
+            0x0004,
+            code(0x16, 0, 7),
+            code(0x14, 5, 12),
+            0x0010,
+            0x0002,
+            code(0x12, 0, 13),
+            code(0x10, 8, 6),
+            0x0003,
+            code(0x0E, 0, 14),
+            code(0x0C, 9, 15),
+            0x0040,
+            0x0001,
+            code(0x0A, 0, 15),
+            code(0x08, 7, 0),
+            0xFFFF,
+            0xFFFF,
+            code(0x06, 0, 12),
+        ])
+        .unwrap();
+        assert_eq!(
+            ops(&codes),
+            vec![
+                UnwindOp::Alloc(0x88),
+                UnwindOp::PushNonVolatile(3),
+                UnwindOp::Alloc(0x15678),
+                UnwindOp::PushNonVolatile(5),
+                UnwindOp::SaveNonVolatile {
+                    register: 6,
+                    offset: 0x20,
+                },
+                UnwindOp::PushNonVolatile(7),
+                UnwindOp::SaveNonVolatile {
+                    register: 12,
+                    offset: 0x20010,
+                },
+                UnwindOp::PushNonVolatile(13),
+                UnwindOp::SaveXmm128 {
+                    register: 6,
+                    offset: 0x30,
+                },
+                UnwindOp::PushNonVolatile(14),
+                UnwindOp::SaveXmm128 {
+                    register: 15,
+                    offset: 0x10040,
+                },
+                UnwindOp::PushNonVolatile(15),
+                UnwindOp::Unknown(7),
+                UnwindOp::PushNonVolatile(12),
+            ]
+        );
+        assert_eq!(codes[0].prolog_offset, 0x20);
+        assert_eq!(codes[13].prolog_offset, 0x06);
+    }
+
+    #[test]
+    fn single_slot_codes() {
+        let codes = decode(&[
+            code(0x0C, 3, 0),
+            code(0x08, 2, 3),
+            code(0x04, 10, 1),
+            code(0x02, 10, 0),
+            code(0x01, 11, 0),
+        ])
+        .unwrap();
+        assert_eq!(
+            ops(&codes),
+            vec![
+                UnwindOp::SetFramePointer,
+                UnwindOp::Alloc(0x20),
+                UnwindOp::PushMachineFrame { error_code: true },
+                UnwindOp::PushMachineFrame { error_code: false },
+                UnwindOp::Unknown(11),
+            ]
+        );
+        assert_eq!(decode(&[]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_codes_missing_their_operands() {
+        assert_eq!(decode(&[code(0x04, 1, 0)]), None);
+        assert_eq!(decode(&[code(0x04, 1, 1), 0x1000]), None);
+        assert_eq!(decode(&[code(0x04, 4, 3)]), None);
+        assert_eq!(decode(&[code(0x04, 5, 3), 0x1000]), None);
+        assert_eq!(decode(&[code(0x04, 8, 3)]), None);
+        assert_eq!(decode(&[code(0x04, 9, 3), 0x1000]), None);
+        //a spare code without its operands is skipped rather than read
+        assert_eq!(
+            decode(&[code(0x04, 7, 0)]).map(|codes| ops(&codes)),
+            Some(vec![UnwindOp::Unknown(7)])
+        );
+    }
+
+    #[test]
+    fn finds_the_covering_function() {
+        let function = |begin: u32, end: u32| RuntimeFunction {
+            begin,
+            end,
+            unwind_info: 0,
+        };
+        let functions = [
+            function(0x1000, 0x1040),
+            function(0x1040, 0x1080),
+            function(0x1100, 0x1180),
+        ];
+        assert_eq!(lookup_function(&functions, 0x1000), Some(&functions[0]));
+        assert_eq!(lookup_function(&functions, 0x1040), Some(&functions[1]));
+        assert_eq!(lookup_function(&functions, 0x117F), Some(&functions[2]));
+        //gaps between functions and either end of the table
+        assert_eq!(lookup_function(&functions, 0x1090), None);
+        assert_eq!(lookup_function(&functions, 0x0FFF), None);
+        assert_eq!(lookup_function(&functions, 0x1180), None);
+    }
+}
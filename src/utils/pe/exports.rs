@@ -0,0 +1,355 @@
+use std::borrow::Cow;
+
+use super::error::PeValidationError;
+use super::headers::{DataDirectory, IMAGE_DIRECTORY_ENTRY_EXPORT};
+use super::image::PeImage;
+
+const SIZE_OF_EXPORT_DIRECTORY: usize = 40;
+
+///A single entry of the export table
+///
+///a function exported under several names shows up once per name
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub name: Option<String>,
+    pub ordinal: u32,
+    pub rva: u32,
+    ///set when the export is forwarded to another dll, e.g. NTDLL.RtlAllocateHeap
+    pub forwarder: Option<String>,
+}
+
+///What a forwarder string points at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwarderTarget {
+    Name(String),
+    Ordinal(u32),
+}
+
+impl Export {
+    ///splits the forwarder string into the module and the export inside it
+    ///
+    ///NTDLL.RtlAllocateHeap becomes ("NTDLL.dll", Name("RtlAllocateHeap")) and NTDLL.#12 becomes
+    ///("NTDLL.dll", Ordinal(12))
+    pub fn forwarder_target(&self) -> Option<(String, ForwarderTarget)> {
+        let forwarder = self.forwarder.as_ref()?;
+        //the module name itself may contain dots so split on the last one
+        let (module, export) = forwarder.rsplit_once('.')?;
+        let target = match export.strip_prefix('#') {
+            Some(ordinal) => ForwarderTarget::Ordinal(ordinal.parse().ok()?),
+            None => ForwarderTarget::Name(export.to_string()),
+        };
+        return Some((format!("{module}.dll"), target));
+    }
+}
+
+///The parsed IMAGE_EXPORT_DIRECTORY
+#[derive(Debug, Clone, Default)]
+pub struct ExportTable {
+    pub dll_name: Option<String>,
+    #[allow(dead_code)]
+    pub time_date_stamp: u32,
+    #[allow(dead_code)]
+    pub ordinal_base: u32,
+    pub exports: Vec<Export>,
+}
+
+impl ExportTable {
+    pub fn find_by_name(&self, name: &str) -> Option<&Export> {
+        return self
+            .exports
+            .iter()
+            .find(|export| export.name.as_deref() == Some(name));
+    }
+
+    pub fn find_by_ordinal(&self, ordinal: u32) -> Option<&Export> {
+        return self.exports.iter().find(|export| export.ordinal == ordinal);
+    }
+}
+
+///Anything an export table can be read out of by rva
+///
+///a pe file on disk hands out slices of itself, a module inside another process has to copy
+pub trait RvaSource {
+    fn bytes(&self, rva: u32, len: usize) -> Option<Cow<'_, [u8]>>;
+    fn string(&self, rva: u32) -> Option<String>;
+}
+
+impl<'a> RvaSource for PeImage<'a> {
+    fn bytes(&self, rva: u32, len: usize) -> Option<Cow<'_, [u8]>> {
+        return self.bytes_at_rva(rva, len).map(Cow::Borrowed);
+    }
+
+    fn string(&self, rva: u32) -> Option<String> {
+        return self.read_string_at_rva(rva);
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///reads the export table, an image without one gives back an empty table
+    pub fn exports(&self) -> Result<ExportTable, PeValidationError> {
+        return match self.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT) {
+            Some(directory) => parse_exports(self, directory),
+            None => Ok(ExportTable::default()),
+        };
+    }
+}
+
+///parses the export directory of any rva source
+///
+///names that point outside the image or at functions that do not exist are skipped instead of
+///failing the whole table, the function behind them is still listed by ordinal
+pub fn parse_exports(
+    source: &impl RvaSource,
+    directory: DataDirectory,
+) -> Result<ExportTable, PeValidationError> {
+    let malformed = PeValidationError::MalformedDirectory("export");
+
+    let header = match source.bytes(directory.virtual_address, SIZE_OF_EXPORT_DIRECTORY) {
+        Some(header) => header,
+        None => return Err(malformed),
+    };
+    let field = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let name_rva = field(12);
+    let ordinal_base = field(16);
+    let number_of_functions = field(20) as usize;
+    let number_of_names = field(24) as usize;
+    let address_of_functions = field(28);
+    let address_of_names = field(32);
+    let address_of_name_ordinals = field(36);
+
+    //the function table has to be fully readable, everything else hangs off of it
+    let functions = match source.bytes(address_of_functions, number_of_functions.saturating_mul(4))
+    {
+        Some(functions) => functions,
+        None if number_of_functions == 0 => Cow::Borrowed(&[][..]),
+        None => return Err(malformed),
+    };
+    let function_rva =
+        |index: usize| u32::from_le_bytes(functions[index * 4..index * 4 + 4].try_into().unwrap());
+
+    //collect the names for each function index, a broken name table just means fewer names
+    let mut names: Vec<Vec<String>> = vec![Vec::new(); number_of_functions];
+    let name_table = source.bytes(address_of_names, number_of_names.saturating_mul(4));
+    let ordinal_table = source.bytes(address_of_name_ordinals, number_of_names.saturating_mul(2));
+    if let (Some(name_table), Some(ordinal_table)) = (name_table, ordinal_table) {
+        for i in 0..number_of_names {
+            let name_rva = u32::from_le_bytes(name_table[i * 4..i * 4 + 4].try_into().unwrap());
+            let index =
+                u16::from_le_bytes(ordinal_table[i * 2..i * 2 + 2].try_into().unwrap()) as usize;
+            if index >= number_of_functions {
+                continue;
+            }
+            if let Some(name) = source.string(name_rva) {
+                names[index].push(name);
+            }
+        }
+    }
+
+    let directory_end = directory.virtual_address as u64 + directory.size as u64;
+    let mut exports = Vec::new();
+    for (index, function_names) in names.into_iter().enumerate() {
+        let rva = function_rva(index);
+        //holes in the ordinal range have an rva of 0
+        if rva == 0 {
+            continue;
+        }
+
+        //an rva inside the export directory is a forwarder string instead of code
+        let forwarder = match rva >= directory.virtual_address && (rva as u64) < directory_end {
+            true => source.string(rva),
+            false => None,
+        };
+        let ordinal = ordinal_base.wrapping_add(index as u32);
+
+        if function_names.is_empty() {
+            exports.push(Export {
+                name: None,
+                ordinal,
+                rva,
+                forwarder,
+            });
+            continue;
+        }
+        for name in function_names {
+            exports.push(Export {
+                name: Some(name),
+                ordinal,
+                rva,
+                forwarder: forwarder.clone(),
+            });
+        }
+    }
+
+    return Ok(ExportTable {
+        dll_name: source.string(name_rva),
+        time_date_stamp: field(4),
+        ordinal_base,
+        exports,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, FixtureExport, PeBuilder, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    //.edata layout of the fixture: directory, function table, name table, then ordinal table
+    const FUNCTIONS: usize = 40;
+
+    fn builder(exports: &[FixtureExport]) -> PeBuilder {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+        builder.exports("test.dll", 5, exports);
+        return builder;
+    }
+
+    fn exports_of(builder: &PeBuilder) -> Result<ExportTable, PeValidationError> {
+        return PeImage::parse(&builder.build()).unwrap().exports();
+    }
+
+    fn listed(table: &ExportTable) -> Vec<(Option<&str>, u32, Option<&str>)> {
+        return table
+            .exports
+            .iter()
+            .map(|export| {
+                (
+                    export.name.as_deref(),
+                    export.ordinal,
+                    export.forwarder.as_deref(),
+                )
+            })
+            .collect();
+    }
+
+    #[test]
+    fn lists_named_ordinal_only_and_forwarded_exports() {
+        let table = exports_of(&builder(&[
+            FixtureExport::Code("code", 0x1000),
+            FixtureExport::Ordinal(0x1010),
+            FixtureExport::Forward("forwarded", "NTDLL.RtlAllocateHeap"),
+            FixtureExport::Ordinal(0),
+        ]))
+        .unwrap();
+        assert_eq!(table.dll_name.as_deref(), Some("test.dll"));
+        assert_eq!(table.ordinal_base, 5);
+        //the hole at ordinal 8 is left out
+        assert_eq!(
+            listed(&table),
+            vec![
+                (Some("code"), 5, None),
+                (None, 6, None),
+                (Some("forwarded"), 7, Some("NTDLL.RtlAllocateHeap")),
+            ]
+        );
+        assert_eq!(
+            table.find_by_name("code").map(|export| export.rva),
+            Some(0x1000)
+        );
+        assert_eq!(
+            table.find_by_ordinal(6).map(|export| export.rva),
+            Some(0x1010)
+        );
+        assert!(table.find_by_ordinal(8).is_none());
+        assert_eq!(
+            table.find_by_name("forwarded").unwrap().forwarder_target(),
+            Some((
+                "NTDLL.dll".to_string(),
+                ForwarderTarget::Name("RtlAllocateHeap".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn splits_forwarder_strings() {
+        let target = |forwarder: &str| {
+            return Export {
+                name: None,
+                ordinal: 1,
+                rva: 0x1000,
+                forwarder: Some(forwarder.to_string()),
+            }
+            .forwarder_target();
+        };
+        assert_eq!(
+            target("NTDLL.#12"),
+            Some(("NTDLL.dll".to_string(), ForwarderTarget::Ordinal(12)))
+        );
+        //api set contracts have dots in them, the export follows the last one
+        assert_eq!(
+            target("api-ms-win-core-heap-l1-1-0.HeapAlloc"),
+            Some((
+                "api-ms-win-core-heap-l1-1-0.dll".to_string(),
+                ForwarderTarget::Name("HeapAlloc".to_string())
+            ))
+        );
+        assert_eq!(target("nodot"), None);
+        assert_eq!(target("NTDLL.#twelve"), None);
+    }
+
+    #[test]
+    fn skips_names_that_cannot_be_read() {
+        let exports = [
+            FixtureExport::Code("a", 0x1000),
+            FixtureExport::Code("b", 0x1010),
+            FixtureExport::Code("c", 0x1020),
+        ];
+        let names = FUNCTIONS + exports.len() * 4;
+        let ordinals = names + exports.len() * 4;
+
+        //a name outside the image and a name for a function that does not exist
+        let mut broken = builder(&exports);
+        let edata = &mut broken.sections[1].data;
+        put(edata, names, &0xFFFF0000u32.to_le_bytes());
+        put(edata, ordinals + 2, &3u16.to_le_bytes());
+        let table = exports_of(&broken).unwrap();
+        assert_eq!(
+            listed(&table),
+            vec![(None, 5, None), (None, 6, None), (Some("c"), 7, None)]
+        );
+
+        //a name table outside the image costs every name but no function
+        let mut broken = builder(&exports);
+        put(
+            &mut broken.sections[1].data,
+            32,
+            &0xFFFF0000u32.to_le_bytes(),
+        );
+        let table = exports_of(&broken).unwrap();
+        assert_eq!(
+            listed(&table),
+            vec![(None, 5, None), (None, 6, None), (None, 7, None)]
+        );
+    }
+
+    #[test]
+    fn rejects_unreadable_function_tables() {
+        let exports = [FixtureExport::Code("a", 0x1000)];
+        let malformed = Some(PeValidationError::MalformedDirectory("export"));
+
+        let mut broken = builder(&exports);
+        put(
+            &mut broken.sections[1].data,
+            28,
+            &0xFFFF0000u32.to_le_bytes(),
+        );
+        assert_eq!(exports_of(&broken).err(), malformed.clone());
+        //more functions than the section holds
+        let mut broken = builder(&exports);
+        put(
+            &mut broken.sections[1].data,
+            20,
+            &0x10000000u32.to_le_bytes(),
+        );
+        assert_eq!(exports_of(&broken).err(), malformed.clone());
+        //a directory outside the image
+        let mut broken = builder(&exports);
+        broken.directories[IMAGE_DIRECTORY_ENTRY_EXPORT].0 = 0xFFFF0000;
+        assert_eq!(exports_of(&broken).err(), malformed);
+
+        //no exports at all is fine
+        let mut empty = builder(&[]);
+        empty.directories[IMAGE_DIRECTORY_ENTRY_EXPORT] = (0, 0);
+        assert!(exports_of(&empty).unwrap().exports.is_empty());
+    }
+}
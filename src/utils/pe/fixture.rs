@@ -0,0 +1,367 @@
+//synthetic images for the unit tests, only the headers and sections a test asks for
+
+use super::headers::{
+    Architecture, IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DIRECTORY_ENTRY_IMPORT, IMAGE_DOS_SIGNATURE,
+    IMAGE_FILE_DLL, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_I386,
+    IMAGE_NT_OPTIONAL_HDR32_MAGIC, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_NT_SIGNATURE,
+    IMAGE_NUMBEROF_DIRECTORY_ENTRIES, SIZE_OF_DATA_DIRECTORY, SIZE_OF_DOS_HEADER,
+    SIZE_OF_FILE_HEADER, SIZE_OF_SECTION_HEADER,
+};
+use super::imports::ImportTarget;
+
+pub const SCN_TEXT: u32 = 0x60000020;
+pub const SCN_RDATA: u32 = 0x40000040;
+pub const SCN_DATA: u32 = 0xC0000040;
+
+///An entry of a fixture export table, ordinals are handed out in the order the entries are given
+pub enum FixtureExport {
+    ///a named function at the given rva
+    Code(&'static str, u32),
+    ///a named export forwarded to another dll, e.g. ("HeapAlloc", "NTDLL.RtlAllocateHeap")
+    Forward(&'static str, &'static str),
+    ///a function that is only exported by ordinal
+    Ordinal(u32),
+}
+
+pub fn by_name(name: &str) -> ImportTarget {
+    return ImportTarget::Name {
+        hint: 0,
+        name: name.to_string(),
+    };
+}
+
+pub struct FixtureSection {
+    pub name: &'static str,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub characteristics: u32,
+    pub data: Vec<u8>,
+}
+
+///Lays out a dll the way a linker would, sections follow each other at SectionAlignment
+pub struct PeBuilder {
+    pub architecture: Architecture,
+    pub image_base: u64,
+    pub section_alignment: u32,
+    pub file_alignment: u32,
+    pub characteristics: u16,
+    pub entry_point: u32,
+    pub directories: [(u32, u32); IMAGE_NUMBEROF_DIRECTORY_ENTRIES],
+    pub sections: Vec<FixtureSection>,
+}
+
+pub fn align(value: u32, alignment: u32) -> u32 {
+    return (value + alignment - 1) / alignment * alignment;
+}
+
+pub fn put(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    if data.len() < offset + bytes.len() {
+        data.resize(offset + bytes.len(), 0);
+    }
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+impl PeBuilder {
+    pub fn new(architecture: Architecture) -> PeBuilder {
+        return PeBuilder {
+            architecture,
+            image_base: match architecture {
+                Architecture::X86 => 0x10000000,
+                Architecture::X64 => 0x180000000,
+            },
+            section_alignment: 0x1000,
+            file_alignment: 0x200,
+            characteristics: IMAGE_FILE_DLL,
+            entry_point: 0,
+            directories: [(0, 0); IMAGE_NUMBEROF_DIRECTORY_ENTRIES],
+            sections: Vec::new(),
+        };
+    }
+
+    pub fn optional_header_size(&self) -> usize {
+        let fixed = match self.architecture {
+            Architecture::X86 => 96,
+            Architecture::X64 => 112,
+        };
+        return fixed + IMAGE_NUMBEROF_DIRECTORY_ENTRIES * SIZE_OF_DATA_DIRECTORY;
+    }
+
+    ///offset of the section table, also the end of the optional header
+    pub fn section_table(&self) -> usize {
+        return SIZE_OF_DOS_HEADER + 4 + SIZE_OF_FILE_HEADER + self.optional_header_size();
+    }
+
+    pub fn size_of_headers(&self) -> u32 {
+        let end = self.section_table() + self.sections.len() * SIZE_OF_SECTION_HEADER;
+        return align(end as u32, self.file_alignment);
+    }
+
+    ///where the next section will be placed, the headers always leave room for a few more
+    pub fn next_rva(&self) -> u32 {
+        return match self.sections.last() {
+            Some(last) => align(
+                last.virtual_address + last.virtual_size.max(1),
+                self.section_alignment,
+            ),
+            None => align(0x400, self.section_alignment),
+        };
+    }
+
+    pub fn size_of_image(&self) -> u32 {
+        return self.next_rva();
+    }
+
+    ///adds a section that is exactly as large as its data, returns its rva
+    pub fn section(&mut self, name: &'static str, characteristics: u32, data: Vec<u8>) -> u32 {
+        let size = data.len() as u32;
+        return self.section_with_size(name, characteristics, data, size);
+    }
+
+    ///adds a section whose virtual size may exceed its data, the rest is zero filled like .bss
+    pub fn section_with_size(
+        &mut self,
+        name: &'static str,
+        characteristics: u32,
+        data: Vec<u8>,
+        virtual_size: u32,
+    ) -> u32 {
+        let virtual_address = self.next_rva();
+        self.sections.push(FixtureSection {
+            name,
+            virtual_address,
+            virtual_size,
+            characteristics,
+            data,
+        });
+        return virtual_address;
+    }
+
+    pub fn directory(&mut self, index: usize, rva: u32, size: u32) {
+        self.directories[index] = (rva, size);
+    }
+
+    pub fn pointer_size(&self) -> usize {
+        return match self.architecture {
+            Architecture::X86 => 4,
+            Architecture::X64 => 8,
+        };
+    }
+
+    ///adds an .idata section with a descriptor, lookup table and IAT per module
+    ///
+    ///returns the rva of the first IAT slot of every module
+    pub fn imports(&mut self, modules: &[(&str, &[ImportTarget])]) -> Vec<u32> {
+        let base = self.next_rva();
+        let pointer_size = self.pointer_size();
+        let ordinal_flag = 1u64 << (pointer_size * 8 - 1);
+        let mut data = Vec::new();
+
+        //descriptors first, then the thunk tables, the strings go behind all of them
+        let mut tables = (modules.len() + 1) * 20;
+        let mut strings = tables;
+        for (_, targets) in modules {
+            strings += (targets.len() + 1) * pointer_size * 2;
+        }
+
+        let mut iats = Vec::new();
+        for (i, (name, targets)) in modules.iter().enumerate() {
+            let lookup = tables;
+            let iat = lookup + (targets.len() + 1) * pointer_size;
+            tables = iat + (targets.len() + 1) * pointer_size;
+
+            let descriptor = i * 20;
+            put(&mut data, descriptor, &(base + lookup as u32).to_le_bytes());
+            put(
+                &mut data,
+                descriptor + 12,
+                &(base + strings as u32).to_le_bytes(),
+            );
+            put(
+                &mut data,
+                descriptor + 16,
+                &(base + iat as u32).to_le_bytes(),
+            );
+            put(&mut data, strings, name.as_bytes());
+            strings = align(strings as u32 + name.len() as u32 + 1, 2) as usize;
+
+            for (j, target) in targets.iter().enumerate() {
+                let entry = match target {
+                    ImportTarget::Name { hint, name } => {
+                        put(&mut data, strings, &hint.to_le_bytes());
+                        put(&mut data, strings + 2, name.as_bytes());
+                        let entry = base as u64 + strings as u64;
+                        strings = align(strings as u32 + 2 + name.len() as u32 + 1, 2) as usize;
+                        entry
+                    }
+                    ImportTarget::Ordinal(ordinal) => ordinal_flag | *ordinal as u64,
+                };
+                let slot = j * pointer_size;
+                put(
+                    &mut data,
+                    lookup + slot,
+                    &entry.to_le_bytes()[..pointer_size],
+                );
+                put(&mut data, iat + slot, &entry.to_le_bytes()[..pointer_size]);
+            }
+            iats.push(base + iat as u32);
+        }
+        data.resize(strings, 0);
+
+        let size = (modules.len() as u32 + 1) * 20;
+        self.section(".idata", SCN_DATA, data);
+        self.directory(IMAGE_DIRECTORY_ENTRY_IMPORT, base, size);
+        return iats;
+    }
+
+    ///adds an .edata section exporting everything under dll_name, returns its rva
+    ///
+    ///the directory covers the whole section so forwarder strings are recognized as such
+    pub fn exports(&mut self, dll_name: &str, ordinal_base: u32, exports: &[FixtureExport]) -> u32 {
+        let base = self.next_rva();
+        let mut named: Vec<(&str, u16)> = Vec::new();
+        for (index, export) in exports.iter().enumerate() {
+            match export {
+                FixtureExport::Code(name, _) | FixtureExport::Forward(name, _) => {
+                    named.push((*name, index as u16))
+                }
+                FixtureExport::Ordinal(_) => {}
+            }
+        }
+        //the name pointer table is sorted so the loader can binary search it
+        named.sort();
+
+        let functions = 40;
+        let names = functions + exports.len() * 4;
+        let ordinals = names + named.len() * 4;
+        let mut strings = ordinals + named.len() * 2;
+        let mut data = Vec::new();
+        let mut string = |data: &mut Vec<u8>, value: &str| -> u32 {
+            put(data, strings, value.as_bytes());
+            put(data, strings + value.len(), &[0]);
+            let rva = base + strings as u32;
+            strings += value.len() + 1;
+            return rva;
+        };
+
+        let name_rva = string(&mut data, dll_name);
+        put(&mut data, 12, &name_rva.to_le_bytes());
+        put(&mut data, 16, &ordinal_base.to_le_bytes());
+        put(&mut data, 20, &(exports.len() as u32).to_le_bytes());
+        put(&mut data, 24, &(named.len() as u32).to_le_bytes());
+        put(&mut data, 28, &(base + functions as u32).to_le_bytes());
+        put(&mut data, 32, &(base + names as u32).to_le_bytes());
+        put(&mut data, 36, &(base + ordinals as u32).to_le_bytes());
+
+        for (index, export) in exports.iter().enumerate() {
+            let rva = match export {
+                FixtureExport::Code(_, rva) | FixtureExport::Ordinal(rva) => *rva,
+                FixtureExport::Forward(_, forwarder) => string(&mut data, forwarder),
+            };
+            put(&mut data, functions + index * 4, &rva.to_le_bytes());
+        }
+        for (i, (name, index)) in named.iter().enumerate() {
+            let rva = string(&mut data, name);
+            put(&mut data, names + i * 4, &rva.to_le_bytes());
+            put(&mut data, ordinals + i * 2, &index.to_le_bytes());
+        }
+
+        let size = data.len() as u32;
+        self.section(".edata", SCN_RDATA, data);
+        self.directory(IMAGE_DIRECTORY_ENTRY_EXPORT, base, size);
+        return base;
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 0, &IMAGE_DOS_SIGNATURE.to_le_bytes());
+        put(&mut data, 0x3C, &(SIZE_OF_DOS_HEADER as u32).to_le_bytes());
+
+        let nt = SIZE_OF_DOS_HEADER;
+        put(&mut data, nt, &IMAGE_NT_SIGNATURE.to_le_bytes());
+        let machine = match self.architecture {
+            Architecture::X86 => IMAGE_FILE_MACHINE_I386,
+            Architecture::X64 => IMAGE_FILE_MACHINE_AMD64,
+        };
+        let file = nt + 4;
+        put(&mut data, file, &machine.to_le_bytes());
+        put(
+            &mut data,
+            file + 2,
+            &(self.sections.len() as u16).to_le_bytes(),
+        );
+        let optional_size = self.optional_header_size() as u16;
+        put(&mut data, file + 16, &optional_size.to_le_bytes());
+        put(&mut data, file + 18, &self.characteristics.to_le_bytes());
+
+        let optional = file + SIZE_OF_FILE_HEADER;
+        let (magic, fixed) = match self.architecture {
+            Architecture::X86 => (IMAGE_NT_OPTIONAL_HDR32_MAGIC, 96),
+            Architecture::X64 => (IMAGE_NT_OPTIONAL_HDR64_MAGIC, 112),
+        };
+        put(&mut data, optional, &magic.to_le_bytes());
+        put(&mut data, optional + 16, &self.entry_point.to_le_bytes());
+        match self.architecture {
+            Architecture::X86 => put(
+                &mut data,
+                optional + 28,
+                &(self.image_base as u32).to_le_bytes(),
+            ),
+            Architecture::X64 => put(&mut data, optional + 24, &self.image_base.to_le_bytes()),
+        }
+        put(
+            &mut data,
+            optional + 32,
+            &self.section_alignment.to_le_bytes(),
+        );
+        put(&mut data, optional + 36, &self.file_alignment.to_le_bytes());
+        put(
+            &mut data,
+            optional + 56,
+            &self.size_of_image().to_le_bytes(),
+        );
+        put(
+            &mut data,
+            optional + 60,
+            &self.size_of_headers().to_le_bytes(),
+        );
+        let directory_count = IMAGE_NUMBEROF_DIRECTORY_ENTRIES as u32;
+        put(
+            &mut data,
+            optional + fixed - 4,
+            &directory_count.to_le_bytes(),
+        );
+        for (i, (rva, size)) in self.directories.iter().enumerate() {
+            let entry = optional + fixed + i * SIZE_OF_DATA_DIRECTORY;
+            put(&mut data, entry, &rva.to_le_bytes());
+            put(&mut data, entry + 4, &size.to_le_bytes());
+        }
+
+        let mut raw = self.size_of_headers();
+        for (i, section) in self.sections.iter().enumerate() {
+            let header = self.section_table() + i * SIZE_OF_SECTION_HEADER;
+            let raw_size = align(section.data.len() as u32, self.file_alignment);
+            let raw_pointer = match raw_size {
+                0 => 0,
+                _ => raw,
+            };
+            put(&mut data, header, section.name.as_bytes());
+            put(&mut data, header + 8, &section.virtual_size.to_le_bytes());
+            put(
+                &mut data,
+                header + 12,
+                &section.virtual_address.to_le_bytes(),
+            );
+            put(&mut data, header + 16, &raw_size.to_le_bytes());
+            put(&mut data, header + 20, &raw_pointer.to_le_bytes());
+            put(
+                &mut data,
+                header + 36,
+                &section.characteristics.to_le_bytes(),
+            );
+            put(&mut data, raw as usize, &section.data);
+            raw += raw_size;
+        }
+        data.resize(raw as usize, 0);
+        return data;
+    }
+}
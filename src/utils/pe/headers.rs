@@ -0,0 +1,339 @@
+use super::reader::{read_bytes, read_u16, read_u32, read_u64, read_u8};
+
+//pure rust copies of the winnt.h constants so the parser does not need winapi
+pub const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D; //MZ
+pub const IMAGE_NT_SIGNATURE: u32 = 0x00004550; //PE\0\0
+
+pub const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+
+pub const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+pub const IMAGE_FILE_DLL: u16 = 0x2000;
+
+pub const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+pub const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
+pub const IMAGE_NUMBEROF_DIRECTORY_ENTRIES: usize = 16;
+pub const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+pub const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+pub const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+pub const IMAGE_DIRECTORY_ENTRY_EXCEPTION: usize = 3;
+pub const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+pub const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+pub const IMAGE_DIRECTORY_ENTRY_TLS: usize = 9;
+pub const IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG: usize = 10;
+pub const IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT: usize = 11;
+#[allow(dead_code)]
+pub const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+pub const IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT: usize = 13;
+pub const IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR: usize = 14;
+
+pub const IMAGE_DLLCHARACTERISTICS_HIGH_ENTROPY_VA: u16 = 0x0020;
+pub const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+pub const IMAGE_DLLCHARACTERISTICS_FORCE_INTEGRITY: u16 = 0x0080;
+pub const IMAGE_DLLCHARACTERISTICS_NX_COMPAT: u16 = 0x0100;
+pub const IMAGE_DLLCHARACTERISTICS_NO_SEH: u16 = 0x0400;
+pub const IMAGE_DLLCHARACTERISTICS_GUARD_CF: u16 = 0x4000;
+
+#[allow(dead_code)]
+pub const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
+#[allow(dead_code)]
+pub const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x00000040;
+#[allow(dead_code)]
+pub const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x00000080;
+pub const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x02000000;
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+pub const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+pub const SIZE_OF_DOS_HEADER: usize = 0x40;
+pub const SIZE_OF_FILE_HEADER: usize = 20;
+pub const SIZE_OF_SECTION_HEADER: usize = 40;
+pub const SIZE_OF_DATA_DIRECTORY: usize = 8;
+
+///the parts of IMAGE_DOS_HEADER the injector cares about
+#[derive(Debug, Clone, Copy)]
+pub struct DosHeader {
+    pub e_magic: u16,
+    pub e_lfanew: u32,
+}
+
+impl DosHeader {
+    pub fn parse(data: &[u8]) -> Option<DosHeader> {
+        return Some(DosHeader {
+            e_magic: read_u16(data, 0)?,
+            e_lfanew: read_u32(data, 0x3C)?,
+        });
+    }
+}
+
+///IMAGE_FILE_HEADER
+#[derive(Debug, Clone, Copy)]
+pub struct FileHeader {
+    pub machine: u16,
+    pub number_of_sections: u16,
+    pub time_date_stamp: u32,
+    #[allow(dead_code)]
+    pub pointer_to_symbol_table: u32,
+    #[allow(dead_code)]
+    pub number_of_symbols: u32,
+    pub size_of_optional_header: u16,
+    pub characteristics: u16,
+}
+
+impl FileHeader {
+    pub fn parse(data: &[u8], offset: usize) -> Option<FileHeader> {
+        return Some(FileHeader {
+            machine: read_u16(data, offset)?,
+            number_of_sections: read_u16(data, offset + 2)?,
+            time_date_stamp: read_u32(data, offset + 4)?,
+            pointer_to_symbol_table: read_u32(data, offset + 8)?,
+            number_of_symbols: read_u32(data, offset + 12)?,
+            size_of_optional_header: read_u16(data, offset + 16)?,
+            characteristics: read_u16(data, offset + 18)?,
+        });
+    }
+
+    pub fn is_dll(&self) -> bool {
+        return self.characteristics & IMAGE_FILE_DLL != 0;
+    }
+}
+
+///IMAGE_DATA_DIRECTORY
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+///The instruction set and bitness of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    X86,
+    X64,
+}
+
+impl std::fmt::Display for Architecture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Architecture::X86 => write!(f, "x86"),
+            Architecture::X64 => write!(f, "x64"),
+        }
+    }
+}
+
+///IMAGE_OPTIONAL_HEADER32 and IMAGE_OPTIONAL_HEADER64 normalised into one struct
+///
+///the fields that are pointer sized in the image are widened to u64, base_of_data only exists in PE32
+//every field of the optional header is mirrored, the injector only reads a few
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct OptionalHeader {
+    pub magic: u16,
+    pub major_linker_version: u8,
+    pub minor_linker_version: u8,
+    pub size_of_code: u32,
+    pub size_of_initialized_data: u32,
+    pub size_of_uninitialized_data: u32,
+    pub address_of_entry_point: u32,
+    pub base_of_code: u32,
+    pub base_of_data: Option<u32>,
+    pub image_base: u64,
+    pub section_alignment: u32,
+    pub file_alignment: u32,
+    pub major_operating_system_version: u16,
+    pub minor_operating_system_version: u16,
+    pub major_image_version: u16,
+    pub minor_image_version: u16,
+    pub major_subsystem_version: u16,
+    pub minor_subsystem_version: u16,
+    pub win32_version_value: u32,
+    pub size_of_image: u32,
+    pub size_of_headers: u32,
+    pub check_sum: u32,
+    pub subsystem: u16,
+    pub dll_characteristics: u16,
+    pub size_of_stack_reserve: u64,
+    pub size_of_stack_commit: u64,
+    pub size_of_heap_reserve: u64,
+    pub size_of_heap_commit: u64,
+    pub loader_flags: u32,
+    pub number_of_rva_and_sizes: u32,
+    pub data_directories: Vec<DataDirectory>,
+}
+
+impl OptionalHeader {
+    ///size of the PE32 optional header up to the start of the data directories
+    pub const FIXED_SIZE_32: usize = 96;
+    ///size of the PE32+ optional header up to the start of the data directories
+    pub const FIXED_SIZE_64: usize = 112;
+
+    ///parses the optional header at offset, never reading past size bytes (SizeOfOptionalHeader)
+    ///
+    ///the layout is picked from the magic at runtime so the injector's own bitness does not matter
+    pub fn parse(data: &[u8], offset: usize, size: usize) -> Option<OptionalHeader> {
+        let header = read_bytes(data, offset, size)?;
+
+        let magic = read_u16(header, 0)?;
+        let fixed_size = match magic {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => Self::FIXED_SIZE_32,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => Self::FIXED_SIZE_64,
+            _ => return None,
+        };
+        if size < fixed_size {
+            return None;
+        }
+        let is_64 = magic == IMAGE_NT_OPTIONAL_HDR64_MAGIC;
+
+        //the fields after SizeOfStackReserve move depending on the pointer size
+        let read_pointer = |offset: usize| -> Option<u64> {
+            match is_64 {
+                true => read_u64(header, offset),
+                false => Some(read_u32(header, offset)? as u64),
+            }
+        };
+        let pointer_size = if is_64 { 8 } else { 4 };
+        let stack_reserve = 72;
+        let stack_commit = stack_reserve + pointer_size;
+        let heap_reserve = stack_commit + pointer_size;
+        let heap_commit = heap_reserve + pointer_size;
+        let loader_flags = heap_commit + pointer_size;
+
+        //only trust as many data directories as actually fit inside the optional header
+        let number_of_rva_and_sizes = read_u32(header, loader_flags + 4)?;
+        let directory_count = (number_of_rva_and_sizes as usize)
+            .min(IMAGE_NUMBEROF_DIRECTORY_ENTRIES)
+            .min((size - fixed_size) / SIZE_OF_DATA_DIRECTORY);
+        let mut data_directories = Vec::with_capacity(directory_count);
+        for i in 0..directory_count {
+            let entry = fixed_size + i * SIZE_OF_DATA_DIRECTORY;
+            data_directories.push(DataDirectory {
+                virtual_address: read_u32(header, entry)?,
+                size: read_u32(header, entry + 4)?,
+            });
+        }
+
+        return Some(OptionalHeader {
+            magic,
+            major_linker_version: read_u8(header, 2)?,
+            minor_linker_version: read_u8(header, 3)?,
+            size_of_code: read_u32(header, 4)?,
+            size_of_initialized_data: read_u32(header, 8)?,
+            size_of_uninitialized_data: read_u32(header, 12)?,
+            address_of_entry_point: read_u32(header, 16)?,
+            base_of_code: read_u32(header, 20)?,
+            base_of_data: match is_64 {
+                true => None,
+                false => Some(read_u32(header, 24)?),
+            },
+            image_base: match is_64 {
+                true => read_u64(header, 24)?,
+                false => read_u32(header, 28)? as u64,
+            },
+            section_alignment: read_u32(header, 32)?,
+            file_alignment: read_u32(header, 36)?,
+            major_operating_system_version: read_u16(header, 40)?,
+            minor_operating_system_version: read_u16(header, 42)?,
+            major_image_version: read_u16(header, 44)?,
+            minor_image_version: read_u16(header, 46)?,
+            major_subsystem_version: read_u16(header, 48)?,
+            minor_subsystem_version: read_u16(header, 50)?,
+            win32_version_value: read_u32(header, 52)?,
+            size_of_image: read_u32(header, 56)?,
+            size_of_headers: read_u32(header, 60)?,
+            check_sum: read_u32(header, 64)?,
+            subsystem: read_u16(header, 68)?,
+            dll_characteristics: read_u16(header, 70)?,
+            size_of_stack_reserve: read_pointer(stack_reserve)?,
+            size_of_stack_commit: read_pointer(stack_commit)?,
+            size_of_heap_reserve: read_pointer(heap_reserve)?,
+            size_of_heap_commit: read_pointer(heap_commit)?,
+            loader_flags: read_u32(header, loader_flags)?,
+            number_of_rva_and_sizes,
+            data_directories,
+        });
+    }
+
+    ///true for PE32+ images
+    pub fn is_64bit(&self) -> bool {
+        return self.magic == IMAGE_NT_OPTIONAL_HDR64_MAGIC;
+    }
+
+    ///size of the fixed part of this header, i.e. the offset of the first data directory
+    pub fn fixed_size(&self) -> usize {
+        return match self.is_64bit() {
+            true => Self::FIXED_SIZE_64,
+            false => Self::FIXED_SIZE_32,
+        };
+    }
+}
+
+///IMAGE_NT_HEADERS
+#[derive(Debug, Clone)]
+pub struct NtHeaders {
+    #[allow(dead_code)]
+    pub signature: u32,
+    pub file_header: FileHeader,
+    pub optional_header: OptionalHeader,
+}
+
+///IMAGE_SECTION_HEADER
+#[derive(Debug, Clone, Copy)]
+pub struct SectionHeader {
+    pub name: [u8; 8],
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub size_of_raw_data: u32,
+    pub pointer_to_raw_data: u32,
+    #[allow(dead_code)]
+    pub pointer_to_relocations: u32,
+    #[allow(dead_code)]
+    pub pointer_to_linenumbers: u32,
+    #[allow(dead_code)]
+    pub number_of_relocations: u16,
+    #[allow(dead_code)]
+    pub number_of_linenumbers: u16,
+    pub characteristics: u32,
+}
+
+impl SectionHeader {
+    pub fn parse(data: &[u8], offset: usize) -> Option<SectionHeader> {
+        return Some(SectionHeader {
+            name: read_bytes(data, offset, 8)?.try_into().ok()?,
+            virtual_size: read_u32(data, offset + 8)?,
+            virtual_address: read_u32(data, offset + 12)?,
+            size_of_raw_data: read_u32(data, offset + 16)?,
+            pointer_to_raw_data: read_u32(data, offset + 20)?,
+            pointer_to_relocations: read_u32(data, offset + 24)?,
+            pointer_to_linenumbers: read_u32(data, offset + 28)?,
+            number_of_relocations: read_u16(data, offset + 32)?,
+            number_of_linenumbers: read_u16(data, offset + 34)?,
+            characteristics: read_u32(data, offset + 36)?,
+        });
+    }
+
+    ///section name with the nul padding removed
+    //.text
+    //.rdata
+    //.bss
+    //etc...
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|&byte| byte == 0).unwrap_or(8);
+        return String::from_utf8_lossy(&self.name[..len]).to_string();
+    }
+
+    ///size the section takes up once mapped, some linkers leave VirtualSize as 0
+    pub fn mapped_size(&self) -> u32 {
+        return match self.virtual_size {
+            0 => self.size_of_raw_data,
+            size => size,
+        };
+    }
+
+    pub fn contains_rva(&self, rva: u32) -> bool {
+        return rva >= self.virtual_address
+            && ((rva - self.virtual_address) as u64)
+                < self.mapped_size().max(self.size_of_raw_data) as u64;
+    }
+}
@@ -0,0 +1,320 @@
+use std::borrow::Cow;
+
+use super::error::PeValidationError;
+use super::headers::{
+    Architecture, DataDirectory, DosHeader, FileHeader, NtHeaders, OptionalHeader, SectionHeader,
+    IMAGE_DOS_SIGNATURE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_I386,
+    IMAGE_NT_OPTIONAL_HDR32_MAGIC, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_NT_SIGNATURE,
+    SIZE_OF_DOS_HEADER, SIZE_OF_FILE_HEADER, SIZE_OF_SECTION_HEADER,
+};
+use super::reader::{read_bytes, read_cstr, read_u16, read_u32, read_u64};
+
+///A parsed pe image that either owns or borrows the file bytes
+///
+///Every header is validated and every offset range checked when the image is parsed, after that the
+///accessors can only hand out data that is actually inside the file
+pub struct PeImage<'a> {
+    data: Cow<'a, [u8]>,
+    dos_header: DosHeader,
+    nt_headers: NtHeaders,
+    sections: Vec<SectionHeader>,
+}
+
+impl<'a> PeImage<'a> {
+    ///parses a pe image borrowing the bytes
+    pub fn parse(data: &'a [u8]) -> Result<PeImage<'a>, PeValidationError> {
+        return PeImage::from_cow(Cow::Borrowed(data));
+    }
+
+    ///parses a pe image taking ownership of the bytes
+    pub fn from_vec(data: Vec<u8>) -> Result<PeImage<'static>, PeValidationError> {
+        return PeImage::from_cow(Cow::Owned(data));
+    }
+
+    fn from_cow(data: Cow<'a, [u8]>) -> Result<PeImage<'a>, PeValidationError> {
+        let bytes: &[u8] = &data;
+
+        let dos_header = match DosHeader::parse(bytes) {
+            Some(dos_header) => dos_header,
+            None => return Err(PeValidationError::TooSmall(bytes.len())),
+        };
+        if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
+            return Err(PeValidationError::BadDosMagic(dos_header.e_magic));
+        }
+
+        //e_lfanew has to leave room for the signature and the file header
+        let nt_offset = dos_header.e_lfanew as usize;
+        if nt_offset < SIZE_OF_DOS_HEADER
+            || read_bytes(bytes, nt_offset, 4 + SIZE_OF_FILE_HEADER).is_none()
+        {
+            return Err(PeValidationError::ELfanewOutOfRange(dos_header.e_lfanew));
+        }
+
+        let signature = read_u32(bytes, nt_offset).unwrap_or_default();
+        if signature != IMAGE_NT_SIGNATURE {
+            return Err(PeValidationError::BadNtSignature(signature));
+        }
+
+        let file_header = match FileHeader::parse(bytes, nt_offset + 4) {
+            Some(file_header) => file_header,
+            None => return Err(PeValidationError::ELfanewOutOfRange(dos_header.e_lfanew)),
+        };
+
+        let optional_offset = nt_offset + 4 + SIZE_OF_FILE_HEADER;
+        let optional_size = file_header.size_of_optional_header as usize;
+        if read_bytes(bytes, optional_offset, optional_size.max(2)).is_none() {
+            return Err(PeValidationError::OptionalHeaderTooSmall(
+                file_header.size_of_optional_header,
+            ));
+        }
+        let minimum_size = match read_u16(bytes, optional_offset).unwrap_or_default() {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => OptionalHeader::FIXED_SIZE_32,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => OptionalHeader::FIXED_SIZE_64,
+            magic => return Err(PeValidationError::BadOptionalHeaderMagic(magic)),
+        };
+        if optional_size < minimum_size {
+            return Err(PeValidationError::OptionalHeaderTooSmall(
+                file_header.size_of_optional_header,
+            ));
+        }
+        let optional_header = match OptionalHeader::parse(bytes, optional_offset, optional_size) {
+            Some(optional_header) => optional_header,
+            None => {
+                return Err(PeValidationError::OptionalHeaderTooSmall(
+                    file_header.size_of_optional_header,
+                ))
+            }
+        };
+
+        //rust implementation of the cpp IMAGE_FIRST_SECTION macro
+        let section_table = optional_offset + optional_size;
+        let section_count = file_header.number_of_sections as usize;
+        if read_bytes(bytes, section_table, section_count * SIZE_OF_SECTION_HEADER).is_none() {
+            return Err(PeValidationError::SectionTableOutOfFile);
+        }
+
+        let mut sections = Vec::with_capacity(section_count);
+        for i in 0..section_count {
+            let section =
+                match SectionHeader::parse(bytes, section_table + i * SIZE_OF_SECTION_HEADER) {
+                    Some(section) => section,
+                    None => return Err(PeValidationError::SectionTableOutOfFile),
+                };
+
+            //the raw data of every section has to be inside the file
+            if section.size_of_raw_data > 0
+                && read_bytes(
+                    bytes,
+                    section.pointer_to_raw_data as usize,
+                    section.size_of_raw_data as usize,
+                )
+                .is_none()
+            {
+                return Err(PeValidationError::SectionOutOfFile(section.name()));
+            }
+            sections.push(section);
+        }
+
+        return Ok(PeImage {
+            data,
+            dos_header,
+            nt_headers: NtHeaders {
+                signature,
+                file_header,
+                optional_header,
+            },
+            sections,
+        });
+    }
+
+    ///the raw file bytes
+    pub fn data(&self) -> &[u8] {
+        return &self.data;
+    }
+
+    pub fn dos_header(&self) -> &DosHeader {
+        return &self.dos_header;
+    }
+
+    pub fn file_header(&self) -> &FileHeader {
+        return &self.nt_headers.file_header;
+    }
+
+    pub fn optional_header(&self) -> &OptionalHeader {
+        return &self.nt_headers.optional_header;
+    }
+
+    pub fn sections(&self) -> &[SectionHeader] {
+        return &self.sections;
+    }
+
+    ///the architecture of the image, None for machines the injector does not support
+    ///
+    ///the machine and the optional header magic have to agree, e.g. an AMD64 PE32 image is rejected
+    pub fn architecture(&self) -> Option<Architecture> {
+        return match (
+            self.file_header().machine,
+            self.optional_header().is_64bit(),
+        ) {
+            (IMAGE_FILE_MACHINE_I386, false) => Some(Architecture::X86),
+            (IMAGE_FILE_MACHINE_AMD64, true) => Some(Architecture::X64),
+            _ => None,
+        };
+    }
+
+    ///size of pointers and import thunks inside the image
+    pub fn pointer_size(&self) -> usize {
+        return match self.optional_header().is_64bit() {
+            true => 8,
+            false => 4,
+        };
+    }
+
+    ///reads a pointer sized value at rva, widened to u64
+    pub fn read_pointer_at_rva(&self, rva: u32) -> Option<u64> {
+        return match self.optional_header().is_64bit() {
+            true => self.read_u64_at_rva(rva),
+            false => Some(self.read_u32_at_rva(rva)? as u64),
+        };
+    }
+
+    ///converts a virtual address that assumes the preferred ImageBase into an rva
+    pub fn va_to_rva(&self, va: u64) -> Option<u32> {
+        let rva = va.checked_sub(self.optional_header().image_base)?;
+        return u32::try_from(rva).ok();
+    }
+
+    ///returns the data directory at index if the image has one and it is not empty
+    pub fn data_directory(&self, index: usize) -> Option<DataDirectory> {
+        let directory = *self.optional_header().data_directories.get(index)?;
+        if directory.virtual_address == 0 || directory.size == 0 {
+            return None;
+        }
+        return Some(directory);
+    }
+
+    ///the section an rva lands in
+    pub fn section_for_rva(&self, rva: u32) -> Option<&SectionHeader> {
+        return self
+            .sections
+            .iter()
+            .find(|section| section.contains_rva(rva));
+    }
+
+    ///converts an rva into an offset into the file bytes
+    ///
+    ///returns None for rvas that are not backed by file data, e.g. the zero filled tail of .bss
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        if rva < self.optional_header().size_of_headers {
+            return match (rva as usize) < self.data.len() {
+                true => Some(rva as usize),
+                false => None,
+            };
+        }
+
+        let section = self.section_for_rva(rva)?;
+        let delta = rva - section.virtual_address;
+        if delta >= section.size_of_raw_data {
+            return None;
+        }
+        return Some(section.pointer_to_raw_data as usize + delta as usize);
+    }
+
+    ///len bytes of file data starting at rva
+    pub fn bytes_at_rva(&self, rva: u32, len: usize) -> Option<&[u8]> {
+        return read_bytes(&self.data, self.rva_to_offset(rva)?, len);
+    }
+
+    pub fn read_u16_at_rva(&self, rva: u32) -> Option<u16> {
+        return read_u16(&self.data, self.rva_to_offset(rva)?);
+    }
+
+    pub fn read_u32_at_rva(&self, rva: u32) -> Option<u32> {
+        return read_u32(&self.data, self.rva_to_offset(rva)?);
+    }
+
+    pub fn read_u64_at_rva(&self, rva: u32) -> Option<u64> {
+        return read_u64(&self.data, self.rva_to_offset(rva)?);
+    }
+
+    ///nul terminated string at rva, lossily converted to utf8
+    pub fn read_string_at_rva(&self, rva: u32) -> Option<String> {
+        let bytes = read_cstr(&self.data, self.rva_to_offset(rva)?)?;
+        return Some(String::from_utf8_lossy(bytes).to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_DATA, SCN_TEXT};
+
+    fn fixture() -> (PeBuilder, Vec<u8>) {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        builder.section(".data", SCN_DATA, vec![1, 2, 3, 4]);
+        let data = builder.build();
+        return (builder, data);
+    }
+
+    #[test]
+    fn parses_both_architectures() {
+        for architecture in [Architecture::X86, Architecture::X64] {
+            let mut builder = PeBuilder::new(architecture);
+            let rva = builder.section(".data", SCN_DATA, vec![1, 2, 3, 4]);
+            let data = builder.build();
+            let image = PeImage::parse(&data).unwrap();
+            assert_eq!(image.architecture(), Some(architecture));
+            assert_eq!(image.optional_header().image_base, builder.image_base);
+            assert_eq!(image.sections().len(), 1);
+            assert_eq!(image.read_u32_at_rva(rva), Some(0x04030201));
+        }
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let (_, data) = fixture();
+        assert_eq!(
+            PeImage::parse(&data[..0x20]).err(),
+            Some(PeValidationError::TooSmall(0x20))
+        );
+        for len in 0..data.len() {
+            assert!(PeImage::parse(&data[..len]).is_err(), "{len} bytes parsed");
+        }
+    }
+
+    #[test]
+    fn rejects_e_lfanew_out_of_range() {
+        let (_, mut data) = fixture();
+        for e_lfanew in [0x10, data.len() as u32 - 8, u32::MAX] {
+            data[0x3C..0x40].copy_from_slice(&e_lfanew.to_le_bytes());
+            assert_eq!(
+                PeImage::parse(&data).err(),
+                Some(PeValidationError::ELfanewOutOfRange(e_lfanew))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_section_table_past_eof() {
+        let (_, mut data) = fixture();
+        let number_of_sections = SIZE_OF_DOS_HEADER + 4 + 2;
+        data[number_of_sections..number_of_sections + 2].copy_from_slice(&0x400u16.to_le_bytes());
+        assert_eq!(
+            PeImage::parse(&data).err(),
+            Some(PeValidationError::SectionTableOutOfFile)
+        );
+    }
+
+    #[test]
+    fn rejects_section_data_past_eof() {
+        let (builder, mut data) = fixture();
+        let pointer_to_raw_data = builder.section_table() + 20;
+        let past_eof = data.len() as u32 - 0x8;
+        data[pointer_to_raw_data..pointer_to_raw_data + 4].copy_from_slice(&past_eof.to_le_bytes());
+        assert_eq!(
+            PeImage::parse(&data).err(),
+            Some(PeValidationError::SectionOutOfFile(".text".to_string()))
+        );
+    }
+}
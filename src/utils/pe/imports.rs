@@ -0,0 +1,540 @@
+use super::error::PeValidationError;
+use super::headers::{
+    IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT, IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT,
+    IMAGE_DIRECTORY_ENTRY_IMPORT,
+};
+use super::image::PeImage;
+use super::reader::{read_cstr, read_u16, read_u32};
+
+const SIZE_OF_IMPORT_DESCRIPTOR: u32 = 20;
+const SIZE_OF_DELAY_IMPORT_DESCRIPTOR: u32 = 32;
+const SIZE_OF_BOUND_IMPORT_DESCRIPTOR: usize = 8;
+
+///dlisRvaBased, without it the delay import descriptor holds VAs instead of RVAs (VC6 era images)
+const DELAY_ATTRIBUTE_RVA_BASED: u32 = 1;
+
+///What a single import thunk asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportTarget {
+    Name { hint: u16, name: String },
+    Ordinal(u16),
+}
+
+impl std::fmt::Display for ImportTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportTarget::Name { name, .. } => write!(f, "{name}"),
+            ImportTarget::Ordinal(ordinal) => write!(f, "#{ordinal}"),
+        }
+    }
+}
+
+///An imported function and the IAT slot the loader writes its address to
+#[derive(Debug, Clone)]
+pub struct ImportThunk {
+    pub iat_rva: u32,
+    pub target: ImportTarget,
+}
+
+///An entry of the import directory
+#[derive(Debug, Clone)]
+pub struct ImportedModule {
+    pub name: String,
+    #[allow(dead_code)]
+    pub time_date_stamp: u32,
+    #[allow(dead_code)]
+    pub iat_rva: u32,
+    pub thunks: Vec<ImportThunk>,
+}
+
+///An entry of the delay load import directory, all addresses are converted to RVAs
+#[derive(Debug, Clone)]
+pub struct DelayImportedModule {
+    pub name: String,
+    #[allow(dead_code)]
+    pub attributes: u32,
+    #[allow(dead_code)]
+    pub module_handle_rva: u32,
+    #[allow(dead_code)]
+    pub iat_rva: u32,
+    #[allow(dead_code)]
+    pub bound_iat_rva: u32,
+    #[allow(dead_code)]
+    pub unload_iat_rva: u32,
+    #[allow(dead_code)]
+    pub time_date_stamp: u32,
+    pub thunks: Vec<ImportThunk>,
+}
+
+///A module the image was bound against and the modules it forwards to
+#[derive(Debug, Clone)]
+pub struct BoundImport {
+    pub name: String,
+    pub time_date_stamp: u32,
+    pub forwarder_refs: Vec<BoundForwarderRef>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BoundForwarderRef {
+    pub name: String,
+    pub time_date_stamp: u32,
+}
+
+impl<'a> PeImage<'a> {
+    ///walks IMAGE_DIRECTORY_ENTRY_IMPORT
+    pub fn imports(&self) -> Result<Vec<ImportedModule>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_IMPORT) {
+            Some(directory) => directory,
+            None => return Ok(Vec::new()),
+        };
+        let malformed = PeValidationError::MalformedDirectory("import");
+
+        let mut modules = Vec::new();
+        let mut descriptor = directory.virtual_address;
+        loop {
+            let field = |offset: u32| self.read_u32_at_rva(descriptor.wrapping_add(offset));
+            let (original_first_thunk, time_date_stamp, name_rva, first_thunk) =
+                match (field(0), field(4), field(12), field(16)) {
+                    (Some(oft), Some(stamp), Some(name), Some(ft)) => (oft, stamp, name, ft),
+                    _ => return Err(malformed),
+                };
+
+            //the table ends with a zeroed descriptor
+            if name_rva == 0 && first_thunk == 0 {
+                break;
+            }
+
+            let name = match self.read_string_at_rva(name_rva) {
+                Some(name) => name,
+                None => return Err(malformed),
+            };
+
+            //old linkers leave the lookup table out, the IAT still holds the names on disk then
+            let lookup_table = match original_first_thunk {
+                0 => first_thunk,
+                rva => rva,
+            };
+
+            modules.push(ImportedModule {
+                name,
+                time_date_stamp,
+                iat_rva: first_thunk,
+                thunks: self.read_thunks(lookup_table, first_thunk, 0, "import")?,
+            });
+            descriptor = descriptor.wrapping_add(SIZE_OF_IMPORT_DESCRIPTOR);
+        }
+
+        return Ok(modules);
+    }
+
+    ///walks IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT
+    pub fn delay_imports(&self) -> Result<Vec<DelayImportedModule>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT) {
+            Some(directory) => directory,
+            None => return Ok(Vec::new()),
+        };
+        let malformed = PeValidationError::MalformedDirectory("delay import");
+        let image_base = self.optional_header().image_base;
+
+        let mut modules = Vec::new();
+        let mut descriptor = directory.virtual_address;
+        loop {
+            let mut fields = [0u32; 8];
+            for (i, field) in fields.iter_mut().enumerate() {
+                *field = match self.read_u32_at_rva(descriptor.wrapping_add(i as u32 * 4)) {
+                    Some(value) => value,
+                    None => return Err(malformed),
+                };
+            }
+            let attributes = fields[0];
+
+            if fields[1] == 0 {
+                break;
+            }
+
+            //convert VA based descriptors so everything below only deals with RVAs
+            let to_rva = |address: u32| -> u32 {
+                if address == 0 || attributes & DELAY_ATTRIBUTE_RVA_BASED != 0 {
+                    return address;
+                }
+                return (address as u64).wrapping_sub(image_base) as u32;
+            };
+            let name_rva = to_rva(fields[1]);
+            let iat_rva = to_rva(fields[3]);
+            let name_table_rva = to_rva(fields[4]);
+
+            let name = match self.read_string_at_rva(name_rva) {
+                Some(name) => name,
+                None => return Err(malformed),
+            };
+
+            //a VA based name table also points at VA based hint/name entries
+            let name_bias = match attributes & DELAY_ATTRIBUTE_RVA_BASED {
+                0 => image_base,
+                _ => 0,
+            };
+
+            modules.push(DelayImportedModule {
+                name,
+                attributes,
+                module_handle_rva: to_rva(fields[2]),
+                iat_rva,
+                bound_iat_rva: to_rva(fields[5]),
+                unload_iat_rva: to_rva(fields[6]),
+                time_date_stamp: fields[7],
+                thunks: self.read_thunks(name_table_rva, iat_rva, name_bias, "delay import")?,
+            });
+            descriptor = descriptor.wrapping_add(SIZE_OF_DELAY_IMPORT_DESCRIPTOR);
+        }
+
+        return Ok(modules);
+    }
+
+    ///walks IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT
+    ///
+    ///module name offsets are relative to the start of the directory which normally lives in the headers
+    pub fn bound_imports(&self) -> Result<Vec<BoundImport>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT) {
+            Some(directory) => directory,
+            None => return Ok(Vec::new()),
+        };
+        let malformed = PeValidationError::MalformedDirectory("bound import");
+
+        let table = match self.bytes_at_rva(directory.virtual_address, directory.size as usize) {
+            Some(table) => table,
+            None => return Err(malformed.clone()),
+        };
+        let read_name = |offset: u16| -> Result<String, PeValidationError> {
+            match read_cstr(table, offset as usize) {
+                Some(name) => Ok(String::from_utf8_lossy(name).to_string()),
+                None => Err(malformed.clone()),
+            }
+        };
+        let read_entry = |offset: usize| -> Result<(u32, u16, u16), PeValidationError> {
+            match (
+                read_u32(table, offset),
+                read_u16(table, offset + 4),
+                read_u16(table, offset + 6),
+            ) {
+                (Some(stamp), Some(name), Some(count)) => Ok((stamp, name, count)),
+                _ => Err(malformed.clone()),
+            }
+        };
+
+        let mut bound = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (time_date_stamp, name_offset, forwarder_count) = read_entry(offset)?;
+            if time_date_stamp == 0 && name_offset == 0 {
+                break;
+            }
+            offset += SIZE_OF_BOUND_IMPORT_DESCRIPTOR;
+
+            let mut forwarder_refs = Vec::new();
+            for _ in 0..forwarder_count {
+                let (time_date_stamp, name_offset, _) = read_entry(offset)?;
+                forwarder_refs.push(BoundForwarderRef {
+                    name: read_name(name_offset)?,
+                    time_date_stamp,
+                });
+                offset += SIZE_OF_BOUND_IMPORT_DESCRIPTOR;
+            }
+
+            bound.push(BoundImport {
+                name: read_name(name_offset)?,
+                time_date_stamp,
+                forwarder_refs,
+            });
+        }
+
+        return Ok(bound);
+    }
+
+    ///reads a zero terminated lookup table, pairing every entry with its IAT slot
+    ///
+    ///name_bias is subtracted from hint/name addresses for VA based delay import tables
+    fn read_thunks(
+        &self,
+        lookup_table: u32,
+        iat: u32,
+        name_bias: u64,
+        directory: &'static str,
+    ) -> Result<Vec<ImportThunk>, PeValidationError> {
+        let malformed = PeValidationError::MalformedDirectory(directory);
+        let pointer_size = self.pointer_size() as u32;
+        let ordinal_flag = 1u64 << (pointer_size * 8 - 1);
+
+        let mut thunks = Vec::new();
+        let mut index = 0u32;
+        loop {
+            let offset = index.wrapping_mul(pointer_size);
+            let entry = match self.read_pointer_at_rva(lookup_table.wrapping_add(offset)) {
+                Some(entry) => entry,
+                None => return Err(malformed),
+            };
+            if entry == 0 {
+                break;
+            }
+
+            let target = match entry & ordinal_flag {
+                0 => {
+                    let rva = entry.wrapping_sub(name_bias) as u32;
+                    match (
+                        self.read_u16_at_rva(rva),
+                        self.read_string_at_rva(rva.wrapping_add(2)),
+                    ) {
+                        (Some(hint), Some(name)) => ImportTarget::Name { hint, name },
+                        _ => return Err(malformed),
+                    }
+                }
+                _ => ImportTarget::Ordinal((entry & 0xFFFF) as u16),
+            };
+
+            thunks.push(ImportThunk {
+                iat_rva: iat.wrapping_add(offset),
+                target,
+            });
+            index += 1;
+        }
+
+        return Ok(thunks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{by_name, put, PeBuilder, SCN_DATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    fn builder(architecture: Architecture) -> PeBuilder {
+        let mut builder = PeBuilder::new(architecture);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+        return builder;
+    }
+
+    fn targets(thunks: &[ImportThunk]) -> Vec<(u32, ImportTarget)> {
+        return thunks
+            .iter()
+            .map(|thunk| (thunk.iat_rva, thunk.target.clone()))
+            .collect();
+    }
+
+    #[test]
+    fn pairs_imports_with_their_iat_slots() {
+        for architecture in [Architecture::X86, Architecture::X64] {
+            let mut builder = builder(architecture);
+            let pointer_size = builder.pointer_size() as u32;
+            let iats = builder.imports(&[
+                (
+                    "kernel32.dll",
+                    &[by_name("Sleep"), ImportTarget::Ordinal(7)],
+                ),
+                ("user32.dll", &[ImportTarget::Ordinal(0xFFFF)]),
+            ]);
+            let data = builder.build();
+            let imports = PeImage::parse(&data).unwrap().imports().unwrap();
+
+            assert_eq!(imports.len(), 2);
+            assert_eq!(imports[0].name, "kernel32.dll");
+            assert_eq!(imports[0].iat_rva, iats[0]);
+            assert_eq!(
+                targets(&imports[0].thunks),
+                vec![
+                    (iats[0], by_name("Sleep")),
+                    (iats[0] + pointer_size, ImportTarget::Ordinal(7)),
+                ]
+            );
+            assert_eq!(imports[1].name, "user32.dll");
+            assert_eq!(
+                targets(&imports[1].thunks),
+                vec![(iats[1], ImportTarget::Ordinal(0xFFFF))]
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_names_outside_the_image() {
+        let malformed = Some(PeValidationError::MalformedDirectory("import"));
+        let imports = |patch: &dyn Fn(&mut Vec<u8>)| {
+            let mut builder = builder(Architecture::X64);
+            builder.imports(&[("kernel32.dll", &[by_name("Sleep")])]);
+            patch(&mut builder.sections[1].data);
+            return PeImage::parse(&builder.build()).unwrap().imports();
+        };
+        assert!(imports(&|_| {}).is_ok());
+        //the module name of the first descriptor
+        assert_eq!(
+            imports(&|idata| put(idata, 12, &0xFFFF0000u32.to_le_bytes())).err(),
+            malformed
+        );
+        //the hint/name entry of its first lookup table slot, lookup tables follow the descriptors
+        assert_eq!(
+            imports(&|idata| put(idata, 40, &0xFFFF0000u64.to_le_bytes())).err(),
+            malformed
+        );
+    }
+
+    //.didat layout: descriptor, terminator, dll name, module handle, name table, IAT, hint/name
+    const NAME: u32 = 0x40;
+    const MODULE_HANDLE: u32 = 0x50;
+    const NAME_TABLE: u32 = 0x60;
+    const IAT: u32 = 0x80;
+    const HINT_NAME: u32 = 0xA0;
+
+    ///an image delay loading delayed.dll!late_fn and delayed.dll!#7, returns it and the .didat rva
+    ///
+    ///without rva_based the descriptor and the name table hold VAs like VC6 left them
+    fn delay_image(architecture: Architecture, rva_based: bool) -> (Vec<u8>, u32) {
+        let mut builder = builder(architecture);
+        let pointer_size = builder.pointer_size();
+        let base = builder.next_rva();
+        let bias = match rva_based {
+            true => 0,
+            false => builder.image_base,
+        };
+        let address = |offset: u32| bias + (base + offset) as u64;
+
+        let mut didat = Vec::new();
+        let descriptor = [
+            rva_based as u32,
+            address(NAME) as u32,
+            address(MODULE_HANDLE) as u32,
+            address(IAT) as u32,
+            address(NAME_TABLE) as u32,
+            0,
+            0,
+            0x5EED,
+        ];
+        for (i, field) in descriptor.iter().enumerate() {
+            put(&mut didat, i * 4, &field.to_le_bytes());
+        }
+        put(&mut didat, NAME as usize, b"delayed.dll\0");
+        let ordinal_flag = 1u64 << (pointer_size * 8 - 1);
+        for (i, entry) in [address(HINT_NAME), ordinal_flag | 7].iter().enumerate() {
+            let slot = NAME_TABLE as usize + i * pointer_size;
+            put(&mut didat, slot, &entry.to_le_bytes()[..pointer_size]);
+        }
+        put(&mut didat, HINT_NAME as usize, &3u16.to_le_bytes());
+        put(&mut didat, HINT_NAME as usize + 2, b"late_fn\0");
+
+        builder.section(".didat", SCN_DATA, didat);
+        builder.directory(IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT, base, 64);
+        return (builder.build(), base);
+    }
+
+    #[test]
+    fn reads_rva_and_va_based_delay_imports() {
+        for (architecture, rva_based) in [
+            (Architecture::X64, true),
+            (Architecture::X86, true),
+            (Architecture::X86, false),
+        ] {
+            let (data, base) = delay_image(architecture, rva_based);
+            let image = PeImage::parse(&data).unwrap();
+            let modules = image.delay_imports().unwrap();
+            assert_eq!(modules.len(), 1);
+            let module = &modules[0];
+            assert_eq!(module.name, "delayed.dll");
+            assert_eq!(module.module_handle_rva, base + MODULE_HANDLE);
+            assert_eq!(module.iat_rva, base + IAT);
+            assert_eq!(module.bound_iat_rva, 0);
+            assert_eq!(module.time_date_stamp, 0x5EED);
+            assert_eq!(
+                targets(&module.thunks),
+                vec![
+                    (
+                        base + IAT,
+                        ImportTarget::Name {
+                            hint: 3,
+                            name: "late_fn".to_string()
+                        }
+                    ),
+                    (
+                        base + IAT + image.pointer_size() as u32,
+                        ImportTarget::Ordinal(7)
+                    ),
+                ]
+            );
+            //delay imports are not regular imports
+            assert!(image.imports().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_delay_imports_with_a_bad_name() {
+        let (mut data, base) = delay_image(Architecture::X64, true);
+        let image = PeImage::parse(&data).unwrap();
+        let descriptor = image.rva_to_offset(base).unwrap();
+        put(&mut data, descriptor + 4, &0xFFFF0000u32.to_le_bytes());
+        assert_eq!(
+            PeImage::parse(&data).unwrap().delay_imports().err(),
+            Some(PeValidationError::MalformedDirectory("delay import"))
+        );
+    }
+
+    ///a bound import directory for a.dll, which forwards to ntdll.dll, and b.dll
+    fn bound_image(patch: impl Fn(&mut Vec<u8>)) -> Vec<u8> {
+        let mut bound = Vec::new();
+        let entries: [(u32, u16, u16); 4] = [
+            (0x11111111, 32, 1),
+            (0x22222222, 38, 0),
+            (0x33333333, 48, 0),
+            (0, 0, 0),
+        ];
+        for (i, (stamp, name, count)) in entries.iter().enumerate() {
+            put(&mut bound, i * 8, &stamp.to_le_bytes());
+            put(&mut bound, i * 8 + 4, &name.to_le_bytes());
+            put(&mut bound, i * 8 + 6, &count.to_le_bytes());
+        }
+        put(&mut bound, 32, b"a.dll\0ntdll.dll\0b.dll\0");
+        patch(&mut bound);
+
+        let mut builder = builder(Architecture::X64);
+        let size = bound.len() as u32;
+        let base = builder.section(".bound", SCN_DATA, bound);
+        builder.directory(IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT, base, size);
+        return builder.build();
+    }
+
+    #[test]
+    fn reads_bound_imports_and_their_forwarders() {
+        let data = bound_image(|_| {});
+        let bound = PeImage::parse(&data).unwrap().bound_imports().unwrap();
+        let listed: Vec<_> = bound
+            .iter()
+            .map(|module| {
+                let forwarders: Vec<_> = module
+                    .forwarder_refs
+                    .iter()
+                    .map(|forwarder| (forwarder.name.as_str(), forwarder.time_date_stamp))
+                    .collect();
+                (module.name.as_str(), module.time_date_stamp, forwarders)
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("a.dll", 0x11111111, vec![("ntdll.dll", 0x22222222)]),
+                ("b.dll", 0x33333333, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bound_imports_past_the_directory() {
+        let malformed = Some(PeValidationError::MalformedDirectory("bound import"));
+        //a name offset past the end of the directory
+        let data = bound_image(|bound| put(bound, 4, &0x100u16.to_le_bytes()));
+        assert_eq!(
+            PeImage::parse(&data).unwrap().bound_imports().err(),
+            malformed
+        );
+        //more forwarders than there are entries
+        let data = bound_image(|bound| put(bound, 6, &0x20u16.to_le_bytes()));
+        assert_eq!(
+            PeImage::parse(&data).unwrap().bound_imports().err(),
+            malformed
+        );
+    }
+}
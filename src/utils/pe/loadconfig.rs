@@ -0,0 +1,343 @@
+use super::error::PeValidationError;
+use super::headers::{
+    IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG, IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE,
+    IMAGE_DLLCHARACTERISTICS_FORCE_INTEGRITY, IMAGE_DLLCHARACTERISTICS_GUARD_CF,
+    IMAGE_DLLCHARACTERISTICS_HIGH_ENTROPY_VA, IMAGE_DLLCHARACTERISTICS_NO_SEH,
+    IMAGE_DLLCHARACTERISTICS_NX_COMPAT,
+};
+use super::image::PeImage;
+use super::reader::{read_u16, read_u32, read_u64};
+
+pub const IMAGE_GUARD_CF_INSTRUMENTED: u32 = 0x00000100;
+#[allow(dead_code)]
+pub const IMAGE_GUARD_CFW_INSTRUMENTED: u32 = 0x00000200;
+#[allow(dead_code)]
+pub const IMAGE_GUARD_CF_FUNCTION_TABLE_PRESENT: u32 = 0x00000400;
+pub const IMAGE_GUARD_SECURITY_COOKIE_UNUSED: u32 = 0x00000800;
+#[allow(dead_code)]
+pub const IMAGE_GUARD_CF_LONGJUMP_TABLE_PRESENT: u32 = 0x00010000;
+///the top 4 bits of GuardFlags hold how many extra bytes follow each GuardCFFunctionTable rva
+const IMAGE_GUARD_CF_FUNCTION_TABLE_SIZE_SHIFT: u32 = 28;
+
+///Field offsets inside IMAGE_LOAD_CONFIG_DIRECTORY32/64, the structure grew with every windows
+///release so a field only exists when Size covers it
+struct LoadConfigLayout {
+    security_cookie: usize,
+    se_handler_table: usize,
+    se_handler_count: usize,
+    guard_cf_check_function_pointer: usize,
+    guard_cf_dispatch_function_pointer: usize,
+    guard_cf_function_table: usize,
+    guard_cf_function_count: usize,
+    guard_flags: usize,
+    dynamic_value_reloc_table: usize,
+    dynamic_value_reloc_table_offset: usize,
+    dynamic_value_reloc_table_section: usize,
+}
+
+const LAYOUT_32: LoadConfigLayout = LoadConfigLayout {
+    security_cookie: 60,
+    se_handler_table: 64,
+    se_handler_count: 68,
+    guard_cf_check_function_pointer: 72,
+    guard_cf_dispatch_function_pointer: 76,
+    guard_cf_function_table: 80,
+    guard_cf_function_count: 84,
+    guard_flags: 88,
+    dynamic_value_reloc_table: 120,
+    dynamic_value_reloc_table_offset: 136,
+    dynamic_value_reloc_table_section: 140,
+};
+
+const LAYOUT_64: LoadConfigLayout = LoadConfigLayout {
+    security_cookie: 88,
+    se_handler_table: 96,
+    se_handler_count: 104,
+    guard_cf_check_function_pointer: 112,
+    guard_cf_dispatch_function_pointer: 120,
+    guard_cf_function_table: 128,
+    guard_cf_function_count: 136,
+    guard_flags: 144,
+    dynamic_value_reloc_table: 192,
+    dynamic_value_reloc_table_offset: 224,
+    dynamic_value_reloc_table_section: 228,
+};
+
+///Where the dynamic value relocation table lives and its header
+#[derive(Debug, Clone)]
+pub struct DynamicRelocationTable {
+    pub rva: u32,
+    pub version: u32,
+    pub size: u32,
+}
+
+///The parsed IMAGE_LOAD_CONFIG_DIRECTORY32/64
+///
+///addresses are the VAs stored in the image, tables are resolved to RVAs
+#[derive(Debug, Clone, Default)]
+pub struct LoadConfig {
+    pub size: u32,
+    #[allow(dead_code)]
+    pub time_date_stamp: u32,
+    pub security_cookie: Option<u64>,
+    pub se_handler_table: Option<u64>,
+    pub se_handler_count: Option<u64>,
+    ///rvas of the SafeSEH handlers, only x86 images have them
+    pub se_handlers: Vec<u32>,
+    #[allow(dead_code)]
+    pub guard_cf_check_function_pointer: Option<u64>,
+    #[allow(dead_code)]
+    pub guard_cf_dispatch_function_pointer: Option<u64>,
+    pub guard_cf_function_table: Option<u64>,
+    pub guard_cf_function_count: Option<u64>,
+    ///rvas of the valid indirect call targets
+    pub guard_cf_functions: Vec<u32>,
+    pub guard_flags: Option<u32>,
+    pub dynamic_relocation_table: Option<DynamicRelocationTable>,
+}
+
+impl LoadConfig {
+    ///true when the /GS cookie is used and has to be randomised by the loader
+    pub fn uses_security_cookie(&self) -> bool {
+        let unused = self.guard_flags.unwrap_or_default() & IMAGE_GUARD_SECURITY_COOKIE_UNUSED;
+        return self.security_cookie.unwrap_or_default() != 0 && unused == 0;
+    }
+
+    ///true when the code was compiled with CFG checks on indirect calls and lists its call targets
+    pub fn uses_guard_cf(&self) -> bool {
+        return self.guard_flags.unwrap_or_default() & IMAGE_GUARD_CF_INSTRUMENTED != 0
+            && self.guard_cf_function_count.unwrap_or_default() != 0;
+    }
+
+    ///true when the image registers SafeSEH handlers
+    pub fn uses_safe_seh(&self) -> bool {
+        return self.se_handler_count.unwrap_or_default() != 0;
+    }
+}
+
+///The DllCharacteristics mitigation flags of an image
+#[derive(Debug, Clone, Copy)]
+pub struct MitigationPolicy {
+    pub dynamic_base: bool,
+    pub high_entropy_va: bool,
+    pub nx_compat: bool,
+    pub guard_cf: bool,
+    pub no_seh: bool,
+    pub force_integrity: bool,
+}
+
+impl MitigationPolicy {
+    pub fn from_dll_characteristics(dll_characteristics: u16) -> MitigationPolicy {
+        let has = |flag: u16| dll_characteristics & flag != 0;
+        return MitigationPolicy {
+            dynamic_base: has(IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE),
+            high_entropy_va: has(IMAGE_DLLCHARACTERISTICS_HIGH_ENTROPY_VA),
+            nx_compat: has(IMAGE_DLLCHARACTERISTICS_NX_COMPAT),
+            guard_cf: has(IMAGE_DLLCHARACTERISTICS_GUARD_CF),
+            no_seh: has(IMAGE_DLLCHARACTERISTICS_NO_SEH),
+            force_integrity: has(IMAGE_DLLCHARACTERISTICS_FORCE_INTEGRITY),
+        };
+    }
+
+    ///every flag with its IMAGE_DLLCHARACTERISTICS_* name, for display
+    pub fn flags(&self) -> [(&'static str, bool); 6] {
+        return [
+            ("DYNAMIC_BASE", self.dynamic_base),
+            ("HIGH_ENTROPY_VA", self.high_entropy_va),
+            ("NX_COMPAT", self.nx_compat),
+            ("GUARD_CF", self.guard_cf),
+            ("NO_SEH", self.no_seh),
+            ("FORCE_INTEGRITY", self.force_integrity),
+        ];
+    }
+}
+
+impl<'a> PeImage<'a> {
+    pub fn mitigation_policy(&self) -> MitigationPolicy {
+        return MitigationPolicy::from_dll_characteristics(
+            self.optional_header().dll_characteristics,
+        );
+    }
+
+    ///reads IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG, None when the image has no load config
+    pub fn load_config(&self) -> Result<Option<LoadConfig>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG) {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+        let malformed = PeValidationError::MalformedDirectory("load config");
+
+        //the Size field is what counts, old linkers put a fixed 0x40 in the data directory
+        let size = match self.read_u32_at_rva(directory.virtual_address) {
+            Some(size) => size,
+            None => return Err(malformed),
+        };
+        let config = match self.bytes_at_rva(directory.virtual_address, size as usize) {
+            Some(config) => config,
+            None => return Err(malformed),
+        };
+
+        let is_64 = self.optional_header().is_64bit();
+        let layout = match is_64 {
+            true => &LAYOUT_64,
+            false => &LAYOUT_32,
+        };
+        let pointer = |offset: usize| match is_64 {
+            true => read_u64(config, offset),
+            false => read_u32(config, offset).map(|value| value as u64),
+        };
+
+        let mut load_config = LoadConfig {
+            size,
+            time_date_stamp: read_u32(config, 4).unwrap_or_default(),
+            security_cookie: pointer(layout.security_cookie),
+            se_handler_table: pointer(layout.se_handler_table),
+            se_handler_count: pointer(layout.se_handler_count),
+            guard_cf_check_function_pointer: pointer(layout.guard_cf_check_function_pointer),
+            guard_cf_dispatch_function_pointer: pointer(layout.guard_cf_dispatch_function_pointer),
+            guard_cf_function_table: pointer(layout.guard_cf_function_table),
+            guard_cf_function_count: pointer(layout.guard_cf_function_count),
+            guard_flags: read_u32(config, layout.guard_flags),
+            ..LoadConfig::default()
+        };
+
+        //SafeSEH only exists for x86, the table is a plain array of handler rvas
+        if !is_64 {
+            if let (Some(table), Some(count)) =
+                (load_config.se_handler_table, load_config.se_handler_count)
+            {
+                load_config.se_handlers = self.rva_table(table, count, 0)?;
+            }
+        }
+
+        if let (Some(table), Some(count), Some(flags)) = (
+            load_config.guard_cf_function_table,
+            load_config.guard_cf_function_count,
+            load_config.guard_flags,
+        ) {
+            let extra = (flags >> IMAGE_GUARD_CF_FUNCTION_TABLE_SIZE_SHIFT) as usize;
+            load_config.guard_cf_functions = self.rva_table(table, count, extra)?;
+        }
+
+        //the table is either given by VA or, on newer linkers, as a section number and offset
+        let dynamic_relocation_table_rva = match (
+            pointer(layout.dynamic_value_reloc_table),
+            read_u32(config, layout.dynamic_value_reloc_table_offset),
+            read_u16(config, layout.dynamic_value_reloc_table_section),
+        ) {
+            (Some(va), _, _) if va != 0 => self.va_to_rva(va),
+            (_, Some(offset), Some(section)) if section != 0 => self
+                .sections()
+                .get(section as usize - 1)
+                .map(|section| section.virtual_address.wrapping_add(offset)),
+            _ => None,
+        };
+        if let Some(rva) = dynamic_relocation_table_rva {
+            let size = rva
+                .checked_add(4)
+                .and_then(|size_rva| self.read_u32_at_rva(size_rva));
+            load_config.dynamic_relocation_table = match (self.read_u32_at_rva(rva), size) {
+                (Some(version), Some(size)) => Some(DynamicRelocationTable { rva, version, size }),
+                _ => return Err(malformed),
+            };
+        }
+
+        return Ok(Some(load_config));
+    }
+
+    //reads count rvas spaced 4 + extra bytes apart starting at the table VA
+    fn rva_table(
+        &self,
+        table: u64,
+        count: u64,
+        extra: usize,
+    ) -> Result<Vec<u32>, PeValidationError> {
+        if table == 0 || count == 0 {
+            return Ok(Vec::new());
+        }
+        let malformed = PeValidationError::MalformedDirectory("load config");
+        let stride = 4 + extra;
+        let len = match (count as usize).checked_mul(stride) {
+            Some(len) => len,
+            None => return Err(malformed),
+        };
+        let bytes = match self
+            .va_to_rva(table)
+            .and_then(|rva| self.bytes_at_rva(rva, len))
+        {
+            Some(bytes) => bytes,
+            None => return Err(malformed),
+        };
+        return Ok(bytes
+            .chunks_exact(stride)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_RDATA};
+    use crate::utils::pe::headers::Architecture;
+
+    const TABLE: usize = 0x100;
+
+    ///an x64 image whose load config points at its dynamic relocation table by VA and by section
+    fn image(table_va: impl Fn(u64, u32) -> u64, section: u16, offset: u32) -> Vec<u8> {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        let rva = builder.next_rva();
+        let mut config = vec![0u8; TABLE + 8];
+        put(&mut config, 0, &232u32.to_le_bytes());
+        let va = table_va(builder.image_base, rva);
+        put(
+            &mut config,
+            LAYOUT_64.dynamic_value_reloc_table,
+            &va.to_le_bytes(),
+        );
+        put(
+            &mut config,
+            LAYOUT_64.dynamic_value_reloc_table_offset,
+            &offset.to_le_bytes(),
+        );
+        put(
+            &mut config,
+            LAYOUT_64.dynamic_value_reloc_table_section,
+            &section.to_le_bytes(),
+        );
+        put(&mut config, TABLE, &[1, 0, 0, 0, 8, 0, 0, 0]);
+        builder.section(".rdata", SCN_RDATA, config);
+        builder.directory(IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG, rva, 232);
+        return builder.build();
+    }
+
+    fn dynamic_relocation_table(data: &[u8]) -> Result<Option<(u32, u32)>, PeValidationError> {
+        let load_config = PeImage::parse(data).unwrap().load_config()?.unwrap();
+        return Ok(load_config
+            .dynamic_relocation_table
+            .map(|table| (table.version, table.size)));
+    }
+
+    #[test]
+    fn finds_the_dynamic_relocation_table_by_va_and_by_section() {
+        let by_va = image(
+            |image_base, rva| image_base + rva as u64 + TABLE as u64,
+            0,
+            0,
+        );
+        assert_eq!(dynamic_relocation_table(&by_va), Ok(Some((1, 8))));
+        let by_section = image(|_, _| 0, 1, TABLE as u32);
+        assert_eq!(dynamic_relocation_table(&by_section), Ok(Some((1, 8))));
+        let without = image(|_, _| 0, 0, TABLE as u32);
+        assert_eq!(dynamic_relocation_table(&without), Ok(None));
+    }
+
+    #[test]
+    fn rejects_a_dynamic_relocation_table_at_the_end_of_the_address_space() {
+        let malformed = Err(PeValidationError::MalformedDirectory("load config"));
+        for rva in [0xFFFF_FFFC, 0xFFFF_FFFD, u32::MAX] {
+            let data = image(|image_base, _| image_base + rva as u64, 0, 0);
+            assert_eq!(dynamic_relocation_table(&data), malformed);
+        }
+    }
+}
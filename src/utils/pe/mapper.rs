@@ -0,0 +1,228 @@
+use std::fmt;
+
+use super::error::PeValidationError;
+use super::headers::{IMAGE_FILE_RELOCS_STRIPPED, SIZE_OF_FILE_HEADER};
+use super::image::PeImage;
+use super::relocs::{apply_relocations, RelocationError};
+
+///SizeOfImage is trusted up to this much, far more than any real dll needs
+pub const MAX_IMAGE_SIZE: u32 = 0x4000_0000;
+
+///Why an image could not be laid out for a given base address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    ImageTooLarge(u32),
+    HeadersOutsideImage(u32),
+    SectionOutsideImage(String),
+    RelocationsStripped(u64),
+    BadRelocations(PeValidationError),
+    Relocation(RelocationError),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::ImageTooLarge(size) => write!(
+                f,
+                "SizeOfImage 0x{size:x} is larger than the 0x{MAX_IMAGE_SIZE:x} bytes allowed"
+            ),
+            MapError::HeadersOutsideImage(size) => {
+                write!(f, "SizeOfHeaders 0x{size:x} is larger than SizeOfImage")
+            }
+            MapError::SectionOutsideImage(name) => {
+                write!(f, "Section {name} extends past SizeOfImage")
+            }
+            MapError::RelocationsStripped(base) => write!(
+                f,
+                "Image has no relocations and cannot be moved to 0x{base:x}"
+            ),
+            MapError::BadRelocations(err) => write!(f, "{err}"),
+            MapError::Relocation(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+///lays the image out the way the windows loader would if it was loaded at target_base
+///
+///the buffer is SizeOfImage bytes long, holds exactly SizeOfHeaders bytes of headers, every section
+///at its VirtualAddress zero padded to its VirtualSize and has the base relocations applied. The
+///ImageBase field in the copied headers is updated to target_base so code reading it back sees the
+///real base
+pub fn map_image(image: &PeImage, target_base: u64) -> Result<Vec<u8>, MapError> {
+    let data = image.data();
+    let optional_header = image.optional_header();
+    //the buffer is allocated from SizeOfImage so the layout is checked against it first
+    if optional_header.size_of_image > MAX_IMAGE_SIZE {
+        return Err(MapError::ImageTooLarge(optional_header.size_of_image));
+    }
+    let size_of_image = optional_header.size_of_image as usize;
+    let size_of_headers = optional_header.size_of_headers as usize;
+    if size_of_headers > size_of_image {
+        return Err(MapError::HeadersOutsideImage(
+            optional_header.size_of_headers,
+        ));
+    }
+    for section in image.sections() {
+        let end = section.virtual_address as u64 + section.mapped_size() as u64;
+        if end > size_of_image as u64 {
+            return Err(MapError::SectionOutsideImage(section.name()));
+        }
+    }
+    let mut mapped = vec![0u8; size_of_image];
+
+    //headers
+    let header_bytes = size_of_headers.min(data.len());
+    mapped[..header_bytes].copy_from_slice(&data[..header_bytes]);
+
+    //sections, whatever is not backed by raw data stays zeroed (.bss and friends)
+    for section in image.sections() {
+        let start = section.virtual_address as usize;
+
+        let raw_size = (section.size_of_raw_data.min(section.mapped_size()) as usize).min(
+            data.len()
+                .saturating_sub(section.pointer_to_raw_data as usize),
+        );
+        if raw_size == 0 {
+            continue;
+        }
+        let raw_start = section.pointer_to_raw_data as usize;
+        mapped[start..start + raw_size].copy_from_slice(&data[raw_start..raw_start + raw_size]);
+    }
+
+    //relocations
+    let delta = target_base.wrapping_sub(optional_header.image_base);
+    if delta != 0 {
+        let relocations = image.relocations().map_err(MapError::BadRelocations)?;
+        if relocations.is_empty()
+            && image.file_header().characteristics & IMAGE_FILE_RELOCS_STRIPPED != 0
+        {
+            return Err(MapError::RelocationsStripped(target_base));
+        }
+        apply_relocations(&mut mapped, &relocations, delta).map_err(MapError::Relocation)?;
+    }
+
+    //ImageBase sits at offset 24 in PE32+ and 28 in PE32 optional headers
+    let image_base_offset = image.dos_header().e_lfanew as usize + 4 + SIZE_OF_FILE_HEADER;
+    match optional_header.is_64bit() {
+        true => write_bytes(
+            &mut mapped,
+            image_base_offset + 24,
+            &target_base.to_le_bytes(),
+        ),
+        false => write_bytes(
+            &mut mapped,
+            image_base_offset + 28,
+            &(target_base as u32).to_le_bytes(),
+        ),
+    }
+
+    return Ok(mapped);
+}
+
+//headers that do not fit in SizeOfHeaders are left alone
+fn write_bytes(data: &mut [u8], offset: usize, bytes: &[u8]) {
+    if let Some(slot) = data.get_mut(offset..offset + bytes.len()) {
+        slot.copy_from_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_DATA, SCN_TEXT};
+    use crate::utils::pe::headers::{Architecture, SIZE_OF_DOS_HEADER};
+    use crate::utils::pe::reader::{read_u32, read_u64};
+
+    const OPTIONAL_HEADER: usize = SIZE_OF_DOS_HEADER + 4 + SIZE_OF_FILE_HEADER;
+
+    fn patch_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn copies_exactly_size_of_headers() {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xCC; 0x20]);
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        let mapped = map_image(&image, builder.image_base).unwrap();
+
+        let size_of_headers = builder.size_of_headers() as usize;
+        assert_eq!(mapped.len(), builder.size_of_image() as usize);
+        assert_eq!(mapped[..size_of_headers], data[..size_of_headers]);
+        //the raw data of .text follows the headers in the file but must not end up there
+        assert!(mapped[size_of_headers..0x1000]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_eq!(mapped[0x1000..0x1020], [0xCC; 0x20]);
+    }
+
+    #[test]
+    fn zero_fills_virtual_size_past_raw_data() {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        let rva = builder.section_with_size(".bss", SCN_DATA, vec![0xAA; 0x10], 0x3000);
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        let mapped = map_image(&image, builder.image_base).unwrap();
+
+        let start = rva as usize;
+        assert_eq!(mapped.len(), start + 0x3000);
+        assert_eq!(mapped[start..start + 0x10], [0xAA; 0x10]);
+        assert!(mapped[start + 0x10..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn rejects_layouts_outside_size_of_image() {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xCC; 0x20]);
+        let data = builder.build();
+        let map = |data: &[u8]| map_image(&PeImage::parse(data).unwrap(), 0x180000000);
+
+        let mut small = data.clone();
+        patch_u32(&mut small, OPTIONAL_HEADER + 56, 0x1010);
+        assert_eq!(
+            map(&small),
+            Err(MapError::SectionOutsideImage(".text".to_string()))
+        );
+
+        let mut headers = data.clone();
+        patch_u32(&mut headers, OPTIONAL_HEADER + 60, 0x3000);
+        assert_eq!(map(&headers), Err(MapError::HeadersOutsideImage(0x3000)));
+
+        let mut huge = data.clone();
+        patch_u32(&mut huge, OPTIONAL_HEADER + 56, 0xFFFFF000);
+        assert_eq!(map(&huge), Err(MapError::ImageTooLarge(0xFFFFF000)));
+    }
+
+    #[test]
+    fn refuses_to_move_an_image_without_relocations() {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.characteristics |= IMAGE_FILE_RELOCS_STRIPPED;
+        builder.section(".text", SCN_TEXT, vec![0xC3]);
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        assert!(map_image(&image, builder.image_base).is_ok());
+        assert_eq!(
+            map_image(&image, 0x20000000),
+            Err(MapError::RelocationsStripped(0x20000000))
+        );
+    }
+
+    #[test]
+    fn rewrites_image_base() {
+        for (architecture, base) in [
+            (Architecture::X86, 0x20000000u64),
+            (Architecture::X64, 0x7FF612340000),
+        ] {
+            let mut builder = PeBuilder::new(architecture);
+            builder.section(".data", SCN_DATA, vec![1]);
+            let data = builder.build();
+            let mapped = map_image(&PeImage::parse(&data).unwrap(), base).unwrap();
+            let image_base = match architecture {
+                Architecture::X86 => read_u32(&mapped, OPTIONAL_HEADER + 28).map(u64::from),
+                Architecture::X64 => read_u64(&mapped, OPTIONAL_HEADER + 24),
+            };
+            assert_eq!(image_base, Some(base));
+        }
+    }
+}
@@ -0,0 +1,24 @@
+pub mod apiset;
+pub mod authenticode;
+pub mod checksum;
+pub mod clr;
+pub mod debug;
+pub mod der;
+pub mod error;
+pub mod exceptions;
+pub mod exports;
+#[cfg(test)]
+pub mod fixture;
+pub mod headers;
+pub mod image;
+pub mod imports;
+pub mod loadconfig;
+pub mod mapper;
+pub mod packing;
+pub mod protection;
+pub mod reader;
+pub mod relocs;
+pub mod resources;
+pub mod rich;
+pub mod tls;
+pub mod version;
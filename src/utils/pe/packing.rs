@@ -0,0 +1,275 @@
+use super::headers::{IMAGE_DIRECTORY_ENTRY_SECURITY, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE};
+use super::image::PeImage;
+use super::reader::read_bytes;
+
+///compressed or encrypted data sits close to the 8 bits per byte maximum, code is around 6
+pub const HIGH_ENTROPY: f64 = 7.2;
+///executable sections that only exist in memory and are this big are where packers unpack to
+pub const LARGE_VIRTUAL_SIZE: u32 = 0x10000;
+
+///What stands out about a single section
+#[derive(Debug, Clone)]
+pub struct SectionAnalysis {
+    pub name: String,
+    ///Shannon entropy of the raw data in bits per byte, 0 for sections without raw data
+    pub entropy: f64,
+    pub writable_and_executable: bool,
+    ///executable with no raw data but a large virtual size, a plain .bss does not count
+    pub empty_on_disk: bool,
+}
+
+impl SectionAnalysis {
+    pub fn high_entropy(&self) -> bool {
+        return self.entropy >= HIGH_ENTROPY;
+    }
+}
+
+///Data appended after the last section that the loader never maps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlay {
+    pub offset: usize,
+    pub size: usize,
+}
+
+///Per section entropy and layout oddities that hint at a packed or self-modifying dll
+#[derive(Debug, Clone)]
+pub struct PackingAnalysis {
+    pub sections: Vec<SectionAnalysis>,
+    pub overlay: Option<Overlay>,
+}
+
+impl PackingAnalysis {
+    ///one line per finding, empty when nothing looks packed
+    pub fn reasons(&self) -> Vec<String> {
+        let mut reasons = Vec::new();
+        for section in &self.sections {
+            if section.high_entropy() {
+                reasons.push(format!(
+                    "{} has an entropy of {:.2} bits per byte",
+                    section.name, section.entropy
+                ));
+            }
+            if section.writable_and_executable {
+                reasons.push(format!("{} is writable and executable", section.name));
+            }
+            if section.empty_on_disk {
+                reasons.push(format!(
+                    "{} is executable and large once mapped but has no data on disk",
+                    section.name
+                ));
+            }
+        }
+        if let Some(overlay) = self.overlay {
+            reasons.push(format!(
+                "0x{:x} bytes are appended after the last section",
+                overlay.size
+            ));
+        }
+        return reasons;
+    }
+
+    pub fn looks_packed(&self) -> bool {
+        return !self.reasons().is_empty();
+    }
+}
+
+///Shannon entropy in bits per byte
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    return counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let probability = count as f64 / len;
+            probability * (1.0 / probability).log2()
+        })
+        .sum();
+}
+
+impl<'a> PeImage<'a> {
+    pub fn packing_analysis(&self) -> PackingAnalysis {
+        let data = self.data();
+        let sections = self
+            .sections()
+            .iter()
+            .map(|section| {
+                let raw = read_bytes(
+                    data,
+                    section.pointer_to_raw_data as usize,
+                    section.size_of_raw_data as usize,
+                )
+                .unwrap_or_default();
+                let characteristics = section.characteristics;
+                SectionAnalysis {
+                    name: section.name(),
+                    entropy: shannon_entropy(raw),
+                    writable_and_executable: characteristics & IMAGE_SCN_MEM_WRITE != 0
+                        && characteristics & IMAGE_SCN_MEM_EXECUTE != 0,
+                    empty_on_disk: section.size_of_raw_data == 0
+                        && section.virtual_size >= LARGE_VIRTUAL_SIZE
+                        && characteristics & IMAGE_SCN_MEM_EXECUTE != 0,
+                }
+            })
+            .collect();
+
+        return PackingAnalysis {
+            sections,
+            overlay: self.overlay(),
+        };
+    }
+
+    ///whatever follows the raw data of the last section, not counting a trailing certificate table
+    pub fn overlay(&self) -> Option<Overlay> {
+        let data = self.data();
+        let sections_end = self
+            .sections()
+            .iter()
+            .filter(|section| section.size_of_raw_data != 0)
+            .map(|section| section.pointer_to_raw_data as usize + section.size_of_raw_data as usize)
+            .max()
+            .unwrap_or(self.optional_header().size_of_headers as usize);
+
+        //the security directory holds a file offset and is appended to the file on purpose
+        let mut end = data.len();
+        if let Some(security) = self.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY) {
+            let certificates = security.virtual_address as usize;
+            if certificates >= sections_end && certificates + security.size as usize >= end {
+                end = certificates;
+            }
+        }
+
+        //zero padding up to an alignment boundary carries nothing
+        let trailing = data.get(sections_end..end)?;
+        if trailing.iter().all(|&byte| byte == 0) {
+            return None;
+        }
+        return Some(Overlay {
+            offset: sections_end,
+            size: end - sections_end,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_DATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    const PAYLOAD: &[u8] = b"appended payload";
+    ///a WIN_CERTIFICATE header with a few bytes of signature, 8 byte aligned like the real thing
+    const CERTIFICATES: &[u8] = &[
+        0x10, 0, 0, 0, 0, 2, 2, 0, 0x30, 0x82, 0x01, 0x00, 0, 0, 0, 0,
+    ];
+
+    ///an image followed by trailing, with the security directory covering certificates at the given
+    ///offset into trailing
+    fn image(trailing: &[u8], certificates: Option<usize>) -> (usize, Vec<u8>) {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        builder.section(".data", SCN_DATA, vec![1; 0x10]);
+        let sections_end = builder.build().len();
+        if let Some(offset) = certificates {
+            let table = (sections_end + offset) as u32;
+            builder.directory(
+                IMAGE_DIRECTORY_ENTRY_SECURITY,
+                table,
+                CERTIFICATES.len() as u32,
+            );
+        }
+        let mut data = builder.build();
+        data.extend_from_slice(trailing);
+        return (sections_end, data);
+    }
+
+    fn overlay(trailing: &[u8], certificates: Option<usize>) -> (usize, Option<Overlay>) {
+        let (sections_end, data) = image(trailing, certificates);
+        return (sections_end, PeImage::parse(&data).unwrap().overlay());
+    }
+
+    #[test]
+    fn finds_appended_data() {
+        let (_, none) = overlay(&[], None);
+        assert_eq!(none, None);
+        //padding to the next file alignment boundary is not an overlay
+        let (_, padding) = overlay(&[0; 0x200], None);
+        assert_eq!(padding, None);
+
+        let (offset, appended) = overlay(PAYLOAD, None);
+        assert_eq!(
+            appended,
+            Some(Overlay {
+                offset,
+                size: PAYLOAD.len(),
+            })
+        );
+    }
+
+    #[test]
+    fn skips_a_trailing_certificate_table() {
+        let (_, signed) = overlay(CERTIFICATES, Some(0));
+        assert_eq!(signed, None);
+
+        //data smuggled in between the sections and the certificates is still found
+        let trailing = [PAYLOAD, CERTIFICATES].concat();
+        let (offset, smuggled) = overlay(&trailing, Some(PAYLOAD.len()));
+        assert_eq!(
+            smuggled,
+            Some(Overlay {
+                offset,
+                size: PAYLOAD.len(),
+            })
+        );
+
+        //a certificate table with more data after it does not hide that data
+        let trailing = [CERTIFICATES, PAYLOAD].concat();
+        let (offset, after) = overlay(&trailing, Some(0));
+        assert_eq!(
+            after,
+            Some(Overlay {
+                offset,
+                size: trailing.len(),
+            })
+        );
+    }
+
+    #[test]
+    fn measures_entropy() {
+        assert_eq!(shannon_entropy(&[]), 0.0);
+        assert_eq!(shannon_entropy(&[0x90; 0x100]), 0.0);
+        assert_eq!(shannon_entropy(&[0, 1, 0, 1]), 1.0);
+        let every_byte: Vec<u8> = (0..=255).collect();
+        assert_eq!(shannon_entropy(&every_byte), 8.0);
+    }
+
+    #[test]
+    fn reports_what_looks_packed() {
+        let (_, data) = image(PAYLOAD, None);
+        let analysis = PeImage::parse(&data).unwrap().packing_analysis();
+        assert_eq!(analysis.sections.len(), 2);
+        assert!(analysis
+            .sections
+            .iter()
+            .all(|section| !section.high_entropy() && !section.writable_and_executable));
+        assert_eq!(
+            analysis.reasons(),
+            vec![format!(
+                "0x{:x} bytes are appended after the last section",
+                PAYLOAD.len()
+            )]
+        );
+
+        let (_, data) = image(&[], None);
+        assert!(!PeImage::parse(&data)
+            .unwrap()
+            .packing_analysis()
+            .looks_packed());
+    }
+}
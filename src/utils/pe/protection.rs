@@ -0,0 +1,202 @@
+use std::fmt;
+
+use super::headers::{
+    OptionalHeader, SectionHeader, IMAGE_SCN_MEM_DISCARDABLE, IMAGE_SCN_MEM_EXECUTE,
+    IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE,
+};
+use super::image::PeImage;
+
+//PAGE_* values from winnt.h, the pe module stays free of winapi
+pub const PAGE_NOACCESS: u32 = 0x01;
+pub const PAGE_READONLY: u32 = 0x02;
+pub const PAGE_READWRITE: u32 = 0x04;
+pub const PAGE_EXECUTE: u32 = 0x10;
+pub const PAGE_EXECUTE_READ: u32 = 0x20;
+pub const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+
+const PAGE_SIZE: u32 = 0x1000;
+
+///The final protection of a mapped region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    NoAccess,
+    ReadOnly,
+    ReadWrite,
+    Execute,
+    ExecuteRead,
+    ExecuteReadWrite,
+}
+
+impl Protection {
+    ///derives the protection from IMAGE_SCN_MEM_*, there is no write only or execute write only
+    ///protection so write always implies read
+    pub fn from_characteristics(characteristics: u32) -> Protection {
+        let execute = characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+        let read = characteristics & IMAGE_SCN_MEM_READ != 0;
+        let write = characteristics & IMAGE_SCN_MEM_WRITE != 0;
+        return match (execute, read, write) {
+            (true, _, true) => Protection::ExecuteReadWrite,
+            (true, true, false) => Protection::ExecuteRead,
+            (true, false, false) => Protection::Execute,
+            (false, _, true) => Protection::ReadWrite,
+            (false, true, false) => Protection::ReadOnly,
+            (false, false, false) => Protection::NoAccess,
+        };
+    }
+
+    ///the PAGE_* value for VirtualProtect
+    pub fn page_flags(&self) -> u32 {
+        match self {
+            Protection::NoAccess => PAGE_NOACCESS,
+            Protection::ReadOnly => PAGE_READONLY,
+            Protection::ReadWrite => PAGE_READWRITE,
+            Protection::Execute => PAGE_EXECUTE,
+            Protection::ExecuteRead => PAGE_EXECUTE_READ,
+            Protection::ExecuteReadWrite => PAGE_EXECUTE_READWRITE,
+        }
+    }
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protection::NoAccess => write!(f, "NOACCESS"),
+            Protection::ReadOnly => write!(f, "R"),
+            Protection::ReadWrite => write!(f, "RW"),
+            Protection::Execute => write!(f, "X"),
+            Protection::ExecuteRead => write!(f, "RX"),
+            Protection::ExecuteReadWrite => write!(f, "RWX"),
+        }
+    }
+}
+
+///A page aligned range of the mapped image and the protection it ends up with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtectionRegion {
+    pub name: String,
+    pub rva: u32,
+    pub size: u32,
+    pub protection: Protection,
+}
+
+///works out the protection of every part of a mapped image
+///
+///headers end up read only and discardable sections inaccessible since relocations are already
+///applied by the time this is used. Images with a section alignment below the page size share
+///pages between sections, those are kept as a single RWX region like the loader does
+pub fn protection_plan(
+    optional_header: &OptionalHeader,
+    sections: &[SectionHeader],
+) -> Vec<ProtectionRegion> {
+    let alignment = optional_header.section_alignment;
+    if alignment < PAGE_SIZE {
+        return vec![ProtectionRegion {
+            name: "image".to_string(),
+            rva: 0,
+            size: align_up(optional_header.size_of_image, PAGE_SIZE),
+            protection: Protection::ExecuteReadWrite,
+        }];
+    }
+
+    let mut plan = vec![ProtectionRegion {
+        name: "headers".to_string(),
+        rva: 0,
+        size: align_up(optional_header.size_of_headers, alignment),
+        protection: Protection::ReadOnly,
+    }];
+    for section in sections {
+        let size = align_up(section.mapped_size(), alignment);
+        if size == 0 {
+            continue;
+        }
+        let protection = match section.characteristics & IMAGE_SCN_MEM_DISCARDABLE {
+            0 => Protection::from_characteristics(section.characteristics),
+            _ => Protection::NoAccess,
+        };
+        plan.push(ProtectionRegion {
+            name: section.name(),
+            rva: section.virtual_address,
+            size,
+            protection,
+        });
+    }
+
+    return plan;
+}
+
+fn align_up(value: u32, alignment: u32) -> u32 {
+    if alignment == 0 {
+        return value;
+    }
+    return value
+        .checked_add(alignment - 1)
+        .map(|value| value / alignment * alignment)
+        .unwrap_or(u32::MAX / alignment * alignment);
+}
+
+impl<'a> PeImage<'a> {
+    pub fn protection_plan(&self) -> Vec<ProtectionRegion> {
+        return protection_plan(self.optional_header(), self.sections());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_DATA, SCN_RDATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    fn plan(builder: &PeBuilder) -> Vec<(String, u32, u32, Protection)> {
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        return image
+            .protection_plan()
+            .into_iter()
+            .map(|region| (region.name, region.rva, region.size, region.protection))
+            .collect();
+    }
+
+    #[test]
+    fn protects_each_section_by_its_characteristics() {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x1800]);
+        builder.section(".rdata", SCN_RDATA, vec![1; 0x10]);
+        builder.section(".data", SCN_DATA, vec![2; 0x10]);
+        builder.section(
+            ".reloc",
+            SCN_RDATA | IMAGE_SCN_MEM_DISCARDABLE,
+            vec![3; 0x10],
+        );
+        assert_eq!(
+            plan(&builder),
+            vec![
+                ("headers".to_string(), 0, 0x1000, Protection::ReadOnly),
+                (".text".to_string(), 0x1000, 0x2000, Protection::ExecuteRead),
+                (".rdata".to_string(), 0x3000, 0x1000, Protection::ReadOnly),
+                (".data".to_string(), 0x4000, 0x1000, Protection::ReadWrite),
+                (".reloc".to_string(), 0x5000, 0x1000, Protection::NoAccess),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_small_alignment_images_as_one_rwx_region() {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section_alignment = 0x200;
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        builder.section(".data", SCN_DATA, vec![1; 0x10]);
+        assert_eq!(
+            plan(&builder),
+            vec![("image".to_string(), 0, 0x1000, Protection::ExecuteReadWrite)]
+        );
+    }
+
+    #[test]
+    fn align_up_saturates_near_u32_max() {
+        assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(align_up(0x1000, 0x1000), 0x1000);
+        assert_eq!(align_up(u32::MAX - 5, 0x1000), 0xFFFFF000);
+        assert_eq!(align_up(u32::MAX, 0x200), 0xFFFFFE00);
+        assert_eq!(align_up(0x1234, 0), 0x1234);
+    }
+}
@@ -0,0 +1,57 @@
+///bounds checked little endian reads over a byte buffer
+///
+///every function returns None instead of reading past the end of the buffer so a truncated or
+///crafted file can never make the parser touch memory it does not own
+
+pub fn read_bytes(data: &[u8], offset: usize, len: usize) -> Option<&[u8]> {
+    return data.get(offset..offset.checked_add(len)?);
+}
+
+pub fn read_u8(data: &[u8], offset: usize) -> Option<u8> {
+    return data.get(offset).copied();
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = read_bytes(data, offset, 2)?;
+    return Some(u16::from_le_bytes([bytes[0], bytes[1]]));
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = read_bytes(data, offset, 4)?;
+    return Some(u32::from_le_bytes(bytes.try_into().ok()?));
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = read_bytes(data, offset, 8)?;
+    return Some(u64::from_le_bytes(bytes.try_into().ok()?));
+}
+
+///reads a nul terminated string starting at offset, the terminator is not included
+///
+///returns None if there is no terminator before the end of the buffer
+pub fn read_cstr(data: &[u8], offset: usize) -> Option<&[u8]> {
+    let rest = data.get(offset..)?;
+    let len = rest.iter().position(|&byte| byte == 0)?;
+    return Some(&rest[..len]);
+}
+
+///reads len utf16 code units starting at offset, lossily converted to utf8
+pub fn read_utf16(data: &[u8], offset: usize, len: usize) -> Option<String> {
+    let bytes = read_bytes(data, offset, len.checked_mul(2)?)?;
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    return Some(String::from_utf16_lossy(&units));
+}
+
+///reads a nul terminated utf16 string starting at offset
+///
+///also returns the number of bytes taken up including the terminator
+pub fn read_utf16z(data: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut len = 0;
+    while read_u16(data, offset + len * 2)? != 0 {
+        len += 1;
+    }
+    return Some((read_utf16(data, offset, len)?, (len + 1) * 2));
+}
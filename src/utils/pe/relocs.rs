@@ -0,0 +1,400 @@
+use std::fmt;
+
+use super::error::PeValidationError;
+use super::headers::IMAGE_DIRECTORY_ENTRY_BASERELOC;
+use super::image::PeImage;
+use super::reader::{read_u16, read_u32, read_u64};
+
+const SIZE_OF_BASE_RELOCATION: u32 = 8;
+
+///The type stored in the top 4 bits of a base relocation entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationType {
+    ///padding, nothing to do
+    Absolute,
+    ///high 16 bits of a 32 bit address
+    High,
+    ///low 16 bits of a 32 bit address
+    Low,
+    ///full 32 bit address
+    HighLow,
+    ///high 16 bits of a 32 bit address, the low 16 bits are carried in the next entry
+    HighAdj(u16),
+    ///full 64 bit address, also what ARM64 images use
+    Dir64,
+    ///MOVW/MOVT pair of an ARM image
+    ArmMov32,
+    ///MOVW/MOVT pair of a Thumb-2 image
+    ThumbMov32,
+    ///anything else, including types that only exist for MIPS/IA64/RISC-V
+    Unknown(u8),
+}
+
+impl RelocationType {
+    fn from_raw(kind: u8) -> RelocationType {
+        match kind {
+            0 => RelocationType::Absolute,
+            1 => RelocationType::High,
+            2 => RelocationType::Low,
+            3 => RelocationType::HighLow,
+            4 => RelocationType::HighAdj(0),
+            5 => RelocationType::ArmMov32,
+            7 => RelocationType::ThumbMov32,
+            10 => RelocationType::Dir64,
+            kind => RelocationType::Unknown(kind),
+        }
+    }
+
+    ///IMAGE_REL_BASED_* name
+    pub fn name(&self) -> String {
+        match self {
+            RelocationType::Absolute => "ABSOLUTE".to_string(),
+            RelocationType::High => "HIGH".to_string(),
+            RelocationType::Low => "LOW".to_string(),
+            RelocationType::HighLow => "HIGHLOW".to_string(),
+            RelocationType::HighAdj(_) => "HIGHADJ".to_string(),
+            RelocationType::Dir64 => "DIR64".to_string(),
+            RelocationType::ArmMov32 => "ARM_MOV32".to_string(),
+            RelocationType::ThumbMov32 => "THUMB_MOV32".to_string(),
+            RelocationType::Unknown(kind) => format!("UNKNOWN({kind})"),
+        }
+    }
+}
+
+///A single base relocation, rva is the address of the value to patch
+#[derive(Debug, Clone, Copy)]
+pub struct Relocation {
+    pub rva: u32,
+    pub kind: RelocationType,
+}
+
+///Why relocations could not be applied to a mapped image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelocationError {
+    Unsupported { rva: u32, kind: String },
+    Unknown { rva: u32, kind: u8 },
+    OutOfBounds { rva: u32 },
+}
+
+impl fmt::Display for RelocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelocationError::Unsupported { rva, kind } => {
+                write!(f, "Unsupported {kind} relocation at 0x{rva:x}")
+            }
+            RelocationError::Unknown { rva, kind } => {
+                write!(f, "Unknown relocation type {kind} at 0x{rva:x}")
+            }
+            RelocationError::OutOfBounds { rva } => {
+                write!(f, "Relocation at 0x{rva:x} is outside the image")
+            }
+        }
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///parses the .reloc blocks into a flat list of typed entries
+    pub fn relocations(&self) -> Result<Vec<Relocation>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) {
+            Some(directory) => directory,
+            None => return Ok(Vec::new()),
+        };
+        let malformed = PeValidationError::MalformedDirectory("base relocation");
+        let table = match self.bytes_at_rva(directory.virtual_address, directory.size as usize) {
+            Some(table) => table,
+            None => return Err(malformed),
+        };
+
+        let mut relocations = Vec::new();
+        let mut block = 0usize;
+        while block + SIZE_OF_BASE_RELOCATION as usize <= table.len() {
+            let page_rva = read_u32(table, block).unwrap_or_default();
+            let size_of_block = read_u32(table, block + 4).unwrap_or_default() as usize;
+
+            //some linkers pad the directory with an empty block
+            if page_rva == 0 && size_of_block == 0 {
+                break;
+            }
+            if size_of_block < SIZE_OF_BASE_RELOCATION as usize
+                || size_of_block % 2 != 0
+                || block + size_of_block > table.len()
+            {
+                return Err(malformed);
+            }
+
+            let mut entry = block + SIZE_OF_BASE_RELOCATION as usize;
+            let block_end = block + size_of_block;
+            while entry + 2 <= block_end {
+                let raw = read_u16(table, entry).unwrap_or_default();
+                entry += 2;
+
+                let rva = page_rva.wrapping_add((raw & 0xFFF) as u32);
+                let kind = match RelocationType::from_raw((raw >> 12) as u8) {
+                    //HIGHADJ takes up two slots, the second one holds the low half of the address
+                    RelocationType::HighAdj(_) => match read_u16(table, entry) {
+                        Some(low) if entry + 2 <= block_end => {
+                            entry += 2;
+                            RelocationType::HighAdj(low)
+                        }
+                        _ => return Err(malformed),
+                    },
+                    kind => kind,
+                };
+                relocations.push(Relocation { rva, kind });
+            }
+
+            block = block_end;
+        }
+
+        return Ok(relocations);
+    }
+}
+
+///applies relocations to an image laid out at its RVAs
+///
+///delta is the distance between the new base and the ImageBase the image was linked at, wrapping
+///arithmetic makes negative deltas work
+pub fn apply_relocations(
+    mapped: &mut [u8],
+    relocations: &[Relocation],
+    delta: u64,
+) -> Result<(), RelocationError> {
+    for relocation in relocations {
+        let rva = relocation.rva;
+        let offset = rva as usize;
+        let out_of_bounds = RelocationError::OutOfBounds { rva };
+
+        match relocation.kind {
+            RelocationType::Absolute => {}
+            RelocationType::High => {
+                let value = read_u16(mapped, offset).ok_or(out_of_bounds)?;
+                let value = ((value as u32) << 16).wrapping_add(delta as u32);
+                write_u16(mapped, offset, (value >> 16) as u16);
+            }
+            RelocationType::Low => {
+                let value = read_u16(mapped, offset).ok_or(out_of_bounds)?;
+                write_u16(mapped, offset, value.wrapping_add(delta as u16));
+            }
+            RelocationType::HighLow => {
+                let value = read_u32(mapped, offset).ok_or(out_of_bounds)?;
+                write_u32(mapped, offset, value.wrapping_add(delta as u32));
+            }
+            RelocationType::HighAdj(low) => {
+                //rebuild the full address, relocate it and round so the sign extended low half adds back up
+                let high = read_u16(mapped, offset).ok_or(out_of_bounds)?;
+                let value = ((high as u32) << 16)
+                    .wrapping_add(low as i16 as i32 as u32)
+                    .wrapping_add(delta as u32)
+                    .wrapping_add(0x8000);
+                write_u16(mapped, offset, (value >> 16) as u16);
+            }
+            RelocationType::Dir64 => {
+                let value = read_u64(mapped, offset).ok_or(out_of_bounds)?;
+                write_u64(mapped, offset, value.wrapping_add(delta));
+            }
+            RelocationType::ArmMov32 | RelocationType::ThumbMov32 => {
+                return Err(RelocationError::Unsupported {
+                    rva,
+                    kind: relocation.kind.name(),
+                })
+            }
+            RelocationType::Unknown(kind) => return Err(RelocationError::Unknown { rva, kind }),
+        }
+    }
+
+    return Ok(());
+}
+
+//the reads above already checked the bounds
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_DATA, SCN_RDATA};
+    use crate::utils::pe::headers::Architecture;
+
+    fn entry(kind: u16, offset: u16) -> u16 {
+        return kind << 12 | offset;
+    }
+
+    fn block(page_rva: u32, size_of_block: u32, entries: &[u16]) -> Vec<u8> {
+        let mut block = page_rva.to_le_bytes().to_vec();
+        block.extend_from_slice(&size_of_block.to_le_bytes());
+        for entry in entries {
+            block.extend_from_slice(&entry.to_le_bytes());
+        }
+        return block;
+    }
+
+    ///parses a .reloc section holding table, the directory covers all of it
+    fn parse(table: Vec<u8>) -> Result<Vec<Relocation>, PeValidationError> {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".data", SCN_DATA, vec![0; 0x100]);
+        let size = table.len() as u32;
+        let rva = builder.section(".reloc", SCN_RDATA, table);
+        builder.directory(IMAGE_DIRECTORY_ENTRY_BASERELOC, rva, size);
+        let data = builder.build();
+        return PeImage::parse(&data).unwrap().relocations();
+    }
+
+    fn kinds(relocations: &[Relocation]) -> Vec<(u32, RelocationType)> {
+        return relocations.iter().map(|r| (r.rva, r.kind)).collect();
+    }
+
+    #[test]
+    fn parses_highadj_second_slot_and_stops_at_padding() {
+        let entries = [entry(3, 0x10), entry(4, 0x20), 0x8001, entry(0, 0)];
+        let mut table = block(0x1000, 16, &entries);
+        table.extend_from_slice(&[0; 8]);
+        //never reached, the empty block ends the table
+        table.extend_from_slice(&block(0x2000, 10, &[entry(3, 0)]));
+        let relocations = parse(table).unwrap();
+        assert_eq!(
+            kinds(&relocations),
+            vec![
+                (0x1010, RelocationType::HighLow),
+                (0x1020, RelocationType::HighAdj(0x8001)),
+                (0x1000, RelocationType::Absolute),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_size_of_block() {
+        let malformed = Err(PeValidationError::MalformedDirectory("base relocation"));
+        //odd
+        assert_eq!(
+            parse(block(0x1000, 11, &[entry(3, 0), 0])).map(|_| ()),
+            malformed
+        );
+        //shorter than the block header
+        assert_eq!(parse(block(0x1000, 4, &[0, 0])).map(|_| ()), malformed);
+        //past the end of the directory
+        assert_eq!(
+            parse(block(0x1000, 16, &[entry(3, 0)])).map(|_| ()),
+            malformed
+        );
+        //HIGHADJ without its second slot
+        assert_eq!(
+            parse(block(0x1000, 10, &[entry(4, 0)])).map(|_| ()),
+            malformed
+        );
+    }
+
+    fn relocate(kind: RelocationType, value: &[u8], delta: i64) -> Vec<u8> {
+        let mut mapped = vec![0u8; 0x10];
+        mapped[4..4 + value.len()].copy_from_slice(value);
+        let relocation = Relocation { rva: 4, kind };
+        apply_relocations(&mut mapped, &[relocation], delta as u64).unwrap();
+        return mapped[4..4 + value.len()].to_vec();
+    }
+
+    #[test]
+    fn applies_every_supported_type() {
+        let high_low = 0x10001000u32.to_le_bytes();
+        assert_eq!(
+            relocate(RelocationType::HighLow, &high_low, 0x10000),
+            0x10011000u32.to_le_bytes()
+        );
+        assert_eq!(
+            relocate(RelocationType::HighLow, &high_low, -0x1000),
+            0x10000000u32.to_le_bytes()
+        );
+
+        let high = 0x1000u16.to_le_bytes();
+        assert_eq!(
+            relocate(RelocationType::High, &high, 0x10000),
+            0x1001u16.to_le_bytes()
+        );
+        assert_eq!(
+            relocate(RelocationType::High, &high, -0x10000),
+            0x0FFFu16.to_le_bytes()
+        );
+
+        let low = 0x1234u16.to_le_bytes();
+        assert_eq!(
+            relocate(RelocationType::Low, &low, 0x10010),
+            0x1244u16.to_le_bytes()
+        );
+        assert_eq!(
+            relocate(RelocationType::Low, &low, -0x10),
+            0x1224u16.to_le_bytes()
+        );
+
+        //0x10008001 is stored as 0x1001 because the low half is sign extended when added back
+        let high_adj = 0x1001u16.to_le_bytes();
+        assert_eq!(
+            relocate(RelocationType::HighAdj(0x8001), &high_adj, 0x10000),
+            0x1002u16.to_le_bytes()
+        );
+        assert_eq!(
+            relocate(RelocationType::HighAdj(0x8001), &high_adj, -0x20000),
+            0x0FFFu16.to_le_bytes()
+        );
+
+        let dir64 = 0x180001000u64.to_le_bytes();
+        assert_eq!(
+            relocate(RelocationType::Dir64, &dir64, 0x7FF000000000),
+            0x7FF180001000u64.to_le_bytes()
+        );
+        assert_eq!(
+            relocate(RelocationType::Dir64, &dir64, -0x80000000),
+            0x100001000u64.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn refuses_arm_and_unknown_types() {
+        let entries = [entry(5, 0), entry(7, 4), entry(9, 8), 0];
+        let relocations = parse(block(0x1000, 16, &entries)).unwrap();
+        let mut mapped = vec![0u8; 0x2000];
+        let apply = |relocation: &Relocation, mapped: &mut [u8]| {
+            return apply_relocations(mapped, &[*relocation], 0x10000);
+        };
+        assert_eq!(
+            apply(&relocations[0], &mut mapped),
+            Err(RelocationError::Unsupported {
+                rva: 0x1000,
+                kind: "ARM_MOV32".to_string()
+            })
+        );
+        assert_eq!(
+            apply(&relocations[1], &mut mapped),
+            Err(RelocationError::Unsupported {
+                rva: 0x1004,
+                kind: "THUMB_MOV32".to_string()
+            })
+        );
+        assert_eq!(
+            apply(&relocations[2], &mut mapped),
+            Err(RelocationError::Unknown {
+                rva: 0x1008,
+                kind: 9
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_relocations_outside_the_image() {
+        let mut mapped = vec![0u8; 0x10];
+        let relocation = Relocation {
+            rva: 0xE,
+            kind: RelocationType::HighLow,
+        };
+        assert_eq!(
+            apply_relocations(&mut mapped, &[relocation], 1),
+            Err(RelocationError::OutOfBounds { rva: 0xE })
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use super::error::PeValidationError;
+use super::headers::IMAGE_DIRECTORY_ENTRY_RESOURCE;
+use super::image::PeImage;
+use super::reader::{read_u16, read_u32, read_utf16};
+
+const SIZE_OF_RESOURCE_DIRECTORY: usize = 16;
+const SIZE_OF_RESOURCE_DIRECTORY_ENTRY: usize = 8;
+const SIZE_OF_RESOURCE_DATA_ENTRY: usize = 16;
+
+///the high bit of an entry marks a named entry or a subdirectory
+const RESOURCE_HIGH_BIT: u32 = 0x80000000;
+
+///the tree is normally type/name/language, anything deeper or larger is treated as malformed
+const MAX_RESOURCE_DEPTH: usize = 8;
+const MAX_RESOURCE_ENTRIES: usize = 0x10000;
+
+#[allow(dead_code)]
+pub const RT_ICON: u16 = 3;
+#[allow(dead_code)]
+pub const RT_STRING: u16 = 6;
+#[allow(dead_code)]
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+#[allow(dead_code)]
+pub const RT_MANIFEST: u16 = 24;
+
+///How a resource directory entry is identified
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceId {
+    Name(String),
+    Id(u16),
+}
+
+impl std::fmt::Display for ResourceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceId::Name(name) => write!(f, "{name}"),
+            ResourceId::Id(id) => write!(f, "#{id}"),
+        }
+    }
+}
+
+///A leaf of the resource tree, rva points at the raw resource bytes
+#[derive(Debug, Clone)]
+pub struct ResourceData {
+    pub rva: u32,
+    pub size: u32,
+    #[allow(dead_code)]
+    pub code_page: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum ResourceNode {
+    Directory(ResourceDirectory),
+    Data(ResourceData),
+}
+
+#[derive(Debug, Clone)]
+pub struct ResourceEntry {
+    pub id: ResourceId,
+    pub node: ResourceNode,
+}
+
+///An IMAGE_RESOURCE_DIRECTORY and everything below it
+#[derive(Debug, Clone, Default)]
+pub struct ResourceDirectory {
+    #[allow(dead_code)]
+    pub time_date_stamp: u32,
+    pub entries: Vec<ResourceEntry>,
+}
+
+impl ResourceDirectory {
+    ///every leaf stored under the top level entry for the given RT_* type
+    pub fn find_type(&self, kind: u16) -> Vec<&ResourceData> {
+        let mut found = Vec::new();
+        for entry in &self.entries {
+            if entry.id == ResourceId::Id(kind) {
+                collect_data(&entry.node, &mut found);
+            }
+        }
+        return found;
+    }
+}
+
+fn collect_data<'a>(node: &'a ResourceNode, found: &mut Vec<&'a ResourceData>) {
+    match node {
+        ResourceNode::Data(data) => found.push(data),
+        ResourceNode::Directory(directory) => {
+            for entry in &directory.entries {
+                collect_data(&entry.node, found);
+            }
+        }
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///walks IMAGE_DIRECTORY_ENTRY_RESOURCE, an image without resources gives back an empty tree
+    pub fn resources(&self) -> Result<ResourceDirectory, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE) {
+            Some(directory) => directory,
+            None => return Ok(ResourceDirectory::default()),
+        };
+
+        //every offset inside the tree is relative to the start of the directory
+        let tree = match self.bytes_at_rva(directory.virtual_address, directory.size as usize) {
+            Some(tree) => tree,
+            None => return Err(PeValidationError::MalformedDirectory("resource")),
+        };
+        return read_resource_directory(tree, 0, 0, &mut 0);
+    }
+
+    ///the raw bytes a resource leaf points at
+    pub fn resource_bytes(&self, data: &ResourceData) -> Option<&[u8]> {
+        return self.bytes_at_rva(data.rva, data.size as usize);
+    }
+}
+
+fn read_resource_directory(
+    tree: &[u8],
+    offset: usize,
+    depth: usize,
+    visited: &mut usize,
+) -> Result<ResourceDirectory, PeValidationError> {
+    let malformed = PeValidationError::MalformedDirectory("resource");
+    //entries can point back at their own directory, stop before that blows up
+    *visited += named_and_ids(tree, offset);
+    if depth >= MAX_RESOURCE_DEPTH || *visited > MAX_RESOURCE_ENTRIES {
+        return Err(malformed);
+    }
+
+    let (time_date_stamp, named, ids) = match (
+        read_u32(tree, offset + 4),
+        read_u16(tree, offset + 12),
+        read_u16(tree, offset + 14),
+    ) {
+        (Some(stamp), Some(named), Some(ids)) => (stamp, named as usize, ids as usize),
+        _ => return Err(malformed),
+    };
+
+    let mut entries = Vec::with_capacity(named + ids);
+    for i in 0..named + ids {
+        let entry = offset + SIZE_OF_RESOURCE_DIRECTORY + i * SIZE_OF_RESOURCE_DIRECTORY_ENTRY;
+        let (name, target) = match (read_u32(tree, entry), read_u32(tree, entry + 4)) {
+            (Some(name), Some(target)) => (name, target),
+            _ => return Err(malformed),
+        };
+
+        //names are a u16 length followed by that many utf16 units
+        let id = match name & RESOURCE_HIGH_BIT {
+            0 => ResourceId::Id(name as u16),
+            _ => {
+                let name_offset = (name & !RESOURCE_HIGH_BIT) as usize;
+                let name = read_u16(tree, name_offset)
+                    .and_then(|len| read_utf16(tree, name_offset + 2, len as usize));
+                match name {
+                    Some(name) => ResourceId::Name(name),
+                    None => return Err(malformed),
+                }
+            }
+        };
+
+        let target_offset = (target & !RESOURCE_HIGH_BIT) as usize;
+        let node = match target & RESOURCE_HIGH_BIT {
+            0 => match (
+                read_u32(tree, target_offset),
+                read_u32(tree, target_offset + 4),
+                read_u32(tree, target_offset + 8),
+            ) {
+                (Some(rva), Some(size), Some(code_page))
+                    if target_offset + SIZE_OF_RESOURCE_DATA_ENTRY <= tree.len() =>
+                {
+                    ResourceNode::Data(ResourceData {
+                        rva,
+                        size,
+                        code_page,
+                    })
+                }
+                _ => return Err(malformed),
+            },
+            _ => ResourceNode::Directory(read_resource_directory(
+                tree,
+                target_offset,
+                depth + 1,
+                visited,
+            )?),
+        };
+
+        entries.push(ResourceEntry { id, node });
+    }
+
+    return Ok(ResourceDirectory {
+        time_date_stamp,
+        entries,
+    });
+}
+
+fn named_and_ids(tree: &[u8], offset: usize) -> usize {
+    let named = read_u16(tree, offset + 12).unwrap_or_default() as usize;
+    let ids = read_u16(tree, offset + 14).unwrap_or_default() as usize;
+    return named + ids;
+}
+
+///resource sections for the unit tests
+#[cfg(test)]
+pub mod fixture {
+    use super::RESOURCE_HIGH_BIT;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_RDATA};
+    use crate::utils::pe::headers::IMAGE_DIRECTORY_ENTRY_RESOURCE;
+
+    pub const LANGUAGE: u16 = 1033;
+
+    fn utf16(text: &str) -> Vec<u8> {
+        return text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    }
+
+    ///adds an .rsrc section with a single resource of type kind stored under name, returns its rva
+    ///
+    ///the tree is type -> name -> language like a resource compiler lays it out, the raw resource
+    ///sits right behind it at offset 0x80
+    pub fn resources(builder: &mut PeBuilder, kind: u16, name: &str, resource: &[u8]) -> u32 {
+        let base = builder.next_rva();
+        let mut tree = Vec::new();
+        let directory = |tree: &mut Vec<u8>, offset: usize, named: u16, ids: u16| {
+            put(tree, offset + 12, &named.to_le_bytes());
+            put(tree, offset + 14, &ids.to_le_bytes());
+        };
+        let entry = |tree: &mut Vec<u8>, offset: usize, name: u32, target: u32| {
+            put(tree, offset, &name.to_le_bytes());
+            put(tree, offset + 4, &target.to_le_bytes());
+        };
+
+        directory(&mut tree, 0x00, 0, 1);
+        entry(&mut tree, 0x10, kind as u32, RESOURCE_HIGH_BIT | 0x18);
+        directory(&mut tree, 0x18, 1, 0);
+        entry(
+            &mut tree,
+            0x28,
+            RESOURCE_HIGH_BIT | 0x60,
+            RESOURCE_HIGH_BIT | 0x30,
+        );
+        directory(&mut tree, 0x30, 0, 1);
+        entry(&mut tree, 0x40, LANGUAGE as u32, 0x48);
+        put(&mut tree, 0x48, &(base + 0x80).to_le_bytes());
+        put(&mut tree, 0x4C, &(resource.len() as u32).to_le_bytes());
+        put(&mut tree, 0x50, &1200u32.to_le_bytes());
+        put(&mut tree, 0x60, &(name.len() as u16).to_le_bytes());
+        put(&mut tree, 0x62, &utf16(name));
+        put(&mut tree, 0x80, resource);
+
+        let size = tree.len() as u32;
+        builder.section(".rsrc", SCN_RDATA, tree);
+        builder.directory(IMAGE_DIRECTORY_ENTRY_RESOURCE, base, size);
+        return base;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{resources, LANGUAGE};
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    fn builder() -> PeBuilder {
+        let mut builder = PeBuilder::new(Architecture::X64);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+        return builder;
+    }
+
+    ///the resource tree of an image whose .rsrc was patched
+    fn tree(patch: impl Fn(&mut Vec<u8>)) -> Result<ResourceDirectory, PeValidationError> {
+        let mut builder = builder();
+        resources(&mut builder, RT_MANIFEST, "MANIFEST", b"<assembly/>");
+        patch(&mut builder.sections[1].data);
+        return PeImage::parse(&builder.build()).unwrap().resources();
+    }
+
+    #[test]
+    fn walks_type_name_and_language() {
+        let mut builder = builder();
+        let base = resources(&mut builder, RT_MANIFEST, "MANIFEST", b"<assembly/>");
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        let tree = image.resources().unwrap();
+
+        assert_eq!(tree.entries.len(), 1);
+        assert_eq!(tree.entries[0].id, ResourceId::Id(RT_MANIFEST));
+        let names = match &tree.entries[0].node {
+            ResourceNode::Directory(names) => names,
+            ResourceNode::Data(_) => panic!("type entry is a leaf"),
+        };
+        assert_eq!(
+            names.entries[0].id,
+            ResourceId::Name("MANIFEST".to_string())
+        );
+        assert_eq!(names.entries[0].id.to_string(), "MANIFEST");
+        let languages = match &names.entries[0].node {
+            ResourceNode::Directory(languages) => languages,
+            ResourceNode::Data(_) => panic!("name entry is a leaf"),
+        };
+        assert_eq!(languages.entries[0].id, ResourceId::Id(LANGUAGE));
+
+        let found = tree.find_type(RT_MANIFEST);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].rva, base + 0x80);
+        assert_eq!(found[0].code_page, 1200);
+        assert_eq!(image.resource_bytes(found[0]), Some(&b"<assembly/>"[..]));
+        assert!(tree.find_type(RT_VERSION).is_empty());
+    }
+
+    #[test]
+    fn stops_at_loops_in_the_tree() {
+        let malformed = Some(PeValidationError::MalformedDirectory("resource"));
+        assert!(tree(|_| {}).is_ok());
+        //the root lists itself as the subdirectory of its only entry
+        let root = RESOURCE_HIGH_BIT.to_le_bytes();
+        assert_eq!(tree(|rsrc| put(rsrc, 0x14, &root)).err(), malformed);
+        //the language directory points back at the type directory
+        let types = (RESOURCE_HIGH_BIT | 0x18).to_le_bytes();
+        assert_eq!(tree(|rsrc| put(rsrc, 0x44, &types)).err(), malformed);
+        //two entries that both lead back to the root fan out, the entry count stops that too
+        let fan = |rsrc: &mut Vec<u8>| {
+            put(rsrc, 0x0E, &2u16.to_le_bytes());
+            put(rsrc, 0x14, &root);
+            put(rsrc, 0x18, &0u32.to_le_bytes());
+            put(rsrc, 0x1C, &root);
+        };
+        assert_eq!(tree(fan).err(), malformed);
+        //more entries than any real tree has
+        let huge = |rsrc: &mut Vec<u8>| put(rsrc, 0x0C, &[0xFF, 0xFF, 0x02, 0x00]);
+        assert_eq!(tree(huge).err(), malformed);
+    }
+
+    #[test]
+    fn rejects_entries_past_the_end_of_the_tree() {
+        let malformed = Some(PeValidationError::MalformedDirectory("resource"));
+        let past_end = (RESOURCE_HIGH_BIT | 0x10000).to_le_bytes();
+        //a name, a subdirectory and a data entry out of bounds
+        assert_eq!(tree(|rsrc| put(rsrc, 0x28, &past_end)).err(), malformed);
+        assert_eq!(tree(|rsrc| put(rsrc, 0x2C, &past_end)).err(), malformed);
+        assert_eq!(
+            tree(|rsrc| put(rsrc, 0x44, &0x10000u32.to_le_bytes())).err(),
+            malformed
+        );
+        //a name whose length runs off the end
+        assert_eq!(
+            tree(|rsrc| put(rsrc, 0x60, &0x8000u16.to_le_bytes())).err(),
+            malformed
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use super::image::PeImage;
+use super::reader::read_u32;
+
+const RICH_SIGNATURE: u32 = 0x68636952; //Rich
+const DANS_SIGNATURE: u32 = 0x536E6144; //DanS
+///the DOS header always comes first, the Rich header can only start after it
+const RICH_SEARCH_START: usize = 0x80;
+
+///A @comp.id entry, how many objects a given tool version contributed to the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RichEntry {
+    pub product_id: u16,
+    pub build: u16,
+    pub count: u32,
+}
+
+impl RichEntry {
+    pub fn comp_id(&self) -> u32 {
+        return (self.product_id as u32) << 16 | self.build as u32;
+    }
+
+    ///the tool the product id stands for, None for ids this table does not know
+    pub fn product_name(&self) -> Option<&'static str> {
+        return match self.product_id {
+            0x0000 => Some("Unmarked objects"),
+            0x0001 => Some("Imports"),
+            0x009a => Some("Cvtres1000"),
+            0x009b => Some("Export1000"),
+            0x009c => Some("Implib1000"),
+            0x009d => Some("Linker1000"),
+            0x009e => Some("Masm1000"),
+            0x00aa => Some("Utc1600_C"),
+            0x00ab => Some("Utc1600_CPP"),
+            0x00fd => Some("AliasObj1400"),
+            0x00fe => Some("Cvtpgd1400"),
+            0x00ff => Some("Cvtres1400"),
+            0x0100 => Some("Export1400"),
+            0x0101 => Some("Implib1400"),
+            0x0102 => Some("Linker1400"),
+            0x0103 => Some("Masm1400"),
+            0x0104 => Some("Utc1900_C"),
+            0x0105 => Some("Utc1900_CPP"),
+            0x0106 => Some("Utc1900_CVTCIL_C"),
+            0x0107 => Some("Utc1900_CVTCIL_CPP"),
+            0x0108 => Some("Utc1900_LTCG_C"),
+            0x0109 => Some("Utc1900_LTCG_CPP"),
+            0x010a => Some("Utc1900_LTCG_MSIL"),
+            0x010b => Some("Utc1900_POGO_I_C"),
+            0x010c => Some("Utc1900_POGO_I_CPP"),
+            0x010d => Some("Utc1900_POGO_O_C"),
+            0x010e => Some("Utc1900_POGO_O_CPP"),
+            _ => None,
+        };
+    }
+
+    ///the Visual Studio release that shipped the tool with this build number
+    pub fn toolset(&self) -> Option<&'static str> {
+        //builds before 2015 were only ever released under a handful of exact numbers
+        let exact = match self.build {
+            21022 => Some("Visual Studio 2008 (9.0)"),
+            30729 => Some("Visual Studio 2008 SP1 (9.0)"),
+            30319 => Some("Visual Studio 2010 (10.0)"),
+            40219 => Some("Visual Studio 2010 SP1 (10.0)"),
+            50727 => Some("Visual Studio 2012 (11.0)"),
+            51025 | 51106 | 60315 | 60610 | 61030 => Some("Visual Studio 2012 Update (11.0)"),
+            21005 => Some("Visual Studio 2013 (12.0)"),
+            30501 | 30723 | 31101 | 40629 => Some("Visual Studio 2013 Update (12.0)"),
+            _ => None,
+        };
+        if exact.is_some() || self.product_id < 0x00fd {
+            return exact;
+        }
+
+        //from 2015 on every release shares the 14.x product ids, the build number tells them apart
+        let toolset = match self.build {
+            23026..=24999 => "Visual Studio 2015 (14.0)",
+            25000..=27507 => "Visual Studio 2017 (14.1x)",
+            27508..=30399 => "Visual Studio 2019 (14.2x)",
+            30400..=u16::MAX => "Visual Studio 2022 (14.3x+)",
+            _ => return None,
+        };
+        return Some(toolset);
+    }
+}
+
+///The decoded Rich header the MSVC linker puts between the DOS stub and the NT headers
+#[derive(Debug, Clone)]
+pub struct RichHeader {
+    ///file offset of the DanS marker
+    #[allow(dead_code)]
+    pub offset: usize,
+    ///the XOR key, which doubles as the checksum the linker computed
+    pub key: u32,
+    pub computed_checksum: u32,
+    pub entries: Vec<RichEntry>,
+}
+
+impl RichHeader {
+    ///false when the header was edited after linking, e.g. entries copied from another binary
+    pub fn checksum_valid(&self) -> bool {
+        return self.key == self.computed_checksum;
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///decodes the Rich header, None for images made by linkers that do not write one
+    pub fn rich_header(&self) -> Option<RichHeader> {
+        let data = self.data();
+        let end = (self.dos_header().e_lfanew as usize).min(data.len());
+
+        //the Rich marker and the key are stored in the clear at the end of the header
+        let mut rich = None;
+        let mut offset = RICH_SEARCH_START;
+        while offset + 8 <= end {
+            if read_u32(data, offset)? == RICH_SIGNATURE {
+                rich = Some(offset);
+                break;
+            }
+            offset += 4;
+        }
+        let rich = rich?;
+        let key = read_u32(data, rich + 4)?;
+
+        //walk back to the masked DanS marker
+        let mut start = rich.checked_sub(4)?;
+        while read_u32(data, start)? ^ key != DANS_SIGNATURE {
+            start = start.checked_sub(4)?;
+            if start < RICH_SEARCH_START {
+                return None;
+            }
+        }
+
+        //DanS is followed by three masked zero dwords and then id/count pairs up to Rich
+        let mut entries = Vec::new();
+        let mut entry = start + 16;
+        while entry + 8 <= rich {
+            let comp_id = read_u32(data, entry)? ^ key;
+            let count = read_u32(data, entry + 4)? ^ key;
+            entries.push(RichEntry {
+                product_id: (comp_id >> 16) as u16,
+                build: comp_id as u16,
+                count,
+            });
+            entry += 8;
+        }
+
+        return Some(RichHeader {
+            offset: start,
+            key,
+            computed_checksum: rich_checksum(data, start, &entries),
+            entries,
+        });
+    }
+}
+
+//the offset of DanS, plus every byte before it rotated by its offset (skipping e_lfanew), plus every
+//comp.id rotated by its count
+fn rich_checksum(data: &[u8], start: usize, entries: &[RichEntry]) -> u32 {
+    let mut checksum = start as u32;
+    for (offset, &byte) in data[..start].iter().enumerate() {
+        if (0x3C..0x40).contains(&offset) {
+            continue;
+        }
+        checksum = checksum.wrapping_add((byte as u32).rotate_left(offset as u32));
+    }
+    for entry in entries {
+        checksum = checksum.wrapping_add(entry.comp_id().rotate_left(entry.count));
+    }
+    return checksum;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    ///checksum of rich_image() as link.exe computes it, worked out separately from this parser
+    const CHECKSUM: u32 = 0xA0E9395F;
+    const ENTRIES: [RichEntry; 3] = [
+        RichEntry {
+            product_id: 0x0105,
+            build: 30159,
+            count: 12,
+        },
+        RichEntry {
+            product_id: 0x0102,
+            build: 30159,
+            count: 1,
+        },
+        RichEntry {
+            product_id: 0x0001,
+            build: 0,
+            count: 37,
+        },
+    ];
+
+    ///an x86 image with the usual DOS stub followed by a Rich header masked with key
+    fn rich_image(key: u32, entries: &[RichEntry]) -> Vec<u8> {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        let data = builder.build();
+
+        let mut stub = vec![
+            0x0E, 0x1F, 0xBA, 0x0E, 0x00, 0xB4, 0x09, 0xCD, 0x21, 0xB8, 0x01, 0x4C, 0xCD, 0x21,
+        ];
+        stub.extend(b"This program cannot be run in DOS mode.\r\r\n$");
+        stub.resize(0x40, 0);
+        let mut rich = Vec::new();
+        for dword in [DANS_SIGNATURE, 0, 0, 0] {
+            rich.extend((dword ^ key).to_le_bytes());
+        }
+        for entry in entries {
+            rich.extend((entry.comp_id() ^ key).to_le_bytes());
+            rich.extend((entry.count ^ key).to_le_bytes());
+        }
+        rich.extend(RICH_SIGNATURE.to_le_bytes());
+        rich.extend(key.to_le_bytes());
+
+        //the NT headers move back to make room, the headers have plenty of padding left for it
+        let inserted = stub.len() + rich.len();
+        let size_of_headers = 0x200;
+        let mut file = data[..0x40].to_vec();
+        file.extend(stub);
+        file.extend(rich);
+        file.extend(&data[0x40..size_of_headers - inserted]);
+        file.extend(&data[size_of_headers..]);
+        file[0x3C..0x40].copy_from_slice(&(0x40 + inserted as u32).to_le_bytes());
+        return file;
+    }
+
+    #[test]
+    fn checksum_matches_a_known_header() {
+        let data = rich_image(CHECKSUM, &ENTRIES);
+        let rich = PeImage::parse(&data).unwrap().rich_header().unwrap();
+        assert_eq!(rich.offset, 0x80);
+        assert_eq!(rich.key, CHECKSUM);
+        assert_eq!(rich.entries, ENTRIES);
+        assert_eq!(rich.computed_checksum, CHECKSUM);
+        assert!(rich.checksum_valid());
+    }
+
+    #[test]
+    fn notices_edited_headers() {
+        //a count bumped after linking
+        let mut entries = ENTRIES;
+        entries[0].count += 1;
+        let data = rich_image(CHECKSUM, &entries);
+        let rich = PeImage::parse(&data).unwrap().rich_header().unwrap();
+        assert_eq!(rich.entries, entries);
+        assert!(!rich.checksum_valid());
+
+        //the stub is covered too, but e_lfanew is not
+        let mut data = rich_image(CHECKSUM, &ENTRIES);
+        data[0x50] ^= 1;
+        assert!(!PeImage::parse(&data)
+            .unwrap()
+            .rich_header()
+            .unwrap()
+            .checksum_valid());
+    }
+
+    #[test]
+    fn needs_both_markers() {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x10]);
+        assert!(PeImage::parse(&builder.build())
+            .unwrap()
+            .rich_header()
+            .is_none());
+
+        //DanS masked with a different key than Rich carries
+        let mut data = rich_image(CHECKSUM, &ENTRIES);
+        data[0x80] ^= 0xFF;
+        assert!(PeImage::parse(&data).unwrap().rich_header().is_none());
+    }
+
+    #[test]
+    fn names_products_and_toolsets() {
+        assert_eq!(ENTRIES[0].comp_id(), 0x010575CF);
+        assert_eq!(ENTRIES[0].product_name(), Some("Utc1900_CPP"));
+        assert_eq!(ENTRIES[0].toolset(), Some("Visual Studio 2019 (14.2x)"));
+        let entry = |product_id: u16, build: u16| RichEntry {
+            product_id,
+            build,
+            count: 1,
+        };
+        assert_eq!(
+            entry(0x00ab, 40219).toolset(),
+            Some("Visual Studio 2010 SP1 (10.0)")
+        );
+        //pre 2015 product ids only ever shipped with the exact builds
+        assert_eq!(entry(0x00ab, 40000).toolset(), None);
+        assert_eq!(
+            entry(0x0104, 24215).toolset(),
+            Some("Visual Studio 2015 (14.0)")
+        );
+        assert_eq!(
+            entry(0x0104, 33145).toolset(),
+            Some("Visual Studio 2022 (14.3x+)")
+        );
+        assert_eq!(entry(0x7777, 1).product_name(), None);
+    }
+}
@@ -0,0 +1,216 @@
+use super::error::PeValidationError;
+use super::headers::IMAGE_DIRECTORY_ENTRY_TLS;
+use super::image::PeImage;
+use super::reader::{read_u32, read_u64};
+
+const SIZE_OF_TLS_DIRECTORY32: usize = 24;
+const SIZE_OF_TLS_DIRECTORY64: usize = 40;
+
+///The parsed IMAGE_TLS_DIRECTORY32/64
+///
+///the addresses are kept as the VAs stored in the image, the callbacks are converted to RVAs
+#[derive(Debug, Clone)]
+pub struct TlsDirectory {
+    pub start_address_of_raw_data: u64,
+    pub end_address_of_raw_data: u64,
+    pub address_of_index: u64,
+    pub address_of_callbacks: u64,
+    pub size_of_zero_fill: u32,
+    #[allow(dead_code)]
+    pub characteristics: u32,
+    pub callbacks: Vec<u32>,
+}
+
+impl TlsDirectory {
+    ///size of the template every thread gets a copy of
+    pub fn template_size(&self) -> u64 {
+        return self
+            .end_address_of_raw_data
+            .saturating_sub(self.start_address_of_raw_data);
+    }
+
+    ///true when the dll relies on the loader setting up static TLS (__declspec(thread) variables)
+    pub fn has_static_data(&self) -> bool {
+        return self.template_size() != 0 || self.size_of_zero_fill != 0;
+    }
+}
+
+impl<'a> PeImage<'a> {
+    ///reads IMAGE_DIRECTORY_ENTRY_TLS, None when the image has no TLS directory
+    pub fn tls(&self) -> Result<Option<TlsDirectory>, PeValidationError> {
+        let directory = match self.data_directory(IMAGE_DIRECTORY_ENTRY_TLS) {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+        let malformed = PeValidationError::MalformedDirectory("TLS");
+
+        let is_64 = self.optional_header().is_64bit();
+        let size = match is_64 {
+            true => SIZE_OF_TLS_DIRECTORY64,
+            false => SIZE_OF_TLS_DIRECTORY32,
+        };
+        let header = match self.bytes_at_rva(directory.virtual_address, size) {
+            Some(header) => header,
+            None => return Err(malformed),
+        };
+        //the four addresses are pointer sized, the two trailing fields are always 32 bit
+        let pointer = |index: usize| -> u64 {
+            match is_64 {
+                true => read_u64(header, index * 8).unwrap_or_default(),
+                false => read_u32(header, index * 4).unwrap_or_default() as u64,
+            }
+        };
+        let trailer = size - 8;
+
+        //the callback array is a zero terminated list of VAs
+        let mut callbacks = Vec::new();
+        let address_of_callbacks = pointer(3);
+        if address_of_callbacks != 0 {
+            let mut slot = match self.va_to_rva(address_of_callbacks) {
+                Some(rva) => rva,
+                None => return Err(malformed),
+            };
+            loop {
+                let callback = match self.read_pointer_at_rva(slot) {
+                    Some(callback) => callback,
+                    //slots in the zero filled tail of a section read as the terminator
+                    None if self.section_for_rva(slot).is_some() => 0,
+                    None => return Err(malformed),
+                };
+                if callback == 0 {
+                    break;
+                }
+                match self.va_to_rva(callback) {
+                    Some(rva) => callbacks.push(rva),
+                    None => return Err(malformed),
+                }
+                slot = slot.wrapping_add(self.pointer_size() as u32);
+            }
+        }
+
+        return Ok(Some(TlsDirectory {
+            start_address_of_raw_data: pointer(0),
+            end_address_of_raw_data: pointer(1),
+            address_of_index: pointer(2),
+            address_of_callbacks,
+            size_of_zero_fill: read_u32(header, trailer).unwrap_or_default(),
+            characteristics: read_u32(header, trailer + 4).unwrap_or_default(),
+            callbacks,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{put, PeBuilder, SCN_DATA, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+
+    const INDEX: u32 = 0x30;
+    const TEMPLATE: u32 = 0x40;
+    ///end of the raw data of .tls, the callback array is placed right in front of it
+    const RAW_END: u32 = 0x200;
+
+    ///an image whose .tls section holds the directory, a 0x10 byte template and the callbacks
+    ///
+    ///callbacks are offsets into .text. The array's terminator falls in the zero filled part of the
+    ///section that has no raw data
+    fn tls_image(architecture: Architecture, callbacks: &[u32]) -> (PeBuilder, Vec<u8>) {
+        let mut builder = PeBuilder::new(architecture);
+        let text = builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+        let pointer_size = builder.pointer_size();
+        let base = builder.next_rva();
+        let va = |rva: u32| builder.image_base + rva as u64;
+        let array = RAW_END - (callbacks.len() * pointer_size) as u32;
+
+        let mut tls = Vec::new();
+        let addresses = [
+            va(base + TEMPLATE),
+            va(base + TEMPLATE + 0x10),
+            va(base + INDEX),
+            va(base + array),
+        ];
+        for (i, address) in addresses.iter().enumerate() {
+            put(
+                &mut tls,
+                i * pointer_size,
+                &address.to_le_bytes()[..pointer_size],
+            );
+        }
+        put(&mut tls, 4 * pointer_size, &0x100u32.to_le_bytes());
+        put(&mut tls, TEMPLATE as usize, &[0xAB; 0x10]);
+        for (i, callback) in callbacks.iter().enumerate() {
+            let slot = array as usize + i * pointer_size;
+            put(
+                &mut tls,
+                slot,
+                &va(text + callback).to_le_bytes()[..pointer_size],
+            );
+        }
+        tls.resize(RAW_END as usize, 0);
+
+        builder.section_with_size(".tls", SCN_DATA, tls, 0x1000);
+        builder.directory(IMAGE_DIRECTORY_ENTRY_TLS, base, 4 * pointer_size as u32 + 8);
+        let data = builder.build();
+        return (builder, data);
+    }
+
+    #[test]
+    fn reads_the_directory_and_callbacks() {
+        for architecture in [Architecture::X86, Architecture::X64] {
+            let (builder, data) = tls_image(architecture, &[0x10, 0x20]);
+            let text = builder.sections[0].virtual_address;
+            let base = builder.image_base + builder.sections[1].virtual_address as u64;
+            let tls = PeImage::parse(&data).unwrap().tls().unwrap().unwrap();
+            assert_eq!(tls.start_address_of_raw_data, base + TEMPLATE as u64);
+            assert_eq!(tls.template_size(), 0x10);
+            assert_eq!(tls.size_of_zero_fill, 0x100);
+            assert_eq!(tls.address_of_index, base + INDEX as u64);
+            assert_eq!(tls.callbacks, vec![text + 0x10, text + 0x20]);
+            assert!(tls.has_static_data());
+        }
+    }
+
+    #[test]
+    fn static_data_needs_a_template_or_zero_fill() {
+        let tls = |start: u64, end: u64, zero_fill: u32| TlsDirectory {
+            start_address_of_raw_data: start,
+            end_address_of_raw_data: end,
+            address_of_index: 0,
+            address_of_callbacks: 0,
+            size_of_zero_fill: zero_fill,
+            characteristics: 0,
+            callbacks: Vec::new(),
+        };
+        assert!(!tls(0, 0, 0).has_static_data());
+        assert!(tls(0, 0, 4).has_static_data());
+        assert!(tls(0x1000, 0x1008, 0).has_static_data());
+        //a reversed range is no template rather than a huge one
+        assert_eq!(tls(0x1008, 0x1000, 0).template_size(), 0);
+    }
+
+    #[test]
+    fn rejects_callbacks_outside_the_image() {
+        let malformed = Some(PeValidationError::MalformedDirectory("TLS"));
+        let (builder, data) = tls_image(Architecture::X64, &[0x10]);
+        let directory = PeImage::parse(&data)
+            .unwrap()
+            .rva_to_offset(builder.sections[1].virtual_address)
+            .unwrap();
+
+        //a callback below the image base
+        let mut below = data.clone();
+        put(
+            &mut below,
+            directory + RAW_END as usize - 8,
+            &0x1000u64.to_le_bytes(),
+        );
+        assert_eq!(PeImage::parse(&below).unwrap().tls().err(), malformed);
+
+        //a callback array that is not mapped at all
+        let mut unmapped = data.clone();
+        let address = builder.image_base + 0x100000;
+        put(&mut unmapped, directory + 24, &address.to_le_bytes());
+        assert_eq!(PeImage::parse(&unmapped).unwrap().tls().err(), malformed);
+    }
+}
@@ -0,0 +1,359 @@
+use super::error::PeValidationError;
+use super::image::PeImage;
+use super::reader::{read_u16, read_u32, read_utf16, read_utf16z};
+use super::resources::RT_VERSION;
+
+const VS_FFI_SIGNATURE: u32 = 0xFEEF04BD;
+const SIZE_OF_FIXED_FILE_INFO: usize = 52;
+
+///A four part version number, e.g. 10.0.19041.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u16, pub u16, pub u16, pub u16);
+
+impl Version {
+    fn from_parts(ms: u32, ls: u32) -> Version {
+        return Version((ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16);
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0, self.1, self.2, self.3)
+    }
+}
+
+///The binary VS_FIXEDFILEINFO part of the version resource
+#[derive(Debug, Clone)]
+pub struct FixedFileInfo {
+    pub file_version: Version,
+    #[allow(dead_code)]
+    pub product_version: Version,
+    #[allow(dead_code)]
+    pub file_flags: u32,
+    #[allow(dead_code)]
+    pub file_os: u32,
+    #[allow(dead_code)]
+    pub file_type: u32,
+}
+
+///A StringTable block, language is the 8 hex digit language and code page key e.g. 040904b0
+#[derive(Debug, Clone)]
+pub struct StringTable {
+    #[allow(dead_code)]
+    pub language: String,
+    pub strings: Vec<(String, String)>,
+}
+
+///The decoded VS_VERSIONINFO resource
+#[derive(Debug, Clone, Default)]
+pub struct VersionInfo {
+    pub fixed: Option<FixedFileInfo>,
+    pub string_tables: Vec<StringTable>,
+}
+
+impl VersionInfo {
+    ///looks a StringFileInfo value up in every language the resource has, first match wins
+    pub fn string(&self, key: &str) -> Option<&str> {
+        for table in &self.string_tables {
+            if let Some((_, value)) = table.strings.iter().find(|(name, _)| name == key) {
+                return Some(value);
+            }
+        }
+        return None;
+    }
+
+    pub fn file_version(&self) -> Option<String> {
+        return match self.string("FileVersion") {
+            Some(version) => Some(version.to_string()),
+            None => Some(self.fixed.as_ref()?.file_version.to_string()),
+        };
+    }
+
+    pub fn product_name(&self) -> Option<&str> {
+        return self.string("ProductName");
+    }
+
+    pub fn company_name(&self) -> Option<&str> {
+        return self.string("CompanyName");
+    }
+
+    pub fn original_filename(&self) -> Option<&str> {
+        return self.string("OriginalFilename");
+    }
+
+    pub fn file_description(&self) -> Option<&str> {
+        return self.string("FileDescription");
+    }
+}
+
+///A header shared by every block of the version resource
+struct VersionBlock {
+    key: String,
+    value_type: u16,
+    value_length: usize,
+    value: usize,
+    children: usize,
+    end: usize,
+}
+
+impl<'a> PeImage<'a> {
+    ///decodes the first RT_VERSION resource, None when the image does not have one
+    pub fn version_info(&self) -> Result<Option<VersionInfo>, PeValidationError> {
+        let resources = self.resources()?;
+        let data = match resources.find_type(RT_VERSION).first() {
+            Some(data) => *data,
+            None => return Ok(None),
+        };
+        let malformed = PeValidationError::MalformedDirectory("version resource");
+        let bytes = match self.resource_bytes(data) {
+            Some(bytes) => bytes,
+            None => return Err(malformed),
+        };
+        return match parse_version_info(bytes) {
+            Some(info) => Ok(Some(info)),
+            None => Err(malformed),
+        };
+    }
+}
+
+fn parse_version_info(data: &[u8]) -> Option<VersionInfo> {
+    let root = read_block(data, 0, data.len())?;
+    if root.key != "VS_VERSION_INFO" {
+        return None;
+    }
+
+    let mut info = VersionInfo::default();
+    if root.value_length >= SIZE_OF_FIXED_FILE_INFO
+        && read_u32(data, root.value)? == VS_FFI_SIGNATURE
+    {
+        let field = |index: usize| read_u32(data, root.value + index * 4);
+        info.fixed = Some(FixedFileInfo {
+            file_version: Version::from_parts(field(2)?, field(3)?),
+            product_version: Version::from_parts(field(4)?, field(5)?),
+            file_flags: field(7)?,
+            file_os: field(8)?,
+            file_type: field(9)?,
+        });
+    }
+
+    //StringFileInfo holds one StringTable per language, VarFileInfo is skipped
+    for file_info in read_children(data, root.children, root.end)? {
+        if file_info.key != "StringFileInfo" {
+            continue;
+        }
+        for table in read_children(data, file_info.children, file_info.end)? {
+            let mut strings = Vec::new();
+            for string in read_children(data, table.children, table.end)? {
+                //value_length counts utf16 units for text values and usually includes the nul
+                let units = match string.value_type {
+                    1 => string.value_length,
+                    _ => string.value_length / 2,
+                };
+                let units = units.min(string.end.saturating_sub(string.value) / 2);
+                let value = read_utf16(data, string.value, units)?;
+                strings.push((string.key, value.trim_end_matches('\0').to_string()));
+            }
+            info.string_tables.push(StringTable {
+                language: table.key,
+                strings,
+            });
+        }
+    }
+
+    return Some(info);
+}
+
+fn read_children(data: &[u8], mut offset: usize, end: usize) -> Option<Vec<VersionBlock>> {
+    let mut children = Vec::new();
+    while offset + 6 <= end {
+        let child = read_block(data, offset, end)?;
+        offset = align4(child.end);
+        children.push(child);
+    }
+    return Some(children);
+}
+
+//wLength, wValueLength, wType, szKey, padding, Value, padding, Children
+fn read_block(data: &[u8], offset: usize, parent_end: usize) -> Option<VersionBlock> {
+    let length = read_u16(data, offset)? as usize;
+    let value_length = read_u16(data, offset + 2)? as usize;
+    let value_type = read_u16(data, offset + 4)?;
+    //a zero length block would never move the walk forward
+    if length < 6 || offset + length > parent_end.min(data.len()) {
+        return None;
+    }
+    let end = offset + length;
+
+    let (key, key_size) = read_utf16z(data, offset + 6)?;
+    let value = align4(offset + 6 + key_size).min(end);
+    let value_bytes = match value_type {
+        1 => value_length * 2,
+        _ => value_length,
+    };
+    let children = align4(value + value_bytes).min(end);
+
+    return Some(VersionBlock {
+        key,
+        value_type,
+        value_length,
+        value,
+        children,
+        end,
+    });
+}
+
+fn align4(offset: usize) -> usize {
+    return (offset + 3) & !3;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pe::fixture::{PeBuilder, SCN_TEXT};
+    use crate::utils::pe::headers::Architecture;
+    use crate::utils::pe::resources::fixture::resources;
+
+    ///a version block: wLength, wValueLength, wType, key, then the value and children 4 aligned
+    fn block(
+        key: &str,
+        value_type: u16,
+        value_length: u16,
+        value: &[u8],
+        children: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut data = vec![0; 6];
+        data[2..4].copy_from_slice(&value_length.to_le_bytes());
+        data[4..6].copy_from_slice(&value_type.to_le_bytes());
+        data.extend(key.encode_utf16().chain([0]).flat_map(u16::to_le_bytes));
+        data.resize(align4(data.len()), 0);
+        data.extend_from_slice(value);
+        for child in children {
+            data.resize(align4(data.len()), 0);
+            data.extend_from_slice(child);
+        }
+        let length = data.len() as u16;
+        data[0..2].copy_from_slice(&length.to_le_bytes());
+        return data;
+    }
+
+    fn string(key: &str, value: &str) -> Vec<u8> {
+        let units: Vec<u16> = value.encode_utf16().chain([0]).collect();
+        let bytes: Vec<u8> = units.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+        return block(key, 1, units.len() as u16, &bytes, &[]);
+    }
+
+    fn fixed_file_info() -> Vec<u8> {
+        let fields = [
+            VS_FFI_SIGNATURE,
+            0x00010000,
+            //10.0.19041.1
+            0x000A0000,
+            0x4A610001,
+            //10.0.0.0
+            0x000A0000,
+            0,
+            0x3F,
+            0,
+            0x00040004,
+            2,
+            0,
+            0,
+            0,
+        ];
+        return fields
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect();
+    }
+
+    fn version_resource(strings: &[(&str, &str)]) -> Vec<u8> {
+        let strings: Vec<Vec<u8>> = strings
+            .iter()
+            .map(|(key, value)| string(key, value))
+            .collect();
+        let string_file_info = block(
+            "StringFileInfo",
+            1,
+            0,
+            &[],
+            &[block("040904b0", 1, 0, &[], &strings)],
+        );
+        let var_file_info = block(
+            "VarFileInfo",
+            1,
+            0,
+            &[],
+            &[block("Translation", 0, 4, &[0x09, 0x04, 0xB0, 0x04], &[])],
+        );
+        return block(
+            "VS_VERSION_INFO",
+            0,
+            SIZE_OF_FIXED_FILE_INFO as u16,
+            &fixed_file_info(),
+            &[var_file_info, string_file_info],
+        );
+    }
+
+    fn version_info(resource: &[u8]) -> Result<Option<VersionInfo>, PeValidationError> {
+        let mut builder = PeBuilder::new(Architecture::X86);
+        builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+        resources(&mut builder, RT_VERSION, "VERSION", resource);
+        return PeImage::parse(&builder.build()).unwrap().version_info();
+    }
+
+    #[test]
+    fn decodes_fixed_info_and_strings() {
+        let info = version_info(&version_resource(&[
+            ("CompanyName", "Example Corp"),
+            ("FileDescription", "Test dll"),
+            ("FileVersion", "1.2.3.4"),
+            ("OriginalFilename", "test.dll"),
+            ("ProductName", "Tests"),
+        ]))
+        .unwrap()
+        .unwrap();
+
+        let fixed = info.fixed.as_ref().unwrap();
+        assert_eq!(fixed.file_version, Version(10, 0, 19041, 1));
+        assert_eq!(fixed.product_version.to_string(), "10.0.0.0");
+        assert_eq!(fixed.file_os, 0x00040004);
+        assert_eq!(info.string_tables.len(), 1);
+        assert_eq!(info.string_tables[0].language, "040904b0");
+        assert_eq!(info.company_name(), Some("Example Corp"));
+        assert_eq!(info.file_description(), Some("Test dll"));
+        assert_eq!(info.original_filename(), Some("test.dll"));
+        assert_eq!(info.product_name(), Some("Tests"));
+        //the string wins over the fixed info
+        assert_eq!(info.file_version(), Some("1.2.3.4".to_string()));
+    }
+
+    #[test]
+    fn falls_back_to_the_fixed_file_version() {
+        let info = version_info(&version_resource(&[("ProductName", "Tests")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.file_version(), Some("10.0.19041.1".to_string()));
+        assert_eq!(info.company_name(), None);
+        assert_eq!(VersionInfo::default().file_version(), None);
+    }
+
+    #[test]
+    fn rejects_blocks_that_overrun_their_parent() {
+        let malformed = Some(PeValidationError::MalformedDirectory("version resource"));
+        let resource = version_resource(&[("ProductName", "Tests")]);
+        assert!(version_info(&resource).is_ok());
+
+        let mut wrong_key = resource.clone();
+        wrong_key[6] = b'X';
+        assert_eq!(version_info(&wrong_key).err(), malformed);
+        //the root claims more than the resource holds
+        let mut too_long = resource.clone();
+        too_long[0..2].copy_from_slice(&(resource.len() as u16 + 4).to_le_bytes());
+        assert_eq!(version_info(&too_long).err(), malformed);
+        //a zero length child would never end the walk
+        let mut empty_child = resource.clone();
+        let child = align4(6 + "VS_VERSION_INFO".len() * 2 + 2) + SIZE_OF_FIXED_FILE_INFO;
+        empty_child[child..child + 2].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(version_info(&empty_child).err(), malformed);
+    }
+}
@@ -0,0 +1,612 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::utils::dependencies::module_key;
+use crate::utils::pe::{
+    apiset::{self, ApiSetSchema},
+    exports::{parse_exports, ExportTable, ForwarderTarget, RvaSource},
+    headers::{
+        Architecture, DosHeader, FileHeader, OptionalHeader, IMAGE_DIRECTORY_ENTRY_EXPORT,
+        IMAGE_DOS_SIGNATURE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_I386, IMAGE_NT_SIGNATURE,
+        SIZE_OF_FILE_HEADER,
+    },
+    reader::read_u32,
+};
+
+///how many forwarders are followed before giving up, real chains are one or two long
+const MAX_FORWARDER_DEPTH: usize = 16;
+///longest export or forwarder name read out of a module
+const MAX_NAME_LENGTH: usize = 512;
+const PAGE_SIZE: u64 = 0x1000;
+
+///Read access to the address space of the target process
+pub trait MemoryReader {
+    ///fills buffer from address, false when any part of it could not be read
+    fn read(&self, address: u64, buffer: &mut [u8]) -> bool;
+}
+
+///A module loaded in the target process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteModule {
+    pub name: String,
+    pub base: u64,
+    pub size: u32,
+}
+
+///Why an export could not be resolved inside the target process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    ModuleNotLoaded(String),
+    BadExportTable(String),
+    ExportNotFound { module: String, export: String },
+    BadForwarder(String),
+    ForwarderLoop(String),
+}
+
+impl ResolveError {
+    ///stable machine readable code for logs and scripts
+    pub fn code(&self) -> &'static str {
+        match self {
+            ResolveError::ModuleNotLoaded(_) => "RES_MODULE_NOT_LOADED",
+            ResolveError::BadExportTable(_) => "RES_BAD_EXPORT_TABLE",
+            ResolveError::ExportNotFound { .. } => "RES_EXPORT_NOT_FOUND",
+            ResolveError::BadForwarder(_) => "RES_BAD_FORWARDER",
+            ResolveError::ForwarderLoop(_) => "RES_FORWARDER_LOOP",
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::ModuleNotLoaded(module) => {
+                write!(f, "{module} is not loaded in the target process")
+            }
+            ResolveError::BadExportTable(module) => {
+                write!(
+                    f,
+                    "Unable to read the export table of {module} in the target process"
+                )
+            }
+            ResolveError::ExportNotFound { module, export } => {
+                write!(f, "{module} does not export {export}")
+            }
+            ResolveError::BadForwarder(forwarder) => {
+                write!(f, "Unable to follow the forwarder {forwarder}")
+            }
+            ResolveError::ForwarderLoop(export) => write!(
+                f,
+                "Gave up on {export} after {MAX_FORWARDER_DEPTH} forwarders, the chain loops"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+fn target_name(target: &ForwarderTarget) -> String {
+    return match target {
+        ForwarderTarget::Name(name) => name.clone(),
+        ForwarderTarget::Ordinal(ordinal) => format!("#{ordinal}"),
+    };
+}
+
+///the architecture of a module from its headers in memory
+pub fn module_architecture(reader: &impl MemoryReader, base: u64) -> Option<Architecture> {
+    let headers = RemoteHeaders::read(reader, base)?;
+    return match (
+        headers.file_header.machine,
+        headers.optional_header.is_64bit(),
+    ) {
+        (IMAGE_FILE_MACHINE_I386, false) => Some(Architecture::X86),
+        (IMAGE_FILE_MACHINE_AMD64, true) => Some(Architecture::X64),
+        _ => None,
+    };
+}
+
+struct RemoteHeaders {
+    file_header: FileHeader,
+    optional_header: OptionalHeader,
+}
+
+impl RemoteHeaders {
+    ///the headers always fit in the first page of a mapped image
+    fn read(reader: &impl MemoryReader, base: u64) -> Option<RemoteHeaders> {
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        if !reader.read(base, &mut page) {
+            return None;
+        }
+        let dos_header = DosHeader::parse(&page)?;
+        if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
+            return None;
+        }
+        let nt_offset = dos_header.e_lfanew as usize;
+        if read_u32(&page, nt_offset)? != IMAGE_NT_SIGNATURE {
+            return None;
+        }
+        let file_header = FileHeader::parse(&page, nt_offset + 4)?;
+        let optional_header = OptionalHeader::parse(
+            &page,
+            nt_offset + 4 + SIZE_OF_FILE_HEADER,
+            file_header.size_of_optional_header as usize,
+        )?;
+        return Some(RemoteHeaders {
+            file_header,
+            optional_header,
+        });
+    }
+}
+
+///A module inside the target read through a MemoryReader
+struct RemoteImage<'r, R: MemoryReader> {
+    reader: &'r R,
+    module: &'r RemoteModule,
+    ///the export directory, read in one go since the tables and names usually live inside it
+    directory: (u32, Vec<u8>),
+}
+
+impl<'r, R: MemoryReader> RemoteImage<'r, R> {
+    fn cached(&self, rva: u32, len: usize) -> Option<&[u8]> {
+        let (start, bytes) = &self.directory;
+        let offset = rva.checked_sub(*start)? as usize;
+        return bytes.get(offset..offset.checked_add(len)?);
+    }
+}
+
+impl<'r, R: MemoryReader> RvaSource for RemoteImage<'r, R> {
+    fn bytes(&self, rva: u32, len: usize) -> Option<Cow<'_, [u8]>> {
+        if let Some(bytes) = self.cached(rva, len) {
+            return Some(Cow::Borrowed(bytes));
+        }
+        if rva as u64 + len as u64 > self.module.size as u64 {
+            return None;
+        }
+        let mut bytes = vec![0u8; len];
+        return match self.reader.read(self.module.base + rva as u64, &mut bytes) {
+            true => Some(Cow::Owned(bytes)),
+            false => None,
+        };
+    }
+
+    fn string(&self, rva: u32) -> Option<String> {
+        let mut bytes = Vec::new();
+        let mut address = self.module.base + rva as u64;
+        //read up to the end of each page so a name right before an unmapped page still works
+        while bytes.len() < MAX_NAME_LENGTH {
+            let (start, cached) = &self.directory;
+            let chunk = match rva
+                .checked_add(bytes.len() as u32)
+                .and_then(|rva| rva.checked_sub(*start))
+            {
+                Some(offset) if (offset as usize) < cached.len() => {
+                    let end = cached.len().min(offset as usize + MAX_NAME_LENGTH);
+                    Cow::Borrowed(&cached[offset as usize..end])
+                }
+                _ => {
+                    let len = (PAGE_SIZE - address % PAGE_SIZE).min(MAX_NAME_LENGTH as u64);
+                    let mut chunk = vec![0u8; len as usize];
+                    if !self.reader.read(address, &mut chunk) {
+                        return None;
+                    }
+                    Cow::Owned(chunk)
+                }
+            };
+            match chunk.iter().position(|&byte| byte == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&chunk[..end]);
+                    return Some(String::from_utf8_lossy(&bytes).to_string());
+                }
+                None => {
+                    address += chunk.len() as u64;
+                    bytes.extend_from_slice(&chunk);
+                }
+            }
+        }
+        return None;
+    }
+}
+
+///reads the export table of a module out of the target's memory
+pub fn remote_exports(
+    reader: &impl MemoryReader,
+    module: &RemoteModule,
+) -> Result<ExportTable, ResolveError> {
+    let bad_table = || ResolveError::BadExportTable(module.name.clone());
+    let headers = RemoteHeaders::read(reader, module.base).ok_or_else(bad_table)?;
+    let directory = match headers
+        .optional_header
+        .data_directories
+        .get(IMAGE_DIRECTORY_ENTRY_EXPORT)
+    {
+        Some(directory) if directory.virtual_address != 0 && directory.size != 0 => *directory,
+        _ => return Ok(ExportTable::default()),
+    };
+    if directory.virtual_address as u64 + directory.size as u64 > module.size as u64 {
+        return Err(bad_table());
+    }
+
+    let mut bytes = vec![0u8; directory.size as usize];
+    if !reader.read(module.base + directory.virtual_address as u64, &mut bytes) {
+        return Err(bad_table());
+    }
+    let image = RemoteImage {
+        reader,
+        module,
+        directory: (directory.virtual_address, bytes),
+    };
+    return parse_exports(&image, directory).map_err(|_| bad_table());
+}
+
+///Resolves exports of modules loaded in the target to addresses inside the target
+///
+///export tables are read straight out of the target's memory so nothing assumes a module sits at
+///the same address as in the injector. Forwarders are followed, through the api set schema when
+///they point at a contract
+pub struct ExportResolver<'r, R: MemoryReader> {
+    reader: &'r R,
+    ///modules by lowercase name
+    modules: HashMap<String, RemoteModule>,
+    api_set: Option<&'r ApiSetSchema>,
+    exports: HashMap<String, Rc<ExportTable>>,
+}
+
+impl<'r, R: MemoryReader> ExportResolver<'r, R> {
+    pub fn new(
+        reader: &'r R,
+        modules: Vec<RemoteModule>,
+        api_set: Option<&'r ApiSetSchema>,
+    ) -> ExportResolver<'r, R> {
+        let mut resolver = ExportResolver {
+            reader,
+            modules: HashMap::new(),
+            api_set,
+            exports: HashMap::new(),
+        };
+        //the first module with a name wins, like the loader's own list
+        resolver.add_modules(modules);
+        return resolver;
+    }
+
+    ///the loaded module a name refers to, api set contracts are redirected to their host
+    ///
+    ///importer is the module the name comes from, it picks api set host overrides
+    pub fn module(&self, name: &str, importer: Option<&str>) -> Option<&RemoteModule> {
+        if let (Some(schema), true) = (self.api_set, apiset::is_api_set_name(name)) {
+            let host = schema.resolve(name, importer)?;
+            return self.modules.get(&module_key(host));
+        }
+        return self.modules.get(&module_key(name));
+    }
+
+    ///adds modules that were loaded after the resolver was made, known ones are left alone
+    pub fn add_modules(&mut self, modules: Vec<RemoteModule>) {
+        for module in modules {
+            self.modules
+                .entry(module_key(&module.name))
+                .or_insert(module);
+        }
+    }
+
+    ///importer is the module asking, like for module() it picks api set host overrides
+    pub fn resolve_name(
+        &mut self,
+        module: &str,
+        name: &str,
+        importer: Option<&str>,
+    ) -> Result<u64, ResolveError> {
+        return self.resolve(module, ForwarderTarget::Name(name.to_string()), importer);
+    }
+
+    pub fn resolve_ordinal(
+        &mut self,
+        module: &str,
+        ordinal: u32,
+        importer: Option<&str>,
+    ) -> Result<u64, ResolveError> {
+        return self.resolve(module, ForwarderTarget::Ordinal(ordinal), importer);
+    }
+
+    fn resolve(
+        &mut self,
+        module: &str,
+        target: ForwarderTarget,
+        importer: Option<&str>,
+    ) -> Result<u64, ResolveError> {
+        let requested = format!("{module}!{}", target_name(&target));
+        let mut module = module.to_string();
+        let mut target = target;
+        //after the first hop the module holding the forwarder is the one importing
+        let mut importer: Option<String> = importer.map(str::to_string);
+
+        for _ in 0..MAX_FORWARDER_DEPTH {
+            let loaded = match self.module(&module, importer.as_deref()) {
+                Some(loaded) => loaded.clone(),
+                None => return Err(ResolveError::ModuleNotLoaded(module)),
+            };
+            let exports = self.exports_of(&loaded)?;
+            let export = match &target {
+                ForwarderTarget::Name(name) => exports.find_by_name(name),
+                ForwarderTarget::Ordinal(ordinal) => exports.find_by_ordinal(*ordinal),
+            };
+            let export = match export {
+                Some(export) => export,
+                None => {
+                    return Err(ResolveError::ExportNotFound {
+                        module: loaded.name,
+                        export: target_name(&target),
+                    })
+                }
+            };
+
+            //NTDLL.RtlAllocateHeap sends the lookup on to another module
+            match (&export.forwarder, export.forwarder_target()) {
+                (None, _) => return Ok(loaded.base + export.rva as u64),
+                (Some(_), Some((next_module, next_target))) => {
+                    importer = Some(module_key(&loaded.name));
+                    module = next_module;
+                    target = next_target;
+                }
+                (Some(forwarder), None) => {
+                    return Err(ResolveError::BadForwarder(forwarder.clone()))
+                }
+            }
+        }
+        return Err(ResolveError::ForwarderLoop(requested));
+    }
+
+    fn exports_of(&mut self, module: &RemoteModule) -> Result<Rc<ExportTable>, ResolveError> {
+        let key = module_key(&module.name);
+        if let Some(exports) = self.exports.get(&key) {
+            return Ok(exports.clone());
+        }
+        let exports = Rc::new(remote_exports(self.reader, module)?);
+        self.exports.insert(key, exports.clone());
+        return Ok(exports);
+    }
+}
+
+///target process memory for the unit tests, made of fixture images mapped at their image base
+#[cfg(test)]
+pub mod fixture {
+    use std::collections::HashMap;
+
+    use super::{MemoryReader, RemoteModule};
+    use crate::utils::pe::{
+        fixture::{FixtureExport, PeBuilder, SCN_TEXT},
+        headers::Architecture,
+        image::PeImage,
+        mapper::map_image,
+    };
+
+    ///mapped images by base address, a read has to stay inside one of them
+    #[derive(Default)]
+    pub struct FixtureMemory {
+        pub images: HashMap<u64, Vec<u8>>,
+    }
+
+    impl MemoryReader for FixtureMemory {
+        fn read(&self, address: u64, buffer: &mut [u8]) -> bool {
+            for (base, image) in &self.images {
+                let bytes = address
+                    .checked_sub(*base)
+                    .and_then(|offset| image.get(offset as usize..))
+                    .and_then(|bytes| bytes.get(..buffer.len()));
+                if let Some(bytes) = bytes {
+                    buffer.copy_from_slice(bytes);
+                    return true;
+                }
+            }
+            return false;
+        }
+    }
+
+    impl FixtureMemory {
+        ///maps a dll exporting exports at base and gives back the module the target would list
+        pub fn load(
+            &mut self,
+            architecture: Architecture,
+            name: &str,
+            base: u64,
+            exports: &[FixtureExport],
+        ) -> RemoteModule {
+            let mut builder = PeBuilder::new(architecture);
+            builder.image_base = base;
+            builder.section(".text", SCN_TEXT, vec![0xC3; 0x100]);
+            builder.exports(name, 1, exports);
+            let data = builder.build();
+            let mapped = map_image(&PeImage::parse(&data).unwrap(), base).unwrap();
+            let module = RemoteModule {
+                name: name.to_string(),
+                base,
+                size: mapped.len() as u32,
+            };
+            self.images.insert(base, mapped);
+            return module;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::FixtureMemory;
+    use super::*;
+    use crate::utils::pe::apiset::{ApiSetEntry, ApiSetHost};
+    use crate::utils::pe::fixture::FixtureExport;
+
+    const A: u64 = 0x10000000;
+    const B: u64 = 0x20000000;
+    const HOST: u64 = 0x30000000;
+
+    fn memory() -> (FixtureMemory, Vec<RemoteModule>) {
+        let mut memory = FixtureMemory::default();
+        let a = memory.load(
+            Architecture::X64,
+            "a.dll",
+            A,
+            &[
+                FixtureExport::Code("a_fn", 0x1000),
+                FixtureExport::Ordinal(0x1010),
+                FixtureExport::Forward("x", "B.y"),
+                FixtureExport::Forward("x_ordinal", "b.#2"),
+                FixtureExport::Forward("contract", "api-ms-win-test-l1-1-0.impl"),
+                FixtureExport::Forward("loop", "a.loop"),
+                FixtureExport::Forward("ping", "b.pong"),
+                FixtureExport::Forward("broken", "nodot"),
+                FixtureExport::Forward("unloaded", "gone.fn"),
+            ],
+        );
+        let b = memory.load(
+            Architecture::X64,
+            "B.DLL",
+            B,
+            &[
+                FixtureExport::Code("y", 0x1020),
+                FixtureExport::Code("y_again", 0x1030),
+                FixtureExport::Forward("pong", "a.ping"),
+            ],
+        );
+        let host = memory.load(
+            Architecture::X64,
+            "host.dll",
+            HOST,
+            &[FixtureExport::Code("impl", 0x1040)],
+        );
+        return (memory, vec![a, b, host]);
+    }
+
+    fn schema() -> ApiSetSchema {
+        return ApiSetSchema {
+            version: 6,
+            entries: vec![ApiSetEntry {
+                name: "api-ms-win-test-l1-1-0".to_string(),
+                hashed_length: "api-ms-win-test-l1-1".len(),
+                hosts: vec![ApiSetHost {
+                    importer: None,
+                    host: "host.dll".to_string(),
+                }],
+            }],
+        };
+    }
+
+    #[test]
+    fn resolves_names_and_ordinals() {
+        let (memory, modules) = memory();
+        let mut resolver = ExportResolver::new(&memory, modules, None);
+        assert_eq!(resolver.resolve_name("a.dll", "a_fn", None), Ok(A + 0x1000));
+        assert_eq!(resolver.resolve_name("A", "a_fn", None), Ok(A + 0x1000));
+        assert_eq!(resolver.resolve_ordinal("a.dll", 1, None), Ok(A + 0x1000));
+        assert_eq!(resolver.resolve_ordinal("a.dll", 2, None), Ok(A + 0x1010));
+        assert_eq!(
+            resolver.resolve_name("a.dll", "nope", None),
+            Err(ResolveError::ExportNotFound {
+                module: "a.dll".to_string(),
+                export: "nope".to_string()
+            })
+        );
+        assert_eq!(
+            resolver.resolve_ordinal("a.dll", 99, None),
+            Err(ResolveError::ExportNotFound {
+                module: "a.dll".to_string(),
+                export: "#99".to_string()
+            })
+        );
+        assert_eq!(
+            resolver.resolve_name("gone.dll", "fn", None),
+            Err(ResolveError::ModuleNotLoaded("gone.dll".to_string()))
+        );
+    }
+
+    #[test]
+    fn follows_forwarder_chains() {
+        let (memory, modules) = memory();
+        let schema = schema();
+        let mut resolver = ExportResolver::new(&memory, modules, Some(&schema));
+        assert_eq!(resolver.resolve_name("a.dll", "x", None), Ok(B + 0x1020));
+        assert_eq!(
+            resolver.resolve_name("a.dll", "x_ordinal", None),
+            Ok(B + 0x1030)
+        );
+        assert_eq!(
+            resolver.resolve_name("a.dll", "contract", None),
+            Ok(HOST + 0x1040)
+        );
+        //the contract itself can be asked for too
+        assert_eq!(
+            resolver.resolve_name("api-ms-win-test-l1-1-0.dll", "impl", None),
+            Ok(HOST + 0x1040)
+        );
+        assert_eq!(
+            resolver.resolve_name("a.dll", "broken", None),
+            Err(ResolveError::BadForwarder("nodot".to_string()))
+        );
+        assert_eq!(
+            resolver.resolve_name("a.dll", "unloaded", None),
+            Err(ResolveError::ModuleNotLoaded("gone.dll".to_string()))
+        );
+
+        //without a schema the contract is looked for as a loaded module
+        let (memory, modules) = self::memory();
+        let mut resolver = ExportResolver::new(&memory, modules, None);
+        assert_eq!(
+            resolver.resolve_name("a.dll", "contract", None),
+            Err(ResolveError::ModuleNotLoaded(
+                "api-ms-win-test-l1-1-0.dll".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn gives_up_on_forwarder_loops() {
+        let (memory, modules) = memory();
+        let mut resolver = ExportResolver::new(&memory, modules, None);
+        assert_eq!(
+            resolver.resolve_name("a.dll", "loop", None),
+            Err(ResolveError::ForwarderLoop("a.dll!loop".to_string()))
+        );
+        assert_eq!(
+            resolver.resolve_name("b.dll", "pong", None),
+            Err(ResolveError::ForwarderLoop("b.dll!pong".to_string()))
+        );
+    }
+
+    #[test]
+    fn reads_strings_across_page_boundaries() {
+        let module = RemoteModule {
+            name: "strings.dll".to_string(),
+            base: A,
+            size: 0x3000,
+        };
+        let mut image = vec![0u8; 0x2000];
+        image[0xFFA..0x1006].copy_from_slice(b"crosses_page");
+        image[0x1FF0..0x1FFE].copy_from_slice(b"ends_at_page\0\0");
+        image[0x1800..0x1804].copy_from_slice(b"tail");
+        image[0x1804..0x1808].copy_from_slice(b"ed\0\0");
+        let mut memory = FixtureMemory::default();
+        memory.images.insert(A, image);
+
+        let mut remote = RemoteImage {
+            reader: &memory,
+            module: &module,
+            directory: (0, Vec::new()),
+        };
+        assert_eq!(remote.string(0xFFA), Some("crosses_page".to_string()));
+        //the page after this one is not mapped, reading ahead must stop at the page end
+        assert_eq!(remote.string(0x1FF0), Some("ends_at_page".to_string()));
+        assert_eq!(remote.string(0x1FFE), Some(String::new()));
+
+        //a name that starts in the cached directory and ends behind it
+        remote.directory = (0x1800, b"tail".to_vec());
+        assert_eq!(remote.string(0x1800), Some("tailed".to_string()));
+
+        //no terminator before the unmapped page
+        memory.images.get_mut(&A).unwrap()[0x1FF0..].fill(b'a');
+        let remote = RemoteImage {
+            reader: &memory,
+            module: &module,
+            directory: (0, Vec::new()),
+        };
+        assert_eq!(remote.string(0x1FF0), None);
+    }
+}
@@ -0,0 +1,532 @@
+use crate::utils::pe::headers::Architecture;
+
+///General purpose registers in encoding order, the x86 stub only uses the first eight
+//not every register is used by a stub but the discriminants have to match the encoding
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Ax,
+    Cx,
+    Dx,
+    Bx,
+    Sp,
+    Bp,
+    Si,
+    Di,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    fn num(self) -> u8 {
+        return self as u8;
+    }
+}
+
+///Operand size of an instruction, Ptr is 64 bit on x64 and 32 bit on x86
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Dword,
+    Ptr,
+}
+
+///A [base + index + disp] memory operand
+#[derive(Debug, Clone, Copy)]
+pub struct Mem {
+    base: Reg,
+    index: Option<Reg>,
+    disp: i32,
+}
+
+pub fn mem(base: Reg, disp: i32) -> Mem {
+    return Mem {
+        base,
+        index: None,
+        disp,
+    };
+}
+
+pub fn mem_index(base: Reg, index: Reg, disp: i32) -> Mem {
+    return Mem {
+        base,
+        index: Some(index),
+        disp,
+    };
+}
+
+///Conditions of the jumps the stubs use, the value is the low nibble of the Jcc opcode
+#[derive(Debug, Clone, Copy)]
+pub enum Cond {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Zero = 0x4,
+    NotZero = 0x5,
+    Sign = 0x8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+enum Rm {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+///Emits the handful of x86/x64 instructions the loader stubs are made of
+///
+///every jump is a rel32 so the size of an instruction never depends on where its label ends up,
+///the size of a stub is fixed the moment it is emitted
+pub struct Assembler {
+    architecture: Architecture,
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    ///position of a rel32 and the label it jumps to
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new(architecture: Architecture) -> Assembler {
+        return Assembler {
+            architecture,
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        };
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        return Label(self.labels.len() - 1);
+    }
+
+    ///places the label at the next instruction
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    ///resolves the jumps and hands out the machine code
+    pub fn finish(mut self) -> Vec<u8> {
+        for (position, label) in &self.fixups {
+            let target = self.labels[label.0].expect("jump to a label that was never bound");
+            let relative = (target as i64 - (*position as i64 + 4)) as i32;
+            self.code[*position..*position + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+        return self.code;
+    }
+
+    fn wide(&self, width: Width) -> bool {
+        return width == Width::Ptr && self.architecture == Architecture::X64;
+    }
+
+    ///REX prefix, opcode and ModRM/SIB/displacement for reg and rm
+    fn emit(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: Rm) {
+        let (base, index) = match &rm {
+            Rm::Reg(reg) => (reg.num(), 0),
+            Rm::Mem(mem) => (mem.base.num(), mem.index.map(Reg::num).unwrap_or(0)),
+        };
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (base >> 3);
+        if rex != 0x40 {
+            debug_assert!(self.architecture == Architecture::X64);
+            self.code.push(rex);
+        }
+        self.code.extend_from_slice(opcode);
+
+        let mem = match rm {
+            Rm::Reg(rm) => {
+                self.code.push(0xC0 | (reg & 7) << 3 | (rm.num() & 7));
+                return;
+            }
+            Rm::Mem(mem) => mem,
+        };
+        //rsp/r12 as a base needs a SIB byte and rbp/r13 cannot go without a displacement
+        let base = mem.base.num() & 7;
+        let needs_sib = mem.index.is_some() || base == 4;
+        let (mode, disp_size) = match mem.disp {
+            0 if base != 5 => (0, 0),
+            -128..=127 => (1, 1),
+            _ => (2, 4),
+        };
+        let rm = match needs_sib {
+            true => 4,
+            false => base,
+        };
+        self.code.push(mode << 6 | (reg & 7) << 3 | rm);
+        if needs_sib {
+            debug_assert!(mem.index != Some(Reg::Sp));
+            let index = mem.index.map(|index| index.num() & 7).unwrap_or(4);
+            self.code.push(index << 3 | base);
+        }
+        self.code
+            .extend_from_slice(&mem.disp.to_le_bytes()[..disp_size]);
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        if reg.num() >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x50 | (reg.num() & 7));
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        if reg.num() >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x58 | (reg.num() & 7));
+    }
+
+    pub fn push_imm8(&mut self, imm: i8) {
+        self.code.push(0x6A);
+        self.code.push(imm as u8);
+    }
+
+    pub fn push_mem(&mut self, mem: Mem) {
+        self.emit(false, &[0xFF], 6, Rm::Mem(mem));
+    }
+
+    pub fn mov(&mut self, width: Width, dst: Reg, src: Reg) {
+        self.emit(self.wide(width), &[0x89], src.num(), Rm::Reg(dst));
+    }
+
+    pub fn mov_load(&mut self, width: Width, dst: Reg, src: Mem) {
+        self.emit(self.wide(width), &[0x8B], dst.num(), Rm::Mem(src));
+    }
+
+    pub fn mov_store(&mut self, width: Width, dst: Mem, src: Reg) {
+        self.emit(self.wide(width), &[0x89], src.num(), Rm::Mem(dst));
+    }
+
+    pub fn mov_store_imm32(&mut self, dst: Mem, imm: u32) {
+        self.emit(false, &[0xC7], 0, Rm::Mem(dst));
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    ///mov r32, imm32, on x64 this zeroes the upper half
+    pub fn mov_imm32(&mut self, dst: Reg, imm: u32) {
+        if dst.num() >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0xB8 | (dst.num() & 7));
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub fn movzx_word(&mut self, dst: Reg, src: Mem) {
+        self.emit(false, &[0x0F, 0xB7], dst.num(), Rm::Mem(src));
+    }
+
+    pub fn lea(&mut self, dst: Reg, src: Mem) {
+        self.emit(self.wide(Width::Ptr), &[0x8D], dst.num(), Rm::Mem(src));
+    }
+
+    pub fn add(&mut self, width: Width, dst: Reg, src: Reg) {
+        self.emit(self.wide(width), &[0x01], src.num(), Rm::Reg(dst));
+    }
+
+    pub fn add_load(&mut self, width: Width, dst: Reg, src: Mem) {
+        self.emit(self.wide(width), &[0x03], dst.num(), Rm::Mem(src));
+    }
+
+    pub fn add_store(&mut self, width: Width, dst: Mem, src: Reg) {
+        self.emit(self.wide(width), &[0x01], src.num(), Rm::Mem(dst));
+    }
+
+    pub fn sub_load(&mut self, width: Width, dst: Reg, src: Mem) {
+        self.emit(self.wide(width), &[0x2B], dst.num(), Rm::Mem(src));
+    }
+
+    pub fn cmp(&mut self, width: Width, left: Reg, right: Reg) {
+        self.emit(self.wide(width), &[0x39], right.num(), Rm::Reg(left));
+    }
+
+    pub fn cmp_load(&mut self, width: Width, left: Reg, right: Mem) {
+        self.emit(self.wide(width), &[0x3B], left.num(), Rm::Mem(right));
+    }
+
+    pub fn test(&mut self, width: Width, left: Reg, right: Reg) {
+        self.emit(self.wide(width), &[0x85], right.num(), Rm::Reg(left));
+    }
+
+    pub fn xor(&mut self, width: Width, dst: Reg, src: Reg) {
+        self.emit(self.wide(width), &[0x31], src.num(), Rm::Reg(dst));
+    }
+
+    //group 1 arithmetic with an immediate, the short form is used when it fits in a byte
+    fn alu_imm(&mut self, width: Width, extension: u8, dst: Reg, imm: i32) {
+        let wide = self.wide(width);
+        match i8::try_from(imm) {
+            Ok(imm) => {
+                self.emit(wide, &[0x83], extension, Rm::Reg(dst));
+                self.code.push(imm as u8);
+            }
+            Err(_) => {
+                self.emit(wide, &[0x81], extension, Rm::Reg(dst));
+                self.code.extend_from_slice(&imm.to_le_bytes());
+            }
+        }
+    }
+
+    pub fn add_imm(&mut self, width: Width, dst: Reg, imm: i32) {
+        self.alu_imm(width, 0, dst, imm);
+    }
+
+    pub fn and_imm(&mut self, width: Width, dst: Reg, imm: i32) {
+        self.alu_imm(width, 4, dst, imm);
+    }
+
+    pub fn sub_imm(&mut self, width: Width, dst: Reg, imm: i32) {
+        self.alu_imm(width, 5, dst, imm);
+    }
+
+    pub fn cmp_imm(&mut self, width: Width, dst: Reg, imm: i32) {
+        self.alu_imm(width, 7, dst, imm);
+    }
+
+    pub fn shr_imm(&mut self, width: Width, dst: Reg, imm: u8) {
+        self.emit(self.wide(width), &[0xC1], 5, Rm::Reg(dst));
+        self.code.push(imm);
+    }
+
+    pub fn call(&mut self, target: Reg) {
+        self.emit(false, &[0xFF], 2, Rm::Reg(target));
+    }
+
+    pub fn call_mem(&mut self, target: Mem) {
+        self.emit(false, &[0xFF], 2, Rm::Mem(target));
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.code.push(0xE9);
+        self.rel32(label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.code.push(0x0F);
+        self.code.push(0x80 | cond as u8);
+        self.rel32(label);
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+
+    ///ret imm16, stdcall functions pop their own arguments
+    pub fn ret_imm(&mut self, bytes: u16) {
+        self.code.push(0xC2);
+        self.code.extend_from_slice(&bytes.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iced_x86::{Decoder, DecoderOptions, Register};
+
+    use super::*;
+
+    const REGS: [Reg; 16] = [
+        Reg::Ax,
+        Reg::Cx,
+        Reg::Dx,
+        Reg::Bx,
+        Reg::Sp,
+        Reg::Bp,
+        Reg::Si,
+        Reg::Di,
+        Reg::R8,
+        Reg::R9,
+        Reg::R10,
+        Reg::R11,
+        Reg::R12,
+        Reg::R13,
+        Reg::R14,
+        Reg::R15,
+    ];
+
+    fn encode(architecture: Architecture, emit: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new(architecture);
+        emit(&mut asm);
+        return asm.finish();
+    }
+
+    fn x64(emit: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        return encode(Architecture::X64, emit);
+    }
+
+    fn iced_register(architecture: Architecture, reg: Reg) -> Register {
+        let registers = match architecture {
+            Architecture::X86 => &[
+                Register::EAX,
+                Register::ECX,
+                Register::EDX,
+                Register::EBX,
+                Register::ESP,
+                Register::EBP,
+                Register::ESI,
+                Register::EDI,
+            ][..],
+            Architecture::X64 => &[
+                Register::RAX,
+                Register::RCX,
+                Register::RDX,
+                Register::RBX,
+                Register::RSP,
+                Register::RBP,
+                Register::RSI,
+                Register::RDI,
+                Register::R8,
+                Register::R9,
+                Register::R10,
+                Register::R11,
+                Register::R12,
+                Register::R13,
+                Register::R14,
+                Register::R15,
+            ][..],
+        };
+        return registers[reg.num() as usize];
+    }
+
+    #[test]
+    fn rsp_and_r12_bases_need_a_sib() {
+        let load = |base, disp| x64(|asm| asm.mov_load(Width::Ptr, Reg::Ax, mem(base, disp)));
+        assert_eq!(load(Reg::Sp, 0), [0x48, 0x8B, 0x04, 0x24]);
+        assert_eq!(load(Reg::Sp, 8), [0x48, 0x8B, 0x44, 0x24, 0x08]);
+        assert_eq!(load(Reg::R12, 0), [0x49, 0x8B, 0x04, 0x24]);
+        assert_eq!(
+            load(Reg::R12, 0x100),
+            [0x49, 0x8B, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00]
+        );
+        //any other base goes without one
+        assert_eq!(load(Reg::Bx, 0), [0x48, 0x8B, 0x03]);
+    }
+
+    #[test]
+    fn rbp_and_r13_bases_keep_a_zero_displacement() {
+        let load = |base| x64(|asm| asm.mov_load(Width::Ptr, Reg::Ax, mem(base, 0)));
+        assert_eq!(load(Reg::Bp), [0x48, 0x8B, 0x45, 0x00]);
+        assert_eq!(load(Reg::R13), [0x49, 0x8B, 0x45, 0x00]);
+        assert_eq!(
+            x64(|asm| asm.lea(Reg::Ax, mem_index(Reg::R13, Reg::Cx, 0))),
+            [0x49, 0x8D, 0x44, 0x0D, 0x00]
+        );
+    }
+
+    #[test]
+    fn extended_registers_set_the_rex_bits() {
+        //REX.R for the reg field
+        assert_eq!(
+            x64(|asm| asm.mov_load(Width::Dword, Reg::R8, mem(Reg::Ax, 0))),
+            [0x44, 0x8B, 0x00]
+        );
+        //REX.X for the index, REX.B for the base
+        assert_eq!(
+            x64(|asm| asm.mov_load(Width::Ptr, Reg::Ax, mem_index(Reg::Bx, Reg::R9, 0))),
+            [0x4A, 0x8B, 0x04, 0x0B]
+        );
+        assert_eq!(
+            x64(|asm| asm.mov_load(Width::Ptr, Reg::Ax, mem_index(Reg::R11, Reg::Cx, 0))),
+            [0x49, 0x8B, 0x04, 0x0B]
+        );
+        assert_eq!(
+            x64(|asm| asm.lea(Reg::R13, mem_index(Reg::R13, Reg::R14, 0))),
+            [0x4F, 0x8D, 0x6C, 0x35, 0x00]
+        );
+        //register operands use REX.B for rm
+        assert_eq!(
+            x64(|asm| asm.mov(Width::Ptr, Reg::R8, Reg::Ax)),
+            [0x49, 0x89, 0xC0]
+        );
+        assert_eq!(
+            x64(|asm| asm.add(Width::Ptr, Reg::Ax, Reg::R9)),
+            [0x4C, 0x01, 0xC8]
+        );
+        assert_eq!(
+            x64(|asm| asm.mov(Width::Dword, Reg::Ax, Reg::Cx)),
+            [0x89, 0xC8]
+        );
+        assert_eq!(x64(|asm| asm.push(Reg::R12)), [0x41, 0x54]);
+        assert_eq!(x64(|asm| asm.pop(Reg::R15)), [0x41, 0x5F]);
+        assert_eq!(
+            x64(|asm| asm.mov_imm32(Reg::R8, 1)),
+            [0x41, 0xB8, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn memory_operands_decode_back() {
+        let displacements = [0, 1, -1, 127, -128, 128, -129, 0x7FFF_FFFF];
+        for architecture in [Architecture::X64, Architecture::X86] {
+            let (regs, bitness) = match architecture {
+                Architecture::X64 => (&REGS[..], 64),
+                Architecture::X86 => (&REGS[..8], 32),
+            };
+            let indexes = regs
+                .iter()
+                .filter(|reg| **reg != Reg::Sp)
+                .map(|reg| Some(*reg))
+                .chain([None]);
+            for index in indexes {
+                for &base in regs {
+                    for disp in displacements {
+                        let operand = match index {
+                            Some(index) => mem_index(base, index, disp),
+                            None => mem(base, disp),
+                        };
+                        let dst = regs[regs.len() - 1];
+                        let code =
+                            encode(architecture, |asm| asm.mov_load(Width::Ptr, dst, operand));
+                        let instruction =
+                            Decoder::new(bitness, &code, DecoderOptions::NONE).decode();
+                        let context = format!("{architecture:?} {operand:?}");
+                        assert_eq!(instruction.len(), code.len(), "{context}");
+                        assert_eq!(
+                            instruction.op0_register(),
+                            iced_register(architecture, dst),
+                            "{context}"
+                        );
+                        assert_eq!(
+                            instruction.memory_base(),
+                            iced_register(architecture, base),
+                            "{context}"
+                        );
+                        let expected_index = index
+                            .map(|index| iced_register(architecture, index))
+                            .unwrap_or(Register::None);
+                        assert_eq!(instruction.memory_index(), expected_index, "{context}");
+                        assert_eq!(
+                            instruction.memory_displacement32() as i32,
+                            disp,
+                            "{context}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jumps_are_relative_to_the_next_instruction() {
+        let code = x64(|asm| {
+            let back = asm.new_label();
+            let forward = asm.new_label();
+            asm.bind(back);
+            asm.jmp(forward);
+            asm.jcc(Cond::Zero, back);
+            asm.bind(forward);
+            asm.ret();
+        });
+        assert_eq!(
+            code,
+            [0xE9, 6, 0, 0, 0, 0x0F, 0x84, 0xF5, 0xFF, 0xFF, 0xFF, 0xC3]
+        );
+    }
+}
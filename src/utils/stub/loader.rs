@@ -0,0 +1,927 @@
+use std::{
+    fmt,
+    mem::{offset_of, size_of},
+};
+
+use super::{
+    assembler::{mem, mem_index, Assembler, Cond, Label, Reg, Width},
+    params::{LoaderParams, LoaderStatus, LOADER_OPTION_RESOLVE_IMPORTS, LOADER_PARAMS_VERSION},
+};
+use crate::utils::pe::{headers::Architecture, imports::ImportTarget};
+
+///The stub finished, DllMain returned TRUE
+pub const LOADER_OK: u32 = 0;
+///LoadLibraryA returned NULL for one of the imported modules
+pub const LOADER_MODULE_NOT_FOUND: u32 = 1;
+///GetProcAddress returned NULL for one of the imports
+pub const LOADER_IMPORT_NOT_FOUND: u32 = 2;
+///DllMain returned FALSE
+pub const LOADER_DLLMAIN_FAILED: u32 = 3;
+///The parameter block is of another version or does not describe the mapped image, when the
+///version is wrong the status is left alone and this is only the thread's exit code
+pub const LOADER_BAD_PARAMS: u32 = 4;
+
+//offsets into the LoaderParams block the stub is started with
+const PARAM_VERSION: i32 = offset_of!(LoaderParams, version) as i32;
+const PARAM_SIZE: i32 = offset_of!(LoaderParams, size) as i32;
+const PARAM_IMAGE_BASE: i32 = offset_of!(LoaderParams, image_base) as i32;
+const PARAM_LOAD_LIBRARY_A: i32 = offset_of!(LoaderParams, load_library_a) as i32;
+const PARAM_GET_PROC_ADDRESS: i32 = offset_of!(LoaderParams, get_proc_address) as i32;
+const PARAM_IMAGE_SIZE: i32 = offset_of!(LoaderParams, image_size) as i32;
+const PARAM_OPTIONS: i32 = offset_of!(LoaderParams, options) as i32;
+const STATUS: usize = offset_of!(LoaderParams, status);
+const STATUS_STAGE: i32 = (STATUS + offset_of!(LoaderStatus, stage)) as i32;
+const STATUS_FINISHED: i32 = (STATUS + offset_of!(LoaderStatus, finished)) as i32;
+const STATUS_ERROR: i32 = (STATUS + offset_of!(LoaderStatus, error)) as i32;
+const STATUS_DLLMAIN_RETURN: i32 = (STATUS + offset_of!(LoaderStatus, dllmain_return)) as i32;
+const STATUS_MODULE: i32 = (STATUS + offset_of!(LoaderStatus, module_rva)) as i32;
+const STATUS_IMPORT: i32 = (STATUS + offset_of!(LoaderStatus, import_thunk)) as i32;
+
+const E_LFANEW: i32 = 0x3C;
+const ADDRESS_OF_ENTRY_POINT: i32 = 0x28;
+const DIRECTORY_IMPORT: i32 = 1;
+const DIRECTORY_BASERELOC: i32 = 5;
+const DIRECTORY_TLS: i32 = 9;
+const NUMBER_OF_DIRECTORIES: i32 = 16;
+const IMPORT_DESCRIPTOR_SIZE: i32 = 20;
+const IMAGE_REL_BASED_HIGHLOW: i32 = 3;
+const IMAGE_REL_BASED_DIR64: i32 = 10;
+const DLL_PROCESS_ATTACH: i8 = 1;
+
+///How far the stub got, written to the status block before every step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoaderStage {
+    NotStarted,
+    Relocations,
+    Imports,
+    TlsCallbacks,
+    DllMain,
+    Done,
+    Unknown(u32),
+}
+
+impl LoaderStage {
+    pub(super) fn value(&self) -> u32 {
+        match self {
+            LoaderStage::NotStarted => 0,
+            LoaderStage::Relocations => 1,
+            LoaderStage::Imports => 2,
+            LoaderStage::TlsCallbacks => 3,
+            LoaderStage::DllMain => 4,
+            LoaderStage::Done => 5,
+            LoaderStage::Unknown(value) => *value,
+        }
+    }
+
+    pub(super) fn from_value(value: u32) -> LoaderStage {
+        match value {
+            0 => LoaderStage::NotStarted,
+            1 => LoaderStage::Relocations,
+            2 => LoaderStage::Imports,
+            3 => LoaderStage::TlsCallbacks,
+            4 => LoaderStage::DllMain,
+            5 => LoaderStage::Done,
+            value => LoaderStage::Unknown(value),
+        }
+    }
+}
+
+impl fmt::Display for LoaderStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoaderStage::NotStarted => write!(f, "startup"),
+            LoaderStage::Relocations => write!(f, "relocations"),
+            LoaderStage::Imports => write!(f, "imports"),
+            LoaderStage::TlsCallbacks => write!(f, "TLS callbacks"),
+            LoaderStage::DllMain => write!(f, "DllMain"),
+            LoaderStage::Done => write!(f, "done"),
+            LoaderStage::Unknown(value) => write!(f, "unknown stage {value}"),
+        }
+    }
+}
+
+///A loader stub that finished without loading the dll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoaderFailure {
+    pub stage: LoaderStage,
+    pub error: u32,
+    pub module: Option<String>,
+    pub import: Option<ImportTarget>,
+    pub dllmain_return: u32,
+}
+
+impl fmt::Display for LoaderFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let module = self.module.as_deref().unwrap_or("an unknown module");
+        match (self.error, &self.import) {
+            (LOADER_MODULE_NOT_FOUND, _) => write!(f, "LoadLibraryA failed for {module}"),
+            (LOADER_IMPORT_NOT_FOUND, Some(import)) => {
+                write!(f, "GetProcAddress failed for {module}!{import}")
+            }
+            (LOADER_IMPORT_NOT_FOUND, None) => {
+                write!(f, "GetProcAddress failed for an import of {module}")
+            }
+            (LOADER_DLLMAIN_FAILED, _) => write!(f, "DllMain returned FALSE"),
+            (LOADER_BAD_PARAMS, _) => {
+                write!(f, "the parameter block does not describe the mapped image")
+            }
+            (error, _) => write!(f, "unknown error {error} during {}", self.stage),
+        }
+    }
+}
+
+///offsets of the image headers the stub reads, relative to the nt headers
+struct Layout {
+    ///where the x86 stub finds its thread parameter relative to esp, the x64 one keeps it in rbp
+    params_slot: Option<i32>,
+    image_base: i32,
+    data_directories: i32,
+    ///AddressOfCallBacks inside the TLS directory
+    tls_callbacks: i32,
+    relocation_type: i32,
+}
+
+impl Layout {
+    fn new(architecture: Architecture) -> Layout {
+        return match architecture {
+            Architecture::X64 => Layout {
+                params_slot: None,
+                image_base: 0x30,
+                data_directories: 0x88,
+                tls_callbacks: 24,
+                relocation_type: IMAGE_REL_BASED_DIR64,
+            },
+            Architecture::X86 => Layout {
+                //four saved registers, the scratch slot and the return address
+                params_slot: Some(24),
+                image_base: 0x34,
+                data_directories: 0x78,
+                tls_callbacks: 12,
+                relocation_type: IMAGE_REL_BASED_HIGHLOW,
+            },
+        };
+    }
+
+    fn directory(&self, index: i32) -> i32 {
+        return self.data_directories + index * 8;
+    }
+
+    ///the signature, the file header and the whole optional header, everything the stub reads
+    fn headers_size(&self) -> i32 {
+        return self.directory(NUMBER_OF_DIRECTORIES);
+    }
+}
+
+///position independent machine code that finishes loading an image mapped by the injector
+///
+///started as the thread routine with a LoaderParams block as its parameter it applies the
+///relocations, resolves the imports, runs the TLS callbacks and DllMain of the image the block
+///points at. Every step is announced in the block's status, the thread exits with one of the
+///LOADER_ codes which also ends up in the status along with the import that failed
+///
+///map_image already relocates for the base the image is written to and updates ImageBase to
+///match, the relocation stage then finds no delta and goes straight to the imports. It only does
+///work for a caller that writes the sections as they are on disk, which keeps the stub a complete
+///loader on its own
+pub fn loader_stub(architecture: Architecture) -> Vec<u8> {
+    return match architecture {
+        Architecture::X64 => loader_x64(),
+        Architecture::X86 => loader_x86(),
+    };
+}
+
+///Win64 calling convention, rbp holds the parameter block, rbx the image base and rsi the nt
+///headers throughout
+fn loader_x64() -> Vec<u8> {
+    let layout = Layout::new(Architecture::X64);
+    let mut asm = Assembler::new(Architecture::X64);
+    let (ptr, dword) = (Width::Ptr, Width::Dword);
+
+    let imports = asm.new_label();
+    let tls = asm.new_label();
+    let entry_point = asm.new_label();
+    let success = asm.new_label();
+    let fail_module = asm.new_label();
+    let fail_import = asm.new_label();
+    let fail_dllmain = asm.new_label();
+    let bad_image = asm.new_label();
+    let reject = asm.new_label();
+    let epilogue = asm.new_label();
+
+    //eight pushes, the shadow space and 8 bytes padding keep rsp 16 byte aligned for the calls
+    for reg in [
+        Reg::Bp,
+        Reg::Bx,
+        Reg::Si,
+        Reg::Di,
+        Reg::R12,
+        Reg::R13,
+        Reg::R14,
+        Reg::R15,
+    ] {
+        asm.push(reg);
+    }
+    asm.sub_imm(ptr, Reg::Sp, 0x28);
+    asm.mov(ptr, Reg::Bp, Reg::Cx);
+    check_params(&mut asm, &layout, reject, bad_image);
+    asm.lea(Reg::Si, mem_index(Reg::Bx, Reg::Ax, 0));
+    set_stage(&mut asm, &layout, LoaderStage::Relocations);
+
+    //relocations, skipped when the image was relocated before it was written
+    //r12 = delta, rdi = block, r13 = end of the directory, r15 = entry, r14 = end of the block
+    let block = asm.new_label();
+    let entry = asm.new_label();
+    let next_entry = asm.new_label();
+    let next_block = asm.new_label();
+    asm.mov(ptr, Reg::R12, Reg::Bx);
+    asm.sub_load(ptr, Reg::R12, mem(Reg::Si, layout.image_base));
+    asm.jcc(Cond::Zero, imports);
+    asm.mov_load(
+        dword,
+        Reg::Ax,
+        mem(Reg::Si, layout.directory(DIRECTORY_BASERELOC)),
+    );
+    asm.mov_load(
+        dword,
+        Reg::Cx,
+        mem(Reg::Si, layout.directory(DIRECTORY_BASERELOC) + 4),
+    );
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, imports);
+    asm.lea(Reg::Di, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.lea(Reg::R13, mem_index(Reg::Di, Reg::Cx, 0));
+    asm.bind(block);
+    asm.cmp(ptr, Reg::Di, Reg::R13);
+    asm.jcc(Cond::AboveOrEqual, imports);
+    asm.mov_load(dword, Reg::Cx, mem(Reg::Di, 4));
+    asm.cmp_imm(dword, Reg::Cx, 8);
+    asm.jcc(Cond::Below, imports);
+    asm.lea(Reg::R15, mem(Reg::Di, 8));
+    asm.lea(Reg::R14, mem_index(Reg::Di, Reg::Cx, 0));
+    asm.bind(entry);
+    asm.cmp(ptr, Reg::R15, Reg::R14);
+    asm.jcc(Cond::AboveOrEqual, next_block);
+    asm.movzx_word(Reg::Ax, mem(Reg::R15, 0));
+    asm.mov(dword, Reg::Dx, Reg::Ax);
+    asm.shr_imm(dword, Reg::Dx, 12);
+    asm.cmp_imm(dword, Reg::Dx, layout.relocation_type);
+    asm.jcc(Cond::NotZero, next_entry);
+    asm.and_imm(dword, Reg::Ax, 0xFFF);
+    asm.add_load(dword, Reg::Ax, mem(Reg::Di, 0));
+    asm.add_store(ptr, mem_index(Reg::Bx, Reg::Ax, 0), Reg::R12);
+    asm.bind(next_entry);
+    asm.add_imm(ptr, Reg::R15, 2);
+    asm.jmp(entry);
+    asm.bind(next_block);
+    asm.mov(ptr, Reg::Di, Reg::R14);
+    asm.jmp(block);
+
+    //imports, rdi = descriptor, r12 = module, r13 = lookup thunk, r14 = IAT slot
+    let descriptor = asm.new_label();
+    let has_lookup = asm.new_label();
+    let thunk = asm.new_label();
+    let ordinal = asm.new_label();
+    let get_proc = asm.new_label();
+    let next_descriptor = asm.new_label();
+    asm.bind(imports);
+    set_stage(&mut asm, &layout, LoaderStage::Imports);
+    resolve_imports_option(&mut asm, &layout, tls);
+    asm.mov_load(
+        dword,
+        Reg::Ax,
+        mem(Reg::Si, layout.directory(DIRECTORY_IMPORT)),
+    );
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, tls);
+    asm.lea(Reg::Di, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.bind(descriptor);
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Di, 12));
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, tls);
+    asm.lea(Reg::Cx, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.call_mem(mem(Reg::Bp, PARAM_LOAD_LIBRARY_A));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, fail_module);
+    asm.mov(ptr, Reg::R12, Reg::Ax);
+    //bound images may have no lookup table, the IAT holds the same thunks then
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Di, 0));
+    asm.mov_load(dword, Reg::Cx, mem(Reg::Di, 16));
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::NotZero, has_lookup);
+    asm.mov(dword, Reg::Ax, Reg::Cx);
+    asm.bind(has_lookup);
+    asm.lea(Reg::R13, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.lea(Reg::R14, mem_index(Reg::Bx, Reg::Cx, 0));
+    asm.bind(thunk);
+    asm.mov_load(ptr, Reg::Ax, mem(Reg::R13, 0));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, next_descriptor);
+    asm.jcc(Cond::Sign, ordinal);
+    //skip the hint of the IMAGE_IMPORT_BY_NAME
+    asm.lea(Reg::Dx, mem_index(Reg::Bx, Reg::Ax, 2));
+    asm.jmp(get_proc);
+    asm.bind(ordinal);
+    asm.mov(dword, Reg::Dx, Reg::Ax);
+    asm.and_imm(dword, Reg::Dx, 0xFFFF);
+    asm.bind(get_proc);
+    asm.mov(ptr, Reg::Cx, Reg::R12);
+    asm.call_mem(mem(Reg::Bp, PARAM_GET_PROC_ADDRESS));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, fail_import);
+    asm.mov_store(ptr, mem(Reg::R14, 0), Reg::Ax);
+    asm.add_imm(ptr, Reg::R13, 8);
+    asm.add_imm(ptr, Reg::R14, 8);
+    asm.jmp(thunk);
+    asm.bind(next_descriptor);
+    asm.add_imm(ptr, Reg::Di, IMPORT_DESCRIPTOR_SIZE);
+    asm.jmp(descriptor);
+
+    //tls callbacks, r13 = callback array
+    let callback = asm.new_label();
+    asm.bind(tls);
+    set_stage(&mut asm, &layout, LoaderStage::TlsCallbacks);
+    asm.mov_load(
+        dword,
+        Reg::Ax,
+        mem(Reg::Si, layout.directory(DIRECTORY_TLS)),
+    );
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, entry_point);
+    asm.mov_load(
+        ptr,
+        Reg::R13,
+        mem_index(Reg::Bx, Reg::Ax, layout.tls_callbacks),
+    );
+    asm.test(ptr, Reg::R13, Reg::R13);
+    asm.jcc(Cond::Zero, entry_point);
+    asm.bind(callback);
+    asm.mov_load(ptr, Reg::Ax, mem(Reg::R13, 0));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, entry_point);
+    asm.mov(ptr, Reg::Cx, Reg::Bx);
+    asm.mov_imm32(Reg::Dx, DLL_PROCESS_ATTACH as u32);
+    asm.xor(dword, Reg::R8, Reg::R8);
+    asm.call(Reg::Ax);
+    asm.add_imm(ptr, Reg::R13, 8);
+    asm.jmp(callback);
+
+    asm.bind(entry_point);
+    set_stage(&mut asm, &layout, LoaderStage::DllMain);
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Si, ADDRESS_OF_ENTRY_POINT));
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, success);
+    asm.add(ptr, Reg::Ax, Reg::Bx);
+    asm.mov(ptr, Reg::Cx, Reg::Bx);
+    asm.mov_imm32(Reg::Dx, DLL_PROCESS_ATTACH as u32);
+    asm.xor(dword, Reg::R8, Reg::R8);
+    asm.call(Reg::Ax);
+    let block = params(&mut asm, &layout, 0);
+    asm.mov_store(dword, mem(block, STATUS_DLLMAIN_RETURN), Reg::Ax);
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, fail_dllmain);
+
+    exit_paths(
+        &mut asm,
+        &layout,
+        [
+            success,
+            fail_module,
+            fail_import,
+            fail_dllmain,
+            bad_image,
+            reject,
+        ],
+        Reg::R13,
+        epilogue,
+    );
+    asm.add_imm(ptr, Reg::Sp, 0x28);
+    for reg in [
+        Reg::R15,
+        Reg::R14,
+        Reg::R13,
+        Reg::R12,
+        Reg::Di,
+        Reg::Si,
+        Reg::Bx,
+        Reg::Bp,
+    ] {
+        asm.pop(reg);
+    }
+    asm.ret();
+    return asm.finish();
+}
+
+///stdcall, ebx holds the image base throughout and [esp] is a scratch slot, the parameter block is
+///read from the thread parameter whenever it is needed
+fn loader_x86() -> Vec<u8> {
+    let layout = Layout::new(Architecture::X86);
+    let mut asm = Assembler::new(Architecture::X86);
+    let (ptr, dword) = (Width::Ptr, Width::Dword);
+
+    let imports = asm.new_label();
+    let tls = asm.new_label();
+    let entry_point = asm.new_label();
+    let success = asm.new_label();
+    let fail_module = asm.new_label();
+    let fail_import = asm.new_label();
+    let fail_dllmain = asm.new_label();
+    let bad_image = asm.new_label();
+    let reject = asm.new_label();
+    let epilogue = asm.new_label();
+
+    for reg in [Reg::Bp, Reg::Bx, Reg::Si, Reg::Di] {
+        asm.push(reg);
+    }
+    asm.sub_imm(ptr, Reg::Sp, 4);
+    check_params(&mut asm, &layout, reject, bad_image);
+    asm.lea(Reg::Si, mem_index(Reg::Bx, Reg::Ax, 0));
+    set_stage(&mut asm, &layout, LoaderStage::Relocations);
+
+    //relocations, skipped when the image was relocated before it was written
+    //ebp = delta, edi = block, [esp] = end of the directory, edx = entry, ecx = end of the block
+    let block = asm.new_label();
+    let entry = asm.new_label();
+    let next_entry = asm.new_label();
+    let next_block = asm.new_label();
+    asm.mov(ptr, Reg::Bp, Reg::Bx);
+    asm.sub_load(ptr, Reg::Bp, mem(Reg::Si, layout.image_base));
+    asm.jcc(Cond::Zero, imports);
+    asm.mov_load(
+        dword,
+        Reg::Ax,
+        mem(Reg::Si, layout.directory(DIRECTORY_BASERELOC)),
+    );
+    asm.mov_load(
+        dword,
+        Reg::Cx,
+        mem(Reg::Si, layout.directory(DIRECTORY_BASERELOC) + 4),
+    );
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, imports);
+    asm.lea(Reg::Di, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.lea(Reg::Ax, mem_index(Reg::Di, Reg::Cx, 0));
+    asm.mov_store(ptr, mem(Reg::Sp, 0), Reg::Ax);
+    asm.bind(block);
+    asm.cmp_load(ptr, Reg::Di, mem(Reg::Sp, 0));
+    asm.jcc(Cond::AboveOrEqual, imports);
+    asm.mov_load(dword, Reg::Cx, mem(Reg::Di, 4));
+    asm.cmp_imm(dword, Reg::Cx, 8);
+    asm.jcc(Cond::Below, imports);
+    asm.lea(Reg::Dx, mem(Reg::Di, 8));
+    asm.add(ptr, Reg::Cx, Reg::Di);
+    asm.bind(entry);
+    asm.cmp(ptr, Reg::Dx, Reg::Cx);
+    asm.jcc(Cond::AboveOrEqual, next_block);
+    //no register left for the type, the entry is read twice instead
+    asm.movzx_word(Reg::Ax, mem(Reg::Dx, 0));
+    asm.shr_imm(dword, Reg::Ax, 12);
+    asm.cmp_imm(dword, Reg::Ax, layout.relocation_type);
+    asm.jcc(Cond::NotZero, next_entry);
+    asm.movzx_word(Reg::Ax, mem(Reg::Dx, 0));
+    asm.and_imm(dword, Reg::Ax, 0xFFF);
+    asm.add_load(dword, Reg::Ax, mem(Reg::Di, 0));
+    asm.add_store(ptr, mem_index(Reg::Bx, Reg::Ax, 0), Reg::Bp);
+    asm.bind(next_entry);
+    asm.add_imm(ptr, Reg::Dx, 2);
+    asm.jmp(entry);
+    asm.bind(next_block);
+    asm.mov(ptr, Reg::Di, Reg::Cx);
+    asm.jmp(block);
+
+    //imports, edi = descriptor, [esp] = module, ebp = lookup thunk, esi = IAT slot
+    let descriptor = asm.new_label();
+    let has_lookup = asm.new_label();
+    let thunk = asm.new_label();
+    let ordinal = asm.new_label();
+    let get_proc = asm.new_label();
+    let next_descriptor = asm.new_label();
+    asm.bind(imports);
+    set_stage(&mut asm, &layout, LoaderStage::Imports);
+    resolve_imports_option(&mut asm, &layout, tls);
+    asm.mov_load(
+        dword,
+        Reg::Ax,
+        mem(Reg::Si, layout.directory(DIRECTORY_IMPORT)),
+    );
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, tls);
+    asm.lea(Reg::Di, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.bind(descriptor);
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Di, 12));
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, tls);
+    asm.add(ptr, Reg::Ax, Reg::Bx);
+    asm.push(Reg::Ax);
+    let block = params(&mut asm, &layout, 4);
+    asm.call_mem(mem(block, PARAM_LOAD_LIBRARY_A));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, fail_module);
+    asm.mov_store(ptr, mem(Reg::Sp, 0), Reg::Ax);
+    //bound images may have no lookup table, the IAT holds the same thunks then
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Di, 0));
+    asm.mov_load(dword, Reg::Cx, mem(Reg::Di, 16));
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::NotZero, has_lookup);
+    asm.mov(dword, Reg::Ax, Reg::Cx);
+    asm.bind(has_lookup);
+    asm.lea(Reg::Bp, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.lea(Reg::Si, mem_index(Reg::Bx, Reg::Cx, 0));
+    asm.bind(thunk);
+    asm.mov_load(ptr, Reg::Ax, mem(Reg::Bp, 0));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, next_descriptor);
+    asm.jcc(Cond::Sign, ordinal);
+    //skip the hint of the IMAGE_IMPORT_BY_NAME
+    asm.lea(Reg::Ax, mem_index(Reg::Bx, Reg::Ax, 2));
+    asm.jmp(get_proc);
+    asm.bind(ordinal);
+    asm.and_imm(dword, Reg::Ax, 0xFFFF);
+    asm.bind(get_proc);
+    asm.push(Reg::Ax);
+    //the module moved up by the push above
+    asm.push_mem(mem(Reg::Sp, 4));
+    let block = params(&mut asm, &layout, 8);
+    asm.call_mem(mem(block, PARAM_GET_PROC_ADDRESS));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, fail_import);
+    asm.mov_store(ptr, mem(Reg::Si, 0), Reg::Ax);
+    asm.add_imm(ptr, Reg::Bp, 4);
+    asm.add_imm(ptr, Reg::Si, 4);
+    asm.jmp(thunk);
+    asm.bind(next_descriptor);
+    asm.add_imm(ptr, Reg::Di, IMPORT_DESCRIPTOR_SIZE);
+    asm.jmp(descriptor);
+
+    //tls callbacks, esi went to the imports so the nt headers are found again, edi = callback array
+    let callback = asm.new_label();
+    asm.bind(tls);
+    set_stage(&mut asm, &layout, LoaderStage::TlsCallbacks);
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Bx, E_LFANEW));
+    asm.lea(Reg::Si, mem_index(Reg::Bx, Reg::Ax, 0));
+    asm.mov_load(
+        dword,
+        Reg::Ax,
+        mem(Reg::Si, layout.directory(DIRECTORY_TLS)),
+    );
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, entry_point);
+    asm.mov_load(
+        ptr,
+        Reg::Di,
+        mem_index(Reg::Bx, Reg::Ax, layout.tls_callbacks),
+    );
+    asm.test(ptr, Reg::Di, Reg::Di);
+    asm.jcc(Cond::Zero, entry_point);
+    asm.bind(callback);
+    asm.mov_load(ptr, Reg::Ax, mem(Reg::Di, 0));
+    asm.test(ptr, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, entry_point);
+    asm.push_imm8(0);
+    asm.push_imm8(DLL_PROCESS_ATTACH);
+    asm.push(Reg::Bx);
+    asm.call(Reg::Ax);
+    asm.add_imm(ptr, Reg::Di, 4);
+    asm.jmp(callback);
+
+    asm.bind(entry_point);
+    set_stage(&mut asm, &layout, LoaderStage::DllMain);
+    asm.mov_load(dword, Reg::Ax, mem(Reg::Si, ADDRESS_OF_ENTRY_POINT));
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, success);
+    asm.add(ptr, Reg::Ax, Reg::Bx);
+    asm.push_imm8(0);
+    asm.push_imm8(DLL_PROCESS_ATTACH);
+    asm.push(Reg::Bx);
+    asm.call(Reg::Ax);
+    let block = params(&mut asm, &layout, 0);
+    asm.mov_store(dword, mem(block, STATUS_DLLMAIN_RETURN), Reg::Ax);
+    asm.test(dword, Reg::Ax, Reg::Ax);
+    asm.jcc(Cond::Zero, fail_dllmain);
+
+    exit_paths(
+        &mut asm,
+        &layout,
+        [
+            success,
+            fail_module,
+            fail_import,
+            fail_dllmain,
+            bad_image,
+            reject,
+        ],
+        Reg::Bp,
+        epilogue,
+    );
+    asm.add_imm(ptr, Reg::Sp, 4);
+    for reg in [Reg::Di, Reg::Si, Reg::Bx, Reg::Bp] {
+        asm.pop(reg);
+    }
+    asm.ret_imm(4);
+    return asm.finish();
+}
+
+///rbp on x64, on x86 the thread parameter is loaded into ecx
+///
+///pushed is how many bytes went on the stack since the prologue
+fn params(asm: &mut Assembler, layout: &Layout, pushed: i32) -> Reg {
+    return match layout.params_slot {
+        Some(slot) => {
+            asm.mov_load(Width::Ptr, Reg::Cx, mem(Reg::Sp, slot + pushed));
+            Reg::Cx
+        }
+        None => Reg::Bp,
+    };
+}
+
+///makes sure the block was written by this version of the injector and that the nt headers up to
+///the end of the optional header lie inside the image, leaves the image base in rbx/ebx and
+///e_lfanew in eax
+///
+///a block of another version cannot be trusted to have a status so reject does not report back
+fn check_params(asm: &mut Assembler, layout: &Layout, reject: Label, bad_image: Label) {
+    let block = params(asm, layout, 0);
+    asm.mov_load(Width::Dword, Reg::Ax, mem(block, PARAM_VERSION));
+    asm.cmp_imm(Width::Dword, Reg::Ax, LOADER_PARAMS_VERSION as i32);
+    asm.jcc(Cond::NotZero, reject);
+    asm.mov_load(Width::Dword, Reg::Ax, mem(block, PARAM_SIZE));
+    asm.cmp_imm(Width::Dword, Reg::Ax, size_of::<LoaderParams>() as i32);
+    asm.jcc(Cond::NotZero, reject);
+    asm.mov_load(Width::Ptr, Reg::Bx, mem(block, PARAM_IMAGE_BASE));
+    //e_lfanew + headers_size <= image_size, rearranged so a huge e_lfanew cannot wrap around
+    asm.mov_load(Width::Dword, Reg::Dx, mem(block, PARAM_IMAGE_SIZE));
+    asm.cmp_imm(Width::Dword, Reg::Dx, layout.headers_size());
+    asm.jcc(Cond::Below, bad_image);
+    asm.sub_imm(Width::Dword, Reg::Dx, layout.headers_size());
+    asm.mov_load(Width::Dword, Reg::Ax, mem(Reg::Bx, E_LFANEW));
+    asm.cmp(Width::Dword, Reg::Dx, Reg::Ax);
+    asm.jcc(Cond::Below, bad_image);
+}
+
+///jumps to skip when the injector already filled the IAT
+fn resolve_imports_option(asm: &mut Assembler, layout: &Layout, skip: Label) {
+    let block = params(asm, layout, 0);
+    asm.mov_load(Width::Dword, Reg::Ax, mem(block, PARAM_OPTIONS));
+    asm.and_imm(Width::Dword, Reg::Ax, LOADER_OPTION_RESOLVE_IMPORTS as i32);
+    asm.jcc(Cond::Zero, skip);
+}
+
+fn set_stage(asm: &mut Assembler, layout: &Layout, stage: LoaderStage) {
+    let block = params(asm, layout, 0);
+    asm.mov_store_imm32(mem(block, STATUS_STAGE), stage.value());
+}
+
+///one label per LOADER_ code followed by the epilogue, which reports the code in the status and
+///leaves it in eax
+///
+///the failure paths expect the import descriptor in rdi/edi and the failing thunk in thunk, reject
+///skips the status
+fn exit_paths(
+    asm: &mut Assembler,
+    layout: &Layout,
+    [success, fail_module, fail_import, fail_dllmain, bad_image, reject]: [Label; 6],
+    thunk: Reg,
+    epilogue: Label,
+) {
+    let leave = asm.new_label();
+
+    asm.bind(success);
+    set_stage(asm, layout, LoaderStage::Done);
+    asm.mov_imm32(Reg::Ax, LOADER_OK);
+    asm.jmp(epilogue);
+
+    asm.bind(fail_module);
+    let block = params(asm, layout, 0);
+    asm.mov_load(Width::Dword, Reg::Ax, mem(Reg::Di, 12));
+    asm.mov_store(Width::Dword, mem(block, STATUS_MODULE), Reg::Ax);
+    asm.mov_imm32(Reg::Ax, LOADER_MODULE_NOT_FOUND);
+    asm.jmp(epilogue);
+
+    asm.bind(fail_import);
+    let block = params(asm, layout, 0);
+    asm.mov_load(Width::Dword, Reg::Ax, mem(Reg::Di, 12));
+    asm.mov_store(Width::Dword, mem(block, STATUS_MODULE), Reg::Ax);
+    asm.mov_load(Width::Ptr, Reg::Ax, mem(thunk, 0));
+    asm.mov_store(Width::Ptr, mem(block, STATUS_IMPORT), Reg::Ax);
+    asm.mov_imm32(Reg::Ax, LOADER_IMPORT_NOT_FOUND);
+    asm.jmp(epilogue);
+
+    asm.bind(reject);
+    asm.mov_imm32(Reg::Ax, LOADER_BAD_PARAMS);
+    asm.jmp(leave);
+
+    asm.bind(bad_image);
+    asm.mov_imm32(Reg::Ax, LOADER_BAD_PARAMS);
+    asm.jmp(epilogue);
+
+    asm.bind(fail_dllmain);
+    asm.mov_imm32(Reg::Ax, LOADER_DLLMAIN_FAILED);
+
+    //finished goes last, the injector takes everything else as final once it sees it
+    asm.bind(epilogue);
+    let block = params(asm, layout, 0);
+    asm.mov_store(Width::Dword, mem(block, STATUS_ERROR), Reg::Ax);
+    asm.mov_store_imm32(mem(block, STATUS_FINISHED), 1);
+    asm.bind(leave);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use iced_x86::{Code, Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
+
+    use super::*;
+
+    const ARCHITECTURES: [Architecture; 2] = [Architecture::X64, Architecture::X86];
+
+    fn decode(architecture: Architecture) -> (Vec<u8>, Vec<Instruction>) {
+        let code = loader_stub(architecture);
+        let bitness = match architecture {
+            Architecture::X86 => 32,
+            Architecture::X64 => 64,
+        };
+        let mut decoder = Decoder::new(bitness, &code, DecoderOptions::NONE);
+        let instructions = decoder.iter().collect();
+        return (code, instructions);
+    }
+
+    ///registers saved by the prologue in push order and the bytes reserved after them
+    fn frame(architecture: Architecture) -> (Vec<Register>, u64) {
+        return match architecture {
+            Architecture::X64 => (
+                vec![
+                    Register::RBP,
+                    Register::RBX,
+                    Register::RSI,
+                    Register::RDI,
+                    Register::R12,
+                    Register::R13,
+                    Register::R14,
+                    Register::R15,
+                ],
+                0x28,
+            ),
+            Architecture::X86 => (
+                vec![Register::EBP, Register::EBX, Register::ESI, Register::EDI],
+                4,
+            ),
+        };
+    }
+
+    #[test]
+    fn decodes_completely() {
+        for architecture in ARCHITECTURES {
+            let (code, instructions) = decode(architecture);
+            for instruction in &instructions {
+                assert!(
+                    !instruction.is_invalid(),
+                    "{architecture:?} invalid opcode at {:#x}",
+                    instruction.ip()
+                );
+            }
+            let end = instructions.last().unwrap().next_ip();
+            assert_eq!(end, code.len() as u64, "{architecture:?}");
+        }
+    }
+
+    #[test]
+    fn jumps_land_on_instruction_boundaries() {
+        for architecture in ARCHITECTURES {
+            let (code, instructions) = decode(architecture);
+            let starts: HashSet<u64> = instructions.iter().map(Instruction::ip).collect();
+            let mut jumps = 0;
+            for instruction in &instructions {
+                let relative = matches!(
+                    instruction.op0_kind(),
+                    OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+                );
+                if !relative {
+                    continue;
+                }
+                //every jump is emitted as a rel32, the short forms would change the layout
+                let rel32_len = match instruction.mnemonic() {
+                    Mnemonic::Jmp => 5,
+                    _ => 6,
+                };
+                assert_eq!(instruction.len(), rel32_len, "{architecture:?}");
+
+                let target = instruction.near_branch_target();
+                assert!(
+                    target < code.len() as u64 && starts.contains(&target),
+                    "{architecture:?} jump at {:#x} to {target:#x}",
+                    instruction.ip()
+                );
+                jumps += 1;
+            }
+            assert!(jumps > 20, "{architecture:?} only {jumps} jumps");
+        }
+    }
+
+    #[test]
+    fn epilogue_undoes_the_prologue() {
+        for architecture in ARCHITECTURES {
+            let (_, instructions) = decode(architecture);
+            let (saved, reserved) = frame(architecture);
+            let stack_pointer = match architecture {
+                Architecture::X64 => Register::RSP,
+                Architecture::X86 => Register::ESP,
+            };
+
+            let (prologue, rest) = instructions.split_at(saved.len());
+            for (instruction, register) in prologue.iter().zip(&saved) {
+                assert_eq!(instruction.mnemonic(), Mnemonic::Push);
+                assert_eq!(instruction.op0_register(), *register);
+            }
+            assert_eq!(rest[0].mnemonic(), Mnemonic::Sub);
+            assert_eq!(rest[0].op0_register(), stack_pointer);
+            assert_eq!(rest[0].immediate(1), reserved);
+
+            //one way out, everything ends in the same epilogue
+            let returns: Vec<&Instruction> = instructions
+                .iter()
+                .filter(|instruction| instruction.mnemonic() == Mnemonic::Ret)
+                .collect();
+            assert_eq!(returns.len(), 1, "{architecture:?}");
+
+            let epilogue = &instructions[instructions.len() - saved.len() - 2..];
+            assert_eq!(epilogue[0].mnemonic(), Mnemonic::Add);
+            assert_eq!(epilogue[0].op0_register(), stack_pointer);
+            assert_eq!(epilogue[0].immediate(1), reserved);
+            let popped: Vec<Register> = epilogue[1..=saved.len()]
+                .iter()
+                .map(|instruction| {
+                    assert_eq!(instruction.mnemonic(), Mnemonic::Pop);
+                    instruction.op0_register()
+                })
+                .collect();
+            let mut expected = saved.clone();
+            expected.reverse();
+            assert_eq!(popped, expected, "{architecture:?}");
+        }
+
+        //calls from the x64 stub need rsp 16 byte aligned, the thread started with it 8 off
+        let (saved, reserved) = frame(Architecture::X64);
+        assert_eq!((8 + saved.len() as u64 * 8 + reserved) % 16, 0);
+    }
+
+    #[test]
+    fn checks_the_headers_fit_before_reading_them() {
+        for architecture in ARCHITECTURES {
+            let (_, instructions) = decode(architecture);
+            let headers_size = Layout::new(architecture).headers_size() as u64;
+            let image_base = match architecture {
+                Architecture::X64 => Register::RBX,
+                Architecture::X86 => Register::EBX,
+            };
+            let start = instructions
+                .iter()
+                .position(|instruction| {
+                    instruction.mnemonic() == Mnemonic::Cmp
+                        && instruction.op0_register() == Register::EDX
+                })
+                .unwrap();
+
+            //image_size >= headers_size, then e_lfanew <= image_size - headers_size
+            let check = &instructions[start..start + 6];
+            assert_eq!(check[0].immediate(1), headers_size, "{architecture:?}");
+            assert_eq!(check[1].mnemonic(), Mnemonic::Jb);
+            assert_eq!(check[2].mnemonic(), Mnemonic::Sub);
+            assert_eq!(check[2].op0_register(), Register::EDX);
+            assert_eq!(check[2].immediate(1), headers_size);
+            assert_eq!(check[3].memory_base(), image_base);
+            assert_eq!(check[3].memory_displacement64(), E_LFANEW as u64);
+            assert_eq!(check[3].op0_register(), Register::EAX);
+            assert_eq!(check[4].mnemonic(), Mnemonic::Cmp);
+            assert_eq!(
+                (check[4].op0_register(), check[4].op1_register()),
+                (Register::EDX, Register::EAX)
+            );
+            assert_eq!(check[5].mnemonic(), Mnemonic::Jb);
+            assert_eq!(check[5].near_branch_target(), check[1].near_branch_target());
+
+            //e_lfanew is the first thing read from the image
+            let first_read = instructions
+                .iter()
+                .position(|instruction| {
+                    instruction.op_kinds().any(|kind| kind == OpKind::Memory)
+                        && instruction.memory_base() == image_base
+                })
+                .unwrap();
+            assert_eq!(first_read, start + 3, "{architecture:?}");
+        }
+        assert_eq!(Layout::new(Architecture::X64).headers_size(), 0x108);
+        assert_eq!(Layout::new(Architecture::X86).headers_size(), 0xF8);
+    }
+
+    #[test]
+    fn returns_the_way_the_thread_routine_is_called() {
+        //x64 leaves nothing on the stack, the x86 thread routine is stdcall with one argument
+        let (_, instructions) = decode(Architecture::X64);
+        assert_eq!(instructions.last().unwrap().code(), Code::Retnq);
+
+        let (_, instructions) = decode(Architecture::X86);
+        let ret = instructions.last().unwrap();
+        assert_eq!(ret.code(), Code::Retnd_imm16);
+        assert_eq!(ret.immediate16(), 4);
+    }
+}
@@ -0,0 +1,5 @@
+//machine code the injector writes into the target process, generated for the target's
+//architecture instead of copied out of the injector
+pub mod assembler;
+pub mod loader;
+pub mod params;
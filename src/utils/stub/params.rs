@@ -0,0 +1,426 @@
+use std::mem::{offset_of, size_of};
+
+use super::loader::{
+    LoaderFailure, LoaderStage, LOADER_IMPORT_NOT_FOUND, LOADER_MODULE_NOT_FOUND, LOADER_OK,
+};
+use crate::utils::pe::{exports::RvaSource, headers::Architecture, imports::ImportTarget};
+
+///Bumped whenever the layout of LoaderParams changes, the stub refuses a block of another version
+pub const LOADER_PARAMS_VERSION: u32 = 1;
+///The stub resolves the imports itself, unset when the injector already filled the IAT
+pub const LOADER_OPTION_RESOLVE_IMPORTS: u32 = 0x1;
+
+///Where the stub reports how far it got, part of the parameter block
+///
+///every field is a plain integer so both stubs can write it and the injector can read it back no
+///matter the architecture
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoaderStatus {
+    ///a LoaderStage, written before every step
+    pub stage: u32,
+    ///set last, everything else is final once this is non zero
+    pub finished: u32,
+    ///one of the LOADER_ codes
+    pub error: u32,
+    ///only meaningful once the stage got to DllMain
+    pub dllmain_return: u32,
+    ///rva of the name of the module LoadLibraryA or GetProcAddress failed for
+    pub module_rva: u32,
+    pub reserved: u32,
+    ///the lookup thunk GetProcAddress failed for, x86 only fills the low half
+    pub import_thunk: u64,
+}
+
+///The block the loader stub is started with, allocated on its own inside the target
+///
+///pointers are always 64 bit so the layout is the same for both stubs, the x86 one only reads the
+///low half
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoaderParams {
+    ///LOADER_PARAMS_VERSION
+    pub version: u32,
+    ///size_of::<LoaderParams>()
+    pub size: u32,
+    ///where the mapped image was written
+    pub image_base: u64,
+    ///LoadLibraryA inside the target
+    pub load_library_a: u64,
+    ///GetProcAddress inside the target
+    pub get_proc_address: u64,
+    ///SizeOfImage, the stub checks the headers it follows stay inside it
+    pub image_size: u32,
+    ///LOADER_OPTION_ flags
+    pub options: u32,
+    pub status: LoaderStatus,
+}
+
+//the stubs address the block by these offsets on both architectures, a change here has to bump
+//LOADER_PARAMS_VERSION
+const _: () = assert!(size_of::<LoaderStatus>() == 32);
+const _: () = assert!(offset_of!(LoaderStatus, stage) == 0);
+const _: () = assert!(offset_of!(LoaderStatus, finished) == 4);
+const _: () = assert!(offset_of!(LoaderStatus, error) == 8);
+const _: () = assert!(offset_of!(LoaderStatus, dllmain_return) == 12);
+const _: () = assert!(offset_of!(LoaderStatus, module_rva) == 16);
+const _: () = assert!(offset_of!(LoaderStatus, import_thunk) == 24);
+const _: () = assert!(size_of::<LoaderParams>() == 72);
+const _: () = assert!(offset_of!(LoaderParams, version) == 0);
+const _: () = assert!(offset_of!(LoaderParams, size) == 4);
+const _: () = assert!(offset_of!(LoaderParams, image_base) == 8);
+const _: () = assert!(offset_of!(LoaderParams, load_library_a) == 16);
+const _: () = assert!(offset_of!(LoaderParams, get_proc_address) == 24);
+const _: () = assert!(offset_of!(LoaderParams, image_size) == 32);
+const _: () = assert!(offset_of!(LoaderParams, options) == 36);
+const _: () = assert!(offset_of!(LoaderParams, status) == 40);
+
+impl LoaderParams {
+    pub fn new(
+        image_base: u64,
+        image_size: u32,
+        load_library_a: u64,
+        get_proc_address: u64,
+        options: u32,
+    ) -> LoaderParams {
+        return LoaderParams {
+            version: LOADER_PARAMS_VERSION,
+            size: size_of::<LoaderParams>() as u32,
+            image_base,
+            load_library_a,
+            get_proc_address,
+            image_size,
+            options,
+            status: LoaderStatus::default(),
+        };
+    }
+
+    ///the block as it is written to the target, the status starts out zeroed
+    pub fn to_bytes(&self) -> [u8; size_of::<LoaderParams>()] {
+        let mut bytes = [0u8; size_of::<LoaderParams>()];
+        let mut put = |offset: usize, value: &[u8]| {
+            bytes[offset..offset + value.len()].copy_from_slice(value);
+        };
+        put(
+            offset_of!(LoaderParams, version),
+            &self.version.to_le_bytes(),
+        );
+        put(offset_of!(LoaderParams, size), &self.size.to_le_bytes());
+        put(
+            offset_of!(LoaderParams, image_base),
+            &self.image_base.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderParams, load_library_a),
+            &self.load_library_a.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderParams, get_proc_address),
+            &self.get_proc_address.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderParams, image_size),
+            &self.image_size.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderParams, options),
+            &self.options.to_le_bytes(),
+        );
+        return bytes;
+    }
+
+    ///address of the status inside a block written at params
+    pub fn status_address(params: u64) -> u64 {
+        return params + offset_of!(LoaderParams, status) as u64;
+    }
+}
+
+impl LoaderStatus {
+    pub fn parse(block: &[u8; size_of::<LoaderStatus>()]) -> LoaderStatus {
+        let u32_at = |offset: usize| {
+            return u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        };
+        let import = offset_of!(LoaderStatus, import_thunk);
+        return LoaderStatus {
+            stage: u32_at(offset_of!(LoaderStatus, stage)),
+            finished: u32_at(offset_of!(LoaderStatus, finished)),
+            error: u32_at(offset_of!(LoaderStatus, error)),
+            dllmain_return: u32_at(offset_of!(LoaderStatus, dllmain_return)),
+            module_rva: u32_at(offset_of!(LoaderStatus, module_rva)),
+            reserved: u32_at(offset_of!(LoaderStatus, reserved)),
+            import_thunk: u64::from_le_bytes(block[import..import + 8].try_into().unwrap()),
+        };
+    }
+
+    pub fn stage(&self) -> LoaderStage {
+        return LoaderStage::from_value(self.stage);
+    }
+
+    pub fn finished(&self) -> bool {
+        return self.finished != 0;
+    }
+
+    ///what went wrong, None when the stub finished successfully
+    ///
+    ///the status only holds rvas, the names are read from the local copy of the image
+    pub fn failure(
+        &self,
+        image: &impl RvaSource,
+        architecture: Architecture,
+    ) -> Option<LoaderFailure> {
+        if self.error == LOADER_OK {
+            return None;
+        }
+        let module = match self.error {
+            LOADER_MODULE_NOT_FOUND | LOADER_IMPORT_NOT_FOUND => image.string(self.module_rva),
+            _ => None,
+        };
+        let ordinal_flag = match architecture {
+            Architecture::X64 => 1 << 63,
+            Architecture::X86 => 1 << 31,
+        };
+        let import = match self.error {
+            LOADER_IMPORT_NOT_FOUND if self.import_thunk & ordinal_flag != 0 => {
+                Some(ImportTarget::Ordinal(self.import_thunk as u16))
+            }
+            LOADER_IMPORT_NOT_FOUND => {
+                //the thunk comes from the target, a hint right at the end of the address space
+                //has no name behind it
+                let hint_rva = self.import_thunk as u32;
+                let name = hint_rva.checked_add(2).and_then(|rva| image.string(rva));
+                image
+                    .bytes(hint_rva, 2)
+                    .zip(name)
+                    .map(|(hint, name)| ImportTarget::Name {
+                        hint: u16::from_le_bytes([hint[0], hint[1]]),
+                        name,
+                    })
+            }
+            _ => None,
+        };
+        return Some(LoaderFailure {
+            stage: self.stage(),
+            error: self.error,
+            module,
+            import,
+            dllmain_return: self.dllmain_return,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::utils::stub::loader::LOADER_DLLMAIN_FAILED;
+
+    ///answers every rva, the hint is the low half of the rva and the name says where it was read
+    struct AnyRva;
+
+    impl RvaSource for AnyRva {
+        fn bytes(&self, rva: u32, len: usize) -> Option<Cow<'_, [u8]>> {
+            return Some(Cow::Owned((rva as u16).to_le_bytes().repeat(len / 2)));
+        }
+
+        fn string(&self, rva: u32) -> Option<String> {
+            return Some(format!("name@{rva:#x}"));
+        }
+    }
+
+    fn failed_import(import_thunk: u64) -> LoaderStatus {
+        return LoaderStatus {
+            stage: LoaderStage::Imports.value(),
+            finished: 1,
+            error: LOADER_IMPORT_NOT_FOUND,
+            module_rva: 0x3000,
+            import_thunk,
+            ..LoaderStatus::default()
+        };
+    }
+
+    fn failed_import_target(import_thunk: u64, architecture: Architecture) -> Option<ImportTarget> {
+        let failure = failed_import(import_thunk).failure(&AnyRva, architecture);
+        let failure = failure.unwrap();
+        assert_eq!(failure.stage, LoaderStage::Imports);
+        assert_eq!(failure.module.as_deref(), Some("name@0x3000"));
+        return failure.import;
+    }
+
+    #[test]
+    fn to_bytes_lays_the_block_out_like_the_stubs_expect() {
+        let params = LoaderParams::new(
+            0x1122334455667788,
+            0x99AABBCC,
+            0x0102030405060708,
+            0x1112131415161718,
+            LOADER_OPTION_RESOLVE_IMPORTS,
+        );
+        let bytes = params.to_bytes();
+        #[rustfmt::skip]
+        let expected: [u8; 40] = [
+            0x01, 0x00, 0x00, 0x00, //version
+            0x48, 0x00, 0x00, 0x00, //size
+            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, //image_base
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, //load_library_a
+            0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11, //get_proc_address
+            0xCC, 0xBB, 0xAA, 0x99, //image_size
+            0x01, 0x00, 0x00, 0x00, //options
+        ];
+        let status = offset_of!(LoaderParams, status);
+        assert_eq!(bytes[..status], expected);
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        assert_eq!(
+            u32_at(offset_of!(LoaderParams, version)),
+            LOADER_PARAMS_VERSION
+        );
+        assert_eq!(u32_at(offset_of!(LoaderParams, size)), bytes.len() as u32);
+        assert_eq!(
+            u64_at(offset_of!(LoaderParams, image_base)),
+            params.image_base
+        );
+        assert_eq!(
+            u64_at(offset_of!(LoaderParams, load_library_a)),
+            params.load_library_a
+        );
+        assert_eq!(
+            u64_at(offset_of!(LoaderParams, get_proc_address)),
+            params.get_proc_address
+        );
+        assert_eq!(
+            u32_at(offset_of!(LoaderParams, image_size)),
+            params.image_size
+        );
+        assert_eq!(u32_at(offset_of!(LoaderParams, options)), params.options);
+
+        //the stub reports into a zeroed status
+        assert_eq!(bytes[status..], [0u8; size_of::<LoaderStatus>()]);
+        assert_eq!(
+            LoaderStatus::parse(bytes[status..].try_into().unwrap()),
+            LoaderStatus::default()
+        );
+        assert_eq!(
+            LoaderParams::status_address(0x10000),
+            0x10000 + status as u64
+        );
+    }
+
+    #[test]
+    fn parse_reads_the_status_the_stub_wrote() {
+        let status = LoaderStatus {
+            stage: LoaderStage::Imports.value(),
+            finished: 1,
+            error: LOADER_IMPORT_NOT_FOUND,
+            dllmain_return: 0xDDDD,
+            module_rva: 0x3040,
+            reserved: 0xEEEE,
+            import_thunk: 0x8000_0000_0000_0007,
+        };
+        let mut block = [0u8; size_of::<LoaderStatus>()];
+        let mut put = |offset: usize, value: &[u8]| {
+            block[offset..offset + value.len()].copy_from_slice(value);
+        };
+        put(offset_of!(LoaderStatus, stage), &status.stage.to_le_bytes());
+        put(
+            offset_of!(LoaderStatus, finished),
+            &status.finished.to_le_bytes(),
+        );
+        put(offset_of!(LoaderStatus, error), &status.error.to_le_bytes());
+        put(
+            offset_of!(LoaderStatus, dllmain_return),
+            &status.dllmain_return.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderStatus, module_rva),
+            &status.module_rva.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderStatus, reserved),
+            &status.reserved.to_le_bytes(),
+        );
+        put(
+            offset_of!(LoaderStatus, import_thunk),
+            &status.import_thunk.to_le_bytes(),
+        );
+
+        let parsed = LoaderStatus::parse(&block);
+        assert_eq!(parsed, status);
+        assert_eq!(parsed.stage(), LoaderStage::Imports);
+        assert!(parsed.finished());
+    }
+
+    #[test]
+    fn failure_reads_ordinal_imports_from_the_thunk() {
+        assert_eq!(
+            failed_import_target(1 << 63 | 7, Architecture::X64),
+            Some(ImportTarget::Ordinal(7))
+        );
+        assert_eq!(
+            failed_import_target(0x8000_0010, Architecture::X86),
+            Some(ImportTarget::Ordinal(16))
+        );
+    }
+
+    #[test]
+    fn failure_reads_named_imports_from_the_image() {
+        for architecture in [Architecture::X86, Architecture::X64] {
+            assert_eq!(
+                failed_import_target(0x2010, architecture),
+                Some(ImportTarget::Name {
+                    hint: 0x2010,
+                    name: "name@0x2012".to_string()
+                })
+            );
+        }
+        //the x86 ordinal flag is just a high rva bit to x64
+        assert_eq!(
+            failed_import_target(0x8000_0010, Architecture::X64),
+            Some(ImportTarget::Name {
+                hint: 0x0010,
+                name: "name@0x80000012".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn failure_gives_up_on_names_past_the_end_of_the_address_space() {
+        //only reachable on x64, x86 takes a thunk that high for an ordinal
+        for import_thunk in [0xFFFF_FFFE, 0xFFFF_FFFF, 0x1_FFFF_FFFE] {
+            assert_eq!(failed_import_target(import_thunk, Architecture::X64), None);
+        }
+        assert_eq!(
+            failed_import_target(0xFFFF_FFFE, Architecture::X86),
+            Some(ImportTarget::Ordinal(0xFFFE))
+        );
+    }
+
+    #[test]
+    fn failure_only_names_modules_and_imports_when_they_failed() {
+        let status = LoaderStatus {
+            error: LOADER_OK,
+            ..failed_import(0x2010)
+        };
+        assert_eq!(status.failure(&AnyRva, Architecture::X64), None);
+
+        let status = LoaderStatus {
+            stage: LoaderStage::DllMain.value(),
+            error: LOADER_DLLMAIN_FAILED,
+            ..failed_import(0x2010)
+        };
+        let failure = status.failure(&AnyRva, Architecture::X64).unwrap();
+        assert_eq!(failure.module, None);
+        assert_eq!(failure.import, None);
+
+        let status = LoaderStatus {
+            error: LOADER_MODULE_NOT_FOUND,
+            ..failed_import(0x2010)
+        };
+        let failure = status.failure(&AnyRva, Architecture::X64).unwrap();
+        assert_eq!(failure.module.as_deref(), Some("name@0x3000"));
+        assert_eq!(failure.import, None);
+    }
+}